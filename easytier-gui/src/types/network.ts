@@ -87,6 +87,7 @@ export interface NetworkInstanceRunningInfo {
 
 export interface NodeInfo {
   virtual_ipv4: string
+  ipv4_conflict: boolean
   hostname: string
   version: string
   ips: {
@@ -128,6 +129,7 @@ export interface Route {
   stun_info?: StunInfo
   inst_id: string
   version: string
+  ipv4_conflict?: boolean
 }
 
 export interface PeerInfo {
@@ -1,5 +1,5 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     path::PathBuf,
     sync::{Arc, Mutex},
 };
@@ -7,7 +7,13 @@ use std::{
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 
-use crate::tunnel::generate_digest_from_str;
+use crate::{common::PeerId, tunnel::generate_digest_from_str};
+
+// all overlay ipv6 addresses of a network are in one /64.
+pub const IPV6_OVERLAY_PREFIX_LEN: u8 = 64;
+
+// prefix length of the virtual ipv4 network if neither configured nor learned from peers.
+pub const DEFAULT_NETWORK_LENGTH: u8 = 24;
 
 #[auto_impl::auto_impl(Box, &)]
 pub trait ConfigLoader: Send + Sync {
@@ -26,12 +32,26 @@ pub trait ConfigLoader: Send + Sync {
     fn get_ipv4(&self) -> Option<std::net::Ipv4Addr>;
     fn set_ipv4(&self, addr: Option<std::net::Ipv4Addr>);
 
+    fn get_ipv6(&self) -> Option<std::net::Ipv6Addr>;
+    fn set_ipv6(&self, addr: Option<std::net::Ipv6Addr>);
+
+    fn get_network_length(&self) -> Option<u8>;
+    fn set_network_length(&self, len: Option<u8>);
+
     fn get_dhcp(&self) -> bool;
     fn set_dhcp(&self, dhcp: bool);
 
+    fn get_dhcp_config(&self) -> DhcpConfig;
+    fn set_dhcp_config(&self, config: DhcpConfig);
+
+    fn get_dns_config(&self) -> Option<DnsConfig>;
+    fn set_dns_config(&self, config: Option<DnsConfig>);
+
     fn add_proxy_cidr(&self, cidr: cidr::IpCidr);
     fn remove_proxy_cidr(&self, cidr: cidr::IpCidr);
     fn get_proxy_cidrs(&self) -> Vec<cidr::IpCidr>;
+    fn add_netmap(&self, netmap: Netmap);
+    fn get_netmaps(&self) -> Vec<Netmap>;
 
     fn get_network_identity(&self) -> NetworkIdentity;
     fn set_network_identity(&self, identity: NetworkIdentity);
@@ -61,12 +81,30 @@ pub trait ConfigLoader: Send + Sync {
     fn get_exit_nodes(&self) -> Vec<Ipv4Addr>;
     fn set_exit_nodes(&self, nodes: Vec<Ipv4Addr>);
 
+    fn get_exit_policy(&self) -> Vec<ExitPolicyRule>;
+    fn set_exit_policy(&self, rules: Vec<ExitPolicyRule>);
+
+    fn get_tags(&self) -> Vec<String>;
+    fn set_tags(&self, tags: Vec<String>);
+
+    fn get_segment_rules(&self) -> Vec<SegmentRule>;
+    fn set_segment_rules(&self, rules: Vec<SegmentRule>);
+
+    fn get_multicast_allowlist(&self) -> Option<Vec<cidr::Ipv4Cidr>>;
+    fn set_multicast_allowlist(&self, groups: Option<Vec<cidr::Ipv4Cidr>>);
+
     fn get_routes(&self) -> Option<Vec<cidr::Ipv4Cidr>>;
     fn set_routes(&self, routes: Option<Vec<cidr::Ipv4Cidr>>);
 
     fn get_socks5_portal(&self) -> Option<url::Url>;
     fn set_socks5_portal(&self, addr: Option<url::Url>);
 
+    fn get_http_proxy_portal(&self) -> Option<url::Url>;
+    fn set_http_proxy_portal(&self, addr: Option<url::Url>);
+
+    fn get_port_forwards(&self) -> Vec<PortForwardConfig>;
+    fn set_port_forwards(&self, rules: Vec<PortForwardConfig>);
+
     fn dump(&self) -> String;
 }
 
@@ -119,6 +157,19 @@ impl NetworkIdentity {
     pub fn default() -> Self {
         Self::new("default".to_string(), "".to_string())
     }
+
+    // an unique local address (rfc 4193), the global id is hashed from the network name
+    // and the interface id is the peer id.
+    pub fn derive_ipv6_addr(&self, peer_id: PeerId) -> Ipv6Addr {
+        let mut global_id = [0u8; 8];
+        generate_digest_from_str(&self.network_name, "", &mut global_id);
+
+        let mut octets = [0u8; 16];
+        octets[0] = 0xfd;
+        octets[1..6].copy_from_slice(&global_id[..5]);
+        octets[12..16].copy_from_slice(&peer_id.to_be_bytes());
+        Ipv6Addr::from(octets)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -129,9 +180,74 @@ pub struct PeerConfig {
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct NetworkConfig {
     pub cidr: String,
+    // advertised instead of cidr, addresses are translated 1:1 between the two
+    pub mapped_cidr: Option<String>,
     pub allow: Option<Vec<String>>,
 }
 
+// 1:1 prefix mapping for a proxied network, so sites with overlapping lans can each be
+// reached through a unique cidr.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Netmap {
+    pub real: cidr::Ipv4Cidr,
+    pub mapped: cidr::Ipv4Cidr,
+}
+
+impl Netmap {
+    pub fn new(real: cidr::Ipv4Cidr, mapped: cidr::Ipv4Cidr) -> Result<Self, anyhow::Error> {
+        if real.network_length() != mapped.network_length() {
+            return Err(anyhow::anyhow!(
+                "netmap cidrs must have the same length: {} {}",
+                real,
+                mapped
+            ));
+        }
+        Ok(Netmap { real, mapped })
+    }
+
+    fn translate(ip: Ipv4Addr, from: &cidr::Ipv4Cidr, to: &cidr::Ipv4Cidr) -> Option<Ipv4Addr> {
+        if !from.contains(&ip) {
+            return None;
+        }
+        let host_mask = !u32::from(from.mask());
+        Some(Ipv4Addr::from(
+            u32::from(to.first_address()) | (u32::from(ip) & host_mask),
+        ))
+    }
+
+    pub fn to_real(&self, ip: Ipv4Addr) -> Option<Ipv4Addr> {
+        Self::translate(ip, &self.mapped, &self.real)
+    }
+
+    pub fn to_mapped(&self, ip: Ipv4Addr) -> Option<Ipv4Addr> {
+        Self::translate(ip, &self.real, &self.mapped)
+    }
+}
+
+// format: <real cidr>-><mapped cidr>
+impl std::str::FromStr for Netmap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((real, mapped)) = s.split_once("->") else {
+            return Err(anyhow::anyhow!("invalid netmap: {}", s));
+        };
+        Netmap::new(
+            real.trim().parse().with_context(|| "invalid real cidr")?,
+            mapped
+                .trim()
+                .parse()
+                .with_context(|| "invalid mapped cidr")?,
+        )
+    }
+}
+
+impl std::fmt::Display for Netmap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}->{}", self.real, self.mapped)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Default)]
 pub struct FileLoggerConfig {
     pub level: Option<String>,
@@ -150,6 +266,359 @@ pub struct VpnPortalConfig {
     pub wireguard_listen: SocketAddr,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Default)]
+pub struct DhcpConfig {
+    // first and last address handed out, the whole virtual network by default
+    pub pool_start: Option<Ipv4Addr>,
+    pub pool_end: Option<Ipv4Addr>,
+    pub exclude: Option<Vec<cidr::Ipv4Cidr>>,
+    pub reservations: Option<Vec<DhcpReservation>>,
+    // the lease is saved to this file so it survives restarts
+    pub lease_file: Option<PathBuf>,
+}
+
+// overlay dns, answers <hostname>.<network name>.<suffix> for every node in the network
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Default)]
+pub struct DnsConfig {
+    // the virtual ip on port 53 if not set. an address outside the virtual network (a magic
+    // address like 100.100.100.101:53) is added to the tun device.
+    pub listen: Option<SocketAddr>,
+    // "et" if not set
+    pub suffix: Option<String>,
+    // where other queries go, the nameservers in /etc/resolv.conf if not set
+    pub upstream: Option<Vec<SocketAddr>>,
+    // linux only, let systemd-resolved send queries for the overlay domain to us
+    #[serde(default)]
+    pub register_resolved: bool,
+}
+
+// a fixed address for the node whose hostname or instance id equals owner
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct DhcpReservation {
+    pub owner: String,
+    pub ipv4: Ipv4Addr,
+}
+
+// format: <hostname|instance id>=10.126.126.5
+impl std::str::FromStr for DhcpReservation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((owner, ipv4)) = s.rsplit_once('=') else {
+            return Err(anyhow::anyhow!("invalid dhcp reservation: {}", s));
+        };
+        Ok(DhcpReservation {
+            owner: owner.trim().to_string(),
+            ipv4: ipv4
+                .trim()
+                .parse()
+                .with_context(|| "invalid reserved ipv4")?,
+        })
+    }
+}
+
+impl std::fmt::Display for DhcpReservation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.owner, self.ipv4)
+    }
+}
+
+// a policy rule selects the exit node for traffic to destinations without a route.
+// the rule with the longest matching dst_cidr wins, ties are broken by config order.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ExitPolicyRule {
+    pub dst_cidr: cidr::Ipv4Cidr,
+    pub src_cidr: Option<cidr::Ipv4Cidr>,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    // virtual ip of the exit node, none means the traffic is not sent to any exit node.
+    pub exit_node: Option<Ipv4Addr>,
+}
+
+impl ExitPolicyRule {
+    pub fn matches(
+        &self,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        src_port: Option<u16>,
+        dst_port: Option<u16>,
+    ) -> bool {
+        if !self.dst_cidr.contains(&dst) {
+            return false;
+        }
+        if let Some(src_cidr) = &self.src_cidr {
+            if !src_cidr.contains(&src) {
+                return false;
+            }
+        }
+        if self.src_port.is_some() && self.src_port != src_port {
+            return false;
+        }
+        if self.dst_port.is_some() && self.dst_port != dst_port {
+            return false;
+        }
+        true
+    }
+}
+
+// format: dst=10.20.0.0/16[,src=10.126.126.0/24][,sport=1234][,dport=443],via=10.126.126.2|direct
+impl std::str::FromStr for ExitPolicyRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut dst_cidr = None;
+        let mut exit_node = None;
+        let mut rule = ExitPolicyRule {
+            dst_cidr: cidr::Ipv4Cidr::new(Ipv4Addr::UNSPECIFIED, 0).unwrap(),
+            src_cidr: None,
+            src_port: None,
+            dst_port: None,
+            exit_node: None,
+        };
+
+        let mut seen_keys = Vec::new();
+        for item in s.split(',') {
+            let Some((key, value)) = item.trim().split_once('=') else {
+                return Err(anyhow::anyhow!("invalid exit policy item: {}", item));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if seen_keys.contains(&key) {
+                return Err(anyhow::anyhow!("duplicate exit policy key: {}", key));
+            }
+            seen_keys.push(key);
+            match key {
+                "dst" => dst_cidr = Some(value.parse().with_context(|| "invalid dst cidr")?),
+                "src" => rule.src_cidr = Some(value.parse().with_context(|| "invalid src cidr")?),
+                "sport" => rule.src_port = Some(value.parse().with_context(|| "invalid sport")?),
+                "dport" => rule.dst_port = Some(value.parse().with_context(|| "invalid dport")?),
+                "via" if value == "direct" => exit_node = Some(None),
+                "via" => {
+                    exit_node = Some(Some(value.parse().with_context(|| "invalid exit node")?))
+                }
+                _ => return Err(anyhow::anyhow!("unknown exit policy key: {}", key)),
+            }
+        }
+
+        rule.dst_cidr = dst_cidr.ok_or(anyhow::anyhow!("exit policy requires dst"))?;
+        // a typo must not silently turn a rule into a direct one
+        rule.exit_node = exit_node.ok_or(anyhow::anyhow!("exit policy requires via"))?;
+        Ok(rule)
+    }
+}
+
+impl std::fmt::Display for ExitPolicyRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "dst={}", self.dst_cidr)?;
+        if let Some(src_cidr) = &self.src_cidr {
+            write!(f, ",src={}", src_cidr)?;
+        }
+        if let Some(src_port) = self.src_port {
+            write!(f, ",sport={}", src_port)?;
+        }
+        if let Some(dst_port) = self.dst_port {
+            write!(f, ",dport={}", dst_port)?;
+        }
+        match self.exit_node {
+            Some(exit_node) => write!(f, ",via={}", exit_node),
+            None => write!(f, ",via=direct"),
+        }
+    }
+}
+
+impl From<ExitPolicyRule> for crate::rpc::ExitPolicyRulePb {
+    fn from(rule: ExitPolicyRule) -> Self {
+        Self {
+            dst_cidr: rule.dst_cidr.to_string(),
+            src_cidr: rule.src_cidr.map(|x| x.to_string()).unwrap_or_default(),
+            src_port: rule.src_port.unwrap_or(0) as u32,
+            dst_port: rule.dst_port.unwrap_or(0) as u32,
+            exit_node: rule.exit_node.map(|x| x.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+impl TryFrom<crate::rpc::ExitPolicyRulePb> for ExitPolicyRule {
+    type Error = anyhow::Error;
+
+    fn try_from(rule: crate::rpc::ExitPolicyRulePb) -> Result<Self, Self::Error> {
+        let port = |p: u32| -> Result<Option<u16>, Self::Error> {
+            match p {
+                0 => Ok(None),
+                p => Ok(Some(u16::try_from(p).with_context(|| "invalid port")?)),
+            }
+        };
+        Ok(Self {
+            dst_cidr: rule.dst_cidr.parse().with_context(|| "invalid dst cidr")?,
+            src_cidr: match rule.src_cidr.as_str() {
+                "" => None,
+                s => Some(s.parse().with_context(|| "invalid src cidr")?),
+            },
+            src_port: port(rule.src_port)?,
+            dst_port: port(rule.dst_port)?,
+            exit_node: match rule.exit_node.as_str() {
+                "" => None,
+                s => Some(s.parse().with_context(|| "invalid exit node")?),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum PortForwardProto {
+    Tcp,
+    Udp,
+}
+
+// forwards connections accepted on bind_addr to dst_addr. with bind_virtual the rule listens on
+// the port of bind_addr on the virtual ip, so a local service can be exposed to the network,
+// otherwise it listens locally and dst_addr is usually an address in the virtual network.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct PortForwardConfig {
+    pub proto: PortForwardProto,
+    pub bind_addr: SocketAddr,
+    pub dst_addr: SocketAddr,
+    #[serde(default)]
+    pub bind_virtual: bool,
+}
+
+// format: tcp://0.0.0.0:8080/10.126.126.5:80 or udp://virtual:5353/127.0.0.1:53
+impl std::str::FromStr for PortForwardConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((proto, rest)) = s.split_once("://") else {
+            return Err(anyhow::anyhow!("port forward requires a protocol: {}", s));
+        };
+        let proto = match proto {
+            "tcp" => PortForwardProto::Tcp,
+            "udp" => PortForwardProto::Udp,
+            _ => return Err(anyhow::anyhow!("unknown port forward protocol: {}", proto)),
+        };
+        let Some((bind, dst)) = rest.split_once('/') else {
+            return Err(anyhow::anyhow!(
+                "port forward requires a destination: {}",
+                s
+            ));
+        };
+
+        let (bind_addr, bind_virtual) = match bind.strip_prefix("virtual:") {
+            Some(port) => {
+                let port = port.parse().with_context(|| "invalid bind port")?;
+                (SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port), true)
+            }
+            None => (bind.parse().with_context(|| "invalid bind addr")?, false),
+        };
+
+        Ok(Self {
+            proto,
+            bind_addr,
+            dst_addr: dst.parse().with_context(|| "invalid dst addr")?,
+            bind_virtual,
+        })
+    }
+}
+
+impl std::fmt::Display for PortForwardConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let proto = match self.proto {
+            PortForwardProto::Tcp => "tcp",
+            PortForwardProto::Udp => "udp",
+        };
+        if self.bind_virtual {
+            write!(
+                f,
+                "{}://virtual:{}/{}",
+                proto,
+                self.bind_addr.port(),
+                self.dst_addr
+            )
+        } else {
+            write!(f, "{}://{}/{}", proto, self.bind_addr, self.dst_addr)
+        }
+    }
+}
+
+impl From<PortForwardConfig> for crate::rpc::PortForwardPb {
+    fn from(rule: PortForwardConfig) -> Self {
+        Self {
+            proto: match rule.proto {
+                PortForwardProto::Tcp => "tcp".to_string(),
+                PortForwardProto::Udp => "udp".to_string(),
+            },
+            bind_addr: rule.bind_addr.to_string(),
+            dst_addr: rule.dst_addr.to_string(),
+            bind_virtual: rule.bind_virtual,
+        }
+    }
+}
+
+impl TryFrom<crate::rpc::PortForwardPb> for PortForwardConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(rule: crate::rpc::PortForwardPb) -> Result<Self, Self::Error> {
+        Ok(Self {
+            proto: match rule.proto.as_str() {
+                "tcp" => PortForwardProto::Tcp,
+                "udp" => PortForwardProto::Udp,
+                _ => return Err(anyhow::anyhow!("unknown port forward protocol")),
+            },
+            bind_addr: rule
+                .bind_addr
+                .parse()
+                .with_context(|| "invalid bind addr")?,
+            dst_addr: rule.dst_addr.parse().with_context(|| "invalid dst addr")?,
+            bind_virtual: rule.bind_virtual,
+        })
+    }
+}
+
+// nodes carrying tag `a` may exchange traffic with nodes carrying tag `b`, `*` matches any node.
+// without any rule all nodes may talk to each other.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct SegmentRule {
+    pub a: String,
+    pub b: String,
+}
+
+impl SegmentRule {
+    fn has_tag(tags: &[String], tag: &str) -> bool {
+        tag == "*" || tags.iter().any(|t| t == tag)
+    }
+
+    pub fn matches(&self, my_tags: &[String], peer_tags: &[String]) -> bool {
+        (Self::has_tag(my_tags, &self.a) && Self::has_tag(peer_tags, &self.b))
+            || (Self::has_tag(my_tags, &self.b) && Self::has_tag(peer_tags, &self.a))
+    }
+
+    pub fn is_allowed(rules: &[SegmentRule], my_tags: &[String], peer_tags: &[String]) -> bool {
+        rules.is_empty() || rules.iter().any(|r| r.matches(my_tags, peer_tags))
+    }
+}
+
+// format: role=server<->team=qa, a single tag allows traffic inside that group
+impl std::str::FromStr for SegmentRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (a, b) = s.split_once("<->").unwrap_or((s, s));
+        let (a, b) = (a.trim(), b.trim());
+        if a.is_empty() || b.is_empty() {
+            return Err(anyhow::anyhow!("invalid segment rule: {}", s));
+        }
+        Ok(SegmentRule {
+            a: a.to_string(),
+            b: b.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for SegmentRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}<->{}", self.a, self.b)
+    }
+}
+
 // Flags is used to control the behavior of the program
 #[derive(derivative::Derivative, Deserialize, Serialize)]
 #[derivative(Debug, Clone, PartialEq, Default)]
@@ -180,6 +649,32 @@ pub struct Flags {
     pub relay_all_peer_rpc: bool,
     #[derivative(Default(value = "false"))]
     pub disable_udp_hole_punching: bool,
+    #[derivative(Default(value = "false"))]
+    pub disable_tcp_hole_punching: bool,
+    #[derivative(Default(value = "false"))]
+    pub disable_port_mapping: bool,
+    #[derivative(Default(value = "false"))]
+    pub enable_lan_discovery: bool,
+    #[derivative(Default(value = "0"))]
+    pub stun_server_port: u16,
+    // host:port an exit node connects to when probed, empty if its upstream is not checked
+    #[derivative(Default(value = "\"\".to_string()"))]
+    pub exit_node_probe_target: String,
+    // probe the exit nodes we use and skip the unhealthy ones
+    #[derivative(Default(value = "false"))]
+    pub enable_exit_node_health_check: bool,
+    #[derivative(Default(value = "false"))]
+    pub exit_node_load_balance: bool,
+    #[derivative(Default(value = "false"))]
+    pub auto_ipv6: bool,
+    #[derivative(Default(value = "false"))]
+    pub enable_ecmp: bool,
+    #[derivative(Default(value = "10"))]
+    pub ecmp_latency_tolerance: u32,
+    #[derivative(Default(value = "false"))]
+    pub disable_relay_for_denied_segments: bool,
+    #[derivative(Default(value = "false"))]
+    pub no_tun_on_ipv4_conflict: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -189,10 +684,18 @@ struct Config {
     instance_name: Option<String>,
     instance_id: Option<uuid::Uuid>,
     ipv4: Option<String>,
+    ipv6: Option<String>,
+    network_length: Option<u8>,
     dhcp: Option<bool>,
+    dhcp_config: Option<DhcpConfig>,
+    dns: Option<DnsConfig>,
     network_identity: Option<NetworkIdentity>,
     listeners: Option<Vec<url::Url>>,
     exit_nodes: Option<Vec<Ipv4Addr>>,
+    exit_policy: Option<Vec<ExitPolicyRule>>,
+    tags: Option<Vec<String>>,
+    segment_rules: Option<Vec<SegmentRule>>,
+    multicast_allowlist: Option<Vec<cidr::Ipv4Cidr>>,
 
     peer: Option<Vec<PeerConfig>>,
     proxy_network: Option<Vec<NetworkConfig>>,
@@ -207,6 +710,9 @@ struct Config {
     routes: Option<Vec<cidr::Ipv4Cidr>>,
 
     socks5_proxy: Option<url::Url>,
+    http_proxy: Option<url::Url>,
+
+    port_forward: Option<Vec<PortForwardConfig>>,
 
     flags: Option<Flags>,
 }
@@ -316,6 +822,23 @@ impl ConfigLoader for TomlConfigLoader {
         };
     }
 
+    fn get_ipv6(&self) -> Option<std::net::Ipv6Addr> {
+        let locked_config = self.config.lock().unwrap();
+        locked_config.ipv6.as_ref().and_then(|s| s.parse().ok())
+    }
+
+    fn set_ipv6(&self, addr: Option<std::net::Ipv6Addr>) {
+        self.config.lock().unwrap().ipv6 = addr.map(|addr| addr.to_string());
+    }
+
+    fn get_network_length(&self) -> Option<u8> {
+        self.config.lock().unwrap().network_length
+    }
+
+    fn set_network_length(&self, len: Option<u8>) {
+        self.config.lock().unwrap().network_length = len;
+    }
+
     fn get_dhcp(&self) -> bool {
         self.config.lock().unwrap().dhcp.unwrap_or_default()
     }
@@ -324,6 +847,27 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().dhcp = Some(dhcp);
     }
 
+    fn get_dhcp_config(&self) -> DhcpConfig {
+        self.config
+            .lock()
+            .unwrap()
+            .dhcp_config
+            .clone()
+            .unwrap_or_default()
+    }
+
+    fn set_dhcp_config(&self, config: DhcpConfig) {
+        self.config.lock().unwrap().dhcp_config = Some(config);
+    }
+
+    fn get_dns_config(&self) -> Option<DnsConfig> {
+        self.config.lock().unwrap().dns.clone()
+    }
+
+    fn set_dns_config(&self, config: Option<DnsConfig>) {
+        self.config.lock().unwrap().dns = config;
+    }
+
     fn add_proxy_cidr(&self, cidr: cidr::IpCidr) {
         let mut locked_config = self.config.lock().unwrap();
         if locked_config.proxy_network.is_none() {
@@ -344,6 +888,7 @@ impl ConfigLoader for TomlConfigLoader {
                 .unwrap()
                 .push(NetworkConfig {
                     cidr: cidr_str,
+                    mapped_cidr: None,
                     allow: None,
                 });
         }
@@ -353,10 +898,11 @@ impl ConfigLoader for TomlConfigLoader {
         let mut locked_config = self.config.lock().unwrap();
         if let Some(proxy_cidrs) = &mut locked_config.proxy_network {
             let cidr_str = cidr.to_string();
-            proxy_cidrs.retain(|c| c.cidr != cidr_str);
+            proxy_cidrs.retain(|c| c.cidr != cidr_str && c.mapped_cidr.as_ref() != Some(&cidr_str));
         }
     }
 
+    // the advertised cidrs, mapped ones replace their real cidr
     fn get_proxy_cidrs(&self) -> Vec<cidr::IpCidr> {
         self.config
             .lock()
@@ -365,12 +911,37 @@ impl ConfigLoader for TomlConfigLoader {
             .as_ref()
             .map(|v| {
                 v.iter()
-                    .map(|c| c.cidr.parse().unwrap())
+                    .map(|c| c.mapped_cidr.as_ref().unwrap_or(&c.cidr).parse().unwrap())
                     .collect::<Vec<cidr::IpCidr>>()
             })
             .unwrap_or_default()
     }
 
+    fn add_netmap(&self, netmap: Netmap) {
+        let mut locked_config = self.config.lock().unwrap();
+        let proxy_network = locked_config.proxy_network.get_or_insert_with(Vec::new);
+        let mapped_str = netmap.mapped.to_string();
+        proxy_network.retain(|c| c.mapped_cidr.as_ref() != Some(&mapped_str));
+        proxy_network.push(NetworkConfig {
+            cidr: netmap.real.to_string(),
+            mapped_cidr: Some(mapped_str),
+            allow: None,
+        });
+    }
+
+    fn get_netmaps(&self) -> Vec<Netmap> {
+        self.config
+            .lock()
+            .unwrap()
+            .proxy_network
+            .iter()
+            .flatten()
+            .filter_map(|c| {
+                Netmap::new(c.cidr.parse().ok()?, c.mapped_cidr.as_ref()?.parse().ok()?).ok()
+            })
+            .collect()
+    }
+
     fn get_id(&self) -> uuid::Uuid {
         let mut locked_config = self.config.lock().unwrap();
         if locked_config.instance_id.is_none() {
@@ -496,6 +1067,48 @@ impl ConfigLoader for TomlConfigLoader {
         self.config.lock().unwrap().exit_nodes = Some(nodes);
     }
 
+    fn get_exit_policy(&self) -> Vec<ExitPolicyRule> {
+        self.config
+            .lock()
+            .unwrap()
+            .exit_policy
+            .clone()
+            .unwrap_or_default()
+    }
+
+    fn set_exit_policy(&self, rules: Vec<ExitPolicyRule>) {
+        self.config.lock().unwrap().exit_policy = Some(rules);
+    }
+
+    fn get_tags(&self) -> Vec<String> {
+        self.config.lock().unwrap().tags.clone().unwrap_or_default()
+    }
+
+    fn set_tags(&self, tags: Vec<String>) {
+        self.config.lock().unwrap().tags = Some(tags);
+    }
+
+    fn get_segment_rules(&self) -> Vec<SegmentRule> {
+        self.config
+            .lock()
+            .unwrap()
+            .segment_rules
+            .clone()
+            .unwrap_or_default()
+    }
+
+    fn set_segment_rules(&self, rules: Vec<SegmentRule>) {
+        self.config.lock().unwrap().segment_rules = Some(rules);
+    }
+
+    fn get_multicast_allowlist(&self) -> Option<Vec<cidr::Ipv4Cidr>> {
+        self.config.lock().unwrap().multicast_allowlist.clone()
+    }
+
+    fn set_multicast_allowlist(&self, groups: Option<Vec<cidr::Ipv4Cidr>>) {
+        self.config.lock().unwrap().multicast_allowlist = groups;
+    }
+
     fn dump(&self) -> String {
         toml::to_string_pretty(&*self.config.lock().unwrap()).unwrap()
     }
@@ -515,6 +1128,27 @@ impl ConfigLoader for TomlConfigLoader {
     fn set_socks5_portal(&self, addr: Option<url::Url>) {
         self.config.lock().unwrap().socks5_proxy = addr;
     }
+
+    fn get_http_proxy_portal(&self) -> Option<url::Url> {
+        self.config.lock().unwrap().http_proxy.clone()
+    }
+
+    fn set_http_proxy_portal(&self, addr: Option<url::Url>) {
+        self.config.lock().unwrap().http_proxy = addr;
+    }
+
+    fn get_port_forwards(&self) -> Vec<PortForwardConfig> {
+        self.config
+            .lock()
+            .unwrap()
+            .port_forward
+            .clone()
+            .unwrap_or_default()
+    }
+
+    fn set_port_forwards(&self, rules: Vec<PortForwardConfig>) {
+        self.config.lock().unwrap().port_forward = Some(rules);
+    }
 }
 
 #[cfg(test)]
@@ -577,4 +1211,143 @@ level = "warn"
 
         println!("{}", ret.dump());
     }
+
+    #[test]
+    fn derive_ipv6_addr_test() {
+        let a = NetworkIdentity::new("net1".to_string(), "sec".to_string());
+        let b = NetworkIdentity::new("net2".to_string(), "sec".to_string());
+
+        let addr = a.derive_ipv6_addr(0x01020304);
+        assert_eq!(0xfd, addr.octets()[0]);
+        assert_eq!([1, 2, 3, 4], addr.octets()[12..16]);
+        assert_eq!(addr, a.derive_ipv6_addr(0x01020304));
+        assert_ne!(addr, a.derive_ipv6_addr(0x01020305));
+        assert_ne!(addr, b.derive_ipv6_addr(0x01020304));
+    }
+
+    #[test]
+    fn exit_policy_rule_test() {
+        let rule: ExitPolicyRule =
+            "dst=10.20.0.0/16,src=10.126.126.0/24,dport=443,via=10.126.126.2"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            "dst=10.20.0.0/16,src=10.126.126.0/24,dport=443,via=10.126.126.2",
+            rule.to_string()
+        );
+
+        let src = "10.126.126.5".parse().unwrap();
+        let dst = "10.20.3.4".parse().unwrap();
+        assert!(rule.matches(src, dst, Some(1234), Some(443)));
+        assert!(!rule.matches(src, dst, Some(1234), Some(80)));
+        assert!(!rule.matches(src, dst, None, None));
+        assert!(!rule.matches("10.0.0.1".parse().unwrap(), dst, None, Some(443)));
+
+        let pb: crate::rpc::ExitPolicyRulePb = rule.clone().into();
+        assert_eq!(rule, ExitPolicyRule::try_from(pb).unwrap());
+
+        let direct: ExitPolicyRule = "dst=0.0.0.0/0,via=direct".parse().unwrap();
+        assert_eq!(None, direct.exit_node);
+        assert!("via=10.126.126.2".parse::<ExitPolicyRule>().is_err());
+        assert!("dst=10.20.0.0/16".parse::<ExitPolicyRule>().is_err());
+        assert!("dst=10.20.0.0/16,vai=10.126.126.2"
+            .parse::<ExitPolicyRule>()
+            .is_err());
+        assert!("dst=10.20.0.0/16,via=direct,via=10.126.126.2"
+            .parse::<ExitPolicyRule>()
+            .is_err());
+    }
+
+    #[test]
+    fn port_forward_config_test() {
+        let rule: PortForwardConfig = "tcp://0.0.0.0:8080/10.126.126.5:80".parse().unwrap();
+        assert_eq!(PortForwardProto::Tcp, rule.proto);
+        assert!(!rule.bind_virtual);
+        assert_eq!("tcp://0.0.0.0:8080/10.126.126.5:80", rule.to_string());
+
+        let rule: PortForwardConfig = "udp://virtual:15432/127.0.0.1:5432".parse().unwrap();
+        assert!(rule.bind_virtual);
+        assert_eq!(15432, rule.bind_addr.port());
+        assert_eq!("udp://virtual:15432/127.0.0.1:5432", rule.to_string());
+
+        let pb: crate::rpc::PortForwardPb = rule.into();
+        assert_eq!(rule, PortForwardConfig::try_from(pb).unwrap());
+
+        assert!("0.0.0.0:8080/10.126.126.5:80"
+            .parse::<PortForwardConfig>()
+            .is_err());
+        assert!("icmp://0.0.0.0:8080/10.126.126.5:80"
+            .parse::<PortForwardConfig>()
+            .is_err());
+
+        let config = TomlConfigLoader::new_from_str(
+            r#"
+[[port_forward]]
+proto = "udp"
+bind_addr = "0.0.0.0:5353"
+dst_addr = "10.126.126.5:53"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["udp://0.0.0.0:5353/10.126.126.5:53"
+                .parse::<PortForwardConfig>()
+                .unwrap()],
+            config.get_port_forwards()
+        );
+    }
+
+    #[test]
+    fn segment_rule_test() {
+        let rule: SegmentRule = "role=server<->team=qa".parse().unwrap();
+        assert_eq!("role=server<->team=qa", rule.to_string());
+
+        let server = vec!["role=server".to_string()];
+        let qa = vec!["team=qa".to_string(), "os=linux".to_string()];
+        let dev = vec!["team=dev".to_string()];
+        assert!(rule.matches(&server, &qa));
+        assert!(rule.matches(&qa, &server));
+        assert!(!rule.matches(&qa, &qa));
+        assert!(!rule.matches(&server, &dev));
+
+        let rules = vec![rule, "team=dev".parse().unwrap()];
+        assert!(SegmentRule::is_allowed(&rules, &dev, &dev));
+        assert!(!SegmentRule::is_allowed(&rules, &dev, &server));
+        assert!(SegmentRule::is_allowed(&[], &dev, &server));
+
+        let any: SegmentRule = "role=server<->*".parse().unwrap();
+        assert!(any.matches(&dev, &server));
+        assert!("<->team=qa".parse::<SegmentRule>().is_err());
+    }
+
+    #[test]
+    fn netmap_test() {
+        let netmap: Netmap = "192.168.1.0/24->10.200.1.0/24".parse().unwrap();
+        assert_eq!("192.168.1.0/24->10.200.1.0/24", netmap.to_string());
+        assert_eq!(
+            Some("192.168.1.20".parse().unwrap()),
+            netmap.to_real("10.200.1.20".parse().unwrap())
+        );
+        assert_eq!(
+            Some("10.200.1.20".parse().unwrap()),
+            netmap.to_mapped("192.168.1.20".parse().unwrap())
+        );
+        assert_eq!(None, netmap.to_real("10.200.2.20".parse().unwrap()));
+        assert!("192.168.1.0/24->10.200.0.0/16".parse::<Netmap>().is_err());
+
+        let config = TomlConfigLoader::default();
+        config.add_proxy_cidr("10.1.0.0/16".parse().unwrap());
+        config.add_netmap(netmap);
+        assert_eq!(
+            vec![
+                "10.1.0.0/16".parse::<cidr::IpCidr>().unwrap(),
+                "10.200.1.0/24".parse().unwrap()
+            ],
+            config.get_proxy_cidrs()
+        );
+        assert_eq!(vec![netmap], config.get_netmaps());
+
+        let config = TomlConfigLoader::new_from_str(&config.dump()).unwrap();
+        assert_eq!(vec![netmap], config.get_netmaps());
+    }
 }
@@ -22,3 +22,5 @@ macro_rules! set_global_var {
 define_global_var!(MANUAL_CONNECTOR_RECONNECT_INTERVAL_MS, u64, 1000);
 
 pub const UDP_HOLE_PUNCH_CONNECTOR_SERVICE_ID: u32 = 2;
+pub const TCP_HOLE_PUNCH_CONNECTOR_SERVICE_ID: u32 = 3;
+pub const EXIT_NODE_PROBE_SERVICE_ID: u32 = 4;
@@ -1,14 +1,17 @@
 use std::collections::hash_map::DefaultHasher;
 use std::{
+    collections::BTreeMap,
     hash::Hasher,
+    net::Ipv4Addr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use crate::rpc::PeerConnInfo;
 use crossbeam::atomic::AtomicCell;
 
 use super::{
-    config::{ConfigLoader, Flags},
+    config::{ConfigLoader, ExitPolicyRule, Flags, DEFAULT_NETWORK_LENGTH},
     netns::NetNS,
     network::IPCollector,
     stun::{StunInfoCollector, StunInfoCollectorTrait},
@@ -17,6 +20,10 @@ use super::{
 
 pub type NetworkIdentity = crate::common::config::NetworkIdentity;
 
+// a membership not refreshed by a report within this time has expired, the igmp group
+// membership interval of rfc 2236
+pub const MULTICAST_GROUP_MEMBERSHIP_INTERVAL: Duration = Duration::from_secs(260);
+
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum GlobalCtxEvent {
     TunDeviceReady(String),
@@ -41,6 +48,11 @@ pub enum GlobalCtxEvent {
 
     DhcpIpv4Changed(Option<std::net::Ipv4Addr>, Option<std::net::Ipv4Addr>), // (old, new)
     DhcpIpv4Conflicted(Option<std::net::Ipv4Addr>),
+
+    Ipv4Conflicted(std::net::Ipv4Addr, Vec<PeerId>), // (static ipv4, peers using it)
+
+    NetworkLengthChanged(u8, u8),               // (old, new)
+    NetworkLengthConflicted(Vec<(PeerId, u8)>), // (peer, configured length)
 }
 
 type EventBus = tokio::sync::broadcast::Sender<GlobalCtxEvent>;
@@ -56,7 +68,22 @@ pub struct GlobalCtx {
     event_bus: EventBus,
 
     cached_ipv4: AtomicCell<Option<std::net::Ipv4Addr>>,
+    cached_ipv6: AtomicCell<Option<std::net::Ipv6Addr>>,
+    // network length advertised by peers, used when not configured locally
+    learned_network_length: AtomicCell<Option<u8>>,
     cached_proxy_cidrs: AtomicCell<Option<Vec<cidr::IpCidr>>>,
+    // sorted by dst prefix length, longest first
+    cached_exit_policy: AtomicCell<Option<Arc<Vec<ExitPolicyRule>>>>,
+    // unix time the dhcp address was leased, older leases win conflicts
+    dhcp_lease_time: AtomicCell<u64>,
+    // port of the stun server we run, zero until it is started
+    stun_server_port: AtomicCell<u16>,
+    // udp hole punching found a global ipv6 to punch with
+    has_global_ipv6: AtomicCell<bool>,
+    // another peer holds the static ipv4 we want, we yield it
+    ipv4_conflicted: AtomicCell<bool>,
+    // unix time our tun device came up with the static ipv4, 0 if it is not up
+    ipv4_claim_time: AtomicCell<u64>,
 
     ip_collector: Arc<IPCollector>,
 
@@ -65,6 +92,11 @@ pub struct GlobalCtx {
     stun_info_collection: Box<dyn StunInfoCollectorTrait>,
 
     running_listeners: Mutex<Vec<url::Url>>,
+    // multicast groups joined by local hosts, learned by igmp snooping
+    // with the time of the last report
+    multicast_groups: Mutex<BTreeMap<Ipv4Addr, Instant>>,
+    // external addresses of running listeners, mapped on the gateway by upnp or pcp
+    mapped_listeners: Mutex<Vec<url::Url>>,
 
     enable_exit_node: bool,
     no_tun: bool,
@@ -78,6 +110,7 @@ impl std::fmt::Debug for GlobalCtx {
             .field("net_ns", &self.net_ns.name())
             .field("event_bus", &"EventBus")
             .field("ipv4", &self.cached_ipv4)
+            .field("ipv6", &self.cached_ipv6)
             .finish()
     }
 }
@@ -93,7 +126,8 @@ impl GlobalCtx {
 
         let (event_bus, _) = tokio::sync::broadcast::channel(1024);
 
-        let stun_info_collection = Arc::new(StunInfoCollector::new_with_default_servers());
+        let stun_info_collection =
+            Arc::new(StunInfoCollector::new_with_default_servers(net_ns.clone()));
 
         let enable_exit_node = config_fs.get_flags().enable_exit_node;
         let no_tun = config_fs.get_flags().no_tun;
@@ -107,7 +141,15 @@ impl GlobalCtx {
 
             event_bus,
             cached_ipv4: AtomicCell::new(None),
+            cached_ipv6: AtomicCell::new(None),
+            learned_network_length: AtomicCell::new(None),
             cached_proxy_cidrs: AtomicCell::new(None),
+            cached_exit_policy: AtomicCell::new(None),
+            dhcp_lease_time: AtomicCell::new(0),
+            stun_server_port: AtomicCell::new(0),
+            has_global_ipv6: AtomicCell::new(false),
+            ipv4_conflicted: AtomicCell::new(false),
+            ipv4_claim_time: AtomicCell::new(0),
 
             ip_collector: Arc::new(IPCollector::new(net_ns, stun_info_collection.clone())),
 
@@ -116,6 +158,8 @@ impl GlobalCtx {
             stun_info_collection: Box::new(stun_info_collection),
 
             running_listeners: Mutex::new(Vec::new()),
+            multicast_groups: Mutex::new(BTreeMap::new()),
+            mapped_listeners: Mutex::new(Vec::new()),
 
             enable_exit_node,
             no_tun,
@@ -148,6 +192,41 @@ impl GlobalCtx {
         self.cached_ipv4.store(None);
     }
 
+    pub fn get_ipv6(&self) -> Option<std::net::Ipv6Addr> {
+        if let Some(ret) = self.cached_ipv6.load() {
+            return Some(ret);
+        }
+        let addr = self.config.get_ipv6();
+        self.cached_ipv6.store(addr);
+        addr
+    }
+
+    pub fn set_ipv6(&self, addr: Option<std::net::Ipv6Addr>) {
+        self.config.set_ipv6(addr);
+        self.cached_ipv6.store(None);
+    }
+
+    pub fn get_network_length(&self) -> u8 {
+        self.config
+            .get_network_length()
+            .or(self.learned_network_length.load())
+            .unwrap_or(DEFAULT_NETWORK_LENGTH)
+    }
+
+    pub fn set_learned_network_length(&self, len: Option<u8>) {
+        let old = self.get_network_length();
+        self.learned_network_length.store(len);
+        let new = self.get_network_length();
+        if old != new {
+            self.issue_event(GlobalCtxEvent::NetworkLengthChanged(old, new));
+        }
+    }
+
+    pub fn get_ipv4_inet(&self) -> Option<cidr::Ipv4Inet> {
+        self.get_ipv4()
+            .and_then(|addr| cidr::Ipv4Inet::new(addr, self.get_network_length()).ok())
+    }
+
     pub fn add_proxy_cidr(&self, cidr: cidr::IpCidr) -> Result<(), std::io::Error> {
         self.config.add_proxy_cidr(cidr);
         self.cached_proxy_cidrs.store(None);
@@ -171,6 +250,25 @@ impl GlobalCtx {
         ret
     }
 
+    // rules in the order they are tried: longest dst prefix first, then config order
+    pub fn get_exit_policy(&self) -> Arc<Vec<ExitPolicyRule>> {
+        if let Some(rules) = self.cached_exit_policy.take() {
+            self.cached_exit_policy.store(Some(rules.clone()));
+            return rules;
+        }
+
+        let mut rules = self.config.get_exit_policy();
+        rules.sort_by_key(|r| std::cmp::Reverse(r.dst_cidr.network_length()));
+        let ret = Arc::new(rules);
+        self.cached_exit_policy.store(Some(ret.clone()));
+        ret
+    }
+
+    pub fn set_exit_policy(&self, rules: Vec<ExitPolicyRule>) {
+        self.config.set_exit_policy(rules);
+        self.cached_exit_policy.store(None);
+    }
+
     pub fn get_id(&self) -> uuid::Uuid {
         self.config.get_id()
     }
@@ -203,6 +301,70 @@ impl GlobalCtx {
         }
     }
 
+    pub fn get_dhcp_lease_time(&self) -> u64 {
+        self.dhcp_lease_time.load()
+    }
+
+    pub fn set_dhcp_lease_time(&self, lease_time: u64) {
+        self.dhcp_lease_time.store(lease_time);
+    }
+
+    pub fn get_stun_server_port(&self) -> u16 {
+        self.stun_server_port.load()
+    }
+
+    pub fn set_stun_server_port(&self, port: u16) {
+        self.stun_server_port.store(port);
+    }
+
+    pub fn has_global_ipv6(&self) -> bool {
+        self.has_global_ipv6.load()
+    }
+
+    pub fn set_has_global_ipv6(&self, has_global_ipv6: bool) {
+        self.has_global_ipv6.store(has_global_ipv6);
+    }
+
+    pub fn is_ipv4_conflicted(&self) -> bool {
+        self.ipv4_conflicted.load()
+    }
+
+    pub fn set_ipv4_conflicted(&self, conflicted: bool) {
+        self.ipv4_conflicted.store(conflicted);
+    }
+
+    pub fn get_ipv4_claim_time(&self) -> u64 {
+        self.ipv4_claim_time.load()
+    }
+
+    pub fn set_ipv4_claim_time(&self, claim_time: u64) {
+        self.ipv4_claim_time.store(claim_time);
+    }
+
+    // hosts may leave without a leave report, so memberships expire unless refreshed
+    pub fn get_multicast_groups(&self) -> Vec<Ipv4Addr> {
+        let mut groups = self.multicast_groups.lock().unwrap();
+        groups.retain(|_, last_report| last_report.elapsed() < MULTICAST_GROUP_MEMBERSHIP_INTERVAL);
+        groups.keys().copied().collect()
+    }
+
+    // returns true if the group is new
+    pub fn join_multicast_group(&self, group: Ipv4Addr) -> bool {
+        self.multicast_groups
+            .lock()
+            .unwrap()
+            .insert(group, Instant::now())
+            .is_none()
+    }
+
+    pub fn leave_multicast_group(&self, group: Ipv4Addr) -> bool {
+        self.multicast_groups
+            .lock()
+            .unwrap()
+            .remove(&group)
+            .is_some()
+    }
+
     pub fn get_running_listeners(&self) -> Vec<url::Url> {
         self.running_listeners.lock().unwrap().clone()
     }
@@ -211,6 +373,14 @@ impl GlobalCtx {
         self.running_listeners.lock().unwrap().push(url);
     }
 
+    pub fn get_mapped_listeners(&self) -> Vec<url::Url> {
+        self.mapped_listeners.lock().unwrap().clone()
+    }
+
+    pub fn set_mapped_listeners(&self, urls: Vec<url::Url>) {
+        *self.mapped_listeners.lock().unwrap() = urls;
+    }
+
     pub fn get_vpn_portal_cidr(&self) -> Option<cidr::Ipv4Cidr> {
         self.config.get_vpn_portal_config().map(|x| x.client_cidr)
     }
@@ -219,6 +389,16 @@ impl GlobalCtx {
         self.config.get_flags()
     }
 
+    // mtu of the tun device, the encryption tag takes room from the configured mtu
+    pub fn get_nic_mtu(&self) -> u16 {
+        let flags = self.get_flags();
+        if flags.enable_encryption {
+            flags.mtu - 20
+        } else {
+            flags.mtu
+        }
+    }
+
     pub fn get_128_key(&self) -> [u8; 16] {
         let mut key = [0u8; 16];
         let secret = self
@@ -1,4 +1,4 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use async_trait::async_trait;
 use tokio::process::Command;
@@ -31,6 +31,30 @@ pub trait IfConfiguerTrait: Send + Sync {
     ) -> Result<(), Error> {
         Ok(())
     }
+    async fn add_ipv6_ip(
+        &self,
+        _name: &str,
+        _address: Ipv6Addr,
+        _cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn add_ipv6_route(
+        &self,
+        _name: &str,
+        _address: Ipv6Addr,
+        _cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn remove_ipv6_route(
+        &self,
+        _name: &str,
+        _address: Ipv6Addr,
+        _cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
     async fn set_link_status(&self, _name: &str, _up: bool) -> Result<(), Error> {
         Ok(())
     }
@@ -43,6 +67,15 @@ pub trait IfConfiguerTrait: Send + Sync {
     async fn set_mtu(&self, _name: &str, _mtu: u32) -> Result<(), Error> {
         Ok(())
     }
+    // send queries for the domain (and only those) to the dns server via this interface
+    async fn set_dns_domain(
+        &self,
+        _name: &str,
+        _server: SocketAddr,
+        _domain: &str,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 fn cidr_to_subnet_mask(prefix_length: u8) -> Ipv4Addr {
@@ -96,6 +129,35 @@ async fn run_shell_cmd(cmd: &str) -> Result<(), Error> {
     Ok(())
 }
 
+// runs the program directly, so the arguments are never parsed by a shell
+async fn run_cmd(program: &str, args: &[&str]) -> Result<(), Error> {
+    let cmd_out = Command::new(program).args(args).output().await?;
+    let stdout = String::from_utf8_lossy(cmd_out.stdout.as_slice()).to_string();
+    let stderr = String::from_utf8_lossy(cmd_out.stderr.as_slice()).to_string();
+
+    let ec = cmd_out.status.code();
+    let succ = cmd_out.status.success();
+    tracing::info!(?program, ?args, ?ec, ?succ, ?stdout, ?stderr, "run cmd");
+
+    if !succ {
+        return Err(Error::ShellCommandError(stdout + &stderr));
+    }
+    Ok(())
+}
+
+// letters, digits and inner hyphens in each label, as accepted by resolvers
+fn is_dns_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
 pub struct MacIfConfiger {}
 #[async_trait]
 impl IfConfiguerTrait for MacIfConfiger {
@@ -151,6 +213,48 @@ impl IfConfiguerTrait for MacIfConfiger {
         .await
     }
 
+    async fn add_ipv6_ip(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(format!("ifconfig {} inet6 {}/{} alias", name, address, cidr_prefix).as_str())
+            .await
+    }
+
+    async fn add_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "route -n add -inet6 {}/{} -interface {}",
+                address, cidr_prefix, name
+            )
+            .as_str(),
+        )
+        .await
+    }
+
+    async fn remove_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "route -n delete -inet6 {}/{} -interface {}",
+                address, cidr_prefix, name
+            )
+            .as_str(),
+        )
+        .await
+    }
+
     async fn set_link_status(&self, name: &str, up: bool) -> Result<(), Error> {
         run_shell_cmd(format!("ifconfig {} {}", name, if up { "up" } else { "down" }).as_str())
             .await
@@ -211,6 +315,42 @@ impl IfConfiguerTrait for LinuxIfConfiger {
             .await
     }
 
+    async fn add_ipv6_ip(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(format!("ip -6 addr add {}/{} dev {}", address, cidr_prefix, name).as_str())
+            .await
+    }
+
+    async fn add_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "ip -6 route add {}/{} dev {} metric 65535",
+                address, cidr_prefix, name
+            )
+            .as_str(),
+        )
+        .await
+    }
+
+    async fn remove_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(format!("ip -6 route del {}/{} dev {}", address, cidr_prefix, name).as_str())
+            .await
+    }
+
     async fn set_link_status(&self, name: &str, up: bool) -> Result<(), Error> {
         run_shell_cmd(format!("ip link set {} {}", name, if up { "up" } else { "down" }).as_str())
             .await
@@ -230,6 +370,28 @@ impl IfConfiguerTrait for LinuxIfConfiger {
     async fn set_mtu(&self, name: &str, mtu: u32) -> Result<(), Error> {
         run_shell_cmd(format!("ip link set dev {} mtu {}", name, mtu).as_str()).await
     }
+
+    async fn set_dns_domain(
+        &self,
+        name: &str,
+        server: SocketAddr,
+        domain: &str,
+    ) -> Result<(), Error> {
+        if !is_dns_domain(domain) {
+            return Err(Error::ShellCommandError(format!(
+                "invalid dns domain: {:?}",
+                domain
+            )));
+        }
+        // resolvectl only accepts a port other than 53 in the ip:port form
+        let server = if server.port() == 53 {
+            server.ip().to_string()
+        } else {
+            server.to_string()
+        };
+        run_cmd("resolvectl", &["dns", name, &server]).await?;
+        run_cmd("resolvectl", &["domain", name, &format!("~{}", domain)]).await
+    }
 }
 
 #[cfg(target_os = "windows")]
@@ -344,6 +506,54 @@ impl IfConfiguerTrait for WindowsIfConfiger {
         .await
     }
 
+    async fn add_ipv6_ip(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "netsh interface ipv6 add address {} {}/{}",
+                name, address, cidr_prefix
+            )
+            .as_str(),
+        )
+        .await
+    }
+
+    async fn add_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "netsh interface ipv6 add route {}/{} {} store=active",
+                address, cidr_prefix, name
+            )
+            .as_str(),
+        )
+        .await
+    }
+
+    async fn remove_ipv6_route(
+        &self,
+        name: &str,
+        address: Ipv6Addr,
+        cidr_prefix: u8,
+    ) -> Result<(), Error> {
+        run_shell_cmd(
+            format!(
+                "netsh interface ipv6 delete route {}/{} {}",
+                address, cidr_prefix, name
+            )
+            .as_str(),
+        )
+        .await
+    }
+
     async fn set_link_status(&self, name: &str, up: bool) -> Result<(), Error> {
         run_shell_cmd(
             format!(
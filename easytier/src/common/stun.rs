@@ -8,7 +8,8 @@ use anyhow::Context;
 use chrono::Local;
 use crossbeam::atomic::AtomicCell;
 use rand::seq::IteratorRandom;
-use tokio::net::{lookup_host, UdpSocket};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpSocket, UdpSocket};
 use tokio::sync::{broadcast, Mutex};
 use tokio::task::JoinSet;
 use tracing::{Instrument, Level};
@@ -18,6 +19,8 @@ use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder};
 
 use crate::common::error::Error;
+use crate::common::netns::NetNS;
+use crate::tunnel::common::setup_sokcet2;
 
 use super::stun_codec_ext::*;
 
@@ -540,16 +543,177 @@ impl UdpNatTypeDetector {
     }
 }
 
+// stun over tcp, the mapped address is only valid while the nat keeps the mapping of
+// the local port, so the caller should reuse the same local port as soon as possible.
+// returns the local address the connection was made from and the mapped address.
+#[tracing::instrument(ret, err, skip(net_ns), level = Level::DEBUG)]
+async fn tcp_bind_request(
+    net_ns: &NetNS,
+    local_port: u16,
+    stun_server: SocketAddr,
+    resp_timeout: Duration,
+) -> Result<(SocketAddr, SocketAddr), Error> {
+    let bind_addr: SocketAddr = format!("0.0.0.0:{}", local_port).parse().unwrap();
+    let socket = {
+        let _g = net_ns.guard();
+        let socket2_socket = socket2::Socket::new(
+            socket2::Domain::for_address(bind_addr),
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        setup_sokcet2(&socket2_socket, &bind_addr)?;
+        TcpSocket::from_std_stream(socket2_socket.into())
+    };
+
+    let mut stream = tokio::time::timeout(resp_timeout, socket.connect(stun_server)).await??;
+    let local_addr = stream.local_addr()?;
+
+    let tid = rand::random::<u32>() as u128;
+    let message = Message::<Attribute>::new(MessageClass::Request, BINDING, u128_to_tid(tid));
+    let mut encoder = MessageEncoder::new();
+    let msg = encoder
+        .encode_into_bytes(message)
+        .with_context(|| "encode stun message")?;
+    stream.write_all(&msg).await?;
+
+    // stun message header is 20 bytes, the length field does not include the header
+    let mut buf = vec![0u8; 20];
+    tokio::time::timeout(resp_timeout, stream.read_exact(&mut buf)).await??;
+    let body_len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    buf.resize(20 + body_len, 0);
+    tokio::time::timeout(resp_timeout, stream.read_exact(&mut buf[20..])).await??;
+
+    let mut decoder = MessageDecoder::<Attribute>::new();
+    let Ok(msg) = decoder
+        .decode_from_bytes(&buf)
+        .with_context(|| format!("decode stun msg {:?}", buf))?
+    else {
+        return Err(Error::Unknown);
+    };
+
+    if msg.class() != MessageClass::SuccessResponse
+        || msg.method() != BINDING
+        || tid_to_u128(&msg.transaction_id()) != tid
+    {
+        return Err(Error::Unknown);
+    }
+
+    let mapped_addr = StunClient::extrace_mapped_addr(&msg).ok_or(Error::NotFound)?;
+    Ok((local_addr, mapped_addr))
+}
+
+#[derive(Debug, Clone)]
+pub struct TcpNatTypeDetectResult {
+    // the socket is bound on the unspecified ip, these are the ips the connections came from
+    local_addrs: Vec<SocketAddr>,
+    // (stun server, mapped addr)
+    mapped_addrs: Vec<(SocketAddr, SocketAddr)>,
+}
+
+impl TcpNatTypeDetectResult {
+    fn new(local_addrs: Vec<SocketAddr>, mapped_addrs: Vec<(SocketAddr, SocketAddr)>) -> Self {
+        Self {
+            local_addrs,
+            mapped_addrs,
+        }
+    }
+
+    // plain stun cannot tell the filtering behavior of a tcp nat, so a nat with
+    // endpoint independent mapping is treated as port restricted.
+    pub fn nat_type(&self) -> NatType {
+        if self.mapped_addrs.len() < 2 {
+            return NatType::Unknown;
+        }
+
+        let mapped_addrs = self
+            .mapped_addrs
+            .iter()
+            .map(|x| x.1)
+            .collect::<BTreeSet<_>>();
+        if mapped_addrs.len() > 1 {
+            return NatType::Symmetric;
+        }
+
+        // all requests were sent from the same local port
+        let mapped_addr = mapped_addrs.into_iter().next().unwrap();
+        if self
+            .local_addrs
+            .iter()
+            .all(|x| x.port() != mapped_addr.port())
+        {
+            NatType::PortRestricted
+        } else if self.local_addrs.contains(&mapped_addr) {
+            NatType::OpenInternet
+        } else {
+            NatType::NoPat
+        }
+    }
+
+    pub fn collect_available_stun_server(&self) -> Vec<SocketAddr> {
+        self.mapped_addrs.iter().map(|x| x.0).collect()
+    }
+}
+
+pub struct TcpNatTypeDetector {
+    net_ns: NetNS,
+    stun_servers: Vec<SocketAddr>,
+    resp_timeout: Duration,
+}
+
+impl TcpNatTypeDetector {
+    pub fn new(net_ns: NetNS, stun_servers: Vec<SocketAddr>) -> Self {
+        Self {
+            net_ns,
+            stun_servers,
+            resp_timeout: Duration::from_millis(3000),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn detect_nat_type(&self) -> Result<TcpNatTypeDetectResult, Error> {
+        // all requests must be sent from the same local port to compare the mappings
+        let local_port = {
+            let _g = self.net_ns.guard();
+            std::net::TcpListener::bind("0.0.0.0:0")?
+                .local_addr()?
+                .port()
+        };
+
+        let mut local_addrs = vec![];
+        let mut mapped_addrs = vec![];
+        for stun_server in self.stun_servers.iter() {
+            match tcp_bind_request(&self.net_ns, local_port, *stun_server, self.resp_timeout).await
+            {
+                Ok((local_addr, mapped_addr)) => {
+                    if !local_addrs.contains(&local_addr) {
+                        local_addrs.push(local_addr);
+                    }
+                    mapped_addrs.push((*stun_server, mapped_addr));
+                }
+                Err(e) => tracing::debug!(?stun_server, ?e, "tcp stun bind request failed"),
+            }
+        }
+
+        Ok(TcpNatTypeDetectResult::new(local_addrs, mapped_addrs))
+    }
+}
+
 #[async_trait::async_trait]
 #[auto_impl::auto_impl(&, Arc, Box)]
 pub trait StunInfoCollectorTrait: Send + Sync {
     fn get_stun_info(&self) -> StunInfo;
     async fn get_udp_port_mapping(&self, local_port: u16) -> Result<SocketAddr, Error>;
+    async fn get_tcp_port_mapping(&self, local_port: u16) -> Result<SocketAddr, Error>;
+    // stun servers run by connected peers, they are preferred over the configured ones.
+    fn set_peer_stun_servers(&self, _servers: Vec<SocketAddr>) {}
 }
 
 pub struct StunInfoCollector {
+    net_ns: NetNS,
     stun_servers: Arc<RwLock<Vec<String>>>,
+    peer_stun_servers: Arc<RwLock<Vec<String>>>,
     udp_nat_test_result: Arc<RwLock<Option<UdpNatTypeDetectResult>>>,
+    tcp_nat_test_result: Arc<RwLock<Option<TcpNatTypeDetectResult>>>,
     nat_test_result_time: Arc<AtomicCell<chrono::DateTime<Local>>>,
     redetect_notify: Arc<tokio::sync::Notify>,
     tasks: JoinSet<()>,
@@ -561,9 +725,16 @@ impl StunInfoCollectorTrait for StunInfoCollector {
         let Some(result) = self.udp_nat_test_result.read().unwrap().clone() else {
             return Default::default();
         };
+        let tcp_nat_type = self
+            .tcp_nat_test_result
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|x| x.nat_type())
+            .unwrap_or(NatType::Unknown);
         StunInfo {
             udp_nat_type: result.nat_type() as i32,
-            tcp_nat_type: 0,
+            tcp_nat_type: tcp_nat_type as i32,
             last_update_time: self.nat_test_result_time.load().timestamp(),
             public_ip: result.public_ips().iter().map(|x| x.to_string()).collect(),
             min_port: result.min_port() as u32,
@@ -580,7 +751,10 @@ impl StunInfoCollectorTrait for StunInfoCollector {
             .map(|x| x.collect_available_stun_server())
             .ok_or(Error::NotFound)?;
 
-        let udp = Arc::new(UdpSocket::bind(format!("0.0.0.0:{}", local_port)).await?);
+        let udp = {
+            let _g = self.net_ns.guard();
+            Arc::new(UdpSocket::bind(format!("0.0.0.0:{}", local_port)).await?)
+        };
         let mut client_builder = StunClientBuilder::new(udp.clone());
 
         for server in stun_servers.iter() {
@@ -601,13 +775,52 @@ impl StunInfoCollectorTrait for StunInfoCollector {
 
         Err(Error::NotFound)
     }
+
+    async fn get_tcp_port_mapping(&self, local_port: u16) -> Result<SocketAddr, Error> {
+        let stun_servers = self
+            .tcp_nat_test_result
+            .read()
+            .unwrap()
+            .clone()
+            .map(|x| x.collect_available_stun_server())
+            .ok_or(Error::NotFound)?;
+
+        for server in stun_servers.iter() {
+            let Ok((_, mapped_addr)) = tcp_bind_request(
+                &self.net_ns,
+                local_port,
+                *server,
+                Duration::from_millis(3000),
+            )
+            .await
+            else {
+                tracing::warn!(?server, "tcp stun bind request failed");
+                continue;
+            };
+            return Ok(mapped_addr);
+        }
+
+        Err(Error::NotFound)
+    }
+
+    fn set_peer_stun_servers(&self, servers: Vec<SocketAddr>) {
+        let servers = servers.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let mut peer_stun_servers = self.peer_stun_servers.write().unwrap();
+        if *peer_stun_servers != servers {
+            *peer_stun_servers = servers;
+            self.redetect_notify.notify_one();
+        }
+    }
 }
 
 impl StunInfoCollector {
-    pub fn new(stun_servers: Vec<String>) -> Self {
+    pub fn new(net_ns: NetNS, stun_servers: Vec<String>) -> Self {
         let mut ret = Self {
+            net_ns,
             stun_servers: Arc::new(RwLock::new(stun_servers)),
+            peer_stun_servers: Arc::new(RwLock::new(Vec::new())),
             udp_nat_test_result: Arc::new(RwLock::new(None)),
+            tcp_nat_test_result: Arc::new(RwLock::new(None)),
             nat_test_result_time: Arc::new(AtomicCell::new(Local::now())),
             redetect_notify: Arc::new(tokio::sync::Notify::new()),
             tasks: JoinSet::new(),
@@ -618,8 +831,8 @@ impl StunInfoCollector {
         ret
     }
 
-    pub fn new_with_default_servers() -> Self {
-        Self::new(Self::get_default_servers())
+    pub fn new_with_default_servers(net_ns: NetNS) -> Self {
+        Self::new(net_ns, Self::get_default_servers())
     }
 
     pub fn get_default_servers() -> Vec<String> {
@@ -650,16 +863,21 @@ impl StunInfoCollector {
 
     fn start_stun_routine(&mut self) {
         let stun_servers = self.stun_servers.clone();
+        let peer_stun_servers = self.peer_stun_servers.clone();
         let udp_nat_test_result = self.udp_nat_test_result.clone();
+        let tcp_nat_test_result = self.tcp_nat_test_result.clone();
         let udp_test_time = self.nat_test_result_time.clone();
         let redetect_notify = self.redetect_notify.clone();
+        let net_ns = self.net_ns.clone();
         self.tasks.spawn(async move {
             loop {
                 let servers = stun_servers.read().unwrap().clone();
-                // use first three and random choose one from the rest
-                let servers = servers
+                // use all peer servers, first two and random choose one from the rest
+                let servers = peer_stun_servers
+                    .read()
+                    .unwrap()
                     .iter()
-                    .take(2)
+                    .chain(servers.iter().take(2))
                     .chain(servers.iter().skip(2).choose(&mut rand::thread_rng()))
                     .map(|x| x.to_string())
                     .collect();
@@ -699,6 +917,23 @@ impl StunInfoCollector {
                     }
                 }
 
+                // most stun servers also serve on tcp with the same port
+                let tcp_stun_servers = udp_nat_test_result
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .map(|x| x.collect_available_stun_server())
+                    .unwrap_or_default();
+                if !tcp_stun_servers.is_empty() {
+                    let ret = TcpNatTypeDetector::new(net_ns.clone(), tcp_stun_servers)
+                        .detect_nat_type()
+                        .await;
+                    tracing::debug!(?ret, "finish tcp nat type detect");
+                    if let Ok(resp) = ret {
+                        *tcp_nat_test_result.write().unwrap() = Some(resp);
+                    }
+                }
+
                 tokio::select! {
                     _ = redetect_notify.notified() => {}
                     _ = tokio::time::sleep(Duration::from_secs(sleep_sec)) => {}
@@ -718,7 +953,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_udp_nat_type_detector() {
-        let collector = StunInfoCollector::new_with_default_servers();
+        let collector = StunInfoCollector::new_with_default_servers(NetNS::new(None));
         collector.update_stun_info();
         loop {
             let ret = collector.get_stun_info();
@@ -732,4 +967,32 @@ mod tests {
         let port_mapping = collector.get_udp_port_mapping(3000).await;
         println!("{:#?}", port_mapping);
     }
+
+    #[test]
+    fn tcp_nat_type_from_mapped_addrs() {
+        let servers: Vec<SocketAddr> = vec![
+            "1.1.1.1:3478".parse().unwrap(),
+            "2.2.2.2:3478".parse().unwrap(),
+        ];
+        let local: SocketAddr = "192.168.1.2:40000".parse().unwrap();
+        let result = |mapped: [&str; 2]| {
+            TcpNatTypeDetectResult::new(
+                vec![local],
+                servers
+                    .iter()
+                    .zip(mapped)
+                    .map(|(server, mapped)| (*server, mapped.parse().unwrap()))
+                    .collect(),
+            )
+        };
+
+        let ret = result(["192.168.1.2:40000", "192.168.1.2:40000"]);
+        assert_eq!(NatType::OpenInternet, ret.nat_type());
+        let ret = result(["8.8.8.8:40000", "8.8.8.8:40000"]);
+        assert_eq!(NatType::NoPat, ret.nat_type());
+        let ret = result(["8.8.8.8:50000", "8.8.8.8:50000"]);
+        assert_eq!(NatType::PortRestricted, ret.nat_type());
+        let ret = result(["8.8.8.8:50000", "8.8.8.8:50001"]);
+        assert_eq!(NatType::Symmetric, ret.nat_type());
+    }
 }
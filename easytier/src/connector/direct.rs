@@ -62,6 +62,7 @@ impl DirectConnectorRpc for DirectConnectorManagerRpcServer {
     async fn get_ip_list(self, _: tarpc::context::Context) -> GetIpListResponse {
         let mut ret = self.global_ctx.get_ip_collector().collect_ip_addrs().await;
         ret.listeners = self.global_ctx.get_running_listeners();
+        ret.listeners.extend(self.global_ctx.get_mapped_listeners());
         ret
     }
 }
@@ -229,6 +230,54 @@ impl DirectConnectorManager {
         }
     }
 
+    fn collect_connect_addrs(listener: &url::Url, ip_list: &GetIpListResponse) -> Vec<String> {
+        let mut addrs = vec![];
+        let listener_host = listener.socket_addrs(|| None).unwrap().pop();
+        // listener with a specific ip (e.g. mapped by upnp on the gateway) can only be connected
+        // as is, its port is not the one listening on the interface ips
+        if listener_host.is_some_and(|h| !h.ip().is_unspecified()) {
+            addrs.push(listener.to_string());
+            return addrs;
+        }
+
+        match listener_host {
+            Some(SocketAddr::V4(_)) => {
+                ip_list.interface_ipv4s.iter().for_each(|ip| {
+                    let mut addr = (*listener).clone();
+                    if addr.set_host(Some(ip.as_str())).is_ok() {
+                        addrs.push(addr.to_string());
+                    }
+                });
+
+                let mut addr = (*listener).clone();
+                if addr.set_host(Some(ip_list.public_ipv4.as_str())).is_ok() {
+                    addrs.push(addr.to_string());
+                }
+            }
+            Some(SocketAddr::V6(_)) => {
+                ip_list.interface_ipv6s.iter().for_each(|ip| {
+                    let mut addr = (*listener).clone();
+                    if addr.set_host(Some(format!("[{}]", ip).as_str())).is_ok() {
+                        addrs.push(addr.to_string());
+                    }
+                });
+
+                let mut addr = (*listener).clone();
+                if addr
+                    .set_host(Some(format!("[{}]", ip_list.public_ipv6).as_str()))
+                    .is_ok()
+                {
+                    addrs.push(addr.to_string());
+                }
+            }
+            p => {
+                tracing::error!(?p, ?listener, "failed to parse ip version from listener");
+            }
+        }
+
+        addrs
+    }
+
     #[tracing::instrument]
     async fn do_try_direct_connect_internal(
         data: Arc<DirectConnectorManagerData>,
@@ -263,56 +312,26 @@ impl DirectConnectorManager {
 
         let mut tasks = JoinSet::new();
 
-        let listener_host = listener.socket_addrs(|| None).unwrap().pop();
-        match listener_host {
-            Some(SocketAddr::V4(_)) => {
-                ip_list.interface_ipv4s.iter().for_each(|ip| {
-                    let mut addr = (*listener).clone();
-                    if addr.set_host(Some(ip.as_str())).is_ok() {
-                        tasks.spawn(Self::try_connect_to_ip(
-                            data.clone(),
-                            dst_peer_id.clone(),
-                            addr.to_string(),
-                        ));
-                    }
-                });
-
-                let mut addr = (*listener).clone();
-                if addr.set_host(Some(ip_list.public_ipv4.as_str())).is_ok() {
-                    tasks.spawn(Self::try_connect_to_ip(
-                        data.clone(),
-                        dst_peer_id.clone(),
-                        addr.to_string(),
-                    ));
+        // try both ipv4 and ipv6 listeners of the selected scheme, a direct ipv6 path is
+        // always better than relaying through other peers.
+        let mut addrs = vec![];
+        for l in available_listeners
+            .iter()
+            .filter(|l| l.scheme() == listener.scheme())
+        {
+            for addr in Self::collect_connect_addrs(l, &ip_list) {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
                 }
             }
-            Some(SocketAddr::V6(_)) => {
-                ip_list.interface_ipv6s.iter().for_each(|ip| {
-                    let mut addr = (*listener).clone();
-                    if addr.set_host(Some(format!("[{}]", ip).as_str())).is_ok() {
-                        tasks.spawn(Self::try_connect_to_ip(
-                            data.clone(),
-                            dst_peer_id.clone(),
-                            addr.to_string(),
-                        ));
-                    }
-                });
+        }
 
-                let mut addr = (*listener).clone();
-                if addr
-                    .set_host(Some(format!("[{}]", ip_list.public_ipv6).as_str()))
-                    .is_ok()
-                {
-                    tasks.spawn(Self::try_connect_to_ip(
-                        data.clone(),
-                        dst_peer_id.clone(),
-                        addr.to_string(),
-                    ));
-                }
-            }
-            p => {
-                tracing::error!(?p, ?listener, "failed to parse ip version from listener");
-            }
+        for addr in addrs {
+            tasks.spawn(Self::try_connect_to_ip(
+                data.clone(),
+                dst_peer_id.clone(),
+                addr,
+            ));
         }
 
         let mut has_succ = false;
@@ -429,6 +448,29 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn collect_connect_addrs_keeps_mapped_listener() {
+        let mut ip_list = GetIpListResponse::new();
+        ip_list.interface_ipv4s.push("192.168.1.2".to_string());
+        ip_list.public_ipv4 = "1.2.3.4".to_string();
+
+        let addrs = DirectConnectorManager::collect_connect_addrs(
+            &"tcp://0.0.0.0:11010".parse().unwrap(),
+            &ip_list,
+        );
+        assert_eq!(
+            addrs,
+            vec!["tcp://192.168.1.2:11010", "tcp://1.2.3.4:11010"]
+        );
+
+        // the external port of a gateway mapping only works with the public ip
+        let addrs = DirectConnectorManager::collect_connect_addrs(
+            &"tcp://1.2.3.4:40000".parse().unwrap(),
+            &ip_list,
+        );
+        assert_eq!(addrs, vec!["tcp://1.2.3.4:40000"]);
+    }
+
     #[tokio::test]
     async fn direct_connector_scheme_blacklist() {
         let p_a = create_mock_peer_manager().await;
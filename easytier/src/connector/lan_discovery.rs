@@ -0,0 +1,513 @@
+// discover peers of the same network on the lan with multicast beacons, and connect to them
+// directly without any configured peer or relay.
+
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{net::UdpSocket, task::JoinSet, time::timeout};
+use tracing::Instrument;
+
+use crate::{
+    common::{
+        config::NetworkIdentity, error::Error, global_ctx::ArcGlobalCtx, netns::NetNS, PeerId,
+    },
+    peers::peer_manager::PeerManager,
+};
+
+use super::create_connector_by_url;
+
+// a link-local group, routers never forward it out of the lan.
+const LAN_DISCOVERY_MULTICAST_ADDR: &str = "224.0.0.254:11009";
+const LAN_DISCOVERY_MAGIC: &[u8; 4] = b"ETLD";
+const LAN_DISCOVERY_MAC_LEN: usize = 32;
+
+const BEACON_INTERVAL_SEC: u64 = 5;
+// beacons too far from local clock are dropped, so a captured beacon can not be replayed forever.
+const BEACON_MAX_CLOCK_SKEW_MS: u64 = 60_000;
+// do not try the same peer again within this period, whether succeeded or not.
+const CONNECT_BACKOFF_SEC: u64 = 30;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct LanDiscoveryBeacon {
+    // keyed digest of the network name, so the name is never multicast in plaintext
+    network_digest: [u8; 32],
+    peer_id: PeerId,
+    listeners: Vec<String>,
+    timestamp_ms: u64,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn network_digest(network: &NetworkIdentity) -> [u8; 32] {
+    let secret = network.network_secret.as_deref().unwrap_or("");
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(network.network_name.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+fn beacon_mac(network: &NetworkIdentity) -> HmacSha256 {
+    let mut key = network.network_name.as_bytes().to_vec();
+    key.push(0);
+    key.extend_from_slice(network.network_secret.as_deref().unwrap_or("").as_bytes());
+    HmacSha256::new_from_slice(&key).unwrap()
+}
+
+impl LanDiscoveryBeacon {
+    // magic | postcard encoded beacon | hmac-sha256 of the beacon, keyed by network name and secret
+    fn encode(&self, network: &NetworkIdentity) -> Result<Vec<u8>, Error> {
+        let payload = postcard::to_allocvec(self).map_err(|e| anyhow::anyhow!(e))?;
+        let mut mac = beacon_mac(network);
+        mac.update(&payload);
+
+        let mut buf = LAN_DISCOVERY_MAGIC.to_vec();
+        buf.extend_from_slice(&payload);
+        buf.extend_from_slice(&mac.finalize().into_bytes());
+        Ok(buf)
+    }
+
+    // returns none if the beacon is not from our network or not authenticated.
+    fn decode(buf: &[u8], network: &NetworkIdentity) -> Option<Self> {
+        if buf.len() < LAN_DISCOVERY_MAGIC.len() + LAN_DISCOVERY_MAC_LEN
+            || !buf.starts_with(LAN_DISCOVERY_MAGIC)
+        {
+            return None;
+        }
+
+        let (payload, tag) = buf[LAN_DISCOVERY_MAGIC.len()..]
+            .split_at(buf.len() - LAN_DISCOVERY_MAGIC.len() - LAN_DISCOVERY_MAC_LEN);
+        let mut mac = beacon_mac(network);
+        mac.update(payload);
+        mac.verify_slice(tag).ok()?;
+
+        let beacon: Self = postcard::from_bytes(payload).ok()?;
+        if beacon.network_digest != network_digest(network) {
+            return None;
+        }
+        Some(beacon)
+    }
+}
+
+fn create_multicast_socket(net_ns: &NetNS, port: u16) -> Result<UdpSocket, Error> {
+    let bind_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let _g = net_ns.guard();
+    let socket2_socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    // multiple instances on the same host share the group port.
+    socket2_socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+    socket2_socket.set_reuse_port(true)?;
+    socket2_socket.set_nonblocking(true)?;
+    socket2_socket.set_multicast_loop_v4(true)?;
+    socket2_socket.set_multicast_ttl_v4(1)?;
+    socket2_socket.bind(&bind_addr.into())?;
+    Ok(UdpSocket::from_std(socket2_socket.into())?)
+}
+
+struct LanDiscoveryConnectorData {
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Arc<PeerManager>,
+    multicast_addr: SocketAddrV4,
+    connecting: timedmap::TimedMap<PeerId, ()>,
+}
+
+impl LanDiscoveryConnectorData {
+    async fn interface_ipv4s(&self) -> Vec<Ipv4Addr> {
+        self.global_ctx
+            .get_ip_collector()
+            .collect_ip_addrs()
+            .await
+            .interface_ipv4s
+            .iter()
+            .filter_map(|ip| ip.parse().ok())
+            .collect()
+    }
+
+    fn build_beacon(&self) -> LanDiscoveryBeacon {
+        LanDiscoveryBeacon {
+            network_digest: network_digest(&self.global_ctx.get_network_identity()),
+            peer_id: self.peer_mgr.my_peer_id(),
+            listeners: self
+                .global_ctx
+                .get_running_listeners()
+                .iter()
+                .filter(|l| l.scheme() != "ring")
+                .map(|l| l.to_string())
+                .collect(),
+            timestamp_ms: now_ms(),
+        }
+    }
+}
+
+pub struct LanDiscoveryConnector {
+    data: Arc<LanDiscoveryConnectorData>,
+    tasks: JoinSet<()>,
+}
+
+impl LanDiscoveryConnector {
+    pub fn new(global_ctx: ArcGlobalCtx, peer_mgr: Arc<PeerManager>) -> Self {
+        Self::new_with_multicast_addr(
+            global_ctx,
+            peer_mgr,
+            LAN_DISCOVERY_MULTICAST_ADDR.parse().unwrap(),
+        )
+    }
+
+    fn new_with_multicast_addr(
+        global_ctx: ArcGlobalCtx,
+        peer_mgr: Arc<PeerManager>,
+        multicast_addr: SocketAddrV4,
+    ) -> Self {
+        Self {
+            data: Arc::new(LanDiscoveryConnectorData {
+                global_ctx,
+                peer_mgr,
+                multicast_addr,
+                connecting: timedmap::TimedMap::new(),
+            }),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<(), Error> {
+        let flags = self.data.global_ctx.get_flags();
+        if flags.disable_p2p || !flags.enable_lan_discovery {
+            return Ok(());
+        }
+
+        let socket = Arc::new(create_multicast_socket(
+            &self.data.global_ctx.net_ns,
+            self.data.multicast_addr.port(),
+        )?);
+
+        let data = self.data.clone();
+        let s = socket.clone();
+        self.tasks.spawn(
+            async move { Self::announce_loop(data, s).await }
+                .instrument(tracing::info_span!("lan_discovery_announce")),
+        );
+
+        let data = self.data.clone();
+        self.tasks.spawn(
+            async move { Self::listen_loop(data, socket).await }
+                .instrument(tracing::info_span!("lan_discovery_listen")),
+        );
+
+        Ok(())
+    }
+
+    async fn announce_loop(data: Arc<LanDiscoveryConnectorData>, socket: Arc<UdpSocket>) {
+        let group = *data.multicast_addr.ip();
+        let mut joined = HashSet::new();
+        loop {
+            // interfaces may come and go, join the group on new ones and announce on all of them.
+            let ifaces = data.interface_ipv4s().await;
+            for iface in ifaces.iter() {
+                if joined.contains(iface) {
+                    continue;
+                }
+                match socket.join_multicast_v4(group, *iface) {
+                    Ok(_) => {
+                        joined.insert(*iface);
+                    }
+                    Err(e) => tracing::debug!(?e, ?iface, "join multicast group failed"),
+                }
+            }
+            if joined.is_empty() {
+                if let Err(e) = socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED) {
+                    tracing::debug!(?e, "join multicast group on default interface failed");
+                } else {
+                    joined.insert(Ipv4Addr::UNSPECIFIED);
+                }
+            }
+
+            match data
+                .build_beacon()
+                .encode(&data.global_ctx.get_network_identity())
+            {
+                Ok(buf) => {
+                    for iface in joined.iter() {
+                        let sock_ref = socket2::SockRef::from(socket.as_ref());
+                        if let Err(e) = sock_ref.set_multicast_if_v4(iface) {
+                            tracing::debug!(?e, ?iface, "set multicast interface failed");
+                            continue;
+                        }
+                        if let Err(e) = socket.send_to(&buf, data.multicast_addr).await {
+                            tracing::debug!(?e, ?iface, "send lan discovery beacon failed");
+                        }
+                    }
+                }
+                Err(e) => tracing::error!(?e, "encode lan discovery beacon failed"),
+            }
+
+            tokio::time::sleep(Duration::from_secs(BEACON_INTERVAL_SEC)).await;
+        }
+    }
+
+    async fn listen_loop(data: Arc<LanDiscoveryConnectorData>, socket: Arc<UdpSocket>) {
+        let mut tasks = JoinSet::new();
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, from) = tokio::select! {
+                ret = socket.recv_from(&mut buf) => match ret {
+                    Ok(ret) => ret,
+                    Err(e) => {
+                        tracing::error!(?e, "recv lan discovery beacon failed");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+                Some(ret) = tasks.join_next() => {
+                    tracing::trace!(?ret, "lan discovery connect task ret");
+                    continue;
+                }
+            };
+
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            let Some(beacon) =
+                LanDiscoveryBeacon::decode(&buf[..len], &data.global_ctx.get_network_identity())
+            else {
+                continue;
+            };
+            if beacon.peer_id == data.peer_mgr.my_peer_id()
+                || now_ms().abs_diff(beacon.timestamp_ms) > BEACON_MAX_CLOCK_SKEW_MS
+            {
+                continue;
+            }
+
+            if !Self::should_dial(&data.build_beacon(), &beacon) {
+                continue;
+            }
+
+            data.connecting.cleanup();
+            if data.connecting.contains(&beacon.peer_id) {
+                continue;
+            }
+            if data
+                .peer_mgr
+                .get_peer_map()
+                .list_peer_conns(beacon.peer_id)
+                .await
+                .is_some_and(|c| !c.is_empty())
+            {
+                continue;
+            }
+
+            tracing::info!(?beacon, ?from, "discovered lan peer");
+            data.connecting
+                .insert(beacon.peer_id, (), Duration::from_secs(CONNECT_BACKOFF_SEC));
+            tasks.spawn(Self::connect_to_peer(data.clone(), beacon, *from.ip()));
+        }
+    }
+
+    // both sides see the beacon of each other, only the one with smaller peer id dials so they
+    // don't connect twice. the other side dials only when it has no listener to be reached.
+    fn should_dial(mine: &LanDiscoveryBeacon, remote: &LanDiscoveryBeacon) -> bool {
+        mine.peer_id < remote.peer_id || mine.listeners.is_empty()
+    }
+
+    // listeners bound to unspecified address are reached with the source ip of the beacon.
+    fn collect_connect_urls(
+        beacon: &LanDiscoveryBeacon,
+        src_ip: Ipv4Addr,
+        default_protocol: &str,
+    ) -> Vec<url::Url> {
+        let mut urls = beacon
+            .listeners
+            .iter()
+            .filter_map(|l| l.parse::<url::Url>().ok())
+            .filter_map(|mut l| {
+                let addr = l.socket_addrs(|| None).ok()?.pop()?;
+                if !addr.is_ipv4() || addr.port() == 0 {
+                    return None;
+                }
+                if addr.ip().is_unspecified() {
+                    l.set_ip_host(src_ip.into()).ok()?;
+                }
+                Some(l)
+            })
+            .collect::<Vec<_>>();
+        urls.sort_by_key(|l| l.scheme() != default_protocol);
+        urls
+    }
+
+    #[tracing::instrument(skip(data), ret, err)]
+    async fn connect_to_peer(
+        data: Arc<LanDiscoveryConnectorData>,
+        beacon: LanDiscoveryBeacon,
+        src_ip: Ipv4Addr,
+    ) -> Result<(), Error> {
+        let default_protocol = data.global_ctx.get_flags().default_protocol;
+        for url in Self::collect_connect_urls(&beacon, src_ip, &default_protocol) {
+            match Self::try_connect(&data, beacon.peer_id, &url).await {
+                Ok(_) => return Ok(()),
+                Err(e) => tracing::info!(?e, ?url, "connect to lan peer failed"),
+            }
+        }
+        Err(anyhow::anyhow!("no listener of lan peer {} is reachable", beacon.peer_id).into())
+    }
+
+    async fn try_connect(
+        data: &LanDiscoveryConnectorData,
+        dst_peer_id: PeerId,
+        url: &url::Url,
+    ) -> Result<(), Error> {
+        let connector = create_connector_by_url(url.as_str(), &data.global_ctx).await?;
+        let (peer_id, conn_id) =
+            timeout(Duration::from_secs(5), data.peer_mgr.try_connect(connector)).await??;
+
+        if peer_id != dst_peer_id {
+            data.peer_mgr
+                .get_peer_map()
+                .close_peer_conn(peer_id, &conn_id)
+                .await?;
+            return Err(anyhow::anyhow!(
+                "peer id mismatch, expect: {}, actual: {}",
+                dst_peer_id,
+                peer_id
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        common::config::{ConfigLoader, NetworkIdentity},
+        connector::lan_discovery::{network_digest, LanDiscoveryBeacon, LanDiscoveryConnector},
+        instance::listeners::ListenerManager,
+        peers::tests::{create_mock_peer_manager, wait_route_appear_with_cost},
+    };
+
+    #[test]
+    fn beacon_authentication() {
+        let network = NetworkIdentity::new("net1".to_string(), "sec1".to_string());
+        let beacon = LanDiscoveryBeacon {
+            network_digest: network_digest(&network),
+            peer_id: 1,
+            listeners: vec!["tcp://0.0.0.0:11010".to_string()],
+            timestamp_ms: 1,
+        };
+        let buf = beacon.encode(&network).unwrap();
+        assert_eq!(LanDiscoveryBeacon::decode(&buf, &network), Some(beacon));
+        assert!(!buf.windows(4).any(|w| w == b"net1"));
+
+        let other_secret = NetworkIdentity::new("net1".to_string(), "sec2".to_string());
+        assert_eq!(LanDiscoveryBeacon::decode(&buf, &other_secret), None);
+        let other_network = NetworkIdentity::new("net2".to_string(), "sec1".to_string());
+        assert_eq!(LanDiscoveryBeacon::decode(&buf, &other_network), None);
+
+        let mut tampered = buf.clone();
+        tampered[6] ^= 1;
+        assert_eq!(LanDiscoveryBeacon::decode(&tampered, &network), None);
+        assert_eq!(LanDiscoveryBeacon::decode(&buf[..10], &network), None);
+    }
+
+    #[test]
+    fn only_one_side_dials() {
+        let beacon = |peer_id, listeners: &[&str]| LanDiscoveryBeacon {
+            network_digest: [0; 32],
+            peer_id,
+            listeners: listeners.iter().map(|l| l.to_string()).collect(),
+            timestamp_ms: 1,
+        };
+        let a = beacon(1, &["tcp://0.0.0.0:11010"]);
+        let b = beacon(2, &["tcp://0.0.0.0:11010"]);
+        assert!(LanDiscoveryConnector::should_dial(&a, &b));
+        assert!(!LanDiscoveryConnector::should_dial(&b, &a));
+
+        // b can not be reached, so it dials a instead
+        let b = beacon(2, &[]);
+        assert!(LanDiscoveryConnector::should_dial(&b, &a));
+    }
+
+    #[test]
+    fn connect_urls_use_beacon_source() {
+        let beacon = LanDiscoveryBeacon {
+            network_digest: [0; 32],
+            peer_id: 1,
+            listeners: vec![
+                "tcp://0.0.0.0:11010".to_string(),
+                "udp://0.0.0.0:11010".to_string(),
+                "udp://[::]:11010".to_string(),
+                "wg://10.1.1.1:11011".to_string(),
+            ],
+            timestamp_ms: 1,
+        };
+        let urls = LanDiscoveryConnector::collect_connect_urls(
+            &beacon,
+            Ipv4Addr::new(192, 168, 1, 2),
+            "udp",
+        )
+        .into_iter()
+        .map(|u| u.to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            vec![
+                "udp://192.168.1.2:11010",
+                "tcp://192.168.1.2:11010",
+                "wg://10.1.1.1:11011"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn lan_discovery_connect() {
+        let p_a = create_mock_peer_manager().await;
+        let p_b = create_mock_peer_manager().await;
+        for p in [&p_a, &p_b] {
+            let mut f = p.get_global_ctx().get_flags();
+            f.enable_lan_discovery = true;
+            p.get_global_ctx().config.set_flags(f);
+        }
+
+        p_b.get_global_ctx()
+            .config
+            .set_listeners(vec!["tcp://0.0.0.0:11061".parse().unwrap()]);
+        let mut lis_b = ListenerManager::new(p_b.get_global_ctx(), p_b.clone());
+        lis_b.prepare_listeners().await.unwrap();
+        lis_b.run().await.unwrap();
+
+        let multicast_addr = "224.0.0.254:11062".parse().unwrap();
+        let mut d_a = LanDiscoveryConnector::new_with_multicast_addr(
+            p_a.get_global_ctx(),
+            p_a.clone(),
+            multicast_addr,
+        );
+        let mut d_b = LanDiscoveryConnector::new_with_multicast_addr(
+            p_b.get_global_ctx(),
+            p_b.clone(),
+            multicast_addr,
+        );
+        d_a.run().await.unwrap();
+        d_b.run().await.unwrap();
+
+        // no peer is configured, a and b only find each other with the beacons.
+        wait_route_appear_with_cost(p_a.clone(), p_b.my_peer_id(), Some(1))
+            .await
+            .unwrap();
+    }
+}
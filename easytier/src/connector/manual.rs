@@ -30,7 +30,7 @@ use crate::{
     use_global_var,
 };
 
-use super::create_connector_by_url;
+use super::{create_connector_by_url, tcp_hole_punch::TcpHolePunchConnector};
 
 type MutexConnector = Arc<Mutex<Box<dyn TunnelConnector>>>;
 type ConnectorMap = Arc<DashMap<String, MutexConnector>>;
@@ -385,7 +385,10 @@ impl ManualConnectorManager {
     }
 }
 
-pub struct ConnectorManagerRpcService(pub Arc<ManualConnectorManager>);
+pub struct ConnectorManagerRpcService {
+    pub conn_manager: Arc<ManualConnectorManager>,
+    pub tcp_hole_puncher: Arc<Mutex<TcpHolePunchConnector>>,
+}
 
 #[tonic::async_trait]
 impl ConnectorManageRpc for ConnectorManagerRpcService {
@@ -394,7 +397,8 @@ impl ConnectorManageRpc for ConnectorManagerRpcService {
         _request: tonic::Request<ListConnectorRequest>,
     ) -> Result<tonic::Response<easytier_rpc::ListConnectorResponse>, tonic::Status> {
         let mut ret = easytier_rpc::ListConnectorResponse::default();
-        let connectors = self.0.list_connectors().await;
+        let mut connectors = self.conn_manager.list_connectors().await;
+        connectors.extend(self.tcp_hole_puncher.lock().await.list_connectors().await);
         ret.connectors = connectors;
         Ok(tonic::Response::new(ret))
     }
@@ -407,14 +411,17 @@ impl ConnectorManageRpc for ConnectorManagerRpcService {
         let url = url::Url::parse(&req.url)
             .map_err(|_| tonic::Status::invalid_argument("invalid url"))?;
         if req.action == easytier_rpc::ConnectorManageAction::Remove as i32 {
-            self.0.remove_connector(url.path()).await.map_err(|e| {
-                tonic::Status::invalid_argument(format!("remove connector failed: {:?}", e))
-            })?;
+            self.conn_manager
+                .remove_connector(url.path())
+                .await
+                .map_err(|e| {
+                    tonic::Status::invalid_argument(format!("remove connector failed: {:?}", e))
+                })?;
             return Ok(tonic::Response::new(
                 easytier_rpc::ManageConnectorResponse::default(),
             ));
         } else {
-            self.0
+            self.conn_manager
                 .add_connector_by_url(url.as_str())
                 .await
                 .map_err(|e| {
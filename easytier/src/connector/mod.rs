@@ -16,7 +16,9 @@ use crate::{
 };
 
 pub mod direct;
+pub mod lan_discovery;
 pub mod manual;
+pub mod tcp_hole_punch;
 pub mod udp_hole_punch;
 
 async fn set_bind_addr_for_peer_connector(
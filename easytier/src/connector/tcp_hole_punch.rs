@@ -0,0 +1,577 @@
+// tcp hole punching with simultaneous open, used when udp is blocked or cannot be punched.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use dashmap::DashMap;
+use tokio::{
+    net::{TcpListener, TcpSocket, TcpStream},
+    task::JoinSet,
+};
+use tracing::Level;
+
+use crate::{
+    common::{
+        constants, error::Error, global_ctx::ArcGlobalCtx, join_joinset_background, netns::NetNS,
+        scoped_task::ScopedTask, stun::StunInfoCollectorTrait, PeerId,
+    },
+    peers::peer_manager::PeerManager,
+    rpc::{Connector, ConnectorStatus, NatType},
+    tunnel::{common::setup_sokcet2, tcp::get_tunnel_with_tcp_stream, Tunnel},
+};
+
+use super::direct::PeerManagerForDirectConnector;
+
+// keep sending syn for this long, both sides start at roughly the same time
+const SIMULTANEOUS_OPEN_TIMEOUT_MS: u64 = 8000;
+const CONNECT_ATTEMPT_TIMEOUT_MS: u64 = 1000;
+const CONNECT_ATTEMPT_INTERVAL_MS: u64 = 200;
+
+#[tarpc::service]
+pub trait TcpHolePunchService {
+    async fn try_punch_hole(local_mapped_addr: SocketAddr) -> Option<SocketAddr>;
+}
+
+fn is_cone(nat_type: NatType) -> bool {
+    nat_type == NatType::FullCone
+        || nat_type == NatType::Restricted
+        || nat_type == NatType::PortRestricted
+}
+
+fn get_avail_port(net_ns: &NetNS) -> Result<u16, Error> {
+    let _g = net_ns.guard();
+    let listener = std::net::TcpListener::bind("0.0.0.0:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+fn create_punch_socket(net_ns: &NetNS, local_port: u16) -> Result<TcpSocket, Error> {
+    let bind_addr: SocketAddr = format!("0.0.0.0:{}", local_port).parse().unwrap();
+    let _g = net_ns.guard();
+    let socket2_socket = socket2::Socket::new(
+        socket2::Domain::for_address(bind_addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    // the listener and the connecting sockets share the same local port.
+    #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+    socket2_socket.set_reuse_port(true)?;
+    setup_sokcet2(&socket2_socket, &bind_addr)?;
+    Ok(TcpSocket::from_std_stream(socket2_socket.into()))
+}
+
+async fn accept_from(listener: TcpListener, remote_addr: SocketAddr) -> Result<TcpStream, Error> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        if addr.ip() == remote_addr.ip() {
+            return Ok(stream);
+        }
+        tracing::warn!(
+            ?addr,
+            ?remote_addr,
+            "ignore unexpected tcp punch connection"
+        );
+    }
+}
+
+async fn connect_repeatedly(
+    net_ns: NetNS,
+    local_port: u16,
+    remote_addr: SocketAddr,
+) -> Result<TcpStream, Error> {
+    let deadline =
+        tokio::time::Instant::now() + Duration::from_millis(SIMULTANEOUS_OPEN_TIMEOUT_MS);
+    let mut last_err = Error::Unknown;
+    while tokio::time::Instant::now() < deadline {
+        let socket = create_punch_socket(&net_ns, local_port)?;
+        match tokio::time::timeout(
+            Duration::from_millis(CONNECT_ATTEMPT_TIMEOUT_MS),
+            socket.connect(remote_addr),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => last_err = e.into(),
+            Err(e) => last_err = e.into(),
+        }
+
+        tokio::time::sleep(Duration::from_millis(CONNECT_ATTEMPT_INTERVAL_MS)).await;
+    }
+
+    Err(last_err)
+}
+
+// connect to remote_addr from local_port repeatedly until the syn of both sides pass the nat.
+// the listening side also accepts on local_port, so the connection is established either by
+// simultaneous open or by a normal handshake once its own nat mapping is open.
+#[tracing::instrument(err(level = Level::DEBUG))]
+async fn simultaneous_open(
+    net_ns: NetNS,
+    local_port: u16,
+    remote_addr: SocketAddr,
+    listen: bool,
+) -> Result<Box<dyn Tunnel>, Error> {
+    let stream = if listen {
+        let listener = create_punch_socket(&net_ns, local_port)?.listen(16)?;
+        tokio::select! {
+            ret = accept_from(listener, remote_addr) => ret,
+            ret = connect_repeatedly(net_ns, local_port, remote_addr) => ret,
+        }?
+    } else {
+        connect_repeatedly(net_ns, local_port, remote_addr).await?
+    };
+
+    let remote_url = format!("tcp://{}", remote_addr).parse().unwrap();
+    Ok(get_tunnel_with_tcp_stream(stream, remote_url)?)
+}
+
+struct TcpHolePunchConnectorData {
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Arc<PeerManager>,
+    // latest punching result of each peer, shown in connector list
+    punch_results: DashMap<PeerId, Connector>,
+}
+
+impl std::fmt::Debug for TcpHolePunchConnectorData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let peer_id = self.peer_mgr.my_peer_id();
+        f.debug_struct("TcpHolePunchConnectorData")
+            .field("peer_id", &peer_id)
+            .finish()
+    }
+}
+
+impl TcpHolePunchConnectorData {
+    fn my_nat_type(&self) -> (NatType, NatType) {
+        let stun_info = self.global_ctx.get_stun_info_collector().get_stun_info();
+        (
+            NatType::try_from(stun_info.udp_nat_type).unwrap_or(NatType::Unknown),
+            NatType::try_from(stun_info.tcp_nat_type).unwrap_or(NatType::Unknown),
+        )
+    }
+
+    fn set_punch_result(&self, peer_id: PeerId, remote_addr: SocketAddr, status: ConnectorStatus) {
+        self.punch_results.insert(
+            peer_id,
+            Connector {
+                url: format!("tcp://{}", remote_addr),
+                status: status.into(),
+            },
+        );
+    }
+}
+
+#[derive(Clone)]
+struct TcpHolePunchRpcServer {
+    data: Arc<TcpHolePunchConnectorData>,
+
+    tasks: Arc<std::sync::Mutex<JoinSet<()>>>,
+}
+
+#[tarpc::server]
+impl TcpHolePunchService for TcpHolePunchRpcServer {
+    #[tracing::instrument(skip(self))]
+    async fn try_punch_hole(
+        self,
+        _: tarpc::context::Context,
+        local_mapped_addr: SocketAddr,
+    ) -> Option<SocketAddr> {
+        let local_port = get_avail_port(&self.data.global_ctx.net_ns).ok()?;
+        let mapped_addr = self
+            .data
+            .global_ctx
+            .get_stun_info_collector()
+            .get_tcp_port_mapping(local_port)
+            .await
+            .ok()?;
+        tracing::info!(?local_mapped_addr, ?mapped_addr, "start tcp hole punching");
+
+        let data = self.data.clone();
+        self.tasks.lock().unwrap().spawn(async move {
+            let ret = simultaneous_open(
+                data.global_ctx.net_ns.clone(),
+                local_port,
+                local_mapped_addr,
+                true,
+            )
+            .await;
+            match ret {
+                Ok(tunnel) => {
+                    if let Err(e) = data.peer_mgr.add_tunnel_as_server(tunnel).await {
+                        tracing::error!(?e, "failed to add tunnel as server in tcp hole punch");
+                    }
+                }
+                Err(e) => {
+                    tracing::info!(?e, ?local_mapped_addr, "tcp hole punching as server failed");
+                }
+            }
+        });
+
+        Some(mapped_addr)
+    }
+}
+
+impl TcpHolePunchRpcServer {
+    pub fn new(data: Arc<TcpHolePunchConnectorData>) -> Self {
+        let tasks = Arc::new(std::sync::Mutex::new(JoinSet::new()));
+        join_joinset_background(tasks.clone(), "TcpHolePunchRpcServer".to_owned());
+        Self { data, tasks }
+    }
+}
+
+pub struct TcpHolePunchConnector {
+    data: Arc<TcpHolePunchConnectorData>,
+    tasks: JoinSet<()>,
+}
+
+// Currently support:
+// Any Type of Full Cone -> Any Type of Full Cone (tcp nat type)
+// udp hole punching is preferred, so only peers that cannot be punched with udp are handled.
+// node with smaller peer_id will be the initiator
+
+impl TcpHolePunchConnector {
+    pub fn new(global_ctx: ArcGlobalCtx, peer_mgr: Arc<PeerManager>) -> Self {
+        Self {
+            data: Arc::new(TcpHolePunchConnectorData {
+                global_ctx,
+                peer_mgr,
+                punch_results: DashMap::new(),
+            }),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub async fn run_as_client(&mut self) -> Result<(), Error> {
+        let data = self.data.clone();
+        self.tasks.spawn(async move {
+            Self::main_loop(data).await;
+        });
+
+        Ok(())
+    }
+
+    pub async fn run_as_server(&mut self) -> Result<(), Error> {
+        self.data.peer_mgr.get_peer_rpc_mgr().run_service(
+            constants::TCP_HOLE_PUNCH_CONNECTOR_SERVICE_ID,
+            TcpHolePunchRpcServer::new(self.data.clone()).serve(),
+        );
+
+        Ok(())
+    }
+
+    pub async fn run(&mut self) -> Result<(), Error> {
+        if self.data.global_ctx.get_flags().disable_p2p {
+            return Ok(());
+        }
+        if self.data.global_ctx.get_flags().disable_tcp_hole_punching {
+            return Ok(());
+        }
+
+        self.run_as_client().await?;
+        self.run_as_server().await?;
+
+        Ok(())
+    }
+
+    pub async fn list_connectors(&self) -> Vec<Connector> {
+        let mut ret = vec![];
+        for item in self.data.punch_results.iter() {
+            let mut connector = item.value().clone();
+            // the punched connection may be closed after it is established
+            if connector.status == ConnectorStatus::Connected as i32 {
+                let alive = self
+                    .data
+                    .peer_mgr
+                    .list_peer_conns(*item.key())
+                    .await
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|c| {
+                        c.tunnel.as_ref().map(|t| t.remote_addr.as_str())
+                            == Some(connector.url.as_str())
+                    });
+                if !alive {
+                    connector.status = ConnectorStatus::Disconnected.into();
+                }
+            }
+            ret.push(connector);
+        }
+        ret
+    }
+
+    fn udp_hole_punch_applicable(
+        data: &TcpHolePunchConnectorData,
+        peer_udp_nat_type: NatType,
+    ) -> bool {
+        if data.global_ctx.get_flags().disable_udp_hole_punching {
+            return false;
+        }
+
+        let (my_udp_nat_type, _) = data.my_nat_type();
+        let is_symmetric = |t: NatType| t == NatType::Symmetric || t == NatType::SymUdpFirewall;
+        if my_udp_nat_type == NatType::Unknown || peer_udp_nat_type == NatType::Unknown {
+            return false;
+        }
+
+        !(is_symmetric(my_udp_nat_type) && is_symmetric(peer_udp_nat_type))
+    }
+
+    async fn collect_peer_to_connect(data: Arc<TcpHolePunchConnectorData>) -> Vec<PeerId> {
+        let mut peers_to_connect = Vec::new();
+
+        // only cone nat can do simultaneous open, open internet or no pat will be
+        // connected by direct connector.
+        let (_, my_tcp_nat_type) = data.my_nat_type();
+        if !is_cone(my_tcp_nat_type) {
+            return peers_to_connect;
+        }
+
+        for route in data.peer_mgr.list_routes().await.iter() {
+            let Some(peer_stun_info) = route.stun_info.as_ref() else {
+                continue;
+            };
+            let Ok(peer_tcp_nat_type) = NatType::try_from(peer_stun_info.tcp_nat_type) else {
+                continue;
+            };
+            let peer_udp_nat_type =
+                NatType::try_from(peer_stun_info.udp_nat_type).unwrap_or(NatType::Unknown);
+
+            let peer_id: PeerId = route.peer_id;
+            let conns = data.peer_mgr.list_peer_conns(peer_id).await;
+            if conns.is_some_and(|c| !c.is_empty()) {
+                continue;
+            }
+
+            if !is_cone(peer_tcp_nat_type) {
+                continue;
+            }
+
+            if Self::udp_hole_punch_applicable(&data, peer_udp_nat_type) {
+                continue;
+            }
+
+            // both sides send syn, so only one of them needs to start punching
+            if data.peer_mgr.my_peer_id() > peer_id {
+                continue;
+            }
+
+            tracing::info!(
+                ?peer_id,
+                ?peer_tcp_nat_type,
+                ?my_tcp_nat_type,
+                ?data.global_ctx.id,
+                "found peer to do tcp hole punching"
+            );
+
+            peers_to_connect.push(peer_id);
+        }
+
+        peers_to_connect
+    }
+
+    #[tracing::instrument(err)]
+    async fn do_hole_punching(
+        data: Arc<TcpHolePunchConnectorData>,
+        dst_peer_id: PeerId,
+    ) -> Result<Box<dyn Tunnel>, anyhow::Error> {
+        tracing::info!(?dst_peer_id, "start tcp hole punching");
+        let local_port = get_avail_port(&data.global_ctx.net_ns)?;
+        let local_mapped_addr = data
+            .global_ctx
+            .get_stun_info_collector()
+            .get_tcp_port_mapping(local_port)
+            .await
+            .with_context(|| "failed to get tcp port mapping")?;
+
+        // client -> server: tell server our mapped addr, server will return its mapped addr
+        // and start sending syn to us.
+        let Some(remote_mapped_addr) = data
+            .peer_mgr
+            .get_peer_rpc_mgr()
+            .do_client_rpc_scoped(
+                constants::TCP_HOLE_PUNCH_CONNECTOR_SERVICE_ID,
+                dst_peer_id,
+                |c| async {
+                    let client =
+                        TcpHolePunchServiceClient::new(tarpc::client::Config::default(), c).spawn();
+                    let remote_mapped_addr = client
+                        .try_punch_hole(tarpc::context::current(), local_mapped_addr)
+                        .await;
+                    tracing::info!(?remote_mapped_addr, ?dst_peer_id, "got remote mapped addr");
+                    remote_mapped_addr
+                },
+            )
+            .await?
+        else {
+            return Err(anyhow::anyhow!("failed to get remote mapped addr"));
+        };
+
+        data.set_punch_result(dst_peer_id, remote_mapped_addr, ConnectorStatus::Connecting);
+        let ret = simultaneous_open(
+            data.global_ctx.net_ns.clone(),
+            local_port,
+            remote_mapped_addr,
+            false,
+        )
+        .await;
+
+        let status = if ret.is_ok() {
+            ConnectorStatus::Connected
+        } else {
+            ConnectorStatus::Disconnected
+        };
+        data.set_punch_result(dst_peer_id, remote_mapped_addr, status);
+
+        ret.with_context(|| "tcp simultaneous open failed")
+    }
+
+    async fn peer_punching_task(
+        data: Arc<TcpHolePunchConnectorData>,
+        peer_id: PeerId,
+    ) -> Result<(), anyhow::Error> {
+        const MAX_BACKOFF_TIME: u64 = 300;
+        let mut backoff_time = vec![15, 15, 30, 30, 60, 120, 180, MAX_BACKOFF_TIME];
+
+        loop {
+            match Self::do_hole_punching(data.clone(), peer_id).await {
+                Err(_) => {
+                    tokio::time::sleep(Duration::from_secs(
+                        backoff_time.pop().unwrap_or(MAX_BACKOFF_TIME),
+                    ))
+                    .await;
+                    continue;
+                }
+
+                Ok(tunnel) => {
+                    let _ = data
+                        .peer_mgr
+                        .add_client_tunnel(tunnel)
+                        .await
+                        .with_context(|| {
+                            "failed to add tunnel as client in tcp hole punch connector"
+                        })?;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn main_loop(data: Arc<TcpHolePunchConnectorData>) {
+        type JoinTaskRet = Result<(), anyhow::Error>;
+        type JoinTask = ScopedTask<JoinTaskRet>;
+        let punching_task = Arc::new(DashMap::<PeerId, JoinTask>::new());
+        let mut last_my_nat_type = NatType::Unknown;
+
+        loop {
+            let (_, my_nat_type) = data.my_nat_type();
+            let peers_to_connect = Self::collect_peer_to_connect(data.clone()).await;
+
+            // remove task not in peers_to_connect
+            let mut to_remove = vec![];
+            for item in punching_task.iter() {
+                if !peers_to_connect.contains(item.key())
+                    || item.value().is_finished()
+                    || my_nat_type != last_my_nat_type
+                {
+                    to_remove.push(*item.key());
+                }
+            }
+            for key in to_remove {
+                if let Some((_, task)) = punching_task.remove(&key) {
+                    task.abort();
+                    match task.await {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(task_ret)) => {
+                            tracing::error!(?task_ret, "tcp hole punching task failed");
+                        }
+                        Err(e) => {
+                            tracing::error!(?e, "tcp hole punching task aborted");
+                        }
+                    }
+                }
+            }
+
+            // forget results of peers that left the network
+            let routes = data.peer_mgr.list_routes().await;
+            data.punch_results
+                .retain(|peer_id, _| routes.iter().any(|r| r.peer_id == *peer_id));
+
+            last_my_nat_type = my_nat_type;
+
+            for peer_id in peers_to_connect {
+                if punching_task.contains_key(&peer_id) {
+                    continue;
+                }
+
+                punching_task.insert(
+                    peer_id,
+                    tokio::spawn(Self::peer_punching_task(data.clone(), peer_id)).into(),
+                );
+            }
+
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::{
+        connector::{
+            tcp_hole_punch::TcpHolePunchConnector,
+            udp_hole_punch::tests::replace_stun_info_collector_with_tcp,
+        },
+        peers::tests::{
+            connect_peer_manager, create_mock_peer_manager, wait_route_appear,
+            wait_route_appear_with_cost,
+        },
+        rpc::{ConnectorStatus, NatType},
+    };
+
+    #[tokio::test]
+    async fn hole_punching_tcp() {
+        let p_a = create_mock_peer_manager().await;
+        let p_b = create_mock_peer_manager().await;
+        let p_c = create_mock_peer_manager().await;
+        // udp is blocked, so udp hole punching is not applicable
+        replace_stun_info_collector_with_tcp(
+            p_a.clone(),
+            NatType::Unknown,
+            NatType::PortRestricted,
+        );
+        replace_stun_info_collector_with_tcp(
+            p_b.clone(),
+            NatType::Unknown,
+            NatType::PortRestricted,
+        );
+        replace_stun_info_collector_with_tcp(
+            p_c.clone(),
+            NatType::Unknown,
+            NatType::PortRestricted,
+        );
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_b.clone(), p_c.clone()).await;
+
+        wait_route_appear(p_a.clone(), p_c.clone()).await.unwrap();
+
+        let mut hole_punching_a = TcpHolePunchConnector::new(p_a.get_global_ctx(), p_a.clone());
+        let mut hole_punching_c = TcpHolePunchConnector::new(p_c.get_global_ctx(), p_c.clone());
+
+        hole_punching_a.run().await.unwrap();
+        hole_punching_c.run().await.unwrap();
+
+        wait_route_appear_with_cost(p_a.clone(), p_c.my_peer_id(), Some(1))
+            .await
+            .unwrap();
+
+        let initiator = if p_a.my_peer_id() < p_c.my_peer_id() {
+            &hole_punching_a
+        } else {
+            &hole_punching_c
+        };
+        let connectors = initiator.list_connectors().await;
+        assert_eq!(connectors.len(), 1);
+        assert_eq!(connectors[0].status, ConnectorStatus::Connected as i32);
+    }
+}
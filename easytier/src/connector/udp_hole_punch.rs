@@ -1,5 +1,5 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
@@ -199,6 +199,8 @@ pub trait UdpHolePunchService {
         round: u32,
         last_port_index: usize,
     ) -> Option<usize>;
+    // ipv6 has no nat in most cases, only the stateful firewall of both sides need to be punched.
+    async fn try_punch_hole_v6(local_addrs: Vec<SocketAddr>) -> Vec<SocketAddr>;
 }
 
 #[derive(Debug)]
@@ -215,20 +217,43 @@ struct UdpHolePunchListener {
 }
 
 impl UdpHolePunchListener {
-    async fn get_avail_port() -> Result<u16, Error> {
+    async fn get_avail_port(net_ns: &NetNS) -> Result<u16, Error> {
+        let _g = net_ns.guard();
         let socket = UdpSocket::bind("0.0.0.0:0").await?;
         Ok(socket.local_addr()?.port())
     }
 
     #[instrument(err)]
     pub async fn new(peer_mgr: Arc<PeerManager>) -> Result<Self, Error> {
-        let port = Self::get_avail_port().await?;
+        let gctx = peer_mgr.get_global_ctx();
+        let port = Self::get_avail_port(&gctx.net_ns).await?;
         let listen_url = format!("udp://0.0.0.0:{}", port);
 
-        let gctx = peer_mgr.get_global_ctx();
         let stun_info_collect = gctx.get_stun_info_collector();
         let mapped_addr = stun_info_collect.get_udp_port_mapping(port).await?;
 
+        Self::new_with_mapped_addr(peer_mgr, listen_url, mapped_addr).await
+    }
+
+    // the ipv6 listener is reachable with any global ipv6 of this node, so the mapped addr
+    // only records the port.
+    #[instrument(err)]
+    pub async fn new_v6(peer_mgr: Arc<PeerManager>) -> Result<Self, Error> {
+        let port = {
+            let _g = peer_mgr.get_global_ctx().net_ns.guard();
+            UdpSocket::bind("[::]:0").await?.local_addr()?.port()
+        };
+        let listen_url = format!("udp://[::]:{}", port);
+        let mapped_addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+
+        Self::new_with_mapped_addr(peer_mgr, listen_url, mapped_addr).await
+    }
+
+    async fn new_with_mapped_addr(
+        peer_mgr: Arc<PeerManager>,
+        listen_url: String,
+        mapped_addr: SocketAddr,
+    ) -> Result<Self, Error> {
         let mut listener = UdpTunnelListener::new(listen_url.parse().unwrap());
 
         {
@@ -304,6 +329,8 @@ struct UdpHolePunchConnectorData {
     punch_predicablely: AtomicBool,
     punch_randomly: AtomicBool,
     udp_array_size: AtomicUsize,
+    #[cfg(test)]
+    ipv6_candidates_override: Option<Vec<Ipv6Addr>>,
 }
 
 impl std::fmt::Debug for UdpHolePunchConnectorData {
@@ -316,11 +343,59 @@ impl std::fmt::Debug for UdpHolePunchConnectorData {
     }
 }
 
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    // exclude unspecified, loopback, multicast, link local (fe80::/10) and unique local (fc00::/7)
+    !ip.is_unspecified()
+        && !ip.is_loopback()
+        && !ip.is_multicast()
+        && (ip.segments()[0] & 0xffc0) != 0xfe80
+        && (ip.segments()[0] & 0xfe00) != 0xfc00
+}
+
 impl UdpHolePunchConnectorData {
+    fn new(global_ctx: ArcGlobalCtx, peer_mgr: Arc<PeerManager>) -> Self {
+        Self {
+            global_ctx,
+            peer_mgr,
+            listeners: Arc::new(Mutex::new(Vec::new())),
+            shuffled_port_vec: Arc::new(generate_shuffled_port_vec()),
+            udp_array: Arc::new(Mutex::new(None)),
+            try_direct_connect: AtomicBool::new(true),
+            punch_predicablely: AtomicBool::new(true),
+            punch_randomly: AtomicBool::new(true),
+            udp_array_size: AtomicUsize::new(80),
+            #[cfg(test)]
+            ipv6_candidates_override: None,
+        }
+    }
+
     fn my_nat_type(&self) -> NatType {
         let stun_info = self.global_ctx.get_stun_info_collector().get_stun_info();
         NatType::try_from(stun_info.udp_nat_type).unwrap()
     }
+
+    async fn collect_ipv6_candidates(&self) -> Vec<Ipv6Addr> {
+        if !self.global_ctx.get_flags().enable_ipv6 {
+            return vec![];
+        }
+
+        #[cfg(test)]
+        if let Some(ret) = &self.ipv6_candidates_override {
+            return ret.clone();
+        }
+
+        let ip_list = self.global_ctx.get_ip_collector().collect_ip_addrs().await;
+        let mut ret = vec![];
+        for ip in std::iter::once(&ip_list.public_ipv6).chain(ip_list.interface_ipv6s.iter()) {
+            let Ok(ip) = ip.parse::<Ipv6Addr>() else {
+                continue;
+            };
+            if is_global_ipv6(&ip) && !ret.contains(&ip) {
+                ret.push(ip);
+            }
+        }
+        ret
+    }
 }
 
 #[derive(Clone)]
@@ -356,27 +431,8 @@ impl UdpHolePunchService for UdpHolePunchRpcServer {
                 || my_udp_nat_type == NatType::Restricted as i32
                 || my_udp_nat_type == NatType::FullCone as i32
             {
-                let notifier = Arc::new(Notify::new());
-
-                let n = notifier.clone();
-                // send punch msg to local_mapped_addr for 3 seconds, 3.3 packet per second
-                self.tasks.lock().unwrap().spawn(async move {
-                    for i in 0..10 {
-                        tracing::info!(?local_mapped_addr, "sending hole punching packet");
-
-                        let udp_packet = new_hole_punch_packet(100, HOLE_PUNCH_PACKET_BODY_LEN);
-                        let _ = socket
-                            .send_to(&udp_packet.into_bytes(), local_mapped_addr)
-                            .await;
-                        let sleep_ms = if i < 4 { 10 } else { 500 };
-                        tokio::time::sleep(std::time::Duration::from_millis(sleep_ms)).await;
-                        if i == 3 {
-                            n.notify_one();
-                        }
-                    }
-                });
-
-                notifier.notified().await;
+                self.send_hole_punch_packets(socket, vec![local_mapped_addr])
+                    .await;
             }
         }
 
@@ -472,6 +528,35 @@ impl UdpHolePunchService for UdpHolePunchRpcServer {
 
         return Some(1);
     }
+
+    #[instrument(skip(self))]
+    async fn try_punch_hole_v6(
+        self,
+        _: tarpc::context::Context,
+        local_addrs: Vec<SocketAddr>,
+    ) -> Vec<SocketAddr> {
+        let local_addrs = local_addrs
+            .into_iter()
+            .filter(|addr| addr.is_ipv6())
+            .collect::<Vec<_>>();
+        let my_ipv6s = self.data.collect_ipv6_candidates().await;
+        if local_addrs.is_empty() || my_ipv6s.is_empty() {
+            return vec![];
+        }
+
+        let Some((socket, port)) = self.select_listener_v6().await else {
+            return vec![];
+        };
+        tracing::info!(?local_addrs, ?my_ipv6s, ?port, "start ipv6 hole punching");
+
+        // open our firewall for the client, the client opens its own when connecting to us.
+        self.send_hole_punch_packets(socket, local_addrs).await;
+
+        my_ipv6s
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip.into(), port))
+            .collect()
+    }
 }
 
 impl UdpHolePunchRpcServer {
@@ -481,6 +566,31 @@ impl UdpHolePunchRpcServer {
         Self { data, tasks }
     }
 
+    // send punch msg to dst_addrs for 3 seconds, 3.3 packet per second.
+    // returns after the first few packets are sent.
+    async fn send_hole_punch_packets(&self, socket: Arc<UdpSocket>, dst_addrs: Vec<SocketAddr>) {
+        let notifier = Arc::new(Notify::new());
+
+        let n = notifier.clone();
+        self.tasks.lock().unwrap().spawn(async move {
+            for i in 0..10 {
+                for dst_addr in dst_addrs.iter() {
+                    tracing::info!(?dst_addr, "sending hole punching packet");
+
+                    let udp_packet = new_hole_punch_packet(100, HOLE_PUNCH_PACKET_BODY_LEN);
+                    let _ = socket.send_to(&udp_packet.into_bytes(), dst_addr).await;
+                }
+                let sleep_ms = if i < 4 { 10 } else { 500 };
+                tokio::time::sleep(std::time::Duration::from_millis(sleep_ms)).await;
+                if i == 3 {
+                    n.notify_one();
+                }
+            }
+        });
+
+        notifier.notified().await;
+    }
+
     async fn find_listener(&self, addr: &SocketAddr) -> Option<Arc<UdpSocket>> {
         let all_listener_sockets = self.data.listeners.lock().await;
 
@@ -504,7 +614,13 @@ impl UdpHolePunchRpcServer {
         });
 
         let mut use_last = false;
-        if all_listener_sockets.lock().await.len() < 4 || use_new_listener {
+        let v4_listener_count = all_listener_sockets
+            .lock()
+            .await
+            .iter()
+            .filter(|listener| listener.mapped_addr.is_ipv4())
+            .count();
+        if v4_listener_count < 4 || use_new_listener {
             tracing::warn!("creating new udp hole punching listener");
             all_listener_sockets.lock().await.push(
                 UdpHolePunchListener::new(self.data.peer_mgr.clone())
@@ -522,12 +638,34 @@ impl UdpHolePunchRpcServer {
             // use the listener that is active most recently
             locked
                 .iter()
+                .filter(|listener| listener.mapped_addr.is_ipv4())
                 .max_by_key(|listener| listener.last_active_time.load())?
         };
 
         Some((listener.get_socket().await, listener.mapped_addr))
     }
 
+    // returns the socket and the port of the ipv6 listener, one listener is enough because
+    // there is no port mapping to be exhausted.
+    async fn select_listener_v6(&self) -> Option<(Arc<UdpSocket>, u16)> {
+        let mut locked = self.data.listeners.lock().await;
+
+        if let Some(listener) = locked
+            .iter()
+            .find(|listener| listener.mapped_addr.is_ipv6() && listener.running.load())
+        {
+            return Some((listener.get_socket().await, listener.mapped_addr.port()));
+        }
+
+        tracing::warn!("creating new udp ipv6 hole punching listener");
+        let listener = UdpHolePunchListener::new_v6(self.data.peer_mgr.clone())
+            .await
+            .ok()?;
+        let ret = (listener.get_socket().await, listener.mapped_addr.port());
+        locked.push(listener);
+        Some(ret)
+    }
+
     #[tracing::instrument(err, ret(level=Level::DEBUG), skip(self, ports))]
     async fn send_symmetric_hole_punch_packet(
         &self,
@@ -568,17 +706,22 @@ pub struct UdpHolePunchConnector {
 impl UdpHolePunchConnector {
     pub fn new(global_ctx: ArcGlobalCtx, peer_mgr: Arc<PeerManager>) -> Self {
         Self {
-            data: Arc::new(UdpHolePunchConnectorData {
-                global_ctx,
-                peer_mgr,
-                listeners: Arc::new(Mutex::new(Vec::new())),
-                shuffled_port_vec: Arc::new(generate_shuffled_port_vec()),
-                udp_array: Arc::new(Mutex::new(None)),
-                try_direct_connect: AtomicBool::new(true),
-                punch_predicablely: AtomicBool::new(true),
-                punch_randomly: AtomicBool::new(true),
-                udp_array_size: AtomicUsize::new(80),
-            }),
+            data: Arc::new(UdpHolePunchConnectorData::new(global_ctx, peer_mgr)),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    // uses the given addrs instead of the collected global ipv6 addrs
+    #[cfg(test)]
+    fn new_with_ipv6_candidates(
+        global_ctx: ArcGlobalCtx,
+        peer_mgr: Arc<PeerManager>,
+        ipv6_candidates: Vec<Ipv6Addr>,
+    ) -> Self {
+        let mut data = UdpHolePunchConnectorData::new(global_ctx, peer_mgr);
+        data.ipv6_candidates_override = Some(ipv6_candidates);
+        Self {
+            data: Arc::new(data),
             tasks: JoinSet::new(),
         }
     }
@@ -615,25 +758,65 @@ impl UdpHolePunchConnector {
         Ok(())
     }
 
-    async fn collect_peer_to_connect(
-        data: Arc<UdpHolePunchConnectorData>,
-    ) -> Vec<(PeerId, NatType)> {
-        let mut peers_to_connect = Vec::new();
-
+    // check if hole punching with ipv4 can be done and whether we should be the initiator.
+    fn need_punch_v4(
+        data: &UdpHolePunchConnectorData,
+        my_nat_type: NatType,
+        peer_id: PeerId,
+        peer_nat_type: NatType,
+    ) -> bool {
         // do not do anything if:
         // 1. our stun test has not finished
         // 2. our nat type is OpenInternet or NoPat, which means we can wait other peers to connect us
-        let my_nat_type = data.my_nat_type();
         if my_nat_type == NatType::Unknown
             || my_nat_type == NatType::OpenInternet
             || my_nat_type == NatType::NoPat
         {
-            return peers_to_connect;
+            return false;
+        }
+
+        // if peer is symmetric ignore it because we cannot connect to it
+        // if peer is open internet or no pat, direct connector will connecto to it
+        if peer_nat_type == NatType::Unknown
+            || peer_nat_type == NatType::OpenInternet
+            || peer_nat_type == NatType::NoPat
+            || peer_nat_type == NatType::Symmetric
+            || peer_nat_type == NatType::SymUdpFirewall
+        {
+            return false;
+        }
+
+        // if we are symmetric, we can only connect to cone peer
+        if (my_nat_type == NatType::Symmetric || my_nat_type == NatType::SymUdpFirewall)
+            && (peer_nat_type == NatType::Symmetric || peer_nat_type == NatType::SymUdpFirewall)
+        {
+            return false;
         }
 
+        // if we have smae level of full cone, node with smaller peer_id will be the initiator
+        if my_nat_type == peer_nat_type {
+            data.peer_mgr.my_peer_id() < peer_id
+        } else {
+            // if we have different level of full cone
+            // we will be the initiator if we have more strict level
+            my_nat_type > peer_nat_type
+        }
+    }
+
+    async fn collect_peer_to_connect(
+        data: Arc<UdpHolePunchConnectorData>,
+    ) -> Vec<(PeerId, NatType)> {
+        let mut peers_to_connect = Vec::new();
+
+        let my_nat_type = data.my_nat_type();
+        // ipv6 punching does not depend on nat type, just try it if we have global ipv6.
+        let has_ipv6 = !data.collect_ipv6_candidates().await.is_empty();
+        // advertised in our route info, so peers know they can punch us with ipv6
+        data.global_ctx.set_has_global_ipv6(has_ipv6);
+
         // collect peer list from peer manager and do some filter:
         // 1. peers without direct conns;
-        // 2. peers is full cone (any restricted type);
+        // 2. peers can be punched with ipv6 or ipv4 (see need_punch_v4);
         for route in data.peer_mgr.list_routes().await.iter() {
             let Some(peer_stun_info) = route.stun_info.as_ref() else {
                 continue;
@@ -648,41 +831,18 @@ impl UdpHolePunchConnector {
                 continue;
             }
 
-            // if peer is symmetric ignore it because we cannot connect to it
-            // if peer is open internet or no pat, direct connector will connecto to it
-            if peer_nat_type == NatType::Unknown
-                || peer_nat_type == NatType::OpenInternet
-                || peer_nat_type == NatType::NoPat
-                || peer_nat_type == NatType::Symmetric
-                || peer_nat_type == NatType::SymUdpFirewall
-            {
-                continue;
-            }
-
-            // if we are symmetric, we can only connect to cone peer
-            if (my_nat_type == NatType::Symmetric || my_nat_type == NatType::SymUdpFirewall)
-                && (peer_nat_type == NatType::Symmetric || peer_nat_type == NatType::SymUdpFirewall)
-            {
+            // with ipv6 on both sides, node with smaller peer_id will be the initiator
+            let punch_v6 =
+                has_ipv6 && route.has_global_ipv6 && data.peer_mgr.my_peer_id() < peer_id;
+            if !punch_v6 && !Self::need_punch_v4(&data, my_nat_type, peer_id, peer_nat_type) {
                 continue;
             }
 
-            // if we have smae level of full cone, node with smaller peer_id will be the initiator
-            if my_nat_type == peer_nat_type {
-                if data.peer_mgr.my_peer_id() > peer_id {
-                    continue;
-                }
-            } else {
-                // if we have different level of full cone
-                // we will be the initiator if we have more strict level
-                if my_nat_type < peer_nat_type {
-                    continue;
-                }
-            }
-
             tracing::info!(
                 ?peer_id,
                 ?peer_nat_type,
                 ?my_nat_type,
+                ?punch_v6,
                 ?data.global_ctx.id,
                 "found peer to do hole punching"
             );
@@ -697,16 +857,8 @@ impl UdpHolePunchConnector {
         socket: Arc<UdpSocket>,
         remote_mapped_addr: SocketAddr,
     ) -> Result<Box<dyn Tunnel>, Error> {
-        let connector = UdpTunnelConnector::new(
-            format!(
-                "udp://{}:{}",
-                remote_mapped_addr.ip(),
-                remote_mapped_addr.port()
-            )
-            .to_string()
-            .parse()
-            .unwrap(),
-        );
+        let connector =
+            UdpTunnelConnector::new(format!("udp://{}", remote_mapped_addr).parse().unwrap());
         connector
             .try_connect_with_socket(socket, remote_mapped_addr)
             .await
@@ -774,6 +926,62 @@ impl UdpHolePunchConnector {
             .with_context(|| "UdpTunnelConnector failed to connect remote")?)
     }
 
+    #[tracing::instrument(err)]
+    async fn do_hole_punching_v6(
+        data: Arc<UdpHolePunchConnectorData>,
+        dst_peer_id: PeerId,
+    ) -> Result<Box<dyn Tunnel>, anyhow::Error> {
+        let my_ipv6s = data.collect_ipv6_candidates().await;
+        if my_ipv6s.is_empty() {
+            return Err(anyhow::anyhow!("no global ipv6 available"));
+        }
+
+        let local_socket_addr: SocketAddr = "[::]:0".parse().unwrap();
+        let socket = {
+            let _g = data.global_ctx.net_ns.guard();
+            let socket2_socket = socket2::Socket::new(
+                socket2::Domain::for_address(local_socket_addr),
+                socket2::Type::DGRAM,
+                Some(socket2::Protocol::UDP),
+            )?;
+            setup_sokcet2(&socket2_socket, &local_socket_addr)?;
+            Arc::new(UdpSocket::from_std(socket2_socket.into())?)
+        };
+        let local_port = socket.local_addr()?.port();
+        let local_addrs = my_ipv6s
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip.into(), local_port))
+            .collect::<Vec<_>>();
+
+        // client -> server: exchange ipv6 candidates, server will send punching packets to ours.
+        let remote_addrs = data
+            .peer_mgr
+            .get_peer_rpc_mgr()
+            .do_client_rpc_scoped(
+                constants::UDP_HOLE_PUNCH_CONNECTOR_SERVICE_ID,
+                dst_peer_id,
+                |c| async {
+                    let client =
+                        UdpHolePunchServiceClient::new(tarpc::client::Config::default(), c).spawn();
+                    let remote_addrs = client
+                        .try_punch_hole_v6(tarpc::context::current(), local_addrs.clone())
+                        .await;
+                    tracing::info!(?remote_addrs, ?dst_peer_id, "got remote ipv6 addrs");
+                    remote_addrs
+                },
+            )
+            .await?;
+
+        for remote_addr in remote_addrs.into_iter().filter(|addr| addr.is_ipv6()) {
+            match Self::try_connect_with_socket(socket.clone(), remote_addr).await {
+                Ok(tunnel) => return Ok(tunnel),
+                Err(e) => tracing::info!(?e, ?remote_addr, "ipv6 hole punching failed"),
+            }
+        }
+
+        Err(anyhow::anyhow!("peer has no reachable ipv6 addr"))
+    }
+
     #[tracing::instrument(err(level = Level::ERROR))]
     async fn do_hole_punching_symmetric(
         data: Arc<UdpHolePunchConnectorData>,
@@ -906,23 +1114,43 @@ impl UdpHolePunchConnector {
         return Err(anyhow::anyhow!("udp array not started"));
     }
 
+    async fn do_hole_punching_v4(
+        data: Arc<UdpHolePunchConnectorData>,
+        peer_id: PeerId,
+        peer_nat_type: NatType,
+    ) -> Result<Box<dyn Tunnel>, anyhow::Error> {
+        let my_nat_type = data.my_nat_type();
+        if !Self::need_punch_v4(&data, my_nat_type, peer_id, peer_nat_type) {
+            return Err(anyhow::anyhow!("ipv4 hole punching is not applicable"));
+        }
+
+        if my_nat_type == NatType::FullCone
+            || my_nat_type == NatType::Restricted
+            || my_nat_type == NatType::PortRestricted
+        {
+            Self::do_hole_punching_cone(data, peer_id).await
+        } else {
+            Self::do_hole_punching_symmetric(data, peer_id).await
+        }
+    }
+
     async fn peer_punching_task(
         data: Arc<UdpHolePunchConnectorData>,
         peer_id: PeerId,
+        peer_nat_type: NatType,
     ) -> Result<(), anyhow::Error> {
         const MAX_BACKOFF_TIME: u64 = 300;
         let mut backoff_time = vec![15, 15, 30, 30, 60, 120, 180, MAX_BACKOFF_TIME];
-        let my_nat_type = data.my_nat_type();
 
         loop {
-            let ret = if my_nat_type == NatType::FullCone
-                || my_nat_type == NatType::Restricted
-                || my_nat_type == NatType::PortRestricted
-            {
-                Self::do_hole_punching_cone(data.clone(), peer_id).await
-            } else {
-                Self::do_hole_punching_symmetric(data.clone(), peer_id).await
-            };
+            // prefer ipv6 because it does not need to guess the port mapping
+            let mut ret = Err(anyhow::anyhow!("ipv6 hole punching is not applicable"));
+            if data.peer_mgr.my_peer_id() < peer_id {
+                ret = Self::do_hole_punching_v6(data.clone(), peer_id).await;
+            }
+            if ret.is_err() {
+                ret = Self::do_hole_punching_v4(data.clone(), peer_id, peer_nat_type).await;
+            }
 
             match ret {
                 Err(_) => {
@@ -993,7 +1221,9 @@ impl UdpHolePunchConnector {
                     }
 
                     let my_nat_type = data.my_nat_type();
-                    if my_nat_type == NatType::Symmetric || my_nat_type == NatType::SymUdpFirewall {
+                    if (my_nat_type == NatType::Symmetric || my_nat_type == NatType::SymUdpFirewall)
+                        && Self::need_punch_v4(&data, my_nat_type, item.0, item.1)
+                    {
                         let mut udp_array = data.udp_array.lock().await;
                         if udp_array.is_none() {
                             *udp_array = Some(Arc::new(UdpSocketArray::new(
@@ -1007,7 +1237,7 @@ impl UdpHolePunchConnector {
 
                     punching_task.insert(
                         item,
-                        tokio::spawn(Self::peer_punching_task(data.clone(), item.0)).into(),
+                        tokio::spawn(Self::peer_punching_task(data.clone(), item.0, item.1)).into(),
                     );
                 }
             } else if punching_task.is_empty() {
@@ -1044,6 +1274,7 @@ pub mod tests {
 
     struct MockStunInfoCollector {
         udp_nat_type: NatType,
+        tcp_nat_type: NatType,
     }
 
     #[async_trait::async_trait]
@@ -1051,7 +1282,7 @@ pub mod tests {
         fn get_stun_info(&self) -> StunInfo {
             StunInfo {
                 udp_nat_type: self.udp_nat_type as i32,
-                tcp_nat_type: NatType::Unknown as i32,
+                tcp_nat_type: self.tcp_nat_type as i32,
                 last_update_time: std::time::Instant::now().elapsed().as_secs() as i64,
                 min_port: 100,
                 max_port: 200,
@@ -1065,10 +1296,25 @@ pub mod tests {
             }
             Ok(format!("127.0.0.1:{}", port).parse().unwrap())
         }
+
+        async fn get_tcp_port_mapping(&self, port: u16) -> Result<std::net::SocketAddr, Error> {
+            Ok(format!("127.0.0.1:{}", port).parse().unwrap())
+        }
     }
 
     pub fn replace_stun_info_collector(peer_mgr: Arc<PeerManager>, udp_nat_type: NatType) {
-        let collector = Box::new(MockStunInfoCollector { udp_nat_type });
+        replace_stun_info_collector_with_tcp(peer_mgr, udp_nat_type, NatType::Unknown);
+    }
+
+    pub fn replace_stun_info_collector_with_tcp(
+        peer_mgr: Arc<PeerManager>,
+        udp_nat_type: NatType,
+        tcp_nat_type: NatType,
+    ) {
+        let collector = Box::new(MockStunInfoCollector {
+            udp_nat_type,
+            tcp_nat_type,
+        });
         peer_mgr
             .get_global_ctx()
             .replace_stun_info_collector(collector);
@@ -1106,6 +1352,54 @@ pub mod tests {
         println!("{:?}", p_a.list_routes().await);
     }
 
+    #[tokio::test]
+    async fn hole_punching_ipv6() {
+        // ipv4 hole punching is not applicable between two symmetric nat
+        let p_a = create_mock_peer_manager_with_mock_stun(NatType::Symmetric).await;
+        let p_b = create_mock_peer_manager_with_mock_stun(NatType::PortRestricted).await;
+        let p_c = create_mock_peer_manager_with_mock_stun(NatType::Symmetric).await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_b.clone(), p_c.clone()).await;
+        wait_route_appear(p_a.clone(), p_c.clone()).await.unwrap();
+
+        let ipv6_candidates = vec!["::1".parse().unwrap()];
+        let mut hole_punching_a = UdpHolePunchConnector::new_with_ipv6_candidates(
+            p_a.get_global_ctx(),
+            p_a.clone(),
+            ipv6_candidates.clone(),
+        );
+        let mut hole_punching_c = UdpHolePunchConnector::new_with_ipv6_candidates(
+            p_c.get_global_ctx(),
+            p_c.clone(),
+            ipv6_candidates,
+        );
+
+        // c has not advertised a global ipv6 yet, so a does not punch it
+        assert!(
+            UdpHolePunchConnector::collect_peer_to_connect(hole_punching_a.data.clone())
+                .await
+                .is_empty()
+        );
+
+        hole_punching_a.run().await.unwrap();
+        hole_punching_c.run().await.unwrap();
+
+        wait_for_condition(
+            || async {
+                wait_route_appear_with_cost(p_a.clone(), p_c.my_peer_id(), Some(1))
+                    .await
+                    .is_ok()
+            },
+            Duration::from_secs(30),
+        )
+        .await;
+
+        let conns = p_a.get_peer_map().list_peer_conns(p_c.my_peer_id()).await;
+        let tunnel = conns.unwrap()[0].tunnel.clone().unwrap();
+        assert!(tunnel.remote_addr.starts_with("udp://[::1]"));
+        assert!(hole_punching_a.data.udp_array.lock().await.is_none());
+    }
+
     #[tokio::test]
     async fn hole_punching_symmetric_only_random() {
         let p_a = create_mock_peer_manager_with_mock_stun(NatType::Symmetric).await;
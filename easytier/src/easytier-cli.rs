@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use std::{net::SocketAddr, time::Duration, vec};
+use std::{collections::BTreeSet, net::SocketAddr, time::Duration, vec};
 
 use clap::{command, Args, Parser, Subcommand};
 use common::stun::StunInfoCollectorTrait;
@@ -15,11 +15,16 @@ mod tunnel;
 mod utils;
 
 use crate::{
-    common::stun::StunInfoCollector,
+    common::{
+        config::{ExitPolicyRule, PortForwardConfig},
+        netns::NetNS,
+        stun::StunInfoCollector,
+    },
     rpc::{
         connector_manage_rpc_client::ConnectorManageRpcClient,
         peer_center_rpc_client::PeerCenterRpcClient, peer_manage_rpc_client::PeerManageRpcClient,
-        *,
+        port_forward_manage_rpc_client::PortForwardManageRpcClient,
+        proxy_flow_rpc_client::ProxyFlowRpcClient, *,
     },
     utils::{cost_to_str, float_to_str},
 };
@@ -49,6 +54,9 @@ enum SubCommand {
     PeerCenter,
     VpnPortal,
     Node(NodeArgs),
+    ExitPolicy(ExitPolicyArgs),
+    Forward(ForwardArgs),
+    Proxy(ProxyArgs),
 }
 
 #[derive(Args, Debug)]
@@ -114,6 +122,68 @@ struct NodeArgs {
     sub_command: Option<NodeSubCommand>,
 }
 
+#[derive(Subcommand, Debug)]
+enum ExitPolicySubCommand {
+    List,
+    /// replace all rules, e.g. dst=10.20.0.0/16,via=10.126.126.2
+    Set {
+        rules: Vec<String>,
+    },
+}
+
+#[derive(Args, Debug)]
+struct ExitPolicyArgs {
+    #[command(subcommand)]
+    sub_command: Option<ExitPolicySubCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ForwardSubCommand {
+    List,
+    /// e.g. tcp://0.0.0.0:8080/10.126.126.5:80 or tcp://virtual:15432/127.0.0.1:5432
+    Add {
+        rule: String,
+    },
+    Remove {
+        rule: String,
+    },
+}
+
+#[derive(Args, Debug)]
+struct ForwardArgs {
+    #[command(subcommand)]
+    sub_command: Option<ForwardSubCommand>,
+}
+
+#[derive(Args, Debug, Default)]
+struct ProxyListArgs {
+    /// tcp, udp or icmp
+    #[arg(long)]
+    protocol: Option<String>,
+
+    /// only flows from this peer id
+    #[arg(long)]
+    peer: Option<u32>,
+
+    /// ip or ip:port of the src or dst
+    #[arg(long)]
+    addr: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ProxySubCommand {
+    /// list flows of the subnet proxy
+    List(ProxyListArgs),
+    /// terminate a flow, e.g. tcp 67e55044-10b1-426f-9247-bb680e5fe0c8
+    Close { protocol: String, id: String },
+}
+
+#[derive(Args, Debug)]
+struct ProxyArgs {
+    #[command(subcommand)]
+    sub_command: Option<ProxySubCommand>,
+}
+
 #[derive(thiserror::Error, Debug)]
 enum Error {
     #[error("tonic transport error")]
@@ -154,6 +224,18 @@ impl CommandHandler {
         Ok(VpnPortalRpcClient::connect(self.addr.clone()).await?)
     }
 
+    async fn get_port_forward_client(
+        &self,
+    ) -> Result<PortForwardManageRpcClient<tonic::transport::Channel>, Error> {
+        Ok(PortForwardManageRpcClient::connect(self.addr.clone()).await?)
+    }
+
+    async fn get_proxy_flow_client(
+        &self,
+    ) -> Result<ProxyFlowRpcClient<tonic::transport::Channel>, Error> {
+        Ok(ProxyFlowRpcClient::connect(self.addr.clone()).await?)
+    }
+
     async fn list_peers(&self) -> Result<ListPeerResponse, Error> {
         let mut client = self.get_peer_manager_client().await?;
         let request = tonic::Request::new(ListPeerRequest::default());
@@ -192,6 +274,7 @@ impl CommandHandler {
             cost: String,
             lat_ms: String,
             loss_rate: String,
+            mtu: String,
             rx_bytes: String,
             tx_bytes: String,
             tunnel_proto: String,
@@ -203,11 +286,14 @@ impl CommandHandler {
         impl From<PeerRoutePair> for PeerTableItem {
             fn from(p: PeerRoutePair) -> Self {
                 PeerTableItem {
-                    ipv4: p.route.ipv4_addr.clone(),
+                    ipv4: ipv4_with_conflict(&p.route.ipv4_addr, p.route.ipv4_conflict),
                     hostname: p.route.hostname.clone(),
                     cost: cost_to_str(p.route.cost),
                     lat_ms: float_to_str(p.get_latency_ms().unwrap_or(0.0), 3),
                     loss_rate: float_to_str(p.get_loss_rate().unwrap_or(0.0), 3),
+                    mtu: p
+                        .get_path_mtu()
+                        .map_or("-".to_string(), |mtu| mtu.to_string()),
                     rx_bytes: format_size(p.get_rx_bytes().unwrap_or(0), humansize::DECIMAL),
                     tx_bytes: format_size(p.get_tx_bytes().unwrap_or(0), humansize::DECIMAL),
                     tunnel_proto: p.get_conn_protos().unwrap_or(vec![]).join(",").to_string(),
@@ -225,11 +311,12 @@ impl CommandHandler {
         impl From<NodeInfo> for PeerTableItem {
             fn from(p: NodeInfo) -> Self {
                 PeerTableItem {
-                    ipv4: p.ipv4_addr.clone(),
+                    ipv4: ipv4_with_conflict(&p.ipv4_addr, p.ipv4_conflict),
                     hostname: p.hostname.clone(),
                     cost: "Local".to_string(),
                     lat_ms: "-".to_string(),
                     loss_rate: "-".to_string(),
+                    mtu: "-".to_string(),
                     rx_bytes: "-".to_string(),
                     tx_bytes: "-".to_string(),
                     tunnel_proto: "-".to_string(),
@@ -244,6 +331,14 @@ impl CommandHandler {
             }
         }
 
+        fn ipv4_with_conflict(ipv4: &str, conflict: bool) -> String {
+            if conflict {
+                format!("{} (CONFLICT)", ipv4)
+            } else {
+                ipv4.to_string()
+            }
+        }
+
         let mut items: Vec<PeerTableItem> = vec![];
         let peer_routes = self.list_peer_route_pair().await?;
         if self.verbose {
@@ -258,9 +353,16 @@ impl CommandHandler {
             .into_inner()
             .node_info
             .ok_or(anyhow::anyhow!("node info not found"))?;
+        let mut conflicts = BTreeSet::new();
+        if node_info.ipv4_conflict {
+            conflicts.insert(node_info.ipv4_addr.clone());
+        }
         items.push(node_info.into());
 
         for p in peer_routes {
+            if p.route.ipv4_conflict {
+                conflicts.insert(p.route.ipv4_addr.clone());
+            }
             items.push(p.into());
         }
 
@@ -269,6 +371,13 @@ impl CommandHandler {
             tabled::Table::new(items).with(Style::modern()).to_string()
         );
 
+        if !conflicts.is_empty() {
+            println!(
+                "WARNING: virtual ipv4 used by more than one node: {}",
+                conflicts.into_iter().collect::<Vec<_>>().join(", ")
+            );
+        }
+
         Ok(())
     }
 
@@ -330,9 +439,42 @@ impl CommandHandler {
             next_hop_hostname: String,
             next_hop_lat: f64,
             cost: i32,
+            exit_node: String,
+            tags: String,
             version: String,
         }
 
+        let tags_str = |route: &Route| {
+            let tags = route.tags.join(",");
+            if route.segment_denied {
+                format!("{} (denied)", tags).trim_start().to_string()
+            } else {
+                tags
+            }
+        };
+
+        // show mapped cidrs together with the real network behind them
+        let proxy_cidrs_str = |proxy_cidrs: &[String], netmaps: &[String]| {
+            proxy_cidrs
+                .iter()
+                .map(|cidr| {
+                    netmaps
+                        .iter()
+                        .find(|m| m.ends_with(&format!("->{}", cidr)))
+                        .unwrap_or(cidr)
+                        .clone()
+                })
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        let exit_node_str = |health: &Option<ExitNodeHealth>| match health {
+            Some(h) if h.unknown => "unknown".to_string(),
+            Some(h) if h.healthy => format!("healthy({}ms)", h.latency_ms),
+            Some(h) => format!("unhealthy({})", h.last_error),
+            None => "-".to_string(),
+        };
+
         let mut items: Vec<RouteTableItem> = vec![];
         let mut client = self.get_peer_manager_client().await?;
         let node_info = client
@@ -345,11 +487,13 @@ impl CommandHandler {
         items.push(RouteTableItem {
             ipv4: node_info.ipv4_addr.clone(),
             hostname: node_info.hostname.clone(),
-            proxy_cidrs: node_info.proxy_cidrs.join(", "),
+            proxy_cidrs: proxy_cidrs_str(&node_info.proxy_cidrs, &node_info.netmaps),
             next_hop_ipv4: "-".to_string(),
             next_hop_hostname: "Local".to_string(),
             next_hop_lat: 0.0,
             cost: 0,
+            exit_node: "-".to_string(),
+            tags: node_info.tags.join(","),
             version: node_info.version.clone(),
         });
         let peer_routes = self.list_peer_route_pair().await?;
@@ -365,11 +509,13 @@ impl CommandHandler {
                 items.push(RouteTableItem {
                     ipv4: p.route.ipv4_addr.clone(),
                     hostname: p.route.hostname.clone(),
-                    proxy_cidrs: p.route.proxy_cidrs.clone().join(",").to_string(),
+                    proxy_cidrs: proxy_cidrs_str(&p.route.proxy_cidrs, &p.route.netmaps),
                     next_hop_ipv4: "DIRECT".to_string(),
                     next_hop_hostname: "".to_string(),
                     next_hop_lat: next_hop_pair.get_latency_ms().unwrap_or(0.0),
                     cost: p.route.cost,
+                    exit_node: exit_node_str(&p.route.exit_node_health),
+                    tags: tags_str(&p.route),
                     version: if p.route.version.is_empty() {
                         "unknown".to_string()
                     } else {
@@ -380,11 +526,13 @@ impl CommandHandler {
                 items.push(RouteTableItem {
                     ipv4: p.route.ipv4_addr.clone(),
                     hostname: p.route.hostname.clone(),
-                    proxy_cidrs: p.route.proxy_cidrs.clone().join(",").to_string(),
+                    proxy_cidrs: proxy_cidrs_str(&p.route.proxy_cidrs, &p.route.netmaps),
                     next_hop_ipv4: next_hop_pair.route.ipv4_addr.clone(),
                     next_hop_hostname: next_hop_pair.route.hostname.clone(),
                     next_hop_lat: next_hop_pair.get_latency_ms().unwrap_or(0.0),
                     cost: p.route.cost,
+                    exit_node: exit_node_str(&p.route.exit_node_health),
+                    tags: tags_str(&p.route),
                     version: if p.route.version.is_empty() {
                         "unknown".to_string()
                     } else {
@@ -409,6 +557,52 @@ impl CommandHandler {
         println!("response: {:#?}", response.into_inner());
         Ok(())
     }
+    async fn handle_proxy_list(&self, args: ProxyListArgs) -> Result<(), Error> {
+        #[derive(tabled::Tabled)]
+        struct ProxyFlowTableItem {
+            id: String,
+            protocol: String,
+            src_peer: u32,
+            src: String,
+            dst: String,
+            state: String,
+            age: String,
+            tx_bytes: String,
+            rx_bytes: String,
+        }
+
+        let mut client = self.get_proxy_flow_client().await?;
+        let flows = client
+            .list_proxy_flow(ListProxyFlowRequest {
+                protocol: args.protocol.unwrap_or_default(),
+                src_peer_id: args.peer.unwrap_or_default(),
+                addr: args.addr.unwrap_or_default(),
+            })
+            .await?
+            .into_inner()
+            .flows;
+
+        let items = flows
+            .into_iter()
+            .map(|flow| ProxyFlowTableItem {
+                id: flow.id,
+                protocol: flow.protocol,
+                src_peer: flow.src_peer_id,
+                src: flow.src_addr,
+                dst: flow.dst_addr,
+                state: flow.state,
+                age: format!("{}s", flow.age_ms / 1000),
+                tx_bytes: format_size(flow.tx_bytes, humansize::DECIMAL),
+                rx_bytes: format_size(flow.rx_bytes, humansize::DECIMAL),
+            })
+            .collect::<Vec<_>>();
+
+        println!(
+            "{}",
+            tabled::Table::new(items).with(Style::modern()).to_string()
+        );
+        Ok(())
+    }
 }
 
 #[tokio::main]
@@ -462,7 +656,7 @@ async fn main() -> Result<(), Error> {
         },
         SubCommand::Stun => {
             timeout(Duration::from_secs(5), async move {
-                let collector = StunInfoCollector::new_with_default_servers();
+                let collector = StunInfoCollector::new_with_default_servers(NetNS::new(None));
                 loop {
                     let ret = collector.get_stun_info();
                     if ret.udp_nat_type != NatType::Unknown as i32 {
@@ -541,8 +735,19 @@ async fn main() -> Result<(), Error> {
                     let stun_info = node_info.stun_info.clone().unwrap_or_default();
 
                     let mut builder = tabled::builder::Builder::default();
-                    builder.push_record(vec!["Virtual IP", node_info.ipv4_addr.as_str()]);
+                    let virtual_ip = if node_info.ipv4_addr.is_empty() {
+                        String::new()
+                    } else {
+                        format!("{}/{}", node_info.ipv4_addr, node_info.network_length)
+                    };
+                    builder.push_record(vec!["Virtual IP", virtual_ip.as_str()]);
+                    if !node_info.ipv6_addr.is_empty() {
+                        builder.push_record(vec!["Virtual IPv6", node_info.ipv6_addr.as_str()]);
+                    }
                     builder.push_record(vec!["Hostname", node_info.hostname.as_str()]);
+                    if !node_info.tags.is_empty() {
+                        builder.push_record(vec!["Tags", node_info.tags.join(", ").as_str()]);
+                    }
                     builder.push_record(vec![
                         "Proxy CIDRs",
                         node_info.proxy_cidrs.join(", ").as_str(),
@@ -567,6 +772,78 @@ async fn main() -> Result<(), Error> {
                 }
             }
         }
+        SubCommand::ExitPolicy(exit_policy_args) => {
+            let mut client = handler.get_peer_manager_client().await?;
+            match exit_policy_args.sub_command {
+                Some(ExitPolicySubCommand::List) | None => {
+                    let rules = client
+                        .list_exit_policy(ListExitPolicyRequest::default())
+                        .await?
+                        .into_inner()
+                        .rules;
+                    for rule in rules {
+                        match ExitPolicyRule::try_from(rule) {
+                            Ok(rule) => println!("{}", rule),
+                            Err(e) => println!("invalid rule: {:?}", e),
+                        }
+                    }
+                }
+                Some(ExitPolicySubCommand::Set { rules }) => {
+                    let rules = rules
+                        .iter()
+                        .map(|r| r.parse::<ExitPolicyRule>().map(Into::into))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    client
+                        .set_exit_policy(SetExitPolicyRequest { rules })
+                        .await?;
+                }
+            }
+        }
+        SubCommand::Forward(forward_args) => {
+            let mut client = handler.get_port_forward_client().await?;
+            match forward_args.sub_command {
+                Some(ForwardSubCommand::List) | None => {
+                    let rules = client
+                        .list_port_forward(ListPortForwardRequest::default())
+                        .await?
+                        .into_inner()
+                        .rules;
+                    for rule in rules {
+                        match PortForwardConfig::try_from(rule) {
+                            Ok(rule) => println!("{}", rule),
+                            Err(e) => println!("invalid rule: {:?}", e),
+                        }
+                    }
+                }
+                Some(ForwardSubCommand::Add { rule }) => {
+                    let rule = rule.parse::<PortForwardConfig>()?;
+                    client
+                        .add_port_forward(AddPortForwardRequest {
+                            rule: Some(rule.into()),
+                        })
+                        .await?;
+                }
+                Some(ForwardSubCommand::Remove { rule }) => {
+                    let rule = rule.parse::<PortForwardConfig>()?;
+                    client
+                        .remove_port_forward(RemovePortForwardRequest {
+                            rule: Some(rule.into()),
+                        })
+                        .await?;
+                }
+            }
+        }
+        SubCommand::Proxy(proxy_args) => match proxy_args.sub_command {
+            Some(ProxySubCommand::List(args)) => handler.handle_proxy_list(args).await?,
+            None => handler.handle_proxy_list(ProxyListArgs::default()).await?,
+            Some(ProxySubCommand::Close { protocol, id }) => {
+                handler
+                    .get_proxy_flow_client()
+                    .await?
+                    .close_proxy_flow(CloseProxyFlowRequest { protocol, id })
+                    .await?;
+            }
+        },
     }
 
     Ok(())
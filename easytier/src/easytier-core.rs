@@ -27,7 +27,8 @@ mod utils;
 mod vpn_portal;
 
 use common::config::{
-    ConsoleLoggerConfig, FileLoggerConfig, NetworkIdentity, PeerConfig, VpnPortalConfig,
+    ConsoleLoggerConfig, DhcpReservation, DnsConfig, ExitPolicyRule, FileLoggerConfig,
+    NetworkIdentity, PeerConfig, PortForwardConfig, SegmentRule, VpnPortalConfig,
 };
 use instance::instance::Instance;
 use tokio::net::TcpSocket;
@@ -79,6 +80,26 @@ struct Cli {
     )]
     ipv4: Option<String>,
 
+    #[arg(
+        long,
+        help = t!("core_clap.network_length").to_string(),
+        value_parser = clap::value_parser!(u8).range(1..=30)
+    )]
+    network_length: Option<u8>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.ipv6").to_string()
+    )]
+    ipv6: Option<String>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.auto_ipv6").to_string(),
+        default_value = "false"
+    )]
+    auto_ipv6: bool,
+
     #[arg(
         short,
         long,
@@ -86,6 +107,32 @@ struct Cli {
     )]
     dhcp: bool,
 
+    #[arg(
+        long,
+        help = t!("core_clap.dhcp_pool").to_string()
+    )]
+    dhcp_pool: Option<String>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.dhcp_exclude").to_string(),
+        num_args = 0..
+    )]
+    dhcp_exclude: Vec<cidr::Ipv4Cidr>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.dhcp_reservation").to_string(),
+        num_args = 0..
+    )]
+    dhcp_reservation: Vec<DhcpReservation>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.dhcp_lease_file").to_string()
+    )]
+    dhcp_lease_file: Option<PathBuf>,
+
     #[arg(
         short,
         long,
@@ -217,6 +264,19 @@ struct Cli {
     )]
     latency_first: bool,
 
+    #[arg(
+        long,
+        help = t!("core_clap.enable_ecmp").to_string(),
+        default_value = "false"
+    )]
+    enable_ecmp: bool,
+
+    #[arg(
+        long,
+        help = t!("core_clap.ecmp_latency_tolerance").to_string()
+    )]
+    ecmp_latency_tolerance: Option<u32>,
+
     #[arg(
         long,
         help = t!("core_clap.exit_nodes").to_string(),
@@ -224,6 +284,34 @@ struct Cli {
     )]
     exit_nodes: Vec<Ipv4Addr>,
 
+    #[arg(
+        long,
+        help = t!("core_clap.exit_policy").to_string(),
+        num_args = 0..
+    )]
+    exit_policy: Vec<ExitPolicyRule>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.tags").to_string(),
+        num_args = 0..
+    )]
+    tags: Vec<String>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.segment_rules").to_string(),
+        num_args = 0..
+    )]
+    segment_rules: Vec<SegmentRule>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.disable_relay_for_denied_segments").to_string(),
+        default_value = "false"
+    )]
+    disable_relay_for_denied_segments: bool,
+
     #[arg(
         long,
         help = t!("core_clap.enable_exit_node").to_string(),
@@ -231,6 +319,26 @@ struct Cli {
     )]
     enable_exit_node: bool,
 
+    #[arg(
+        long,
+        help = t!("core_clap.exit_node_probe_target").to_string()
+    )]
+    exit_node_probe_target: Option<String>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.enable_exit_node_health_check").to_string(),
+        default_value = "false"
+    )]
+    enable_exit_node_health_check: bool,
+
+    #[arg(
+        long,
+        help = t!("core_clap.exit_node_load_balance").to_string(),
+        default_value = "false"
+    )]
+    exit_node_load_balance: bool,
+
     #[arg(
         long,
         help = t!("core_clap.no_tun").to_string(),
@@ -238,6 +346,13 @@ struct Cli {
     )]
     no_tun: bool,
 
+    #[arg(
+        long,
+        help = t!("core_clap.no_tun_on_ipv4_conflict").to_string(),
+        default_value = "false"
+    )]
+    no_tun_on_ipv4_conflict: bool,
+
     #[arg(
         long,
         help = t!("core_clap.use_smoltcp").to_string(),
@@ -252,6 +367,13 @@ struct Cli {
     )]
     manual_routes: Option<Vec<String>>,
 
+    #[arg(
+        long,
+        help = t!("core_clap.multicast_allowlist").to_string(),
+        num_args = 0..
+    )]
+    multicast_allowlist: Option<Vec<String>>,
+
     #[arg(
         long,
         help = t!("core_clap.relay_network_whitelist").to_string(),
@@ -273,6 +395,67 @@ struct Cli {
     )]
     disable_udp_hole_punching: bool,
 
+    #[arg(
+        long,
+        help = t!("core_clap.disable_tcp_hole_punching").to_string(),
+        default_value = "false"
+    )]
+    disable_tcp_hole_punching: bool,
+
+    #[arg(
+        long,
+        help = t!("core_clap.disable_port_mapping").to_string(),
+        default_value = "false"
+    )]
+    disable_port_mapping: bool,
+
+    #[arg(
+        long,
+        help = t!("core_clap.enable_lan_discovery").to_string(),
+        default_value = "false"
+    )]
+    enable_lan_discovery: bool,
+
+    #[arg(
+        long,
+        help = t!("core_clap.stun_server_port").to_string(),
+        value_parser = clap::value_parser!(u16).range(1..65535)
+    )]
+    stun_server_port: Option<u16>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.enable_dns").to_string(),
+        default_value = "false"
+    )]
+    enable_dns: bool,
+
+    #[arg(
+        long,
+        help = t!("core_clap.dns_listen").to_string()
+    )]
+    dns_listen: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.dns_suffix").to_string()
+    )]
+    dns_suffix: Option<String>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.dns_upstream").to_string(),
+        num_args = 0..
+    )]
+    dns_upstream: Vec<SocketAddr>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.dns_register_resolved").to_string(),
+        default_value = "false"
+    )]
+    dns_register_resolved: bool,
+
     #[arg(
         long,
         help = t!("core_clap.relay_all_peer_rpc").to_string(),
@@ -286,6 +469,20 @@ struct Cli {
         help = t!("core_clap.socks5").to_string()
     )]
     socks5: Option<u16>,
+
+    #[cfg(feature = "socks5")]
+    #[arg(
+        long,
+        help = t!("core_clap.http_proxy").to_string()
+    )]
+    http_proxy: Option<String>,
+
+    #[arg(
+        long,
+        help = t!("core_clap.port_forward").to_string(),
+        num_args = 0..
+    )]
+    port_forward: Vec<PortForwardConfig>,
 }
 
 rust_i18n::i18n!("locales", fallback = "en");
@@ -387,6 +584,50 @@ impl From<Cli> for TomlConfigLoader {
 
         cfg.set_dhcp(cli.dhcp);
 
+        let mut dhcp_config = cfg.get_dhcp_config();
+        if let Some(pool) = &cli.dhcp_pool {
+            let (start, end) = pool
+                .split_once('-')
+                .with_context(|| format!("dhcp pool should be start-end: {}", pool))
+                .unwrap();
+            dhcp_config.pool_start = Some(
+                start
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("failed to parse dhcp pool start: {}", start))
+                    .unwrap(),
+            );
+            dhcp_config.pool_end = Some(
+                end.trim()
+                    .parse()
+                    .with_context(|| format!("failed to parse dhcp pool end: {}", end))
+                    .unwrap(),
+            );
+        }
+        if !cli.dhcp_exclude.is_empty() {
+            dhcp_config.exclude = Some(cli.dhcp_exclude.clone());
+        }
+        if !cli.dhcp_reservation.is_empty() {
+            dhcp_config.reservations = Some(cli.dhcp_reservation.clone());
+        }
+        if cli.dhcp_lease_file.is_some() {
+            dhcp_config.lease_file = cli.dhcp_lease_file.clone();
+        }
+        cfg.set_dhcp_config(dhcp_config);
+
+        if cli.enable_dns {
+            cfg.set_dns_config(Some(DnsConfig {
+                listen: cli.dns_listen,
+                suffix: cli.dns_suffix.clone(),
+                upstream: if cli.dns_upstream.is_empty() {
+                    None
+                } else {
+                    Some(cli.dns_upstream.clone())
+                },
+                register_resolved: cli.dns_register_resolved,
+            }));
+        }
+
         if let Some(ipv4) = &cli.ipv4 {
             cfg.set_ipv4(Some(
                 ipv4.parse()
@@ -395,6 +636,16 @@ impl From<Cli> for TomlConfigLoader {
             ))
         }
 
+        cfg.set_network_length(cli.network_length);
+
+        if let Some(ipv6) = &cli.ipv6 {
+            cfg.set_ipv6(Some(
+                ipv6.parse()
+                    .with_context(|| format!("failed to parse ipv6 address: {}", ipv6))
+                    .unwrap(),
+            ))
+        }
+
         cfg.set_peers(
             cli.peers
                 .iter()
@@ -415,6 +666,14 @@ impl From<Cli> for TomlConfigLoader {
         );
 
         for n in cli.proxy_networks.iter() {
+            if n.contains("->") {
+                cfg.add_netmap(
+                    n.parse()
+                        .with_context(|| format!("failed to parse proxy network: {}", n))
+                        .unwrap(),
+                );
+                continue;
+            }
             cfg.add_proxy_cidr(
                 n.parse()
                     .with_context(|| format!("failed to parse proxy network: {}", n))
@@ -489,6 +748,19 @@ impl From<Cli> for TomlConfigLoader {
             });
         }
 
+        if let Some(groups) = &cli.multicast_allowlist {
+            cfg.set_multicast_allowlist(Some(
+                groups
+                    .iter()
+                    .map(|s| {
+                        s.parse()
+                            .with_context(|| format!("failed to parse multicast group: {}", s))
+                            .unwrap()
+                    })
+                    .collect(),
+            ));
+        }
+
         if cli.manual_routes.is_some() {
             cfg.set_routes(Some(
                 cli.manual_routes
@@ -513,6 +785,19 @@ impl From<Cli> for TomlConfigLoader {
             ));
         }
 
+        #[cfg(feature = "socks5")]
+        if let Some(http_proxy) = &cli.http_proxy {
+            let url = match http_proxy.parse::<u16>() {
+                Ok(port) => format!("http://0.0.0.0:{}", port),
+                Err(_) => http_proxy.clone(),
+            };
+            cfg.set_http_proxy_portal(Some(
+                url.parse()
+                    .with_context(|| format!("failed to parse http proxy: {}", http_proxy))
+                    .unwrap(),
+            ));
+        }
+
         let mut f = cfg.get_flags();
         if cli.default_protocol.is_some() {
             f.default_protocol = cli.default_protocol.as_ref().unwrap().clone();
@@ -520,21 +805,47 @@ impl From<Cli> for TomlConfigLoader {
         f.enable_encryption = !cli.disable_encryption;
         f.enable_ipv6 = !cli.disable_ipv6;
         f.latency_first = cli.latency_first;
+        f.enable_ecmp = cli.enable_ecmp;
+        if let Some(tolerance) = cli.ecmp_latency_tolerance {
+            f.ecmp_latency_tolerance = tolerance;
+        }
         f.dev_name = cli.dev_name.unwrap_or(Default::default());
         if let Some(mtu) = cli.mtu {
             f.mtu = mtu;
         }
         f.enable_exit_node = cli.enable_exit_node;
+        f.exit_node_probe_target = cli.exit_node_probe_target.clone().unwrap_or_default();
+        f.enable_exit_node_health_check = cli.enable_exit_node_health_check;
+        f.exit_node_load_balance = cli.exit_node_load_balance;
         f.no_tun = cli.no_tun || cfg!(not(feature = "tun"));
+        f.no_tun_on_ipv4_conflict = cli.no_tun_on_ipv4_conflict;
         f.use_smoltcp = cli.use_smoltcp;
         if let Some(wl) = cli.relay_network_whitelist {
             f.foreign_network_whitelist = wl.join(" ");
         }
         f.disable_p2p = cli.disable_p2p;
         f.relay_all_peer_rpc = cli.relay_all_peer_rpc;
+        f.disable_tcp_hole_punching = cli.disable_tcp_hole_punching;
+        f.disable_port_mapping = cli.disable_port_mapping;
+        f.enable_lan_discovery = cli.enable_lan_discovery;
+        f.stun_server_port = cli.stun_server_port.unwrap_or(0);
+        f.auto_ipv6 = cli.auto_ipv6;
+        f.disable_relay_for_denied_segments = cli.disable_relay_for_denied_segments;
         cfg.set_flags(f);
 
         cfg.set_exit_nodes(cli.exit_nodes.clone());
+        if !cli.exit_policy.is_empty() {
+            cfg.set_exit_policy(cli.exit_policy.clone());
+        }
+        if !cli.tags.is_empty() {
+            cfg.set_tags(cli.tags.clone());
+        }
+        if !cli.segment_rules.is_empty() {
+            cfg.set_segment_rules(cli.segment_rules.clone());
+        }
+        if !cli.port_forward.is_empty() {
+            cfg.set_port_forwards(cli.port_forward.clone());
+        }
 
         cfg
     }
@@ -663,6 +974,27 @@ pub async fn async_main(cli: Cli) {
                 GlobalCtxEvent::DhcpIpv4Conflicted(ip) => {
                     print_event(format!("dhcp ip conflict. ip: {:?}", ip));
                 }
+
+                GlobalCtxEvent::Ipv4Conflicted(ip, peer_ids) => {
+                    print_event(format!(
+                        "ipv4 conflict, same address used by multiple peers. ip: {}, peers: {:?}",
+                        ip, peer_ids
+                    ));
+                }
+
+                GlobalCtxEvent::NetworkLengthChanged(old, new) => {
+                    print_event(format!(
+                        "network length changed. old: {}, new: {}",
+                        old, new
+                    ));
+                }
+
+                GlobalCtxEvent::NetworkLengthConflicted(lengths) => {
+                    print_event(format!(
+                        "peers are configured with different network lengths: {:?}",
+                        lengths
+                    ));
+                }
             }
         }
     });
@@ -674,7 +1006,35 @@ pub async fn async_main(cli: Cli) {
 
     inst.run().await.unwrap();
 
-    inst.wait().await;
+    let port_mapping_manager = inst.get_port_mapping_manager();
+    tokio::select! {
+        _ = inst.wait() => {}
+        _ = wait_for_exit_signal() => {
+            println!("exit signal received, exiting...");
+        }
+    }
+
+    // remove the port mappings on gateway before exit
+    port_mapping_manager
+        .lock()
+        .await
+        .remove_all_mappings()
+        .await;
+}
+
+// ctrl-c, or sigterm sent by service managers on unix
+async fn wait_for_exit_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
 
 fn main() {
@@ -182,10 +182,22 @@ impl<A: Authentication> Config<A> {
 }
 
 #[async_trait::async_trait]
-pub trait AsyncTcpConnector {
+pub trait AsyncTcpConnector: Send + Sync {
     type S: AsyncRead + AsyncWrite + Unpin + Send + Sync;
 
     async fn tcp_connect(&self, addr: SocketAddr, timeout_s: u64) -> Result<Self::S>;
+
+    /// Bind the socket the client sends UDP datagrams to.
+    async fn udp_bind(&self, addr: SocketAddr) -> Result<UdpSocket> {
+        Ok(UdpSocket::bind(addr).await?)
+    }
+
+    /// Relay the datagrams of a UDP association until an error occurs. `client_ip` is the peer
+    /// of the TCP connection the association arrived on, if known.
+    async fn udp_associate(&self, inbound: UdpSocket, client_ip: Option<IpAddr>) -> Result<()> {
+        let _ = client_ip;
+        transfer_udp(inbound).await
+    }
 }
 
 pub struct DefaultTcpConnector {}
@@ -209,6 +221,8 @@ pub struct Socks5Socket<T: AsyncRead + AsyncWrite + Unpin, A: Authentication, C:
     cmd: Option<Socks5Command>,
     /// Socket address which will be used in the reply message.
     reply_ip: Option<IpAddr>,
+    /// IP address of the client, only datagrams from it are relayed in UDP ASSOCIATE.
+    client_ip: Option<IpAddr>,
     /// If the client has been authenticated, that's where we store his credentials
     /// to be accessed from the socket
     credentials: Option<A::Item>,
@@ -226,6 +240,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin, A: Authentication, C: AsyncTcpConnector>
             target_addr: None,
             cmd: None,
             reply_ip: None,
+            client_ip: None,
             credentials: None,
             tcp_connector,
         }
@@ -246,6 +261,13 @@ impl<T: AsyncRead + AsyncWrite + Unpin, A: Authentication, C: AsyncTcpConnector>
         self.reply_ip = Some(addr);
     }
 
+    /// Set the IP address of the client, the peer of the inner socket.
+    ///
+    /// A UDP association only accepts datagrams from this address, see page 7 of RFC 1928.
+    pub fn set_client_ip(&mut self, addr: IpAddr) {
+        self.client_ip = Some(addr);
+    }
+
     /// Process clients SOCKS requests
     /// This is the entry point where a whole request is processed.
     pub async fn upgrade_to_socks5(mut self) -> Result<Socks5Socket<T, A, C>> {
@@ -643,25 +665,29 @@ impl<T: AsyncRead + AsyncWrite + Unpin, A: Authentication, C: AsyncTcpConnector>
         // We do NOT limit the access from the client currently in this implementation.
         let _not_used = self.target_addr.as_ref();
 
-        // Listen with UDP6 socket, so the client can connect to it with either
-        // IPv4 or IPv6.
-        let peer_sock = UdpSocket::bind("[::]:0").await?;
-
         // Respect the pre-populated reply IP address.
+        let reply_ip = self.reply_ip.context("invalid reply ip")?;
+        let peer_sock = self
+            .tcp_connector
+            .udp_bind(SocketAddr::new(reply_ip, 0))
+            .await?;
+
         self.inner
             .write(&new_reply(
                 &ReplyError::Succeeded,
-                SocketAddr::new(
-                    self.reply_ip.context("invalid reply ip")?,
-                    peer_sock.local_addr()?.port(),
-                ),
+                SocketAddr::new(reply_ip, peer_sock.local_addr()?.port()),
             ))
             .await
             .context("Can't write successful reply")?;
 
         debug!("Wrote success");
 
-        transfer_udp(peer_sock).await?;
+        // The association terminates when the TCP connection it arrived on terminates.
+        let mut buf = [0u8; 1];
+        tokio::select! {
+            ret = self.tcp_connector.udp_associate(peer_sock, self.client_ip) => ret?,
+            _ = self.inner.read(&mut buf) => debug!("UDP association closed by the client"),
+        }
 
         Ok(())
     }
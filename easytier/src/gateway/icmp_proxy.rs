@@ -1,6 +1,6 @@
 use std::{
     mem::MaybeUninit,
-    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
     sync::Arc,
     thread,
     time::Duration,
@@ -8,8 +8,10 @@ use std::{
 
 use pnet::packet::{
     icmp::{self, echo_reply::MutableEchoReplyPacket, IcmpCode, IcmpTypes, MutableIcmpPacket},
+    icmpv6::{self, Icmpv6Code, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet},
     ip::IpNextHeaderProtocols,
     ipv4::Ipv4Packet,
+    ipv6::Ipv6Packet,
     Packet,
 };
 use socket2::Socket;
@@ -23,11 +25,12 @@ use tracing::Instrument;
 use crate::{
     common::{error::Error, global_ctx::ArcGlobalCtx, PeerId},
     peers::{peer_manager::PeerManager, PeerPacketFilter},
+    rpc::ProxyFlow,
     tunnel::packet_def::{PacketType, ZCPacket},
 };
 
 use super::{
-    ip_reassembler::{compose_ipv4_packet, IpReassembler},
+    ip_reassembler::{compose_ipv4_packet, compose_ipv6_packets, IpReassembler},
     CidrSet,
 };
 
@@ -40,21 +43,49 @@ struct IcmpNatKey {
 
 #[derive(Debug)]
 struct IcmpNatEntry {
+    id: uuid::Uuid,
     src_peer_id: PeerId,
     my_peer_id: PeerId,
     src_ip: IpAddr,
+    // the dst the peer sent to, used as reply source. may be a mapped address
+    dst_ip: IpAddr,
     start_time: std::time::Instant,
+    // size of the echo request, the entry is gone once the reply arrives
+    tx_bytes: u64,
 }
 
 impl IcmpNatEntry {
-    fn new(src_peer_id: PeerId, my_peer_id: PeerId, src_ip: IpAddr) -> Result<Self, Error> {
+    fn new(
+        src_peer_id: PeerId,
+        my_peer_id: PeerId,
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        tx_bytes: u64,
+    ) -> Result<Self, Error> {
         Ok(Self {
+            id: uuid::Uuid::new_v4(),
             src_peer_id,
             my_peer_id,
             src_ip,
+            dst_ip,
             start_time: std::time::Instant::now(),
+            tx_bytes,
         })
     }
+
+    fn to_proxy_flow(&self) -> ProxyFlow {
+        ProxyFlow {
+            id: self.id.to_string(),
+            protocol: "icmp".to_owned(),
+            src_peer_id: self.src_peer_id,
+            src_addr: self.src_ip.to_string(),
+            dst_addr: self.dst_ip.to_string(),
+            state: "waiting_reply".to_owned(),
+            age_ms: self.start_time.elapsed().as_millis() as u64,
+            tx_bytes: self.tx_bytes,
+            rx_bytes: 0,
+        }
+    }
 }
 
 type IcmpNatTable = Arc<dashmap::DashMap<IcmpNatKey, IcmpNatEntry>>;
@@ -68,6 +99,7 @@ pub struct IcmpProxy {
 
     cidr_set: CidrSet,
     socket: std::sync::Mutex<Option<socket2::Socket>>,
+    socket_v6: std::sync::Mutex<Option<socket2::Socket>>,
 
     nat_table: IcmpNatTable,
 
@@ -127,7 +159,9 @@ fn socket_recv_loop(socket: Socket, nat_table: IcmpNatTable, sender: UnboundedSe
             continue;
         };
 
-        let src_v4 = ipv4_packet.get_source();
+        let IpAddr::V4(src_v4) = v.dst_ip else {
+            continue;
+        };
         let payload_len = len - ipv4_packet.get_header_length() as usize * 4;
         let id = ipv4_packet.get_identification();
         let _ = compose_ipv4_packet(
@@ -156,6 +190,65 @@ fn socket_recv_loop(socket: Socket, nat_table: IcmpNatTable, sender: UnboundedSe
     }
 }
 
+// raw icmpv6 sockets deliver the icmp message without the ip header
+fn socket_recv_loop_v6(socket: Socket, nat_table: IcmpNatTable, sender: UnboundedSender<ZCPacket>) {
+    let mut buf = [0u8; 8192];
+    let data: &mut [MaybeUninit<u8>] = unsafe { std::mem::transmute(&mut buf[..]) };
+
+    loop {
+        let Ok((len, peer_ip)) = socket_recv(&socket, data) else {
+            continue;
+        };
+
+        let Some(icmp_packet) = icmpv6::echo_reply::EchoReplyPacket::new(&buf[..len]) else {
+            continue;
+        };
+
+        if icmp_packet.get_icmpv6_type() != Icmpv6Types::EchoReply {
+            continue;
+        }
+
+        let key = IcmpNatKey {
+            dst_ip: peer_ip,
+            icmp_id: icmp_packet.get_identifier(),
+            icmp_seq: icmp_packet.get_sequence_number(),
+        };
+
+        let Some((_, v)) = nat_table.remove(&key) else {
+            continue;
+        };
+
+        let (IpAddr::V6(src_v6), IpAddr::V6(dest_ip)) = (v.dst_ip, v.src_ip) else {
+            continue;
+        };
+
+        // the pseudo header changed, so the checksum has to be recomputed
+        let mut icmp_packet = MutableIcmpv6Packet::new(&mut buf[..len]).unwrap();
+        icmp_packet.set_checksum(icmpv6::checksum(
+            &icmp_packet.to_immutable(),
+            &src_v6,
+            &dest_ip,
+        ));
+
+        for packet in compose_ipv6_packets(
+            &src_v6,
+            &dest_ip,
+            IpNextHeaderProtocols::Icmpv6,
+            &buf[..len],
+            1200,
+            rand::random(),
+        ) {
+            let mut p = ZCPacket::new_with_payload(&packet);
+            p.fill_peer_manager_hdr(v.my_peer_id, v.src_peer_id, PacketType::Data as u8);
+            p.mut_peer_manager_header().unwrap().set_no_proxy(true);
+
+            if let Err(e) = sender.send(p) {
+                tracing::error!("send icmp packet to peer failed: {:?}, may exiting..", e);
+            }
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl PeerPacketFilter for IcmpProxy {
     async fn try_process_packet_from_peer(&self, packet: ZCPacket) -> Option<ZCPacket> {
@@ -178,6 +271,7 @@ impl IcmpProxy {
             peer_manager,
             cidr_set,
             socket: std::sync::Mutex::new(None),
+            socket_v6: std::sync::Mutex::new(None),
 
             nat_table: Arc::new(dashmap::DashMap::new()),
             tasks: Mutex::new(JoinSet::new()),
@@ -203,6 +297,22 @@ impl IcmpProxy {
         Ok(socket)
     }
 
+    fn create_raw_socket_v6(self: &Arc<Self>) -> Result<Socket, Error> {
+        let _g = self.global_ctx.net_ns.guard();
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV6,
+            socket2::Type::RAW,
+            Some(socket2::Protocol::ICMPV6),
+        )?;
+        socket.bind(&socket2::SockAddr::from(SocketAddrV6::new(
+            Ipv6Addr::UNSPECIFIED,
+            0,
+            0,
+            0,
+        )))?;
+        Ok(socket)
+    }
+
     pub async fn start(self: &Arc<Self>) -> Result<(), Error> {
         let socket = self.create_raw_socket();
         match socket {
@@ -217,6 +327,16 @@ impl IcmpProxy {
             }
         }
 
+        // hosts without ipv6 just don't proxy icmpv6
+        match self.create_raw_socket_v6() {
+            Ok(socket) => {
+                self.socket_v6.lock().unwrap().replace(socket);
+            }
+            Err(e) => {
+                tracing::warn!("create icmpv6 socket failed: {:?}", e);
+            }
+        }
+
         self.start_icmp_proxy().await?;
         self.start_nat_table_cleaner().await?;
         Ok(())
@@ -242,10 +362,18 @@ impl IcmpProxy {
         if let Some(socket) = self.socket.lock().unwrap().as_ref() {
             let socket = socket.try_clone()?;
             let nat_table = self.nat_table.clone();
+            let sender = sender.clone();
             thread::spawn(|| {
                 socket_recv_loop(socket, nat_table, sender);
             });
         }
+        if let Some(socket) = self.socket_v6.lock().unwrap().as_ref() {
+            let socket = socket.try_clone()?;
+            let nat_table = self.nat_table.clone();
+            thread::spawn(|| {
+                socket_recv_loop_v6(socket, nat_table, sender);
+            });
+        }
 
         let peer_manager = self.peer_manager.clone();
         self.tasks.lock().await.spawn(
@@ -289,6 +417,61 @@ impl IcmpProxy {
         Ok(())
     }
 
+    fn send_icmpv6_packet(
+        &self,
+        dst_ip: Ipv6Addr,
+        icmp_packet: &icmpv6::echo_request::EchoRequestPacket,
+    ) -> Result<(), Error> {
+        // the kernel fills in the icmpv6 checksum
+        self.socket_v6.lock().unwrap().as_ref().unwrap().send_to(
+            icmp_packet.packet(),
+            &SocketAddrV6::new(dst_ip, 0, 0, 0).into(),
+        )?;
+
+        Ok(())
+    }
+
+    async fn send_icmpv6_reply_to_peer(
+        &self,
+        src_ip: &Ipv6Addr,
+        dst_ip: &Ipv6Addr,
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+        icmp_packet: &icmpv6::echo_request::EchoRequestPacket<'_>,
+    ) {
+        let mut buf = vec![0u8; icmp_packet.packet().len()];
+        let mut reply_packet = icmpv6::echo_reply::MutableEchoReplyPacket::new(&mut buf).unwrap();
+        reply_packet.set_icmpv6_type(Icmpv6Types::EchoReply);
+        reply_packet.set_icmpv6_code(Icmpv6Code::new(0));
+        reply_packet.set_identifier(icmp_packet.get_identifier());
+        reply_packet.set_sequence_number(icmp_packet.get_sequence_number());
+        reply_packet.set_payload(icmp_packet.payload());
+
+        let checksum = icmpv6::checksum(&Icmpv6Packet::new(&buf).unwrap(), src_ip, dst_ip);
+        MutableIcmpv6Packet::new(&mut buf)
+            .unwrap()
+            .set_checksum(checksum);
+
+        for buf in compose_ipv6_packets(
+            src_ip,
+            dst_ip,
+            IpNextHeaderProtocols::Icmpv6,
+            &buf,
+            1200,
+            rand::random(),
+        ) {
+            let mut packet = ZCPacket::new_with_payload(&buf);
+            packet.fill_peer_manager_hdr(src_peer_id, dst_peer_id, PacketType::Data as u8);
+            let _ = self
+                .icmp_sender
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .send(packet);
+        }
+    }
+
     async fn send_icmp_reply_to_peer(
         &self,
         src_ip: &Ipv4Addr,
@@ -332,6 +515,23 @@ impl IcmpProxy {
         );
     }
 
+    pub fn list_flows(&self) -> Vec<ProxyFlow> {
+        self.nat_table
+            .iter()
+            .map(|entry| entry.to_proxy_flow())
+            .collect()
+    }
+
+    // returns false if no flow has the id, a late reply to a closed flow is dropped
+    pub fn close_flow(&self, id: &uuid::Uuid) -> bool {
+        let mut found = false;
+        self.nat_table.retain(|_, entry| {
+            found |= entry.id == *id;
+            entry.id != *id
+        });
+        found
+    }
+
     async fn try_handle_peer_packet(&self, packet: &ZCPacket) -> Option<()> {
         if self.cidr_set.is_empty()
             && !self.global_ctx.enable_exit_node()
@@ -340,18 +540,25 @@ impl IcmpProxy {
             return None;
         }
 
-        let _ = self.global_ctx.get_ipv4()?;
         let hdr = packet.peer_manager_header().unwrap();
-        let is_exit_node = hdr.is_exit_node();
-
         if hdr.packet_type != PacketType::Data as u8 || hdr.is_no_proxy() {
             return None;
         };
 
-        let ipv4 = Ipv4Packet::new(&packet.payload())?;
+        match packet.payload().first()? >> 4 {
+            4 => self.try_handle_peer_packet_v4(packet).await,
+            6 => self.try_handle_peer_packet_v6(packet).await,
+            _ => None,
+        }
+    }
 
-        if ipv4.get_version() != 4 || ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Icmp
-        {
+    async fn try_handle_peer_packet_v4(&self, packet: &ZCPacket) -> Option<()> {
+        let _ = self.global_ctx.get_ipv4()?;
+        let hdr = packet.peer_manager_header().unwrap();
+        let is_exit_node = hdr.is_exit_node();
+
+        let ipv4 = Ipv4Packet::new(&packet.payload())?;
+        if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
             return None;
         }
 
@@ -396,9 +603,10 @@ impl IcmpProxy {
 
         let icmp_id = icmp_packet.get_identifier();
         let icmp_seq = icmp_packet.get_sequence_number();
+        let real_dst_ip = self.cidr_set.map_to_real_v4(ipv4.get_destination());
 
         let key = IcmpNatKey {
-            dst_ip: ipv4.get_destination().into(),
+            dst_ip: real_dst_ip.into(),
             icmp_id,
             icmp_seq,
         };
@@ -407,6 +615,8 @@ impl IcmpProxy {
             hdr.from_peer_id.into(),
             hdr.to_peer_id.into(),
             ipv4.get_source().into(),
+            ipv4.get_destination().into(),
+            icmp_packet.packet().len() as u64,
         )
         .ok()?;
 
@@ -414,10 +624,77 @@ impl IcmpProxy {
             tracing::info!("icmp nat table entry replaced: {:?}", old);
         }
 
-        if let Err(e) = self.send_icmp_packet(ipv4.get_destination(), &icmp_packet) {
+        if let Err(e) = self.send_icmp_packet(real_dst_ip, &icmp_packet) {
             tracing::error!("send icmp packet failed: {:?}", e);
         }
 
         Some(())
     }
+
+    async fn try_handle_peer_packet_v6(&self, packet: &ZCPacket) -> Option<()> {
+        let hdr = packet.peer_manager_header().unwrap();
+        let is_exit_node = hdr.is_exit_node();
+
+        let ipv6 = Ipv6Packet::new(packet.payload())?;
+        if ipv6.get_next_header() != IpNextHeaderProtocols::Icmpv6 {
+            return None;
+        }
+
+        let dst_ip = ipv6.get_destination();
+        let is_local = self.global_ctx.no_tun() && Some(dst_ip) == self.global_ctx.get_ipv6();
+        if !self.cidr_set.contains(dst_ip.into()) && !is_exit_node && !is_local {
+            return None;
+        }
+
+        // neighbor discovery and other icmpv6 messages go to the nic
+        let icmp_packet = icmpv6::echo_request::EchoRequestPacket::new(ipv6.payload())?;
+        if icmp_packet.get_icmpv6_type() != Icmpv6Types::EchoRequest {
+            tracing::trace!(
+                "unsupported icmpv6 type: {:?}",
+                icmp_packet.get_icmpv6_type()
+            );
+            return None;
+        }
+
+        if is_local {
+            self.send_icmpv6_reply_to_peer(
+                &dst_ip,
+                &ipv6.get_source(),
+                hdr.to_peer_id.get(),
+                hdr.from_peer_id.get(),
+                &icmp_packet,
+            )
+            .await;
+            return Some(());
+        }
+
+        if self.socket_v6.lock().unwrap().is_none() {
+            return None;
+        }
+
+        let key = IcmpNatKey {
+            dst_ip: dst_ip.into(),
+            icmp_id: icmp_packet.get_identifier(),
+            icmp_seq: icmp_packet.get_sequence_number(),
+        };
+
+        let value = IcmpNatEntry::new(
+            hdr.from_peer_id.into(),
+            hdr.to_peer_id.into(),
+            ipv6.get_source().into(),
+            dst_ip.into(),
+            icmp_packet.packet().len() as u64,
+        )
+        .ok()?;
+
+        if let Some(old) = self.nat_table.insert(key, value) {
+            tracing::info!("icmp nat table entry replaced: {:?}", old);
+        }
+
+        if let Err(e) = self.send_icmpv6_packet(dst_ip, &icmp_packet) {
+            tracing::error!("send icmpv6 packet failed: {:?}", e);
+        }
+
+        Some(())
+    }
 }
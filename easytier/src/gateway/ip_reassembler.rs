@@ -1,8 +1,11 @@
 use dashmap::DashMap;
-use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::{MutableFragmentPacket, MutableIpv6Packet};
+use pnet::packet::udp::{self, MutableUdpPacket};
 use pnet::packet::Packet;
-use std::net::Ipv4Addr;
+use std::cell::RefCell;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 use std::time::{Duration, Instant};
 
 use crate::common::error::Error;
@@ -239,8 +242,119 @@ where
     Ok(())
 }
 
+// builds the ipv4 fragments of an udp datagram
+pub fn compose_udp_packets(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    payload: &[u8],
+    ip_id: u16,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let mut buf = vec![0u8; 20 + 8 + payload.len()];
+    buf[28..].copy_from_slice(payload);
+
+    let mut udp_packet = MutableUdpPacket::new(&mut buf[20..]).unwrap();
+    udp_packet.set_source(src.port());
+    udp_packet.set_destination(dst.port());
+    udp_packet.set_length(payload.len() as u16 + 8);
+    udp_packet.set_checksum(udp::ipv4_checksum(
+        &udp_packet.to_immutable(),
+        src.ip(),
+        dst.ip(),
+    ));
+
+    let packets = RefCell::new(vec![]);
+    compose_ipv4_packet(
+        &mut buf,
+        src.ip(),
+        dst.ip(),
+        IpNextHeaderProtocols::Udp,
+        payload.len() + 8,
+        1200,
+        ip_id,
+        |packet| {
+            packets.borrow_mut().push(packet.to_vec());
+            Ok(())
+        },
+    )?;
+    Ok(packets.into_inner())
+}
+
+// builds an ipv6 packet around payload, split with fragment headers if larger than payload_mtu
+pub fn compose_ipv6_packets(
+    src: &Ipv6Addr,
+    dst: &Ipv6Addr,
+    next_header: IpNextHeaderProtocol,
+    payload: &[u8],
+    payload_mtu: usize,
+    ip_id: u32,
+) -> Vec<Vec<u8>> {
+    assert_eq!(0, payload_mtu % 8);
+    let fragmented = payload.len() > payload_mtu;
+    let ext_len = if fragmented { 8 } else { 0 };
+
+    let mut ret = vec![];
+    for (i, chunk) in payload.chunks(payload_mtu).enumerate() {
+        let mut buf = vec![0u8; 40 + ext_len + chunk.len()];
+        buf[40 + ext_len..].copy_from_slice(chunk);
+
+        let mut ipv6_packet = MutableIpv6Packet::new(&mut buf).unwrap();
+        ipv6_packet.set_version(6);
+        ipv6_packet.set_payload_length((ext_len + chunk.len()) as u16);
+        ipv6_packet.set_hop_limit(32);
+        ipv6_packet.set_source(*src);
+        ipv6_packet.set_destination(*dst);
+        if fragmented {
+            ipv6_packet.set_next_header(IpNextHeaderProtocols::Ipv6Frag);
+            let mut frag = MutableFragmentPacket::new(&mut buf[40..48]).unwrap();
+            frag.set_next_header(next_header);
+            frag.set_fragment_offset((i * payload_mtu) as u16);
+            frag.set_last_fragment((i + 1) * payload_mtu >= payload.len());
+            frag.set_id(ip_id);
+        } else {
+            ipv6_packet.set_next_header(next_header);
+        }
+        ret.push(buf);
+    }
+    ret
+}
+
+// builds the ipv6 packets of an udp datagram
+pub fn compose_udp_packets_v6(
+    src: SocketAddrV6,
+    dst: SocketAddrV6,
+    payload: &[u8],
+    ip_id: u32,
+) -> Vec<Vec<u8>> {
+    let mut buf = vec![0u8; 8 + payload.len()];
+    buf[8..].copy_from_slice(payload);
+
+    let mut udp_packet = MutableUdpPacket::new(&mut buf).unwrap();
+    udp_packet.set_source(src.port());
+    udp_packet.set_destination(dst.port());
+    udp_packet.set_length(payload.len() as u16 + 8);
+    udp_packet.set_checksum(udp::ipv6_checksum(
+        &udp_packet.to_immutable(),
+        src.ip(),
+        dst.ip(),
+    ));
+
+    compose_ipv6_packets(
+        src.ip(),
+        dst.ip(),
+        IpNextHeaderProtocols::Udp,
+        &buf,
+        1200,
+        ip_id,
+    )
+}
+
 #[cfg(test)]
 mod tests {
+    use pnet::packet::{
+        ipv6::{FragmentPacket, Ipv6Packet},
+        udp::UdpPacket,
+    };
+
     use super::*;
 
     #[test]
@@ -296,4 +410,62 @@ mod tests {
         resembler.remove_expired_packets();
         assert_eq!(0, resembler.packets.len());
     }
+
+    #[test]
+    fn compose_udp_packets_test() {
+        let src = "10.126.126.1:20000".parse().unwrap();
+        let dst = "10.126.126.2:53".parse().unwrap();
+
+        let packets = compose_udp_packets(src, dst, b"hello", 7).unwrap();
+        assert_eq!(1, packets.len());
+        let ipv4 = Ipv4Packet::new(&packets[0]).unwrap();
+        assert_eq!(*dst.ip(), ipv4.get_destination());
+        assert_eq!(7, ipv4.get_identification());
+        let udp_packet = UdpPacket::new(ipv4.payload()).unwrap();
+        assert_eq!(53, udp_packet.get_destination());
+        assert_eq!(b"hello", udp_packet.payload());
+
+        // large datagrams are fragmented
+        let packets = compose_udp_packets(src, dst, &[1u8; 3000], 8).unwrap();
+        assert_eq!(3, packets.len());
+        let first = Ipv4Packet::new(&packets[0]).unwrap();
+        assert_eq!(Ipv4Flags::MoreFragments, first.get_flags());
+        let total: usize = packets.iter().map(|p| p.len() - 20).sum();
+        assert_eq!(3000 + 8, total);
+    }
+
+    #[test]
+    fn compose_udp_packets_v6_test() {
+        let src = "[fd00::1]:20000".parse().unwrap();
+        let dst = "[fd00::2]:53".parse().unwrap();
+
+        let packets = compose_udp_packets_v6(src, dst, b"hello", 7);
+        assert_eq!(1, packets.len());
+        let ipv6 = Ipv6Packet::new(&packets[0]).unwrap();
+        assert_eq!(*dst.ip(), ipv6.get_destination());
+        assert_eq!(IpNextHeaderProtocols::Udp, ipv6.get_next_header());
+        let udp_packet = UdpPacket::new(ipv6.payload()).unwrap();
+        assert_eq!(53, udp_packet.get_destination());
+        assert_eq!(b"hello", udp_packet.payload());
+        assert_eq!(
+            udp_packet.get_checksum(),
+            udp::ipv6_checksum(&udp_packet, src.ip(), dst.ip())
+        );
+
+        // large datagrams carry fragment headers
+        let packets = compose_udp_packets_v6(src, dst, &[1u8; 3000], 8);
+        assert_eq!(3, packets.len());
+        let mut total = 0;
+        for (i, packet) in packets.iter().enumerate() {
+            let ipv6 = Ipv6Packet::new(packet).unwrap();
+            assert_eq!(IpNextHeaderProtocols::Ipv6Frag, ipv6.get_next_header());
+            let frag = FragmentPacket::new(ipv6.payload()).unwrap();
+            assert_eq!(IpNextHeaderProtocols::Udp, frag.get_next_header());
+            assert_eq!(8, frag.get_id());
+            assert_eq!((i * 1200) as u16, frag.get_fragment_offset());
+            assert_eq!(i == 2, frag.is_last_fragment());
+            total += ipv6.payload().len() - 8;
+        }
+        assert_eq!(3000 + 8, total);
+    }
 }
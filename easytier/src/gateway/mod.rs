@@ -1,10 +1,13 @@
 use std::sync::{Arc, Mutex};
 use tokio::task::JoinSet;
 
-use crate::common::global_ctx::ArcGlobalCtx;
+use crate::common::{config::Netmap, global_ctx::ArcGlobalCtx};
 
 pub mod icmp_proxy;
 pub mod ip_reassembler;
+#[cfg(feature = "smoltcp")]
+pub mod port_forward;
+pub mod proxy_flow;
 pub mod tcp_proxy;
 #[cfg(feature = "smoltcp")]
 pub mod tokio_smoltcp;
@@ -13,12 +16,15 @@ pub mod udp_proxy;
 #[cfg(feature = "socks5")]
 pub mod fast_socks5;
 #[cfg(feature = "socks5")]
+pub mod http_proxy;
+#[cfg(feature = "socks5")]
 pub mod socks5;
 
 #[derive(Debug)]
 struct CidrSet {
     global_ctx: ArcGlobalCtx,
     cidr_set: Arc<Mutex<Vec<cidr::IpCidr>>>,
+    netmaps: Arc<Mutex<Vec<Netmap>>>,
     tasks: JoinSet<()>,
 }
 
@@ -27,6 +33,7 @@ impl CidrSet {
         let mut ret = Self {
             global_ctx,
             cidr_set: Arc::new(Mutex::new(vec![])),
+            netmaps: Arc::new(Mutex::new(vec![])),
             tasks: JoinSet::new(),
         };
         ret.run_cidr_updater();
@@ -36,6 +43,7 @@ impl CidrSet {
     fn run_cidr_updater(&mut self) {
         let global_ctx = self.global_ctx.clone();
         let cidr_set = self.cidr_set.clone();
+        let netmaps = self.netmaps.clone();
         self.tasks.spawn(async move {
             let mut last_cidrs = vec![];
             loop {
@@ -47,13 +55,17 @@ impl CidrSet {
                         cidr_set.lock().unwrap().push(cidr.clone());
                     }
                 }
+                *netmaps.lock().unwrap() = global_ctx.config.get_netmaps();
                 tokio::time::sleep(std::time::Duration::from_secs(1)).await;
             }
         });
     }
 
     pub fn contains_v4(&self, ip: std::net::Ipv4Addr) -> bool {
-        let ip = ip.into();
+        self.contains(ip.into())
+    }
+
+    pub fn contains(&self, ip: std::net::IpAddr) -> bool {
         let s = self.cidr_set.lock().unwrap();
         for cidr in s.iter() {
             if cidr.contains(&ip) {
@@ -63,6 +75,16 @@ impl CidrSet {
         false
     }
 
+    // peers address mapped networks by their mapped cidr, translate to the real lan address
+    pub fn map_to_real_v4(&self, ip: std::net::Ipv4Addr) -> std::net::Ipv4Addr {
+        self.netmaps
+            .lock()
+            .unwrap()
+            .iter()
+            .find_map(|netmap| netmap.to_real(ip))
+            .unwrap_or(ip)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.cidr_set.lock().unwrap().is_empty()
     }
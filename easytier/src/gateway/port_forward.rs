@@ -0,0 +1,1004 @@
+// port forwarding between local sockets and the virtual network. tcp goes through a smoltcp stack
+// bound to the virtual ip and udp datagrams are built by hand, so neither needs a tun device.
+// with a tun device, outbound udp uses kernel sockets instead, the kernel owns the ports of the
+// virtual ip then and replies to its own sockets must not be taken.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam::atomic::AtomicCell;
+use dashmap::{DashMap, DashSet};
+use pnet::packet::{
+    ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, tcp::TcpPacket, udp::UdpPacket, Packet,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket},
+    select,
+    sync::{mpsc, watch, Mutex},
+    task::JoinSet,
+    time::timeout,
+};
+
+use crate::{
+    common::{
+        config::{PortForwardConfig, PortForwardProto},
+        error::Error,
+        global_ctx::ArcGlobalCtx,
+        netns::NetNS,
+        scoped_task::ScopedTask,
+        PeerId,
+    },
+    gateway::{
+        ip_reassembler::{compose_udp_packets, IpReassembler},
+        tokio_smoltcp::{channel_device, Net, NetConfig},
+    },
+    peers::{peer_manager::PeerManager, PeerPacketFilter},
+    rpc::{self as easytier_rpc, port_forward_manage_rpc_server::PortForwardManageRpc},
+    tunnel::packet_def::{PacketType, ZCPacket},
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+
+async fn copy_bidirectional<A, B>(mut a: A, mut b: B)
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Err(e) = tokio::io::copy_bidirectional(&mut a, &mut b).await {
+        tracing::debug!(?e, "port forward connection closed");
+    }
+}
+
+async fn connect_local(net_ns: &NetNS, addr: SocketAddr) -> Result<tokio::net::TcpStream, Error> {
+    let _g = net_ns.guard();
+    Ok(timeout(CONNECT_TIMEOUT, tokio::net::TcpStream::connect(addr)).await??)
+}
+
+// the smoltcp stack on the current virtual ip, rebuilt when the ip changes
+struct ForwardNet {
+    ipv4: Ipv4Addr,
+    smoltcp_net: Arc<Net>,
+    _forward_tasks: JoinSet<()>,
+}
+
+impl ForwardNet {
+    fn new(
+        ipv4: Ipv4Addr,
+        peer_manager: Arc<PeerManager>,
+        packet_recv: Arc<Mutex<mpsc::Receiver<ZCPacket>>>,
+    ) -> Self {
+        let network_length = peer_manager.get_global_ctx().get_network_length();
+        let mut forward_tasks = JoinSet::new();
+        let mut cap = smoltcp::phy::DeviceCapabilities::default();
+        cap.max_transmission_unit = 1280;
+        cap.medium = smoltcp::phy::Medium::Ip;
+        let (dev, stack_sink, mut stack_stream) = channel_device::ChannelDevice::new(cap);
+
+        forward_tasks.spawn(async move {
+            let mut smoltcp_stack_receiver = packet_recv.lock().await;
+            while let Some(packet) = smoltcp_stack_receiver.recv().await {
+                if let Err(e) = stack_sink.send(Ok(packet.payload().to_vec())).await {
+                    tracing::error!("send to smoltcp stack failed: {:?}", e);
+                }
+            }
+        });
+
+        forward_tasks.spawn(async move {
+            while let Some(data) = stack_stream.recv().await {
+                let Some(ipv4) = Ipv4Packet::new(&data) else {
+                    tracing::error!(?data, "smoltcp stack stream get non ipv4 packet");
+                    continue;
+                };
+
+                let dst = ipv4.get_destination();
+                let packet = ZCPacket::new_with_payload(&data);
+                if let Err(e) = peer_manager.send_msg_ipv4(packet, dst).await {
+                    tracing::error!("send to peer failed in smoltcp sender: {:?}", e);
+                }
+            }
+        });
+
+        let interface_config = smoltcp::iface::Config::new(smoltcp::wire::HardwareAddress::Ip);
+        let net = Net::new(
+            dev,
+            NetConfig::new(
+                interface_config,
+                format!("{}/{}", ipv4, network_length).parse().unwrap(),
+                vec![format!("{}", ipv4).parse().unwrap()],
+            ),
+        );
+
+        Self {
+            ipv4,
+            smoltcp_net: Arc::new(net),
+            _forward_tasks: forward_tasks,
+        }
+    }
+}
+
+// local and remote address of a tcp connection made through the smoltcp stack
+type TcpEntry = (SocketAddr, SocketAddr);
+
+struct TcpEntryGuard(Arc<DashSet<TcpEntry>>, TcpEntry);
+
+impl Drop for TcpEntryGuard {
+    fn drop(&mut self) {
+        self.0.remove(&self.1);
+    }
+}
+
+// datagrams from one peer to an inbound udp rule, relayed through a local socket
+struct UdpInboundSession {
+    socket: Arc<UdpSocket>,
+    last_active: AtomicCell<Instant>,
+    _forward_task: ScopedTask<()>,
+}
+
+impl UdpInboundSession {
+    fn new(
+        peer_manager: Arc<PeerManager>,
+        net_ns: &NetNS,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        remote_peer_id: PeerId,
+        dst_addr: SocketAddr,
+    ) -> Result<Self, Error> {
+        let socket = {
+            let _g = net_ns.guard();
+            let bind_addr: SocketAddr = match dst_addr {
+                SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+                SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+            };
+            let socket = std::net::UdpSocket::bind(bind_addr)?;
+            socket.connect(dst_addr)?;
+            socket.set_nonblocking(true)?;
+            Arc::new(UdpSocket::from_std(socket)?)
+        };
+
+        let recv_socket = socket.clone();
+        let forward_task = tokio::spawn(async move {
+            let my_peer_id = peer_manager.my_peer_id();
+            let mut buf = vec![0u8; 65536];
+            let mut ip_id = 1u16;
+            loop {
+                let len = match recv_socket.recv(&mut buf).await {
+                    Ok(len) => len,
+                    Err(e) => {
+                        tracing::debug!(?e, "port forward udp recv failed");
+                        continue;
+                    }
+                };
+                let Ok(packets) = compose_udp_packets(local, remote, &buf[..len], ip_id) else {
+                    continue;
+                };
+                ip_id = ip_id.wrapping_add(1);
+                for packet in packets {
+                    let mut packet = ZCPacket::new_with_payload(&packet);
+                    packet.fill_peer_manager_hdr(
+                        my_peer_id,
+                        remote_peer_id,
+                        PacketType::Data as u8,
+                    );
+                    packet.mut_peer_manager_header().unwrap().set_no_proxy(true);
+                    if let Err(e) = peer_manager.send_msg(packet, remote_peer_id).await {
+                        tracing::warn!(?e, "port forward udp send to peer failed");
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            socket,
+            last_active: AtomicCell::new(Instant::now()),
+            _forward_task: forward_task.into(),
+        })
+    }
+}
+
+struct UdpInboundRule {
+    dst_addr: SocketAddr,
+    sessions: DashMap<SocketAddrV4, Arc<UdpInboundSession>>,
+}
+
+// datagrams from one local client of an outbound udp rule
+struct UdpOutboundSession {
+    rule: PortForwardConfig,
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    last_active: AtomicCell<Instant>,
+}
+
+// datagrams from one local client of an outbound udp rule, sent through the tun device by a
+// kernel socket
+struct UdpOutboundTunSession {
+    socket: Arc<UdpSocket>,
+    last_active: AtomicCell<Instant>,
+    _forward_task: ScopedTask<()>,
+}
+
+impl UdpOutboundTunSession {
+    fn new(
+        net_ns: &NetNS,
+        listen_socket: Arc<UdpSocket>,
+        client: SocketAddr,
+        dst_addr: SocketAddr,
+    ) -> Result<Self, Error> {
+        let socket = {
+            let _g = net_ns.guard();
+            let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(dst_addr)?;
+            socket.set_nonblocking(true)?;
+            Arc::new(UdpSocket::from_std(socket)?)
+        };
+
+        let recv_socket = socket.clone();
+        let forward_task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let len = match recv_socket.recv(&mut buf).await {
+                    Ok(len) => len,
+                    Err(e) => {
+                        tracing::debug!(?e, "port forward udp recv failed");
+                        continue;
+                    }
+                };
+                if let Err(e) = listen_socket.send_to(&buf[..len], client).await {
+                    tracing::warn!(?e, ?client, "port forward udp send failed");
+                }
+            }
+        });
+
+        Ok(Self {
+            socket,
+            last_active: AtomicCell::new(Instant::now()),
+            _forward_task: forward_task.into(),
+        })
+    }
+}
+
+#[derive(Clone)]
+enum UdpPortHandler {
+    Inbound(Arc<UdpInboundRule>),
+    Outbound(Arc<UdpOutboundSession>),
+}
+
+pub struct PortForwardManager {
+    global_ctx: ArcGlobalCtx,
+    peer_manager: Arc<PeerManager>,
+
+    tasks: Mutex<JoinSet<()>>,
+    packet_sender: mpsc::Sender<ZCPacket>,
+    packet_recv: Arc<Mutex<mpsc::Receiver<ZCPacket>>>,
+    net: watch::Sender<Option<Arc<ForwardNet>>>,
+
+    // ports on the virtual ip handled by this manager
+    tcp_ports: DashSet<u16>,
+    tcp_entries: Arc<DashSet<TcpEntry>>,
+    udp_ports: DashMap<u16, UdpPortHandler>,
+    next_udp_port: AtomicU16,
+
+    rules: std::sync::Mutex<HashMap<PortForwardConfig, Option<ScopedTask<()>>>>,
+}
+
+#[async_trait::async_trait]
+impl PeerPacketFilter for PortForwardManager {
+    async fn try_process_packet_from_peer(&self, packet: ZCPacket) -> Option<ZCPacket> {
+        let hdr = packet.peer_manager_header().unwrap();
+        if hdr.packet_type != PacketType::Data as u8 {
+            return Some(packet);
+        };
+        let from_peer_id = hdr.from_peer_id.get();
+
+        let Some(ipv4) = Ipv4Packet::new(packet.payload()) else {
+            return Some(packet);
+        };
+        if ipv4.get_version() != 4 || Some(ipv4.get_destination()) != self.global_ctx.get_ipv4() {
+            return Some(packet);
+        }
+
+        match ipv4.get_next_level_protocol() {
+            IpNextHeaderProtocols::Tcp => {
+                let Some(tcp_packet) = TcpPacket::new(ipv4.payload()) else {
+                    return Some(packet);
+                };
+                let local =
+                    SocketAddr::new(ipv4.get_destination().into(), tcp_packet.get_destination());
+                let remote = SocketAddr::new(ipv4.get_source().into(), tcp_packet.get_source());
+                if !self.tcp_ports.contains(&local.port())
+                    && !self.tcp_entries.contains(&(local, remote))
+                {
+                    return Some(packet);
+                }
+                let _ = self.packet_sender.try_send(packet);
+                None
+            }
+            IpNextHeaderProtocols::Udp => {
+                if self.handle_udp_packet(from_peer_id, &ipv4).await.is_some() {
+                    None
+                } else {
+                    Some(packet)
+                }
+            }
+            _ => Some(packet),
+        }
+    }
+}
+
+impl PortForwardManager {
+    pub fn new(global_ctx: ArcGlobalCtx, peer_manager: Arc<PeerManager>) -> Arc<Self> {
+        let (packet_sender, packet_recv) = mpsc::channel(1024);
+        Arc::new(Self {
+            global_ctx,
+            peer_manager,
+
+            tasks: Mutex::new(JoinSet::new()),
+            packet_sender,
+            packet_recv: Arc::new(Mutex::new(packet_recv)),
+            net: watch::channel(None).0,
+
+            tcp_ports: DashSet::new(),
+            tcp_entries: Arc::new(DashSet::new()),
+            udp_ports: DashMap::new(),
+            next_udp_port: AtomicU16::new(20000),
+
+            rules: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn handle_udp_packet(&self, from_peer_id: PeerId, ipv4: &Ipv4Packet<'_>) -> Option<()> {
+        // fragments are left to the udp proxy or the tun device
+        if IpReassembler::is_packet_fragmented(ipv4) {
+            return None;
+        }
+        let udp_packet = UdpPacket::new(ipv4.payload())?;
+        let handler = self.udp_ports.get(&udp_packet.get_destination())?.clone();
+        let local = SocketAddrV4::new(ipv4.get_destination(), udp_packet.get_destination());
+        let remote = SocketAddrV4::new(ipv4.get_source(), udp_packet.get_source());
+
+        match handler {
+            UdpPortHandler::Inbound(rule) => {
+                let session = rule
+                    .sessions
+                    .entry(remote)
+                    .or_try_insert_with(|| {
+                        UdpInboundSession::new(
+                            self.peer_manager.clone(),
+                            &self.global_ctx.net_ns,
+                            local,
+                            remote,
+                            from_peer_id,
+                            rule.dst_addr,
+                        )
+                        .map(Arc::new)
+                    })
+                    .ok()?
+                    .clone();
+                session.last_active.store(Instant::now());
+                if let Err(e) = session.socket.send(udp_packet.payload()).await {
+                    tracing::warn!(?e, ?remote, "port forward udp send failed");
+                }
+            }
+            UdpPortHandler::Outbound(session) => {
+                if SocketAddr::V4(remote) != session.rule.dst_addr {
+                    return None;
+                }
+                session.last_active.store(Instant::now());
+                if let Err(e) = session
+                    .socket
+                    .send_to(udp_packet.payload(), session.client)
+                    .await
+                {
+                    tracing::warn!(?e, client = ?session.client, "port forward udp send failed");
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn add_udp_outbound_session(&self, session: Arc<UdpOutboundSession>) -> u16 {
+        loop {
+            let port = self
+                .next_udp_port
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                    Some(if x >= 60000 { 20000 } else { x + 1 })
+                })
+                .unwrap();
+            if let dashmap::mapref::entry::Entry::Vacant(e) = self.udp_ports.entry(port) {
+                e.insert(UdpPortHandler::Outbound(session));
+                return port;
+            }
+        }
+    }
+
+    async fn run_net_update_task(self: &Arc<Self>) {
+        let this = Arc::downgrade(self);
+        let global_ctx = self.global_ctx.clone();
+        self.tasks.lock().await.spawn(async move {
+            let mut prev_ipv4 = None;
+            let mut prev_network_length = global_ctx.get_network_length();
+            loop {
+                let mut event_recv = global_ctx.subscribe();
+
+                let cur_ipv4 = global_ctx.get_ipv4();
+                let cur_network_length = global_ctx.get_network_length();
+                if prev_ipv4 == cur_ipv4 && prev_network_length != cur_network_length {
+                    // the length may be learned from peers later, keep the connections
+                    let Some(this) = this.upgrade() else {
+                        return;
+                    };
+                    let net = this.net.borrow().clone();
+                    if let Some(net) = net {
+                        net.smoltcp_net.set_prefix_len(cur_network_length);
+                    }
+                }
+                prev_network_length = cur_network_length;
+                if prev_ipv4 != cur_ipv4 {
+                    prev_ipv4 = cur_ipv4;
+                    let Some(this) = this.upgrade() else {
+                        return;
+                    };
+                    this.tcp_entries.clear();
+                    let net = cur_ipv4.map(|ipv4| {
+                        Arc::new(ForwardNet::new(
+                            ipv4,
+                            this.peer_manager.clone(),
+                            this.packet_recv.clone(),
+                        ))
+                    });
+                    // drop the old stack first so the new one can take the packet receiver
+                    this.net.send_replace(None);
+                    this.net.send_replace(net);
+                }
+
+                select! {
+                    _ = event_recv.recv() => {}
+                    _ = tokio::time::sleep(Duration::from_secs(120)) => {}
+                }
+            }
+        });
+    }
+
+    async fn run_udp_session_cleaner(self: &Arc<Self>) {
+        let this = Arc::downgrade(self);
+        self.tasks.lock().await.spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(15)).await;
+                let Some(this) = this.upgrade() else {
+                    return;
+                };
+                for handler in this.udp_ports.iter() {
+                    if let UdpPortHandler::Inbound(rule) = handler.value() {
+                        rule.sessions
+                            .retain(|_, s| s.last_active.load().elapsed() < UDP_SESSION_TIMEOUT);
+                    }
+                }
+            }
+        });
+    }
+
+    async fn serve_tcp_inbound(net: Arc<ForwardNet>, net_ns: NetNS, rule: PortForwardConfig) {
+        let bind_addr = SocketAddr::new(net.ipv4.into(), rule.bind_addr.port());
+        let mut listener = match net.smoltcp_net.tcp_bind(bind_addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!(?e, %rule, "port forward bind on virtual ip failed");
+                return;
+            }
+        };
+
+        let mut conns = JoinSet::new();
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    tracing::error!(?e, %rule, "port forward accept failed");
+                    return;
+                }
+            };
+            tracing::info!(?remote, %rule, "port forward accepted a connection");
+            let net_ns = net_ns.clone();
+            conns.spawn(async move {
+                match connect_local(&net_ns, rule.dst_addr).await {
+                    Ok(local) => copy_bidirectional(stream, local).await,
+                    Err(e) => tracing::warn!(?e, %rule, "port forward connect failed"),
+                }
+            });
+            while conns.try_join_next().is_some() {}
+        }
+    }
+
+    async fn run_tcp_inbound(self: Arc<Self>, rule: PortForwardConfig) {
+        let mut net_recv = self.net.subscribe();
+        loop {
+            let net = net_recv.borrow_and_update().clone();
+            if let Some(net) = net {
+                select! {
+                    _ = Self::serve_tcp_inbound(net, self.global_ctx.net_ns.clone(), rule) => {}
+                    _ = net_recv.changed() => continue,
+                }
+            }
+            if net_recv.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn forward_tcp_outbound(
+        net: Arc<ForwardNet>,
+        net_ns: NetNS,
+        tcp_entries: Arc<DashSet<TcpEntry>>,
+        stream: tokio::net::TcpStream,
+        dst_addr: SocketAddr,
+    ) -> Result<(), Error> {
+        if dst_addr.ip() == IpAddr::V4(net.ipv4) {
+            let local_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), dst_addr.port());
+            let local = connect_local(&net_ns, local_addr).await?;
+            copy_bidirectional(stream, local).await;
+            return Ok(());
+        }
+
+        let smoltcp_net = net.smoltcp_net.clone();
+        let port = smoltcp_net.get_port();
+        let entry = (SocketAddr::new(net.ipv4.into(), port), dst_addr);
+        tcp_entries.insert(entry);
+        let _guard = TcpEntryGuard(tcp_entries, entry);
+        // the stack must not be kept alive by the connection once the ip changes
+        drop(net);
+
+        let remote = timeout(CONNECT_TIMEOUT, smoltcp_net.tcp_connect(dst_addr, port)).await??;
+        copy_bidirectional(stream, remote).await;
+        Ok(())
+    }
+
+    async fn run_tcp_outbound(self: Arc<Self>, rule: PortForwardConfig, listener: TcpListener) {
+        let mut conns = JoinSet::new();
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::error!(?e, %rule, "port forward accept failed");
+                    continue;
+                }
+            };
+            let Some(net) = self.net.borrow().clone() else {
+                tracing::warn!(%rule, "port forward has no virtual ip, drop connection");
+                continue;
+            };
+            let net_ns = self.global_ctx.net_ns.clone();
+            let tcp_entries = self.tcp_entries.clone();
+            conns.spawn(async move {
+                let ret =
+                    Self::forward_tcp_outbound(net, net_ns, tcp_entries, stream, rule.dst_addr)
+                        .await;
+                if let Err(e) = ret {
+                    tracing::warn!(?e, %rule, "port forward connect failed");
+                }
+            });
+            while conns.try_join_next().is_some() {}
+        }
+    }
+
+    async fn run_udp_outbound_via_tun(
+        self: Arc<Self>,
+        rule: PortForwardConfig,
+        socket: Arc<UdpSocket>,
+    ) {
+        let mut clients: HashMap<SocketAddr, UdpOutboundTunSession> = HashMap::new();
+        let mut buf = vec![0u8; 65536];
+        let mut cleanup = tokio::time::interval(Duration::from_secs(15));
+
+        loop {
+            select! {
+                ret = socket.recv_from(&mut buf) => {
+                    let (len, client) = match ret {
+                        Ok(x) => x,
+                        Err(e) => {
+                            tracing::debug!(?e, %rule, "port forward udp recv failed");
+                            continue;
+                        }
+                    };
+                    let session = match clients.entry(client) {
+                        std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                        std::collections::hash_map::Entry::Vacant(e) => {
+                            match UdpOutboundTunSession::new(
+                                &self.global_ctx.net_ns,
+                                socket.clone(),
+                                client,
+                                rule.dst_addr,
+                            ) {
+                                Ok(session) => e.insert(session),
+                                Err(e) => {
+                                    tracing::warn!(?e, %rule, "port forward udp connect failed");
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    session.last_active.store(Instant::now());
+                    if let Err(e) = session.socket.send(&buf[..len]).await {
+                        tracing::warn!(?e, %rule, "port forward udp send failed");
+                    }
+                }
+                _ = cleanup.tick() => {
+                    clients.retain(|_, s| s.last_active.load().elapsed() < UDP_SESSION_TIMEOUT);
+                }
+            }
+        }
+    }
+
+    async fn run_udp_outbound(self: Arc<Self>, rule: PortForwardConfig, socket: Arc<UdpSocket>) {
+        if !self.global_ctx.no_tun() {
+            return self.run_udp_outbound_via_tun(rule, socket).await;
+        }
+        let SocketAddr::V4(dst_addr) = rule.dst_addr else {
+            return;
+        };
+        let mut clients: HashMap<SocketAddr, (u16, Arc<UdpOutboundSession>)> = HashMap::new();
+        let mut buf = vec![0u8; 65536];
+        let mut ip_id = 1u16;
+        let mut cleanup = tokio::time::interval(Duration::from_secs(15));
+
+        loop {
+            select! {
+                ret = socket.recv_from(&mut buf) => {
+                    let (len, client) = match ret {
+                        Ok(x) => x,
+                        Err(e) => {
+                            tracing::debug!(?e, %rule, "port forward udp recv failed");
+                            continue;
+                        }
+                    };
+                    let Some(ipv4) = self.global_ctx.get_ipv4() else {
+                        continue;
+                    };
+                    let (port, session) = clients.entry(client).or_insert_with(|| {
+                        let session = Arc::new(UdpOutboundSession {
+                            rule,
+                            socket: socket.clone(),
+                            client,
+                            last_active: AtomicCell::new(Instant::now()),
+                        });
+                        (self.add_udp_outbound_session(session.clone()), session)
+                    });
+                    session.last_active.store(Instant::now());
+
+                    let src = SocketAddrV4::new(ipv4, *port);
+                    let Ok(packets) = compose_udp_packets(src, dst_addr, &buf[..len], ip_id) else {
+                        continue;
+                    };
+                    ip_id = ip_id.wrapping_add(1);
+                    for packet in packets {
+                        let packet = ZCPacket::new_with_payload(&packet);
+                        if let Err(e) = self.peer_manager.send_msg_ipv4(packet, *dst_addr.ip()).await {
+                            tracing::warn!(?e, %rule, "port forward udp send to peer failed");
+                        }
+                    }
+                }
+                _ = cleanup.tick() => {
+                    clients.retain(|_, (port, session)| {
+                        if session.last_active.load().elapsed() < UDP_SESSION_TIMEOUT {
+                            return true;
+                        }
+                        self.udp_ports.remove(port);
+                        false
+                    });
+                }
+            }
+        }
+    }
+
+    async fn start_rule(self: &Arc<Self>, rule: PortForwardConfig) -> Result<(), Error> {
+        if self.rules.lock().unwrap().contains_key(&rule) {
+            return Err(anyhow::anyhow!("port forward already exists: {}", rule).into());
+        }
+        if !rule.bind_virtual && !rule.dst_addr.is_ipv4() {
+            return Err(
+                anyhow::anyhow!("port forward dst must be an ipv4 address: {}", rule).into(),
+            );
+        }
+
+        let port = rule.bind_addr.port();
+        let task = match (rule.proto, rule.bind_virtual) {
+            (PortForwardProto::Tcp, true) => {
+                if !self.tcp_ports.insert(port) {
+                    return Err(anyhow::anyhow!("virtual tcp port {} is in use", port).into());
+                }
+                Some(tokio::spawn(self.clone().run_tcp_inbound(rule)))
+            }
+            (PortForwardProto::Udp, true) => {
+                let dashmap::mapref::entry::Entry::Vacant(e) = self.udp_ports.entry(port) else {
+                    return Err(anyhow::anyhow!("virtual udp port {} is in use", port).into());
+                };
+                e.insert(UdpPortHandler::Inbound(Arc::new(UdpInboundRule {
+                    dst_addr: rule.dst_addr,
+                    sessions: DashMap::new(),
+                })));
+                None
+            }
+            (PortForwardProto::Tcp, false) => {
+                let listener = {
+                    let _g = self.global_ctx.net_ns.guard();
+                    TcpListener::bind(rule.bind_addr).await?
+                };
+                Some(tokio::spawn(self.clone().run_tcp_outbound(rule, listener)))
+            }
+            (PortForwardProto::Udp, false) => {
+                let socket = {
+                    let _g = self.global_ctx.net_ns.guard();
+                    Arc::new(UdpSocket::bind(rule.bind_addr).await?)
+                };
+                Some(tokio::spawn(self.clone().run_udp_outbound(rule, socket)))
+            }
+        };
+
+        tracing::info!(%rule, "port forward started");
+        self.rules
+            .lock()
+            .unwrap()
+            .insert(rule, task.map(ScopedTask::from));
+        Ok(())
+    }
+
+    fn stop_rule(&self, rule: &PortForwardConfig) -> Result<(), Error> {
+        if self.rules.lock().unwrap().remove(rule).is_none() {
+            return Err(anyhow::anyhow!("port forward not found: {}", rule).into());
+        }
+
+        let port = rule.bind_addr.port();
+        match (rule.proto, rule.bind_virtual) {
+            (PortForwardProto::Tcp, true) => {
+                self.tcp_ports.remove(&port);
+            }
+            (PortForwardProto::Udp, true) => {
+                self.udp_ports.remove(&port);
+            }
+            (PortForwardProto::Udp, false) => self.udp_ports.retain(
+                |_, h| !matches!(h, UdpPortHandler::Outbound(session) if session.rule == *rule),
+            ),
+            (PortForwardProto::Tcp, false) => {}
+        }
+        tracing::info!(%rule, "port forward stopped");
+        Ok(())
+    }
+
+    pub fn list_rules(&self) -> Vec<PortForwardConfig> {
+        self.global_ctx.config.get_port_forwards()
+    }
+
+    pub async fn add_rule(self: &Arc<Self>, rule: PortForwardConfig) -> Result<(), Error> {
+        self.start_rule(rule).await?;
+        let mut rules = self.global_ctx.config.get_port_forwards();
+        rules.push(rule);
+        self.global_ctx.config.set_port_forwards(rules);
+        Ok(())
+    }
+
+    pub fn remove_rule(&self, rule: &PortForwardConfig) -> Result<(), Error> {
+        self.stop_rule(rule)?;
+        let mut rules = self.global_ctx.config.get_port_forwards();
+        rules.retain(|r| r != rule);
+        self.global_ctx.config.set_port_forwards(rules);
+        Ok(())
+    }
+
+    pub async fn run(self: &Arc<Self>) -> Result<(), Error> {
+        self.peer_manager
+            .add_packet_process_pipeline(Box::new(self.clone()))
+            .await;
+
+        self.run_net_update_task().await;
+        self.run_udp_session_cleaner().await;
+
+        for rule in self.global_ctx.config.get_port_forwards() {
+            self.start_rule(rule).await?;
+        }
+        Ok(())
+    }
+}
+
+pub struct PortForwardManagerRpcService {
+    pub manager: Arc<PortForwardManager>,
+}
+
+#[tonic::async_trait]
+impl PortForwardManageRpc for PortForwardManagerRpcService {
+    async fn list_port_forward(
+        &self,
+        _request: tonic::Request<easytier_rpc::ListPortForwardRequest>,
+    ) -> Result<tonic::Response<easytier_rpc::ListPortForwardResponse>, tonic::Status> {
+        Ok(tonic::Response::new(
+            easytier_rpc::ListPortForwardResponse {
+                rules: self
+                    .manager
+                    .list_rules()
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            },
+        ))
+    }
+
+    async fn add_port_forward(
+        &self,
+        request: tonic::Request<easytier_rpc::AddPortForwardRequest>,
+    ) -> Result<tonic::Response<easytier_rpc::AddPortForwardResponse>, tonic::Status> {
+        let rule = request
+            .into_inner()
+            .rule
+            .ok_or(tonic::Status::invalid_argument("port forward rule missing"))?;
+        let rule = PortForwardConfig::try_from(rule)
+            .map_err(|e| tonic::Status::invalid_argument(format!("invalid rule: {:?}", e)))?;
+        self.manager.add_rule(rule).await.map_err(|e| {
+            tonic::Status::invalid_argument(format!("add port forward failed: {:?}", e))
+        })?;
+        Ok(tonic::Response::new(
+            easytier_rpc::AddPortForwardResponse::default(),
+        ))
+    }
+
+    async fn remove_port_forward(
+        &self,
+        request: tonic::Request<easytier_rpc::RemovePortForwardRequest>,
+    ) -> Result<tonic::Response<easytier_rpc::RemovePortForwardResponse>, tonic::Status> {
+        let rule = request
+            .into_inner()
+            .rule
+            .ok_or(tonic::Status::invalid_argument("port forward rule missing"))?;
+        let rule = PortForwardConfig::try_from(rule)
+            .map_err(|e| tonic::Status::invalid_argument(format!("invalid rule: {:?}", e)))?;
+        self.manager.remove_rule(&rule).map_err(|e| {
+            tonic::Status::invalid_argument(format!("remove port forward failed: {:?}", e))
+        })?;
+        Ok(tonic::Response::new(
+            easytier_rpc::RemovePortForwardResponse::default(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        common::config::Flags,
+        peers::{
+            exit_node_health::tests::create_mock_peer_manager_with_exit_nodes,
+            tests::{connect_peer_manager, create_mock_peer_manager, wait_route_appear},
+        },
+        tunnel::common::tests::wait_for_condition,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn port_forward_through_virtual_ip() {
+        // without a tun device the udp datagrams of a take ports of its virtual ip
+        let peer_mgr_a = create_mock_peer_manager_with_exit_nodes(
+            Flags {
+                no_tun: true,
+                ..Default::default()
+            },
+            Some("10.144.144.1".parse().unwrap()),
+            vec![],
+        )
+        .await;
+        let peer_mgr_b = create_mock_peer_manager().await;
+        let ip_b: Ipv4Addr = "10.144.144.2".parse().unwrap();
+        peer_mgr_b.get_global_ctx().set_ipv4(Some(ip_b));
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+        wait_for_condition(
+            || async {
+                peer_mgr_a
+                    .get_peer_map()
+                    .get_peer_id_by_ipv4(&ip_b)
+                    .await
+                    .is_some()
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        let tcp_echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_echo_addr = tcp_echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp_echo.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        let udp_echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_echo_addr = udp_echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, addr) = udp_echo.recv_from(&mut buf).await.unwrap();
+            udp_echo.send_to(&buf[..len], addr).await.unwrap();
+        });
+
+        // b exposes the echo servers on its virtual ip, a forwards local ports to them
+        let mgr_b = PortForwardManager::new(peer_mgr_b.get_global_ctx(), peer_mgr_b.clone());
+        mgr_b.run().await.unwrap();
+        let rule = format!("tcp://virtual:15432/{}", tcp_echo_addr);
+        mgr_b.add_rule(rule.parse().unwrap()).await.unwrap();
+        let rule = format!("udp://virtual:15353/{}", udp_echo_addr);
+        mgr_b.add_rule(rule.parse().unwrap()).await.unwrap();
+
+        let mgr_a = PortForwardManager::new(peer_mgr_a.get_global_ctx(), peer_mgr_a.clone());
+        mgr_a.run().await.unwrap();
+        let tcp_rule: PortForwardConfig =
+            "tcp://127.0.0.1:25432/10.144.144.2:15432".parse().unwrap();
+        mgr_a.add_rule(tcp_rule).await.unwrap();
+        mgr_a
+            .add_rule("udp://127.0.0.1:25353/10.144.144.2:15353".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(2, mgr_a.list_rules().len());
+
+        let mut stream = tokio::net::TcpStream::connect("127.0.0.1:25432")
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"hello", &buf);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"world", "127.0.0.1:25353").await.unwrap();
+        let mut buf = [0u8; 64];
+        let (len, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"world", &buf[..len]);
+
+        mgr_a.remove_rule(&tcp_rule).unwrap();
+        assert_eq!(1, mgr_a.list_rules().len());
+        assert!(mgr_a.remove_rule(&tcp_rule).is_err());
+    }
+
+    #[tokio::test]
+    async fn port_forward_udp_outbound_with_tun() {
+        let peer_mgr = create_mock_peer_manager().await;
+        peer_mgr
+            .get_global_ctx()
+            .set_ipv4(Some("10.144.144.1".parse().unwrap()));
+
+        let udp_echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_echo_addr = udp_echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, addr) = udp_echo.recv_from(&mut buf).await.unwrap();
+            udp_echo.send_to(&buf[..len], addr).await.unwrap();
+        });
+
+        let mgr = PortForwardManager::new(peer_mgr.get_global_ctx(), peer_mgr.clone());
+        mgr.run().await.unwrap();
+        let rule = format!("udp://127.0.0.1:25354/{}", udp_echo_addr);
+        mgr.add_rule(rule.parse().unwrap()).await.unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"world", "127.0.0.1:25354").await.unwrap();
+        let mut buf = [0u8; 64];
+        let (len, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"world", &buf[..len]);
+        // the kernel socket owns its port, none of the virtual ip is taken
+        assert!(mgr.udp_ports.is_empty());
+    }
+}
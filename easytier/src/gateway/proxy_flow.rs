@@ -0,0 +1,193 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Weak},
+};
+
+use crate::rpc::{
+    proxy_flow_rpc_server::ProxyFlowRpc, CloseProxyFlowRequest, CloseProxyFlowResponse,
+    ListProxyFlowRequest, ListProxyFlowResponse, ProxyFlow,
+};
+
+use super::{icmp_proxy::IcmpProxy, tcp_proxy::TcpProxy, udp_proxy::UdpProxy};
+
+// the filter is an ip or ip:port, a bare ip matches any port
+fn addr_matches(flow_addr: &str, filter: &str) -> bool {
+    if flow_addr == filter {
+        return true;
+    }
+    let flow_ip = match flow_addr.parse::<SocketAddr>() {
+        Ok(addr) => {
+            if let Ok(filter) = filter.parse::<SocketAddr>() {
+                return addr == filter;
+            }
+            addr.ip()
+        }
+        Err(_) => match flow_addr.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return false,
+        },
+    };
+    filter.parse::<IpAddr>().is_ok_and(|ip| ip == flow_ip)
+}
+
+pub fn proxy_flow_matches(flow: &ProxyFlow, req: &ListProxyFlowRequest) -> bool {
+    if !req.protocol.is_empty() && !req.protocol.eq_ignore_ascii_case(&flow.protocol) {
+        return false;
+    }
+    if req.src_peer_id != 0 && req.src_peer_id != flow.src_peer_id {
+        return false;
+    }
+    req.addr.is_empty()
+        || addr_matches(&flow.src_addr, &req.addr)
+        || addr_matches(&flow.dst_addr, &req.addr)
+}
+
+pub struct ProxyFlowRpcService {
+    tcp_proxy: Weak<TcpProxy>,
+    udp_proxy: Weak<UdpProxy>,
+    icmp_proxy: Weak<IcmpProxy>,
+}
+
+impl ProxyFlowRpcService {
+    pub fn new(
+        tcp_proxy: &Arc<TcpProxy>,
+        udp_proxy: &Arc<UdpProxy>,
+        icmp_proxy: &Arc<IcmpProxy>,
+    ) -> Self {
+        Self {
+            tcp_proxy: Arc::downgrade(tcp_proxy),
+            udp_proxy: Arc::downgrade(udp_proxy),
+            icmp_proxy: Arc::downgrade(icmp_proxy),
+        }
+    }
+
+    fn list_all_flows(&self) -> Vec<ProxyFlow> {
+        let mut flows = vec![];
+        if let Some(tcp_proxy) = self.tcp_proxy.upgrade() {
+            flows.extend(tcp_proxy.list_flows());
+        }
+        if let Some(udp_proxy) = self.udp_proxy.upgrade() {
+            flows.extend(udp_proxy.list_flows());
+        }
+        if let Some(icmp_proxy) = self.icmp_proxy.upgrade() {
+            flows.extend(icmp_proxy.list_flows());
+        }
+        flows
+    }
+}
+
+#[tonic::async_trait]
+impl ProxyFlowRpc for ProxyFlowRpcService {
+    async fn list_proxy_flow(
+        &self,
+        request: tonic::Request<ListProxyFlowRequest>,
+    ) -> Result<tonic::Response<ListProxyFlowResponse>, tonic::Status> {
+        let req = request.into_inner();
+        let mut flows: Vec<_> = self
+            .list_all_flows()
+            .into_iter()
+            .filter(|flow| proxy_flow_matches(flow, &req))
+            .collect();
+        flows.sort_by_key(|flow| std::cmp::Reverse(flow.age_ms));
+        Ok(tonic::Response::new(ListProxyFlowResponse { flows }))
+    }
+
+    async fn close_proxy_flow(
+        &self,
+        request: tonic::Request<CloseProxyFlowRequest>,
+    ) -> Result<tonic::Response<CloseProxyFlowResponse>, tonic::Status> {
+        let req = request.into_inner();
+        let id = uuid::Uuid::parse_str(&req.id)
+            .map_err(|e| tonic::Status::invalid_argument(format!("invalid flow id: {}", e)))?;
+        let unavailable = || tonic::Status::unavailable("proxy not available");
+        let closed = match req.protocol.to_ascii_lowercase().as_str() {
+            "tcp" => {
+                let tcp_proxy = self.tcp_proxy.upgrade().ok_or_else(unavailable)?;
+                tcp_proxy.close_flow(&id).await
+            }
+            "udp" => {
+                let udp_proxy = self.udp_proxy.upgrade().ok_or_else(unavailable)?;
+                udp_proxy.close_flow(&id).await
+            }
+            "icmp" => {
+                let icmp_proxy = self.icmp_proxy.upgrade().ok_or_else(unavailable)?;
+                icmp_proxy.close_flow(&id)
+            }
+            protocol => {
+                return Err(tonic::Status::invalid_argument(format!(
+                    "unknown protocol: {}",
+                    protocol
+                )))
+            }
+        };
+        if !closed {
+            return Err(tonic::Status::not_found(format!(
+                "no {} flow with id {}",
+                req.protocol, req.id
+            )));
+        }
+        Ok(tonic::Response::new(CloseProxyFlowResponse {}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(protocol: &str, src_peer_id: u32, src_addr: &str, dst_addr: &str) -> ProxyFlow {
+        ProxyFlow {
+            protocol: protocol.to_owned(),
+            src_peer_id,
+            src_addr: src_addr.to_owned(),
+            dst_addr: dst_addr.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn filter_proxy_flows() {
+        let tcp = flow("tcp", 1, "10.144.144.1:40000", "10.1.2.3:22");
+        let icmp = flow("icmp", 2, "10.144.144.2", "10.1.2.3");
+        let v6 = flow("udp", 1, "[fd00::1]:5353", "[fd00::2]:53");
+
+        let all = ListProxyFlowRequest::default();
+        assert!(proxy_flow_matches(&tcp, &all));
+        assert!(proxy_flow_matches(&icmp, &all));
+
+        let req = ListProxyFlowRequest {
+            protocol: "TCP".to_owned(),
+            ..Default::default()
+        };
+        assert!(proxy_flow_matches(&tcp, &req));
+        assert!(!proxy_flow_matches(&icmp, &req));
+
+        let req = ListProxyFlowRequest {
+            src_peer_id: 2,
+            ..Default::default()
+        };
+        assert!(!proxy_flow_matches(&tcp, &req));
+        assert!(proxy_flow_matches(&icmp, &req));
+
+        // a bare ip matches the src or dst of any protocol
+        let req = ListProxyFlowRequest {
+            addr: "10.1.2.3".to_owned(),
+            ..Default::default()
+        };
+        assert!(proxy_flow_matches(&tcp, &req));
+        assert!(proxy_flow_matches(&icmp, &req));
+
+        let req = ListProxyFlowRequest {
+            addr: "10.1.2.3:80".to_owned(),
+            ..Default::default()
+        };
+        assert!(!proxy_flow_matches(&tcp, &req));
+        assert!(!proxy_flow_matches(&icmp, &req));
+
+        let req = ListProxyFlowRequest {
+            addr: "fd00::2".to_owned(),
+            ..Default::default()
+        };
+        assert!(proxy_flow_matches(&v6, &req));
+        assert!(!proxy_flow_matches(&tcp, &req));
+    }
+}
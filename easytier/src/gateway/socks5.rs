@@ -1,30 +1,39 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
-    time::Duration,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
     gateway::{
         fast_socks5::{
+            new_udp_header, parse_udp_request,
             server::{
                 AcceptAuthentication, AsyncTcpConnector, Config, SimpleUserPassword, Socks5Socket,
             },
-            util::stream::tcp_connect_with_timeout,
+            util::{stream::tcp_connect_with_timeout, target_addr::TargetAddr},
         },
+        http_proxy::{serve_http_proxy, HttpProxyAuth},
+        ip_reassembler::{compose_udp_packets, IpReassembler},
         tokio_smoltcp::TcpStream,
     },
     tunnel::packet_def::PacketType,
 };
 use anyhow::Context;
-use dashmap::DashSet;
-use pnet::packet::{ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, tcp::TcpPacket, Packet};
+use crossbeam::atomic::AtomicCell;
+use dashmap::{DashMap, DashSet};
+use pnet::packet::{
+    ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, tcp::TcpPacket, udp::UdpPacket, Packet,
+};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     select,
 };
 use tokio::{
-    net::TcpListener,
+    net::{lookup_host, TcpListener, UdpSocket},
     sync::{mpsc, Mutex},
     task::JoinSet,
     time::timeout,
@@ -110,6 +119,294 @@ struct Socks5Entry {
 
 type Socks5EntrySet = Arc<DashSet<Socks5Entry>>;
 
+const UDP_ASSOCIATION_TIMEOUT: Duration = Duration::from_secs(180);
+
+// a socks5 udp association. datagrams go through the kernel when a tun device exists, otherwise
+// they are sent into the virtual network from a port on the virtual ip.
+struct Socks5UdpAssociation {
+    global_ctx: Arc<GlobalCtx>,
+    peer_manager: Arc<PeerManager>,
+    ipv4_addr: Ipv4Addr,
+    virtual_port: u16,
+
+    inbound: UdpSocket,
+    outbound: UdpSocket,
+    // peer of the tcp control connection, datagrams from other hosts are dropped
+    client_ip: Option<IpAddr>,
+    client_addr: AtomicCell<Option<SocketAddr>>,
+    last_active: AtomicCell<Instant>,
+}
+
+// udp associations by their port on the virtual ip
+type Socks5UdpEntryMap = Arc<DashMap<u16, Arc<Socks5UdpAssociation>>>;
+
+impl Socks5UdpAssociation {
+    fn is_active(&self) -> bool {
+        self.last_active.load().elapsed() < UDP_ASSOCIATION_TIMEOUT
+    }
+
+    async fn reply_to_client(&self, mut src: SocketAddr, payload: &[u8]) -> Result<(), Error> {
+        let Some(client_addr) = self.client_addr.load() else {
+            return Ok(());
+        };
+        if src.ip().is_loopback() {
+            src.set_ip(self.ipv4_addr.into());
+        }
+        self.last_active.store(Instant::now());
+
+        let mut data = new_udp_header(src).map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        data.extend_from_slice(payload);
+        self.inbound.send_to(&data, client_addr).await?;
+        Ok(())
+    }
+
+    async fn send_to_target(
+        &self,
+        target: SocketAddr,
+        payload: &[u8],
+        ip_id: u16,
+    ) -> Result<(), Error> {
+        let target_v4 = match target {
+            SocketAddr::V4(target_v4) if self.global_ctx.no_tun() => target_v4,
+            _ => {
+                self.outbound.send_to(payload, target).await?;
+                return Ok(());
+            }
+        };
+
+        if *target_v4.ip() == self.ipv4_addr {
+            let local_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), target.port());
+            self.outbound.send_to(payload, local_addr).await?;
+            return Ok(());
+        }
+
+        let src = SocketAddrV4::new(self.ipv4_addr, self.virtual_port);
+        for packet in compose_udp_packets(src, target_v4, payload, ip_id)? {
+            let packet = ZCPacket::new_with_payload(&packet);
+            self.peer_manager
+                .send_msg_ipv4(packet, *target_v4.ip())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_client_datagrams(&self) -> Result<(), Error> {
+        let mut buf = vec![0u8; 65536];
+        let mut ip_id = 1u16;
+        loop {
+            let (size, client_addr) = self.inbound.recv_from(&mut buf).await?;
+            if self.client_ip.is_some_and(|ip| ip != client_addr.ip()) {
+                tracing::debug!(
+                    ?client_addr,
+                    "socks5 udp datagram from unknown client dropped"
+                );
+                continue;
+            }
+            self.client_addr.store(Some(client_addr));
+            self.last_active.store(Instant::now());
+
+            let (frag, target_addr, data) = match parse_udp_request(&buf[..size]).await {
+                Ok(x) => x,
+                Err(e) => {
+                    tracing::warn!(?e, "socks5 udp request malformed");
+                    continue;
+                }
+            };
+            if frag != 0 {
+                continue;
+            }
+            let target_addr = match target_addr {
+                TargetAddr::Ip(addr) => addr,
+                TargetAddr::Domain(domain, port) => {
+                    match lookup_host((domain.as_str(), port)).await {
+                        Ok(mut addrs) => match addrs.next() {
+                            Some(addr) => addr,
+                            None => continue,
+                        },
+                        Err(e) => {
+                            tracing::warn!(?e, ?domain, "socks5 udp target resolve failed");
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            if let Err(e) = self.send_to_target(target_addr, data, ip_id).await {
+                tracing::warn!(?e, ?target_addr, "socks5 udp send failed");
+            }
+            ip_id = ip_id.wrapping_add(1);
+        }
+    }
+
+    async fn handle_kernel_responses(&self) -> Result<(), Error> {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (size, remote_addr) = self.outbound.recv_from(&mut buf).await?;
+            if let Err(e) = self.reply_to_client(remote_addr, &buf[..size]).await {
+                tracing::warn!(?e, "socks5 udp reply failed");
+            }
+        }
+    }
+
+    async fn run(&self) -> Result<(), Error> {
+        let mut idle_check = tokio::time::interval(Duration::from_secs(15));
+        select! {
+            ret = self.handle_client_datagrams() => ret,
+            ret = self.handle_kernel_responses() => ret,
+            _ = async {
+                loop {
+                    idle_check.tick().await;
+                    if !self.is_active() {
+                        break;
+                    }
+                }
+            } => {
+                tracing::info!("socks5 udp association idle timeout");
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Socks5UdpEntryGuard(Socks5UdpEntryMap, u16);
+
+impl Drop for Socks5UdpEntryGuard {
+    fn drop(&mut self) {
+        self.0.remove(&self.1);
+    }
+}
+
+#[derive(Clone)]
+struct Socks5UdpCtx {
+    peer_manager: Arc<PeerManager>,
+    ipv4_addr: Ipv4Addr,
+    entries: Socks5UdpEntryMap,
+    next_port: Arc<AtomicU16>,
+}
+
+impl Socks5UdpCtx {
+    async fn bind(&self, addr: SocketAddr) -> Result<UdpSocket, Error> {
+        let _g = self.peer_manager.get_global_ctx().net_ns.guard();
+        Ok(UdpSocket::bind(addr).await?)
+    }
+
+    async fn associate(&self, inbound: UdpSocket, client_ip: Option<IpAddr>) -> Result<(), Error> {
+        let outbound = self.bind("0.0.0.0:0".parse().unwrap()).await?;
+        let global_ctx = self.peer_manager.get_global_ctx();
+        let new_association = |virtual_port| {
+            Arc::new(Socks5UdpAssociation {
+                global_ctx: global_ctx.clone(),
+                peer_manager: self.peer_manager.clone(),
+                ipv4_addr: self.ipv4_addr,
+                virtual_port,
+                inbound,
+                outbound,
+                client_ip,
+                client_addr: AtomicCell::new(None),
+                last_active: AtomicCell::new(Instant::now()),
+            })
+        };
+
+        // with a tun device the kernel carries the datagrams, nothing to intercept on the
+        // virtual ip and the ports may belong to local sockets
+        if !global_ctx.no_tun() {
+            tracing::info!("socks5 udp association created");
+            return new_association(0).run().await;
+        }
+
+        let port = loop {
+            // below the ports used by port forwarding
+            let port = self
+                .next_port
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                    Some(if x >= 19999 { 10000 } else { x + 1 })
+                })
+                .unwrap();
+            if !self.entries.contains_key(&port) {
+                break port;
+            }
+        };
+        let association = new_association(port);
+        self.entries.insert(port, association.clone());
+        let _guard = Socks5UdpEntryGuard(self.entries.clone(), port);
+
+        tracing::info!(port, "socks5 udp association created");
+        association.run().await
+    }
+}
+
+struct SmolTcpConnector(
+    Arc<Net>,
+    Socks5EntrySet,
+    std::sync::Mutex<Option<Socks5Entry>>,
+    Socks5UdpCtx,
+);
+
+#[async_trait::async_trait]
+impl AsyncTcpConnector for SmolTcpConnector {
+    type S = SocksTcpStream;
+
+    async fn tcp_connect(
+        &self,
+        addr: SocketAddr,
+        timeout_s: u64,
+    ) -> crate::gateway::fast_socks5::Result<SocksTcpStream> {
+        let local_addr = self.0.get_address();
+        let port = self.0.get_port();
+
+        let entry = Socks5Entry {
+            src: SocketAddr::new(local_addr, port),
+            dst: addr,
+        };
+        *self.2.lock().unwrap() = Some(entry.clone());
+        self.1.insert(entry);
+
+        if addr.ip() == local_addr {
+            let modified_addr =
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), addr.port());
+
+            Ok(SocksTcpStream::TcpStream(
+                tcp_connect_with_timeout(modified_addr, timeout_s).await?,
+            ))
+        } else {
+            let remote_socket = timeout(
+                Duration::from_secs(timeout_s),
+                self.0.tcp_connect(addr, port),
+            )
+            .await
+            .with_context(|| "connect to remote timeout")?;
+
+            Ok(SocksTcpStream::SmolTcpStream(remote_socket.map_err(
+                |e| super::fast_socks5::SocksError::Other(e.into()),
+            )?))
+        }
+    }
+
+    async fn udp_bind(&self, addr: SocketAddr) -> crate::gateway::fast_socks5::Result<UdpSocket> {
+        Ok(self.3.bind(addr).await.map_err(anyhow::Error::from)?)
+    }
+
+    async fn udp_associate(
+        &self,
+        inbound: UdpSocket,
+        client_ip: Option<IpAddr>,
+    ) -> crate::gateway::fast_socks5::Result<()> {
+        Ok(self
+            .3
+            .associate(inbound, client_ip)
+            .await
+            .map_err(anyhow::Error::from)?)
+    }
+}
+
+impl Drop for SmolTcpConnector {
+    fn drop(&mut self) {
+        if let Some(entry) = self.2.lock().unwrap().take() {
+            self.1.remove(&entry);
+        }
+    }
+}
+
 struct Socks5ServerNet {
     ipv4_addr: Ipv4Addr,
     auth: Option<SimpleUserPassword>,
@@ -118,6 +415,7 @@ struct Socks5ServerNet {
     forward_tasks: Arc<std::sync::Mutex<JoinSet<()>>>,
 
     entries: Socks5EntrySet,
+    udp_ctx: Socks5UdpCtx,
 }
 
 impl Socks5ServerNet {
@@ -127,7 +425,15 @@ impl Socks5ServerNet {
         peer_manager: Arc<PeerManager>,
         packet_recv: Arc<Mutex<mpsc::Receiver<ZCPacket>>>,
         entries: Socks5EntrySet,
+        udp_entries: Socks5UdpEntryMap,
     ) -> Self {
+        let network_length = peer_manager.get_global_ctx().get_network_length();
+        let udp_ctx = Socks5UdpCtx {
+            peer_manager: peer_manager.clone(),
+            ipv4_addr,
+            entries: udp_entries,
+            next_port: Arc::new(AtomicU16::new(10000)),
+        };
         let mut forward_tasks = JoinSet::new();
         let mut cap = smoltcp::phy::DeviceCapabilities::default();
         cap.max_transmission_unit = 1280;
@@ -173,7 +479,7 @@ impl Socks5ServerNet {
             dev,
             NetConfig::new(
                 interface_config,
-                format!("{}/24", ipv4_addr).parse().unwrap(),
+                format!("{}/{}", ipv4_addr, network_length).parse().unwrap(),
                 vec![format!("{}", ipv4_addr).parse().unwrap()],
             ),
         );
@@ -186,80 +492,36 @@ impl Socks5ServerNet {
             forward_tasks: Arc::new(std::sync::Mutex::new(forward_tasks)),
 
             entries,
+            udp_ctx,
         }
     }
 
+    fn new_connector(&self) -> SmolTcpConnector {
+        SmolTcpConnector(
+            self.smoltcp_net.clone(),
+            self.entries.clone(),
+            std::sync::Mutex::new(None),
+            self.udp_ctx.clone(),
+        )
+    }
+
     fn handle_tcp_stream(&self, stream: tokio::net::TcpStream) {
         let mut config = Config::<AcceptAuthentication>::default();
         config.set_request_timeout(10);
         config.set_skip_auth(false);
         config.set_allow_no_auth(true);
+        config.set_udp_support(true);
 
-        struct SmolTcpConnector(
-            Arc<Net>,
-            Socks5EntrySet,
-            std::sync::Mutex<Option<Socks5Entry>>,
-        );
-
-        #[async_trait::async_trait]
-        impl AsyncTcpConnector for SmolTcpConnector {
-            type S = SocksTcpStream;
-
-            async fn tcp_connect(
-                &self,
-                addr: SocketAddr,
-                timeout_s: u64,
-            ) -> crate::gateway::fast_socks5::Result<SocksTcpStream> {
-                let local_addr = self.0.get_address();
-                let port = self.0.get_port();
-
-                let entry = Socks5Entry {
-                    src: SocketAddr::new(local_addr, port),
-                    dst: addr,
-                };
-                *self.2.lock().unwrap() = Some(entry.clone());
-                self.1.insert(entry);
-
-                if addr.ip() == local_addr {
-                    let modified_addr =
-                        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), addr.port());
-
-                    Ok(SocksTcpStream::TcpStream(
-                        tcp_connect_with_timeout(modified_addr, timeout_s).await?,
-                    ))
-                } else {
-                    let remote_socket = timeout(
-                        Duration::from_secs(timeout_s),
-                        self.0.tcp_connect(addr, port),
-                    )
-                    .await
-                    .with_context(|| "connect to remote timeout")?;
-
-                    Ok(SocksTcpStream::SmolTcpStream(remote_socket.map_err(
-                        |e| super::fast_socks5::SocksError::Other(e.into()),
-                    )?))
-                }
-            }
+        let reply_ip = stream.local_addr().map(|addr| addr.ip());
+        let client_ip = stream.peer_addr().map(|addr| addr.ip());
+        let mut socket = Socks5Socket::new(stream, Arc::new(config), self.new_connector());
+        if let Ok(reply_ip) = reply_ip {
+            socket.set_reply_ip(reply_ip);
         }
-
-        impl Drop for SmolTcpConnector {
-            fn drop(&mut self) {
-                if let Some(entry) = self.2.lock().unwrap().take() {
-                    self.1.remove(&entry);
-                }
-            }
+        if let Ok(client_ip) = client_ip {
+            socket.set_client_ip(client_ip);
         }
 
-        let socket = Socks5Socket::new(
-            stream,
-            Arc::new(config),
-            SmolTcpConnector(
-                self.smoltcp_net.clone(),
-                self.entries.clone(),
-                std::sync::Mutex::new(None),
-            ),
-        );
-
         self.forward_tasks.lock().unwrap().spawn(async move {
             match socket.upgrade_to_socks5().await {
                 Ok(_) => {
@@ -271,6 +533,15 @@ impl Socks5ServerNet {
             };
         });
     }
+
+    fn handle_http_stream(&self, stream: tokio::net::TcpStream, auth: Option<HttpProxyAuth>) {
+        let connector = self.new_connector();
+        self.forward_tasks.lock().unwrap().spawn(async move {
+            if let Err(e) = serve_http_proxy(stream, auth, connector).await {
+                tracing::error!("http proxy handle failed: {:?}", e);
+            }
+        });
+    }
 }
 
 pub struct Socks5Server {
@@ -284,6 +555,7 @@ pub struct Socks5Server {
 
     net: Arc<Mutex<Option<Socks5ServerNet>>>,
     entries: Socks5EntrySet,
+    udp_entries: Socks5UdpEntryMap,
 }
 
 #[async_trait::async_trait]
@@ -297,6 +569,12 @@ impl PeerPacketFilter for Socks5Server {
         let payload_bytes = packet.payload();
 
         let ipv4 = Ipv4Packet::new(payload_bytes).unwrap();
+        if ipv4.get_version() == 4 && ipv4.get_next_level_protocol() == IpNextHeaderProtocols::Udp {
+            if self.try_handle_udp_packet(&ipv4).await.is_some() {
+                return None;
+            }
+            return Some(packet);
+        }
         if ipv4.get_version() != 4 || ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
             return Some(packet);
         }
@@ -334,24 +612,46 @@ impl Socks5Server {
 
             net: Arc::new(Mutex::new(None)),
             entries: Arc::new(DashSet::new()),
+            udp_entries: Arc::new(DashMap::new()),
         })
     }
 
+    async fn try_handle_udp_packet(&self, ipv4: &Ipv4Packet<'_>) -> Option<()> {
+        if IpReassembler::is_packet_fragmented(ipv4) {
+            return None;
+        }
+        let udp_packet = UdpPacket::new(ipv4.payload())?;
+        let association = self.udp_entries.get(&udp_packet.get_destination())?.clone();
+        if ipv4.get_destination() != association.ipv4_addr {
+            return None;
+        }
+
+        let src = SocketAddr::new(ipv4.get_source().into(), udp_packet.get_source());
+        if let Err(e) = association.reply_to_client(src, udp_packet.payload()).await {
+            tracing::warn!(?e, "socks5 udp reply failed");
+        }
+        Some(())
+    }
+
     async fn run_net_update_task(self: &Arc<Self>) {
         let net = self.net.clone();
         let global_ctx = self.global_ctx.clone();
         let peer_manager = self.peer_manager.clone();
         let packet_recv = self.packet_recv.clone();
         let entries = self.entries.clone();
+        let udp_entries = self.udp_entries.clone();
         self.tasks.lock().await.spawn(async move {
             let mut prev_ipv4 = None;
+            let mut prev_network_length = global_ctx.get_network_length();
             loop {
                 let mut event_recv = global_ctx.subscribe();
 
                 let cur_ipv4 = global_ctx.get_ipv4();
+                let cur_network_length = global_ctx.get_network_length();
                 if prev_ipv4 != cur_ipv4 {
                     prev_ipv4 = cur_ipv4;
                     entries.clear();
+                    udp_entries.clear();
 
                     if cur_ipv4.is_none() {
                         let _ = net.lock().await.take();
@@ -362,9 +662,16 @@ impl Socks5Server {
                             peer_manager.clone(),
                             packet_recv.clone(),
                             entries.clone(),
+                            udp_entries.clone(),
                         ));
                     }
+                } else if prev_network_length != cur_network_length {
+                    // the length may be learned from peers later, keep the connections
+                    if let Some(net) = net.lock().await.as_ref() {
+                        net.smoltcp_net.set_prefix_len(cur_network_length);
+                    }
                 }
+                prev_network_length = cur_network_length;
 
                 select! {
                     _ = event_recv.recv() => {}
@@ -374,20 +681,31 @@ impl Socks5Server {
         });
     }
 
-    pub async fn run(self: &Arc<Self>) -> Result<(), Error> {
-        let Some(proxy_url) = self.global_ctx.config.get_socks5_portal() else {
-            return Ok(());
-        };
-
+    async fn bind_portal(&self, proxy_url: &url::Url) -> Result<TcpListener, Error> {
         let bind_addr = format!(
             "{}:{}",
             proxy_url.host_str().unwrap(),
             proxy_url.port().unwrap()
         );
 
-        let listener = {
-            let _g = self.global_ctx.net_ns.guard();
-            TcpListener::bind(bind_addr.parse::<SocketAddr>().unwrap()).await?
+        let _g = self.global_ctx.net_ns.guard();
+        Ok(TcpListener::bind(bind_addr.parse::<SocketAddr>().unwrap()).await?)
+    }
+
+    pub async fn run(self: &Arc<Self>) -> Result<(), Error> {
+        let socks5_url = self.global_ctx.config.get_socks5_portal();
+        let http_url = self.global_ctx.config.get_http_proxy_portal();
+        if socks5_url.is_none() && http_url.is_none() {
+            return Ok(());
+        }
+
+        let socks5_listener = match socks5_url.as_ref() {
+            Some(url) => Some(self.bind_portal(url).await?),
+            None => None,
+        };
+        let http_listener = match http_url.as_ref() {
+            Some(url) => Some(self.bind_portal(url).await?),
+            None => None,
         };
 
         self.peer_manager
@@ -396,21 +714,420 @@ impl Socks5Server {
 
         self.run_net_update_task().await;
 
-        let net = self.net.clone();
-        self.tasks.lock().await.spawn(async move {
-            loop {
-                match listener.accept().await {
-                    Ok((socket, _addr)) => {
-                        tracing::info!("accept a new connection, {:?}", socket);
-                        if let Some(net) = net.lock().await.as_ref() {
-                            net.handle_tcp_stream(socket);
+        if let Some(listener) = socks5_listener {
+            let net = self.net.clone();
+            self.tasks.lock().await.spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((socket, _addr)) => {
+                            tracing::info!("accept a new connection, {:?}", socket);
+                            if let Some(net) = net.lock().await.as_ref() {
+                                net.handle_tcp_stream(socket);
+                            }
                         }
+                        Err(err) => tracing::error!("accept error = {:?}", err),
                     }
-                    Err(err) => tracing::error!("accept error = {:?}", err),
                 }
+            });
+        }
+
+        if let Some(listener) = http_listener {
+            let net = self.net.clone();
+            let auth = http_url.as_ref().and_then(HttpProxyAuth::from_url);
+            self.tasks.lock().await.spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((socket, _addr)) => {
+                            tracing::info!("accept a new http proxy connection, {:?}", socket);
+                            if let Some(net) = net.lock().await.as_ref() {
+                                net.handle_http_stream(socket, auth.clone());
+                            }
+                        }
+                        Err(err) => tracing::error!("http proxy accept error = {:?}", err),
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        common::{
+            config::{ConfigLoader, TomlConfigLoader},
+            global_ctx::GlobalCtx,
+        },
+        gateway::{port_forward::PortForwardManager, tcp_proxy::TcpProxy},
+        peers::{
+            peer_manager::RouteAlgoType,
+            tests::{connect_peer_manager, wait_route_appear},
+        },
+        tunnel::common::tests::wait_for_condition,
+    };
+
+    use super::*;
+
+    async fn create_no_tun_peer_manager(
+        ipv4: Ipv4Addr,
+        socks5_portal: Option<url::Url>,
+    ) -> Arc<PeerManager> {
+        let config = TomlConfigLoader::default();
+        config.set_inst_name(format!("test_{}", config.get_id()));
+        config.set_ipv4(Some(ipv4));
+        config.set_socks5_portal(socks5_portal);
+        let mut flags = config.get_flags();
+        flags.no_tun = true;
+        config.set_flags(flags);
+
+        let (s, _r) = mpsc::channel(1000);
+        let peer_mgr = Arc::new(PeerManager::new(
+            RouteAlgoType::Ospf,
+            Arc::new(GlobalCtx::new(config)),
+            s,
+        ));
+        peer_mgr.run().await.unwrap();
+        peer_mgr
+    }
+
+    #[tokio::test]
+    async fn socks5_udp_associate_without_tun() {
+        let portal_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let ip_b: Ipv4Addr = "10.144.144.2".parse().unwrap();
+        let peer_mgr_a = create_no_tun_peer_manager(
+            "10.144.144.1".parse().unwrap(),
+            Some(
+                format!("socks5://127.0.0.1:{}", portal_port)
+                    .parse()
+                    .unwrap(),
+            ),
+        )
+        .await;
+        let peer_mgr_b = create_no_tun_peer_manager(ip_b, None).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+        wait_for_condition(
+            || async {
+                peer_mgr_a
+                    .get_peer_map()
+                    .get_peer_id_by_ipv4(&ip_b)
+                    .await
+                    .is_some()
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // b exposes an udp echo server on its virtual ip
+        let udp_echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_echo_addr = udp_echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, addr) = udp_echo.recv_from(&mut buf).await.unwrap();
+            udp_echo.send_to(&buf[..len], addr).await.unwrap();
+        });
+        let port_forward = PortForwardManager::new(peer_mgr_b.get_global_ctx(), peer_mgr_b.clone());
+        port_forward.run().await.unwrap();
+        let rule = format!("udp://virtual:15353/{}", udp_echo_addr);
+        port_forward.add_rule(rule.parse().unwrap()).await.unwrap();
+
+        let socks5_server =
+            Socks5Server::new(peer_mgr_a.get_global_ctx(), peer_mgr_a.clone(), None);
+        socks5_server.run().await.unwrap();
+        wait_for_condition(
+            || async { socks5_server.net.lock().await.is_some() },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        let mut control = tokio::net::TcpStream::connect(("127.0.0.1", portal_port))
+            .await
+            .unwrap();
+        control.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        control.read_exact(&mut method).await.unwrap();
+        assert_eq!([5, 0], method);
+        control
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!([5, 0, 0, 1], reply[..4]);
+        let relay_addr = SocketAddr::new(
+            Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]).into(),
+            u16::from_be_bytes([reply[8], reply[9]]),
+        );
+
+        let mut request =
+            new_udp_header("10.144.144.2:15353".parse::<SocketAddr>().unwrap()).unwrap();
+        request.extend_from_slice(b"hello");
+
+        // datagrams from hosts other than the control connection peer are dropped
+        let stranger = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        stranger.send_to(&request, relay_addr).await.unwrap();
+        let mut buf = [0u8; 64];
+        assert!(
+            timeout(Duration::from_millis(500), stranger.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&request, relay_addr).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let (_, src, payload) = parse_udp_request(&buf[..len]).await.unwrap();
+        assert_eq!("10.144.144.2:15353", src.to_string());
+        assert_eq!(b"hello", payload);
+
+        // closing the control connection ends the association
+        drop(control);
+        wait_for_condition(
+            || async { socks5_server.udp_entries.is_empty() },
+            Duration::from_secs(5),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn http_proxy_without_tun() {
+        let portal_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let ip_b: Ipv4Addr = "10.144.145.2".parse().unwrap();
+        let peer_mgr_a = create_no_tun_peer_manager("10.144.145.1".parse().unwrap(), None).await;
+        peer_mgr_a
+            .get_global_ctx()
+            .config
+            .set_http_proxy_portal(Some(
+                format!("http://u:p@127.0.0.1:{}", portal_port)
+                    .parse()
+                    .unwrap(),
+            ));
+        let peer_mgr_b = create_no_tun_peer_manager(ip_b, None).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+        wait_for_condition(
+            || async {
+                peer_mgr_a
+                    .get_peer_map()
+                    .get_peer_id_by_ipv4(&ip_b)
+                    .await
+                    .is_some()
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // b exposes a server on its virtual ip that echoes the first read and closes
+        let tcp_echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_echo_addr = tcp_echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tcp_echo.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 1024];
+                    let n = stream.read(&mut buf).await.unwrap_or(0);
+                    let _ = stream.write_all(&buf[..n]).await;
+                });
             }
         });
+        let port_forward = PortForwardManager::new(peer_mgr_b.get_global_ctx(), peer_mgr_b.clone());
+        port_forward.run().await.unwrap();
+        let rule = format!("tcp://virtual:18080/{}", tcp_echo_addr);
+        port_forward.add_rule(rule.parse().unwrap()).await.unwrap();
 
-        Ok(())
+        let socks5_server =
+            Socks5Server::new(peer_mgr_a.get_global_ctx(), peer_mgr_a.clone(), None);
+        socks5_server.run().await.unwrap();
+        wait_for_condition(
+            || async { socks5_server.net.lock().await.is_some() },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        async fn request(port: u16, head: &str) -> String {
+            let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .unwrap();
+            stream.write_all(head.as_bytes()).await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let mut len = 0;
+            while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = timeout(Duration::from_secs(5), stream.read(&mut buf[len..]))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_ne!(0, n);
+                len += n;
+            }
+            if buf[..len].starts_with(b"HTTP/1.1 200") {
+                stream.write_all(b"ping").await.unwrap();
+                let mut ping = [0u8; 4];
+                stream.read_exact(&mut ping).await.unwrap();
+                assert_eq!(b"ping", &ping);
+            }
+            String::from_utf8_lossy(&buf[..len]).to_string()
+        }
+
+        let resp = request(portal_port, "CONNECT 10.144.145.2:18080 HTTP/1.1\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 407"), "{}", resp);
+
+        let resp = request(
+            portal_port,
+            "CONNECT 10.144.145.2:18080 HTTP/1.1\r\nProxy-Authorization: Basic dTpw\r\n\r\n",
+        )
+        .await;
+        assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+
+        // the echo server sends back the request the proxy forwarded
+        let resp = request(
+            portal_port,
+            "GET http://10.144.145.2:18080/echo HTTP/1.1\r\nHost: 10.144.145.2:18080\r\nProxy-Authorization: Basic dTpw\r\n\r\n",
+        )
+        .await;
+        assert_eq!(
+            "GET /echo HTTP/1.1\r\nHost: 10.144.145.2:18080\r\nConnection: close\r\n\r\n",
+            resp
+        );
+
+        // a pipelined request must not reach the target, the proxy closes after the response
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", portal_port))
+            .await
+            .unwrap();
+        stream
+            .write_all(
+                b"GET http://10.144.145.2:18080/a HTTP/1.1\r\nProxy-Authorization: Basic dTpw\r\n\r\n\
+                GET http://10.144.145.2:18080/b HTTP/1.1\r\nProxy-Authorization: Basic dTpw\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        timeout(Duration::from_secs(5), stream.read_to_string(&mut resp))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            "GET /a HTTP/1.1\r\nHost: 10.144.145.2:18080\r\nConnection: close\r\n\r\n",
+            resp
+        );
+    }
+
+    #[tokio::test]
+    async fn tcp_proxy_close_flow_without_tun() {
+        let portal_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let ip_b: Ipv4Addr = "10.144.146.2".parse().unwrap();
+        let peer_mgr_a = create_no_tun_peer_manager(
+            "10.144.146.1".parse().unwrap(),
+            Some(
+                format!("socks5://127.0.0.1:{}", portal_port)
+                    .parse()
+                    .unwrap(),
+            ),
+        )
+        .await;
+        let peer_mgr_b = create_no_tun_peer_manager(ip_b, None).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+        wait_for_condition(
+            || async {
+                peer_mgr_a
+                    .get_peer_map()
+                    .get_peer_id_by_ipv4(&ip_b)
+                    .await
+                    .is_some()
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // the tcp proxy of b forwards connections to its virtual ip to the local echo server
+        let tcp_echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_echo_port = tcp_echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tcp_echo.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        let tcp_proxy = TcpProxy::new(peer_mgr_b.get_global_ctx(), peer_mgr_b.clone());
+        tcp_proxy.start().await.unwrap();
+
+        let socks5_server =
+            Socks5Server::new(peer_mgr_a.get_global_ctx(), peer_mgr_a.clone(), None);
+        socks5_server.run().await.unwrap();
+        wait_for_condition(
+            || async { socks5_server.net.lock().await.is_some() },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        let mut client = tokio::net::TcpStream::connect(("127.0.0.1", portal_port))
+            .await
+            .unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!([5, 0], method);
+        let mut request = vec![5, 1, 0, 1];
+        request.extend_from_slice(&ip_b.octets());
+        request.extend_from_slice(&tcp_echo_port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!([5, 0], reply[..2]);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0u8; 4];
+        timeout(Duration::from_secs(5), client.read_exact(&mut ping))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"ping", &ping);
+
+        let flows = tcp_proxy.list_flows();
+        assert_eq!(1, flows.len());
+        assert_eq!("connected", flows[0].state);
+        assert!(tcp_proxy.close_flow(&flows[0].id.parse().unwrap()).await);
+
+        // the client sees the connection closed and the flow is gone
+        let mut buf = [0u8; 16];
+        let ret = timeout(Duration::from_secs(10), client.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(matches!(ret, Ok(0) | Err(_)), "{:?}", ret);
+        wait_for_condition(
+            || async { tcp_proxy.list_flows().is_empty() },
+            Duration::from_secs(10),
+        )
+        .await;
     }
 }
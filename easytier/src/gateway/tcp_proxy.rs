@@ -3,25 +3,29 @@ use crossbeam::atomic::AtomicCell;
 use dashmap::DashMap;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
-use pnet::packet::tcp::{ipv4_checksum, MutableTcpPacket, TcpPacket};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::tcp::{ipv4_checksum, ipv6_checksum, MutableTcpPacket, TcpPacket};
 use pnet::packet::MutablePacket;
 use pnet::packet::Packet;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::atomic::{AtomicBool, AtomicU16};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::io::copy_bidirectional;
+use tokio::io::{copy_bidirectional, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, Mutex, Notify};
 use tokio::task::JoinSet;
 use tracing::Instrument;
 
 use crate::common::error::Result;
 use crate::common::global_ctx::{ArcGlobalCtx, GlobalCtx};
 use crate::common::join_joinset_background;
+use crate::common::PeerId;
 
+use crate::peers::path_mtu::{clamp_tcp_mss, is_tcp_syn};
 use crate::peers::peer_manager::PeerManager;
 use crate::peers::{NicPacketFilter, PeerPacketFilter};
+use crate::rpc::ProxyFlow;
 use crate::tunnel::packet_def::{PacketType, ZCPacket};
 
 use super::CidrSet;
@@ -29,7 +33,11 @@ use super::CidrSet;
 #[cfg(feature = "smoltcp")]
 use super::tokio_smoltcp::{self, channel_device, Net, NetConfig};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+// address of the smoltcp stack, peers never see it because the nic filter rewrites it
+const SMOLTCP_LOCAL_IPV4: Ipv4Addr = Ipv4Addr::new(192, 88, 99, 254);
+const SMOLTCP_LOCAL_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0xc058, 0x63fe, 0, 0, 0, 0, 0xfe);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum NatDstEntryState {
     // receive syn packet but not start connecting to dst
     SynReceived,
@@ -41,25 +49,68 @@ enum NatDstEntryState {
     Closed,
 }
 
+impl NatDstEntryState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NatDstEntryState::SynReceived => "syn_received",
+            NatDstEntryState::ConnectingDst => "connecting",
+            NatDstEntryState::Connected => "connected",
+            NatDstEntryState::Closed => "closed",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct NatDstEntry {
     id: uuid::Uuid,
+    src_peer_id: PeerId,
     src: SocketAddr,
     dst: SocketAddr,
+    // differs from dst if the peer addressed a mapped network
+    real_dst: SocketAddr,
     start_time: Instant,
     tasks: Mutex<JoinSet<()>>,
     state: AtomicCell<NatDstEntryState>,
+    // wakes the connection task to close the flow on request
+    close_notify: Notify,
+    // tcp payload bytes seen from the src peer and sent back to it
+    tx_bytes: AtomicU64,
+    rx_bytes: AtomicU64,
 }
 
 impl NatDstEntry {
-    pub fn new(src: SocketAddr, dst: SocketAddr) -> Self {
+    pub fn new(
+        src_peer_id: PeerId,
+        src: SocketAddr,
+        dst: SocketAddr,
+        real_dst: SocketAddr,
+    ) -> Self {
         Self {
             id: uuid::Uuid::new_v4(),
+            src_peer_id,
             src,
             dst,
+            real_dst,
             start_time: Instant::now(),
             tasks: Mutex::new(JoinSet::new()),
             state: AtomicCell::new(NatDstEntryState::SynReceived),
+            close_notify: Notify::new(),
+            tx_bytes: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn to_proxy_flow(&self) -> ProxyFlow {
+        ProxyFlow {
+            id: self.id.to_string(),
+            protocol: "tcp".to_owned(),
+            src_peer_id: self.src_peer_id,
+            src_addr: self.src.to_string(),
+            dst_addr: self.dst.to_string(),
+            state: self.state.load().as_str().to_owned(),
+            age_ms: self.start_time.elapsed().as_millis() as u64,
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
         }
     }
 }
@@ -82,6 +133,15 @@ impl ProxyTcpStream {
         }
     }
 
+    pub async fn shutdown(&mut self) -> Result<()> {
+        match self {
+            Self::KernelTcpStream(stream) => stream.shutdown().await?,
+            #[cfg(feature = "smoltcp")]
+            Self::SmolTcpStream(stream) => stream.shutdown().await?,
+        }
+        Ok(())
+    }
+
     pub async fn copy_bidirectional(&mut self, dst: &mut TcpStream) -> Result<()> {
         match self {
             Self::KernelTcpStream(stream) => {
@@ -112,11 +172,15 @@ impl SmolTcpListener {
 
         let (tx, rx) = mpsc::unbounded_channel();
         let locked_net = net.lock().await;
-        for _ in 0..listen_count {
+        let listen_addrs: [SocketAddr; 2] = [
+            "0.0.0.0:8899".parse().unwrap(),
+            SocketAddr::new(SMOLTCP_LOCAL_IPV6.into(), 8899),
+        ];
+        for listen_addr in (0..listen_count).flat_map(|_| listen_addrs) {
             let mut tcp = locked_net
                 .as_ref()
                 .unwrap()
-                .tcp_bind("0.0.0.0:8899".parse().unwrap())
+                .tcp_bind(listen_addr)
                 .await
                 .unwrap();
             let tx = tx.clone();
@@ -143,7 +207,8 @@ impl SmolTcpListener {
 }
 
 enum ProxyTcpListener {
-    KernelTcpListener(TcpListener),
+    // the v6 listener is bound on the same port if the host supports ipv6
+    KernelTcpListener(TcpListener, Option<TcpListener>),
     #[cfg(feature = "smoltcp")]
     SmolTcpListener(SmolTcpListener),
 }
@@ -151,8 +216,14 @@ enum ProxyTcpListener {
 impl ProxyTcpListener {
     pub async fn accept(&mut self) -> Result<(ProxyTcpStream, SocketAddr)> {
         match self {
-            Self::KernelTcpListener(listener) => {
-                let (stream, addr) = listener.accept().await?;
+            Self::KernelTcpListener(listener, listener_v6) => {
+                let (stream, addr) = match listener_v6 {
+                    Some(listener_v6) => tokio::select! {
+                        ret = listener.accept() => ret?,
+                        ret = listener_v6.accept() => ret?,
+                    },
+                    None => listener.accept().await?,
+                };
                 Ok((ProxyTcpStream::KernelTcpStream(stream), addr))
             }
             #[cfg(feature = "smoltcp")]
@@ -222,14 +293,59 @@ impl PeerPacketFilter for TcpProxy {
 #[async_trait::async_trait]
 impl NicPacketFilter for TcpProxy {
     async fn try_process_packet_from_nic(&self, zc_packet: &mut ZCPacket) {
+        match zc_packet.payload().first().map(|b| b >> 4) {
+            Some(4) => self.try_rewrite_nic_packet_v4(zc_packet),
+            Some(6) => self.try_rewrite_nic_packet_v6(zc_packet),
+            _ => {}
+        }
+    }
+}
+
+impl TcpProxy {
+    pub fn new(global_ctx: Arc<GlobalCtx>, peer_manager: Arc<PeerManager>) -> Arc<Self> {
+        let (smoltcp_stack_sender, smoltcp_stack_receiver) = mpsc::channel::<ZCPacket>(1000);
+
+        Arc::new(Self {
+            global_ctx: global_ctx.clone(),
+            peer_manager,
+
+            local_port: AtomicU16::new(0),
+            tasks: Arc::new(std::sync::Mutex::new(JoinSet::new())),
+
+            syn_map: Arc::new(DashMap::new()),
+            conn_map: Arc::new(DashMap::new()),
+            addr_conn_map: Arc::new(DashMap::new()),
+
+            cidr_set: CidrSet::new(global_ctx),
+
+            smoltcp_stack_sender: Some(smoltcp_stack_sender),
+            smoltcp_stack_receiver: Arc::new(Mutex::new(Some(smoltcp_stack_receiver))),
+
+            #[cfg(feature = "smoltcp")]
+            smoltcp_net: Arc::new(Mutex::new(None)),
+
+            enable_smoltcp: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    fn find_nat_entry(&self, dst_addr: &SocketAddr) -> Option<ArcNatDstEntry> {
+        tracing::trace!(dst_addr = ?dst_addr, "tcp packet try find entry");
+        let nat_entry = match self.addr_conn_map.get(dst_addr) {
+            Some(entry) => entry.clone(),
+            None => self.syn_map.get(dst_addr)?.clone(),
+        };
+        assert_eq!(nat_entry.src, *dst_addr);
+        Some(nat_entry)
+    }
+
+    fn try_rewrite_nic_packet_v4(&self, zc_packet: &mut ZCPacket) {
         let Some(my_ipv4) = self.get_local_ip() else {
             return;
         };
 
         let data = zc_packet.payload();
         let ip_packet = Ipv4Packet::new(data).unwrap();
-        if ip_packet.get_version() != 4
-            || ip_packet.get_source() != my_ipv4
+        if ip_packet.get_source() != my_ipv4
             || ip_packet.get_next_level_protocol() != IpNextHeaderProtocols::Tcp
         {
             return;
@@ -244,19 +360,12 @@ impl NicPacketFilter for TcpProxy {
             ip_packet.get_destination(),
             tcp_packet.get_destination(),
         ));
-
-        tracing::trace!(dst_addr = ?dst_addr, "tcp packet try find entry");
-        let entry = if let Some(entry) = self.addr_conn_map.get(&dst_addr) {
-            entry
-        } else {
-            let Some(syn_entry) = self.syn_map.get(&dst_addr) else {
-                return;
-            };
-            syn_entry
+        let Some(nat_entry) = self.find_nat_entry(&dst_addr) else {
+            return;
         };
-        let nat_entry = entry.clone();
-        drop(entry);
-        assert_eq!(nat_entry.src, dst_addr);
+        nat_entry
+            .rx_bytes
+            .fetch_add(tcp_packet.payload().len() as u64, Ordering::Relaxed);
 
         let IpAddr::V4(ip) = nat_entry.dst.ip() else {
             panic!("v4 nat entry src ip is not v4");
@@ -280,33 +389,59 @@ impl NicPacketFilter for TcpProxy {
 
         tracing::trace!(dst_addr = ?dst_addr, nat_entry = ?nat_entry, packet = ?ip_packet, "tcp packet after modified");
     }
-}
 
-impl TcpProxy {
-    pub fn new(global_ctx: Arc<GlobalCtx>, peer_manager: Arc<PeerManager>) -> Arc<Self> {
-        let (smoltcp_stack_sender, smoltcp_stack_receiver) = mpsc::channel::<ZCPacket>(1000);
+    fn try_rewrite_nic_packet_v6(&self, zc_packet: &mut ZCPacket) {
+        let Some(my_ipv6) = self.get_local_ipv6() else {
+            return;
+        };
 
-        Arc::new(Self {
-            global_ctx: global_ctx.clone(),
-            peer_manager,
+        let Some(ip_packet) = Ipv6Packet::new(zc_packet.payload()) else {
+            return;
+        };
+        if ip_packet.get_source() != my_ipv6
+            || ip_packet.get_next_header() != IpNextHeaderProtocols::Tcp
+        {
+            return;
+        }
 
-            local_port: AtomicU16::new(0),
-            tasks: Arc::new(std::sync::Mutex::new(JoinSet::new())),
+        let Some(tcp_packet) = TcpPacket::new(ip_packet.payload()) else {
+            return;
+        };
+        if tcp_packet.get_source() != self.get_local_port() {
+            return;
+        }
 
-            syn_map: Arc::new(DashMap::new()),
-            conn_map: Arc::new(DashMap::new()),
-            addr_conn_map: Arc::new(DashMap::new()),
+        let dst_addr = SocketAddr::V6(SocketAddrV6::new(
+            ip_packet.get_destination(),
+            tcp_packet.get_destination(),
+            0,
+            0,
+        ));
+        let Some(nat_entry) = self.find_nat_entry(&dst_addr) else {
+            return;
+        };
+        nat_entry
+            .rx_bytes
+            .fetch_add(tcp_packet.payload().len() as u64, Ordering::Relaxed);
 
-            cidr_set: CidrSet::new(global_ctx),
+        let IpAddr::V6(ip) = nat_entry.dst.ip() else {
+            panic!("v6 nat entry src ip is not v6");
+        };
 
-            smoltcp_stack_sender: Some(smoltcp_stack_sender),
-            smoltcp_stack_receiver: Arc::new(Mutex::new(Some(smoltcp_stack_receiver))),
+        zc_packet
+            .mut_peer_manager_header()
+            .unwrap()
+            .set_no_proxy(true);
 
-            #[cfg(feature = "smoltcp")]
-            smoltcp_net: Arc::new(Mutex::new(None)),
+        let mut ip_packet = MutableIpv6Packet::new(zc_packet.mut_payload()).unwrap();
+        ip_packet.set_source(ip);
+        let dst = ip_packet.get_destination();
 
-            enable_smoltcp: Arc::new(AtomicBool::new(true)),
-        })
+        let mut tcp_packet = MutableTcpPacket::new(ip_packet.payload_mut()).unwrap();
+        tcp_packet.set_source(nat_entry.dst.port());
+        tcp_packet.set_checksum(ipv6_checksum(&tcp_packet.to_immutable(), &ip, &dst));
+
+        tracing::trace!(dst_addr = ?dst_addr, nat_entry = ?nat_entry, "tcp v6 packet after modified");
     }
 
     fn update_tcp_packet_checksum(
@@ -339,6 +474,27 @@ impl TcpProxy {
         Ok(())
     }
 
+    // the network length may be learned from peers after the smoltcp stack is built
+    #[cfg(feature = "smoltcp")]
+    fn run_network_length_updater(&self, mut network_length: u8) {
+        let global_ctx = self.global_ctx.clone();
+        let smoltcp_net = self.smoltcp_net.clone();
+        let mut event_recv = global_ctx.subscribe();
+        self.tasks.lock().unwrap().spawn(async move {
+            use tokio::sync::broadcast::error::RecvError;
+            while !matches!(event_recv.recv().await, Err(RecvError::Closed)) {
+                let cur_network_length = global_ctx.get_network_length();
+                if cur_network_length == network_length {
+                    continue;
+                }
+                network_length = cur_network_length;
+                if let Some(net) = smoltcp_net.lock().await.as_ref() {
+                    net.set_prefix_len(network_length);
+                }
+            }
+        });
+    }
+
     async fn run_syn_map_cleaner(&self) -> Result<()> {
         let syn_map = self.syn_map.clone();
         let tasks = self.tasks.clone();
@@ -393,14 +549,22 @@ impl TcpProxy {
                         ?data,
                         "receive from smoltcp stack and send to peer mgr packet"
                     );
-                    let Some(ipv4) = Ipv4Packet::new(&data) else {
-                        tracing::error!(?data, "smoltcp stack stream get non ipv4 packet");
-                        continue;
-                    };
-
-                    let dst = ipv4.get_destination();
                     let packet = ZCPacket::new_with_payload(&data);
-                    if let Err(e) = peer_mgr.send_msg_ipv4(packet, dst).await {
+                    let ret = match data.first().map(|b| b >> 4) {
+                        Some(4) => {
+                            let dst = Ipv4Packet::new(&data).unwrap().get_destination();
+                            peer_mgr.send_msg_ipv4(packet, dst).await
+                        }
+                        Some(6) => {
+                            let dst = Ipv6Packet::new(&data).unwrap().get_destination();
+                            peer_mgr.send_msg_ipv6(packet, dst).await
+                        }
+                        _ => {
+                            tracing::error!(?data, "smoltcp stack stream get non ip packet");
+                            continue;
+                        }
+                    };
+                    if let Err(e) = ret {
                         tracing::error!("send to peer failed in smoltcp sender: {:?}", e);
                     }
                 }
@@ -409,18 +573,28 @@ impl TcpProxy {
             });
 
             let interface_config = smoltcp::iface::Config::new(smoltcp::wire::HardwareAddress::Ip);
+            let network_length = self.global_ctx.get_network_length();
             let net = Net::new(
                 dev,
                 NetConfig::new(
                     interface_config,
-                    format!("{}/24", self.get_local_ip().unwrap())
+                    format!("{}/{}", self.get_local_ip().unwrap(), network_length)
                         .parse()
                         .unwrap(),
                     vec![format!("{}", self.get_local_ip().unwrap()).parse().unwrap()],
                 ),
             );
             net.set_any_ip(true);
+            // smoltcp has no any ip for v6, proxied v6 packets are always addressed to this one
+            net.add_ip_addr(smoltcp::wire::IpCidr::new(SMOLTCP_LOCAL_IPV6.into(), 128))
+                .unwrap();
+            net.routes_mut(|routes| {
+                routes
+                    .add_default_ipv6_route(SMOLTCP_LOCAL_IPV6.into())
+                    .unwrap();
+            });
             self.smoltcp_net.lock().await.replace(net);
+            self.run_network_length_updater(network_length);
             let tcp = SmolTcpListener::new(self.smoltcp_net.clone(), 64).await;
 
             self.enable_smoltcp
@@ -436,18 +610,42 @@ impl TcpProxy {
             let tcp_listener = net_ns
                 .run_async(|| async { TcpListener::bind(&listen_addr).await })
                 .await?;
-            self.local_port.store(
-                tcp_listener.local_addr()?.port(),
-                std::sync::atomic::Ordering::Relaxed,
-            );
+            let local_port = tcp_listener.local_addr()?.port();
+            self.local_port
+                .store(local_port, std::sync::atomic::Ordering::Relaxed);
+
+            let listen_addr_v6 = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), local_port);
+            let tcp_listener_v6 = match net_ns.run(|| Self::bind_v6_listener(listen_addr_v6)) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    tracing::warn!(?e, "bind v6 tcp proxy listener failed, ipv6 not proxied");
+                    None
+                }
+            };
 
             self.enable_smoltcp
                 .store(false, std::sync::atomic::Ordering::Relaxed);
 
-            return Ok(ProxyTcpListener::KernelTcpListener(tcp_listener));
+            return Ok(ProxyTcpListener::KernelTcpListener(
+                tcp_listener,
+                tcp_listener_v6,
+            ));
         }
     }
 
+    fn bind_v6_listener(listen_addr: SocketAddr) -> std::io::Result<TcpListener> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV6,
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        socket.set_only_v6(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&listen_addr.into())?;
+        socket.listen(1024)?;
+        TcpListener::from_std(socket.into())
+    }
+
     async fn run_listener(&self) -> Result<()> {
         // bind on both v4 & v6
         let mut tcp_listener = self.get_proxy_listener().await?;
@@ -469,13 +667,22 @@ impl TcpProxy {
                     "tcp connection accepted for proxy, nat dst: {:?}",
                     entry.dst
                 );
-                assert_eq!(entry.state.load(), NatDstEntryState::SynReceived);
 
                 let entry_clone = entry.clone();
                 drop(entry);
                 syn_map.remove_if(&socket_addr, |_, entry| entry.id == entry_clone.id);
 
-                entry_clone.state.store(NatDstEntryState::ConnectingDst);
+                // the flow may have been closed by the user meanwhile
+                if entry_clone
+                    .state
+                    .compare_exchange(
+                        NatDstEntryState::SynReceived,
+                        NatDstEntryState::ConnectingDst,
+                    )
+                    .is_err()
+                {
+                    continue;
+                }
 
                 let _ = addr_conn_map.insert(entry_clone.src, entry_clone.clone());
                 let old_nat_val = conn_map.insert(entry_clone.id, entry_clone.clone());
@@ -521,25 +728,28 @@ impl TcpProxy {
         }
 
         let _guard = global_ctx.net_ns.guard();
-        let socket = TcpSocket::new_v4().unwrap();
-        if let Err(e) = socket.set_nodelay(true) {
-            tracing::warn!("set_nodelay failed, ignore it: {:?}", e);
-        }
 
         let nat_dst = if Some(nat_entry.dst.ip()) == global_ctx.get_ipv4().map(|ip| IpAddr::V4(ip))
         {
-            format!("127.0.0.1:{}", nat_entry.dst.port())
-                .parse()
-                .unwrap()
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), nat_entry.dst.port())
+        } else if Some(nat_entry.dst.ip()) == global_ctx.get_ipv6().map(IpAddr::V6) {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), nat_entry.dst.port())
         } else {
-            nat_entry.dst
+            nat_entry.real_dst
         };
 
-        let Ok(Ok(dst_tcp_stream)) = tokio::time::timeout(
-            Duration::from_secs(10),
-            TcpSocket::new_v4().unwrap().connect(nat_dst),
-        )
-        .await
+        let connect = async {
+            let socket = match nat_dst {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            if let Err(e) = socket.set_nodelay(true) {
+                tracing::warn!("set_nodelay failed, ignore it: {:?}", e);
+            }
+            socket.connect(nat_dst).await
+        };
+
+        let Ok(Ok(dst_tcp_stream)) = tokio::time::timeout(Duration::from_secs(10), connect).await
         else {
             tracing::error!("connect to dst failed: {:?}", nat_entry);
             nat_entry.state.store(NatDstEntryState::Closed);
@@ -550,8 +760,17 @@ impl TcpProxy {
 
         tracing::info!(?nat_entry, ?nat_dst, "tcp connection to dst established");
 
-        assert_eq!(nat_entry.state.load(), NatDstEntryState::ConnectingDst);
-        nat_entry.state.store(NatDstEntryState::Connected);
+        if nat_entry
+            .state
+            .compare_exchange(NatDstEntryState::ConnectingDst, NatDstEntryState::Connected)
+            .is_err()
+        {
+            tracing::info!(?nat_entry, "nat entry closed while connecting to dst");
+            let mut src_tcp_stream = src_tcp_stream;
+            Self::shutdown_src_stream(&mut src_tcp_stream).await;
+            Self::remove_entry_from_all_conn_map(conn_map, addr_conn_map, nat_entry);
+            return;
+        }
 
         Self::handle_nat_connection(
             src_tcp_stream,
@@ -571,8 +790,15 @@ impl TcpProxy {
         nat_entry: ArcNatDstEntry,
     ) {
         let nat_entry_clone = nat_entry.clone();
-        nat_entry.tasks.lock().await.spawn(async move {
-            let ret = src_tcp_stream.copy_bidirectional(&mut dst_tcp_stream).await;
+        let mut tasks = nat_entry.tasks.lock().await;
+        tasks.spawn(async move {
+            let ret = tokio::select! {
+                ret = src_tcp_stream.copy_bidirectional(&mut dst_tcp_stream) => ret,
+                _ = nat_entry_clone.close_notify.notified() => {
+                    Self::shutdown_src_stream(&mut src_tcp_stream).await;
+                    Ok(())
+                }
+            };
             tracing::info!(nat_entry = ?nat_entry_clone, ret = ?ret, "nat tcp connection closed");
             nat_entry_clone.state.store(NatDstEntryState::Closed);
 
@@ -580,6 +806,44 @@ impl TcpProxy {
         });
     }
 
+    // the fin must still be rewritten for the client, so the entry is removed after this
+    async fn shutdown_src_stream(src_tcp_stream: &mut ProxyTcpStream) {
+        let ret = tokio::time::timeout(Duration::from_secs(5), src_tcp_stream.shutdown()).await;
+        tracing::debug!(?ret, "shutdown proxied tcp stream");
+    }
+
+    pub fn list_flows(&self) -> Vec<ProxyFlow> {
+        let mut flows: Vec<_> = self
+            .syn_map
+            .iter()
+            .map(|entry| entry.to_proxy_flow())
+            .collect();
+        flows.extend(self.conn_map.iter().map(|entry| entry.to_proxy_flow()));
+        flows
+    }
+
+    // returns false if no flow has the id
+    pub async fn close_flow(&self, id: &uuid::Uuid) -> bool {
+        let nat_entry = match self.conn_map.get(id) {
+            Some(entry) => entry.clone(),
+            None => {
+                let Some(entry) = self.syn_map.iter().find(|entry| entry.id == *id) else {
+                    return false;
+                };
+                entry.clone()
+            }
+        };
+        tracing::info!(?nat_entry, "close nat entry by request");
+
+        // a connected flow is shut down by its task, which then removes the entries. the
+        // notification is kept if the task has not started yet
+        nat_entry.state.store(NatDstEntryState::Closed);
+        self.syn_map
+            .remove_if(&nat_entry.src, |_, entry| entry.id == nat_entry.id);
+        nat_entry.close_notify.notify_one();
+        true
+    }
+
     pub fn get_local_port(&self) -> u16 {
         self.local_port.load(std::sync::atomic::Ordering::Relaxed)
     }
@@ -589,12 +853,23 @@ impl TcpProxy {
             .enable_smoltcp
             .load(std::sync::atomic::Ordering::Relaxed)
         {
-            Some(Ipv4Addr::new(192, 88, 99, 254))
+            Some(SMOLTCP_LOCAL_IPV4)
         } else {
             self.global_ctx.get_ipv4()
         }
     }
 
+    pub fn get_local_ipv6(&self) -> Option<Ipv6Addr> {
+        if self
+            .enable_smoltcp
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            Some(SMOLTCP_LOCAL_IPV6)
+        } else {
+            self.global_ctx.get_ipv6()
+        }
+    }
+
     async fn try_handle_peer_packet(&self, packet: &mut ZCPacket) -> Option<()> {
         if self.cidr_set.is_empty()
             && !self.global_ctx.enable_exit_node()
@@ -603,18 +878,71 @@ impl TcpProxy {
             return None;
         }
 
-        let ipv4_addr = self.get_local_ip()?;
         let hdr = packet.peer_manager_header().unwrap();
         let is_exit_node = hdr.is_exit_node();
+        let from_peer_id = hdr.from_peer_id.get();
 
         if hdr.packet_type != PacketType::Data as u8 || hdr.is_no_proxy() {
             return None;
         };
 
         let payload_bytes = packet.mut_payload();
+        match payload_bytes.first()? >> 4 {
+            4 => self.try_handle_peer_packet_v4(payload_bytes, from_peer_id, is_exit_node)?,
+            6 => self.try_handle_peer_packet_v6(payload_bytes, from_peer_id, is_exit_node)?,
+            _ => return None,
+        }
+
+        // the local stack sizes its segments by the syn, they must fit the path back
+        if is_tcp_syn(payload_bytes) {
+            let policy =
+                PeerManager::get_next_hop_policy(self.global_ctx.get_flags().latency_first);
+            let mtu = self
+                .peer_manager
+                .get_peer_map()
+                .get_path_mtu(from_peer_id, policy)
+                .await;
+            clamp_tcp_mss(payload_bytes, mtu);
+        }
+        Some(())
+    }
+
+    // returns none if the packet belongs to no proxied connection
+    fn check_nat_entry(
+        &self,
+        tcp_packet: &TcpPacket,
+        src_peer_id: PeerId,
+        src: SocketAddr,
+        dst: SocketAddr,
+        real_dst: impl FnOnce() -> SocketAddr,
+    ) -> Option<()> {
+        let is_tcp_syn = tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::SYN != 0;
+        let is_tcp_ack = tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::ACK != 0;
+        let nat_entry = if is_tcp_syn && !is_tcp_ack {
+            let nat_entry = Arc::new(NatDstEntry::new(src_peer_id, src, dst, real_dst()));
+            let old_val = self.syn_map.insert(src, nat_entry.clone());
+            tracing::info!(src = ?src, dst = ?dst, old_entry = ?old_val, "tcp syn received");
+            nat_entry
+        } else {
+            // if not in syn map and addr conn map, may forwarding n2n packet
+            self.find_nat_entry(&src)?
+        };
+        nat_entry
+            .tx_bytes
+            .fetch_add(tcp_packet.payload().len() as u64, Ordering::Relaxed);
+        Some(())
+    }
+
+    fn try_handle_peer_packet_v4(
+        &self,
+        payload_bytes: &mut [u8],
+        src_peer_id: PeerId,
+        is_exit_node: bool,
+    ) -> Option<()> {
+        let ipv4_addr = self.get_local_ip()?;
 
         let ipv4 = Ipv4Packet::new(payload_bytes)?;
-        if ipv4.get_version() != 4 || ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+        if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
             return None;
         }
 
@@ -631,25 +959,19 @@ impl TcpProxy {
         let ip_packet = Ipv4Packet::new(payload_bytes).unwrap();
         let tcp_packet = TcpPacket::new(ip_packet.payload()).unwrap();
 
-        let source_ip = ip_packet.get_source();
-        let source_port = tcp_packet.get_source();
-        let src = SocketAddr::V4(SocketAddrV4::new(source_ip, source_port));
-
-        let is_tcp_syn = tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::SYN != 0;
-        let is_tcp_ack = tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::ACK != 0;
-        if is_tcp_syn && !is_tcp_ack {
-            let dest_ip = ip_packet.get_destination();
-            let dest_port = tcp_packet.get_destination();
-            let dst = SocketAddr::V4(SocketAddrV4::new(dest_ip, dest_port));
-
-            let old_val = self
-                .syn_map
-                .insert(src, Arc::new(NatDstEntry::new(src, dst)));
-            tracing::info!(src = ?src, dst = ?dst, old_entry = ?old_val, "tcp syn received");
-        } else if !self.addr_conn_map.contains_key(&src) && !self.syn_map.contains_key(&src) {
-            // if not in syn map and addr conn map, may forwarding n2n packet
-            return None;
-        }
+        let src = SocketAddr::V4(SocketAddrV4::new(
+            ip_packet.get_source(),
+            tcp_packet.get_source(),
+        ));
+        let dest_ip = ip_packet.get_destination();
+        let dest_port = tcp_packet.get_destination();
+        let dst = SocketAddr::V4(SocketAddrV4::new(dest_ip, dest_port));
+        self.check_nat_entry(&tcp_packet, src_peer_id, src, dst, || {
+            SocketAddr::V4(SocketAddrV4::new(
+                self.cidr_set.map_to_real_v4(dest_ip),
+                dest_port,
+            ))
+        })?;
 
         let mut ip_packet = MutableIpv4Packet::new(payload_bytes).unwrap();
         ip_packet.set_destination(ipv4_addr);
@@ -662,7 +984,60 @@ impl TcpProxy {
         drop(tcp_packet);
         Self::update_ip_packet_checksum(&mut ip_packet);
 
-        tracing::trace!(?source, ?ipv4_addr, ?packet, "tcp packet after modified");
+        tracing::trace!(?source, ?ipv4_addr, "tcp packet after modified");
+
+        Some(())
+    }
+
+    fn try_handle_peer_packet_v6(
+        &self,
+        payload_bytes: &mut [u8],
+        src_peer_id: PeerId,
+        is_exit_node: bool,
+    ) -> Option<()> {
+        let ipv6_addr = self.get_local_ipv6()?;
+
+        let ipv6 = Ipv6Packet::new(payload_bytes)?;
+        if ipv6.get_next_header() != IpNextHeaderProtocols::Tcp {
+            return None;
+        }
+
+        let dest_ip = ipv6.get_destination();
+        let is_local = self.global_ctx.no_tun() && Some(dest_ip) == self.global_ctx.get_ipv6();
+        if !self.cidr_set.contains(dest_ip.into()) && !is_exit_node && !is_local {
+            return None;
+        }
+
+        tracing::trace!(ipv6 = ?ipv6, cidr_set = ?self.cidr_set, "proxy tcp v6 packet received");
+
+        let tcp_packet = TcpPacket::new(ipv6.payload())?;
+        let src = SocketAddr::V6(SocketAddrV6::new(
+            ipv6.get_source(),
+            tcp_packet.get_source(),
+            0,
+            0,
+        ));
+        let dst = SocketAddr::V6(SocketAddrV6::new(
+            dest_ip,
+            tcp_packet.get_destination(),
+            0,
+            0,
+        ));
+        self.check_nat_entry(&tcp_packet, src_peer_id, src, dst, || dst)?;
+
+        let mut ip_packet = MutableIpv6Packet::new(payload_bytes).unwrap();
+        ip_packet.set_destination(ipv6_addr);
+        let source = ip_packet.get_source();
+
+        let mut tcp_packet = MutableTcpPacket::new(ip_packet.payload_mut()).unwrap();
+        tcp_packet.set_destination(self.get_local_port());
+        tcp_packet.set_checksum(ipv6_checksum(
+            &tcp_packet.to_immutable(),
+            &source,
+            &ipv6_addr,
+        ));
+
+        tracing::trace!(?source, ?ipv6_addr, "tcp v6 packet after modified");
 
         Some(())
     }
@@ -188,6 +188,36 @@ impl Net {
         iface.any_ip()
     }
 
+    /// Add another address to the interface, e.g. an ipv6 address besides the ipv4 one.
+    pub fn add_ip_addr(&self, ip_addr: IpCidr) -> io::Result<()> {
+        let iface = self.reactor.iface().clone();
+        let mut iface = iface.lock();
+        let mut ret = Ok(());
+        iface.update_ip_addrs(|ip_addrs| {
+            if ip_addrs.push(ip_addr).is_err() {
+                ret = Err(io::Error::new(
+                    io::ErrorKind::OutOfMemory,
+                    "too many ip addresses",
+                ));
+            }
+        });
+        ret
+    }
+
+    /// Change the prefix length of the primary address, sockets are kept.
+    pub fn set_prefix_len(&self, prefix_len: u8) {
+        let iface = self.reactor.iface().clone();
+        let mut iface = iface.lock();
+        let address = self.ip_addr.address();
+        iface.update_ip_addrs(|ip_addrs| {
+            for ip_addr in ip_addrs.iter_mut() {
+                if ip_addr.address() == address {
+                    *ip_addr = IpCidr::new(address, prefix_len);
+                }
+            }
+        });
+    }
+
     pub fn routes<F: FnOnce(&Routes)>(&self, f: F) {
         let iface = self.reactor.iface().clone();
         let iface = iface.lock();
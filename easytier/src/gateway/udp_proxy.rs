@@ -1,6 +1,9 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    sync::{atomic::AtomicBool, Arc},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
@@ -9,6 +12,7 @@ use dashmap::DashMap;
 use pnet::packet::{
     ip::IpNextHeaderProtocols,
     ipv4::Ipv4Packet,
+    ipv6::Ipv6Packet,
     udp::{self, MutableUdpPacket},
     Packet,
 };
@@ -26,8 +30,9 @@ use tracing::Level;
 
 use crate::{
     common::{error::Error, global_ctx::ArcGlobalCtx, PeerId},
-    gateway::ip_reassembler::compose_ipv4_packet,
+    gateway::ip_reassembler::{compose_ipv4_packet, compose_udp_packets_v6},
     peers::{peer_manager::PeerManager, PeerPacketFilter},
+    rpc::ProxyFlow,
     tunnel::{
         common::setup_sokcet2,
         packet_def::{PacketType, ZCPacket},
@@ -43,41 +48,77 @@ struct UdpNatKey {
 
 #[derive(Debug)]
 struct UdpNatEntry {
+    id: uuid::Uuid,
     src_peer_id: PeerId,
     my_peer_id: PeerId,
     src_socket: SocketAddr,
     socket: UdpSocket,
+    // real dst ip to the mapped one the peer sent to, for rewriting reply sources
+    mapped_dst_ips: DashMap<Ipv4Addr, Ipv4Addr>,
     forward_task: Mutex<Option<JoinHandle<()>>>,
     stopped: AtomicBool,
     start_time: std::time::Instant,
     last_active_time: AtomicCell<std::time::Instant>,
+    // one socket serves all dsts of the src, only the last one is kept for display
+    last_dst: AtomicCell<Option<SocketAddr>>,
+    tx_bytes: AtomicU64,
+    rx_bytes: AtomicU64,
 }
 
 impl UdpNatEntry {
     #[tracing::instrument(err(level = Level::WARN))]
     fn new(src_peer_id: PeerId, my_peer_id: PeerId, src_socket: SocketAddr) -> Result<Self, Error> {
         // TODO: try use src port, so we will be ip restricted nat type
-        let socket2_socket = socket2::Socket::new(
-            socket2::Domain::IPV4,
-            socket2::Type::DGRAM,
-            Some(socket2::Protocol::UDP),
-        )?;
-        let dst_socket_addr = "0.0.0.0:0".parse().unwrap();
+        let (domain, dst_socket_addr) = match src_socket {
+            SocketAddr::V4(_) => (socket2::Domain::IPV4, "0.0.0.0:0".parse().unwrap()),
+            SocketAddr::V6(_) => (socket2::Domain::IPV6, "[::]:0".parse().unwrap()),
+        };
+        let socket2_socket =
+            socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
         setup_sokcet2(&socket2_socket, &dst_socket_addr)?;
         let socket = UdpSocket::from_std(socket2_socket.into())?;
 
         Ok(Self {
+            id: uuid::Uuid::new_v4(),
             src_peer_id,
             my_peer_id,
             src_socket,
             socket,
+            mapped_dst_ips: DashMap::new(),
             forward_task: Mutex::new(None),
             stopped: AtomicBool::new(false),
             start_time: std::time::Instant::now(),
             last_active_time: AtomicCell::new(std::time::Instant::now()),
+            last_dst: AtomicCell::new(None),
+            tx_bytes: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
         })
     }
 
+    fn to_proxy_flow(&self) -> ProxyFlow {
+        let stopped = self.stopped.load(Ordering::Relaxed);
+        ProxyFlow {
+            id: self.id.to_string(),
+            protocol: "udp".to_owned(),
+            src_peer_id: self.src_peer_id,
+            src_addr: self.src_socket.to_string(),
+            dst_addr: self
+                .last_dst
+                .load()
+                .map(|dst| dst.to_string())
+                .unwrap_or_default(),
+            state: match (stopped, self.is_active()) {
+                (true, _) => "closed",
+                (false, true) => "active",
+                (false, false) => "idle",
+            }
+            .to_owned(),
+            age_ms: self.start_time.elapsed().as_millis() as u64,
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+        }
+    }
+
     pub fn stop(&self) {
         self.stopped
             .store(true, std::sync::atomic::Ordering::Relaxed);
@@ -85,7 +126,7 @@ impl UdpNatEntry {
 
     async fn compose_ipv4_packet(
         self: &Arc<Self>,
-        packet_sender: &mut UnboundedSender<ZCPacket>,
+        packet_sender: &UnboundedSender<ZCPacket>,
         buf: &mut [u8],
         src_v4: &SocketAddrV4,
         payload_len: usize,
@@ -117,26 +158,32 @@ impl UdpNatEntry {
             payload_len + 8, // include udp header
             payload_mtu,
             ip_id,
-            |buf| {
-                let mut p = ZCPacket::new_with_payload(buf);
-                p.fill_peer_manager_hdr(self.my_peer_id, self.src_peer_id, PacketType::Data as u8);
-                p.mut_peer_manager_header().unwrap().set_no_proxy(true);
-
-                if let Err(e) = packet_sender.send(p) {
-                    tracing::error!("send icmp packet to peer failed: {:?}, may exiting..", e);
-                    return Err(Error::AnyhowError(e.into()));
-                }
-                Ok(())
-            },
+            |buf| self.send_response_packet(packet_sender, buf),
         )?;
 
         Ok(())
     }
 
+    fn send_response_packet(
+        &self,
+        packet_sender: &UnboundedSender<ZCPacket>,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let mut p = ZCPacket::new_with_payload(buf);
+        p.fill_peer_manager_hdr(self.my_peer_id, self.src_peer_id, PacketType::Data as u8);
+        p.mut_peer_manager_header().unwrap().set_no_proxy(true);
+
+        if let Err(e) = packet_sender.send(p) {
+            tracing::error!("send udp packet to peer failed: {:?}, may exiting..", e);
+            return Err(Error::AnyhowError(e.into()));
+        }
+        Ok(())
+    }
+
     async fn forward_task(
         self: Arc<Self>,
-        mut packet_sender: UnboundedSender<ZCPacket>,
-        virtual_ipv4: Ipv4Addr,
+        packet_sender: UnboundedSender<ZCPacket>,
+        virtual_ip: Option<IpAddr>,
     ) {
         let mut buf = [0u8; 65536];
         let mut udp_body: &mut [u8] = unsafe { std::mem::transmute(&mut buf[20 + 8..]) };
@@ -161,34 +208,53 @@ impl UdpNatEntry {
             };
 
             tracing::trace!(?len, ?src_socket, "udp nat packet response received");
+            self.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
 
             if self.stopped.load(std::sync::atomic::Ordering::Relaxed) {
                 break;
             }
 
-            let SocketAddr::V4(mut src_v4) = src_socket else {
-                continue;
-            };
+            let ret = match (src_socket, self.src_socket) {
+                (SocketAddr::V4(mut src_v4), SocketAddr::V4(_)) => {
+                    self.mark_active();
 
-            self.mark_active();
+                    if src_v4.ip().is_loopback() {
+                        if let Some(IpAddr::V4(virtual_ipv4)) = virtual_ip {
+                            src_v4.set_ip(virtual_ipv4);
+                        }
+                    } else if let Some(mapped_ip) = self.mapped_dst_ips.get(src_v4.ip()) {
+                        src_v4.set_ip(*mapped_ip);
+                    }
 
-            if src_v4.ip().is_loopback() {
-                src_v4.set_ip(virtual_ipv4);
-            }
+                    Self::compose_ipv4_packet(
+                        &self,
+                        &packet_sender,
+                        &mut buf,
+                        &src_v4,
+                        len,
+                        1200,
+                        ip_id,
+                    )
+                    .await
+                }
+                (SocketAddr::V6(mut src_v6), SocketAddr::V6(nat_src_v6)) => {
+                    self.mark_active();
 
-            let Ok(_) = Self::compose_ipv4_packet(
-                &self,
-                &mut packet_sender,
-                &mut buf,
-                &src_v4,
-                len,
-                1200,
-                ip_id,
-            )
-            .await
-            else {
-                break;
+                    if src_v6.ip().is_loopback() {
+                        if let Some(IpAddr::V6(virtual_ipv6)) = virtual_ip {
+                            src_v6.set_ip(virtual_ipv6);
+                        }
+                    }
+
+                    compose_udp_packets_v6(src_v6, nat_src_v6, &buf[28..28 + len], ip_id as u32)
+                        .iter()
+                        .try_for_each(|p| self.send_response_packet(&packet_sender, p))
+                }
+                _ => continue,
             };
+            if ret.is_err() {
+                break;
+            }
             ip_id = ip_id.wrapping_add(1);
         }
 
@@ -230,22 +296,30 @@ impl UdpProxy {
             return None;
         }
 
-        let _ = self.global_ctx.get_ipv4()?;
         let hdr = packet.peer_manager_header().unwrap();
-        let is_exit_node = hdr.is_exit_node();
         if hdr.packet_type != PacketType::Data as u8 || hdr.is_no_proxy() {
             return None;
         };
 
+        match packet.payload().first()? >> 4 {
+            4 => self.try_handle_packet_v4(packet).await,
+            6 => self.try_handle_packet_v6(packet).await,
+            _ => None,
+        }
+    }
+
+    async fn try_handle_packet_v4(&self, packet: &ZCPacket) -> Option<()> {
+        let virtual_ipv4 = self.global_ctx.get_ipv4()?;
+        let is_exit_node = packet.peer_manager_header().unwrap().is_exit_node();
+
         let ipv4 = Ipv4Packet::new(packet.payload())?;
-        if ipv4.get_version() != 4 || ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
             return None;
         }
 
         if !self.cidr_set.contains_v4(ipv4.get_destination())
             && !is_exit_node
-            && !(self.global_ctx.no_tun()
-                && Some(ipv4.get_destination()) == self.global_ctx.get_ipv4())
+            && !(self.global_ctx.no_tun() && ipv4.get_destination() == virtual_ipv4)
         {
             return None;
         }
@@ -270,14 +344,88 @@ impl UdpProxy {
             "udp nat packet request received"
         );
 
-        let nat_key = UdpNatKey {
-            src_socket: SocketAddr::new(ipv4.get_source().into(), udp_packet.get_source()),
+        let src_socket = SocketAddr::new(ipv4.get_source().into(), udp_packet.get_source());
+        let nat_entry = self
+            .get_nat_entry(packet, src_socket, Some(virtual_ipv4.into()))
+            .await?;
+        nat_entry.last_dst.store(Some(SocketAddr::new(
+            ipv4.get_destination().into(),
+            udp_packet.get_destination(),
+        )));
+
+        // TODO: should it be async.
+        let dst_socket = if ipv4.get_destination() == virtual_ipv4 {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), udp_packet.get_destination())
+        } else {
+            let real_dst_ip = self.cidr_set.map_to_real_v4(ipv4.get_destination());
+            if real_dst_ip != ipv4.get_destination() {
+                nat_entry
+                    .mapped_dst_ips
+                    .insert(real_dst_ip, ipv4.get_destination());
+            }
+            SocketAddr::new(real_dst_ip.into(), udp_packet.get_destination())
         };
+
+        self.send_to_dst(&nat_entry, udp_packet.payload(), dst_socket)
+            .await;
+        Some(())
+    }
+
+    // fragmented v6 datagrams carry a fragment header and are left to the nic
+    async fn try_handle_packet_v6(&self, packet: &ZCPacket) -> Option<()> {
+        let virtual_ipv6 = self.global_ctx.get_ipv6();
+        let is_exit_node = packet.peer_manager_header().unwrap().is_exit_node();
+
+        let ipv6 = Ipv6Packet::new(packet.payload())?;
+        if ipv6.get_next_header() != IpNextHeaderProtocols::Udp {
+            return None;
+        }
+
+        let dst_ip = ipv6.get_destination();
+        let is_local = self.global_ctx.no_tun() && Some(dst_ip) == virtual_ipv6;
+        if !self.cidr_set.contains(dst_ip.into()) && !is_exit_node && !is_local {
+            return None;
+        }
+
+        let udp_packet = udp::UdpPacket::new(ipv6.payload())?;
+        tracing::trace!(?ipv6, ?udp_packet, "udp nat v6 packet request received");
+
+        let src_socket = SocketAddr::new(ipv6.get_source().into(), udp_packet.get_source());
+        let nat_entry = self
+            .get_nat_entry(packet, src_socket, virtual_ipv6.map(Into::into))
+            .await?;
+        nat_entry.last_dst.store(Some(SocketAddr::new(
+            dst_ip.into(),
+            udp_packet.get_destination(),
+        )));
+
+        let dst_socket = if Some(dst_ip) == virtual_ipv6 {
+            SocketAddr::new(
+                std::net::Ipv6Addr::LOCALHOST.into(),
+                udp_packet.get_destination(),
+            )
+        } else {
+            SocketAddr::new(dst_ip.into(), udp_packet.get_destination())
+        };
+
+        self.send_to_dst(&nat_entry, udp_packet.payload(), dst_socket)
+            .await;
+        Some(())
+    }
+
+    async fn get_nat_entry(
+        &self,
+        packet: &ZCPacket,
+        src_socket: SocketAddr,
+        virtual_ip: Option<IpAddr>,
+    ) -> Option<Arc<UdpNatEntry>> {
+        let hdr = packet.peer_manager_header().unwrap();
+        let nat_key = UdpNatKey { src_socket };
         let nat_entry = self
             .nat_table
             .entry(nat_key)
             .or_try_insert_with::<Error>(|| {
-                tracing::info!(?packet, ?ipv4, ?udp_packet, "udp nat table entry created");
+                tracing::info!(?packet, ?src_socket, "udp nat table entry created");
                 let _g = self.global_ctx.net_ns.guard();
                 Ok(Arc::new(UdpNatEntry::new(
                     hdr.from_peer_id.get(),
@@ -296,40 +444,58 @@ impl UdpProxy {
                 .replace(tokio::spawn(UdpNatEntry::forward_task(
                     nat_entry.clone(),
                     self.sender.clone(),
-                    self.global_ctx.get_ipv4()?,
+                    virtual_ip,
                 )));
         }
 
         nat_entry.mark_active();
+        Some(nat_entry)
+    }
 
-        // TODO: should it be async.
-        let dst_socket = if Some(ipv4.get_destination()) == self.global_ctx.get_ipv4() {
-            format!("127.0.0.1:{}", udp_packet.get_destination())
-                .parse()
-                .unwrap()
-        } else {
-            SocketAddr::new(ipv4.get_destination().into(), udp_packet.get_destination())
-        };
-
+    async fn send_to_dst(&self, nat_entry: &Arc<UdpNatEntry>, payload: &[u8], dst: SocketAddr) {
         let send_ret = {
             let _g = self.global_ctx.net_ns.guard();
-            nat_entry
-                .socket
-                .send_to(udp_packet.payload(), dst_socket)
-                .await
+            nat_entry.socket.send_to(payload, dst).await
         };
 
-        if let Err(send_err) = send_ret {
-            tracing::error!(
-                ?send_err,
-                ?nat_key,
-                ?nat_entry,
-                ?send_err,
-                "udp nat send failed"
-            );
+        match send_ret {
+            Ok(len) => {
+                nat_entry.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+            }
+            Err(send_err) => {
+                tracing::error!(?send_err, ?dst, ?nat_entry, "udp nat send failed");
+            }
         }
+    }
 
-        Some(())
+    pub fn list_flows(&self) -> Vec<ProxyFlow> {
+        self.nat_table
+            .iter()
+            .map(|entry| entry.to_proxy_flow())
+            .collect()
+    }
+
+    // returns false if no flow has the id
+    pub async fn close_flow(&self, id: &uuid::Uuid) -> bool {
+        let Some(key) = self
+            .nat_table
+            .iter()
+            .find(|entry| entry.id == *id)
+            .map(|entry| *entry.key())
+        else {
+            return false;
+        };
+        let Some((_, nat_entry)) = self.nat_table.remove_if(&key, |_, entry| entry.id == *id)
+        else {
+            return false;
+        };
+        tracing::info!(?nat_entry, "close udp nat entry by request");
+
+        nat_entry.stop();
+        if let Some(task) = nat_entry.forward_task.lock().await.take() {
+            task.abort();
+        }
+        true
     }
 }
 
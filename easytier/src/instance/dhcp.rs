@@ -0,0 +1,289 @@
+// sticky dhcp address selection. a node prefers, in order, its reservation, its current address,
+// the address saved in the lease file and finally an address derived from its hostname, so it
+// keeps the same ip across restarts and peer join order. if two nodes claim the same address,
+// the one with the older lease keeps it.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::Ipv4Addr,
+    path::Path,
+};
+
+use anyhow::Context;
+use cidr::Ipv4Inet;
+use serde::{Deserialize, Serialize};
+
+use crate::common::{config::DhcpConfig, error::Error, PeerId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct DhcpLease {
+    pub ipv4: Ipv4Addr,
+    // unix time in seconds the lease was acquired, 0 for reserved addresses
+    pub lease_time: u64,
+}
+
+impl DhcpLease {
+    pub fn load(path: &Path) -> Option<Self> {
+        let content = std::fs::read_to_string(path).ok()?;
+        toml::from_str(&content).ok()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let content = toml::to_string(self).with_context(|| "serialize dhcp lease failed")?;
+        std::fs::write(path, content).with_context(|| "write dhcp lease file failed")?;
+        Ok(())
+    }
+}
+
+// a peer using an address, statically configured peers have lease_time 0
+#[derive(Debug, Clone, Copy)]
+pub struct DhcpOccupant {
+    pub peer_id: PeerId,
+    pub lease_time: u64,
+}
+
+impl DhcpOccupant {
+    // the older lease wins, ties are broken by the smaller peer id
+    fn wins_over(&self, lease_time: u64, peer_id: PeerId) -> bool {
+        (self.lease_time, self.peer_id) < (lease_time, peer_id)
+    }
+}
+
+pub fn add_dhcp_occupant(
+    used: &mut HashMap<Ipv4Addr, DhcpOccupant>,
+    ipv4: Ipv4Addr,
+    occupant: DhcpOccupant,
+) {
+    let entry = used.entry(ipv4).or_insert(occupant);
+    if occupant.wins_over(entry.lease_time, entry.peer_id) {
+        *entry = occupant;
+    }
+}
+
+pub struct DhcpAllocator {
+    network: Ipv4Inet,
+    config: DhcpConfig,
+    hostname: String,
+    inst_id: uuid::Uuid,
+    my_peer_id: PeerId,
+}
+
+impl DhcpAllocator {
+    pub fn new(
+        network: Ipv4Inet,
+        config: DhcpConfig,
+        hostname: String,
+        inst_id: uuid::Uuid,
+        my_peer_id: PeerId,
+    ) -> Self {
+        Self {
+            network,
+            config,
+            hostname,
+            inst_id,
+            my_peer_id,
+        }
+    }
+
+    fn is_mine(&self, owner: &str) -> bool {
+        owner == self.hostname || owner == self.inst_id.to_string()
+    }
+
+    fn reservation(&self) -> Option<Ipv4Addr> {
+        self.config
+            .reservations
+            .iter()
+            .flatten()
+            .find(|r| self.is_mine(&r.owner))
+            .map(|r| r.ipv4)
+    }
+
+    fn reserved_for_other(&self, ipv4: Ipv4Addr) -> bool {
+        self.config
+            .reservations
+            .iter()
+            .flatten()
+            .any(|r| r.ipv4 == ipv4 && !self.is_mine(&r.owner))
+    }
+
+    // inclusive range of addresses in the pool, as integers
+    fn pool_range(&self) -> (u32, u32) {
+        let first = u32::from(self.network.first_address()) + 1;
+        let last = u32::from(self.network.last_address()).saturating_sub(1);
+        let start = self.config.pool_start.map(u32::from).unwrap_or(first);
+        let end = self.config.pool_end.map(u32::from).unwrap_or(last);
+        (start.max(first), end.min(last))
+    }
+
+    fn usable(&self, ipv4: Ipv4Addr) -> bool {
+        let (start, end) = self.pool_range();
+        let ip = u32::from(ipv4);
+        ip >= start
+            && ip <= end
+            && !self
+                .config
+                .exclude
+                .iter()
+                .flatten()
+                .any(|cidr| cidr.contains(&ipv4))
+            && !self.reserved_for_other(ipv4)
+    }
+
+    fn can_keep(&self, lease: &DhcpLease, used: &HashMap<Ipv4Addr, DhcpOccupant>) -> bool {
+        used.get(&lease.ipv4)
+            .map(|o| !o.wins_over(lease.lease_time, self.my_peer_id))
+            .unwrap_or(true)
+    }
+
+    pub fn select(
+        &self,
+        current: Option<DhcpLease>,
+        saved: Option<DhcpLease>,
+        used: &HashMap<Ipv4Addr, DhcpOccupant>,
+        now: u64,
+    ) -> Option<DhcpLease> {
+        if let Some(ipv4) = self.reservation() {
+            let lease = DhcpLease {
+                ipv4,
+                lease_time: 0,
+            };
+            if self.can_keep(&lease, used) {
+                return Some(lease);
+            }
+        }
+
+        for lease in [current, saved].into_iter().flatten() {
+            if self.usable(lease.ipv4) && self.can_keep(&lease, used) {
+                return Some(lease);
+            }
+        }
+
+        // start probing at an address derived from the hostname
+        let (start, end) = self.pool_range();
+        if start > end {
+            return None;
+        }
+        let size = (end - start) as u64 + 1;
+        let mut hasher = DefaultHasher::new();
+        self.hostname.hash(&mut hasher);
+        let offset = hasher.finish() % size;
+
+        (0..size)
+            .map(|i| Ipv4Addr::from(start + ((offset + i) % size) as u32))
+            .find(|ip| self.usable(*ip) && !used.contains_key(ip))
+            .map(|ipv4| DhcpLease {
+                ipv4,
+                lease_time: now,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::config::DhcpReservation;
+
+    use super::*;
+
+    fn allocator(config: DhcpConfig, hostname: &str, peer_id: PeerId) -> DhcpAllocator {
+        DhcpAllocator::new(
+            "10.126.126.0/24".parse().unwrap(),
+            config,
+            hostname.to_string(),
+            uuid::Uuid::nil(),
+            peer_id,
+        )
+    }
+
+    #[test]
+    fn dhcp_select_sticky() {
+        let alloc = allocator(DhcpConfig::default(), "node-a", 10);
+        let mut used = HashMap::new();
+        let first = alloc.select(None, None, &used, 100).unwrap();
+        assert_eq!(100, first.lease_time);
+
+        // the derived address does not depend on other peers joining
+        add_dhcp_occupant(
+            &mut used,
+            Ipv4Addr::from(u32::from(first.ipv4) ^ 1),
+            DhcpOccupant {
+                peer_id: 1,
+                lease_time: 50,
+            },
+        );
+        let second = alloc.select(None, None, &used, 200).unwrap();
+        assert_eq!(first.ipv4, second.ipv4);
+
+        // the saved lease is reused and keeps its age
+        let saved = DhcpLease {
+            ipv4: "10.126.126.77".parse().unwrap(),
+            lease_time: 30,
+        };
+        assert_eq!(Some(saved), alloc.select(None, Some(saved), &used, 200));
+
+        // an older lease of a peer takes the address away
+        add_dhcp_occupant(
+            &mut used,
+            saved.ipv4,
+            DhcpOccupant {
+                peer_id: 2,
+                lease_time: 20,
+            },
+        );
+        let moved = alloc.select(Some(saved), Some(saved), &used, 200).unwrap();
+        assert_ne!(saved.ipv4, moved.ipv4);
+
+        // a younger lease does not
+        let mut used = HashMap::new();
+        add_dhcp_occupant(
+            &mut used,
+            saved.ipv4,
+            DhcpOccupant {
+                peer_id: 2,
+                lease_time: 40,
+            },
+        );
+        assert_eq!(Some(saved), alloc.select(Some(saved), None, &used, 200));
+    }
+
+    #[test]
+    fn dhcp_select_pool_and_reservation() {
+        let config = DhcpConfig {
+            pool_start: Some("10.126.126.10".parse().unwrap()),
+            pool_end: Some("10.126.126.12".parse().unwrap()),
+            exclude: Some(vec!["10.126.126.11/32".parse().unwrap()]),
+            reservations: Some(vec![
+                "node-b=10.126.126.12".parse::<DhcpReservation>().unwrap(),
+                "node-c=10.126.126.200".parse().unwrap(),
+            ]),
+            lease_file: None,
+        };
+
+        // .11 is excluded and .12 belongs to node-b
+        let alloc = allocator(config.clone(), "node-a", 10);
+        let used = HashMap::new();
+        let lease = alloc.select(None, None, &used, 100).unwrap();
+        assert_eq!("10.126.126.10".parse::<Ipv4Addr>().unwrap(), lease.ipv4);
+
+        let mut used = HashMap::new();
+        add_dhcp_occupant(
+            &mut used,
+            lease.ipv4,
+            DhcpOccupant {
+                peer_id: 1,
+                lease_time: 1,
+            },
+        );
+        assert_eq!(None, alloc.select(None, None, &used, 100));
+
+        // reservations may be outside the pool
+        let alloc = allocator(config, "node-c", 10);
+        assert_eq!(
+            Some(DhcpLease {
+                ipv4: "10.126.126.200".parse().unwrap(),
+                lease_time: 0,
+            }),
+            alloc.select(None, None, &used, 100)
+        );
+    }
+}
@@ -0,0 +1,624 @@
+// overlay dns, answers <hostname>.<network name>.<suffix> with the virtual ips we already learn
+// from route sync and forwards every other query to the upstream nameservers.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpSocket, UdpSocket},
+    task::JoinSet,
+    time::timeout,
+};
+
+use crate::{
+    common::{config::DnsConfig, error::Error, global_ctx::ArcGlobalCtx, scoped_task::ScopedTask},
+    peers::peer_manager::PeerManager,
+};
+
+const DEFAULT_DNS_SUFFIX: &str = "et";
+const DNS_TTL_SEC: u32 = 60;
+const UPSTREAM_TIMEOUT_SEC: u64 = 5;
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_TYPE_ANY: u16 = 255;
+const DNS_FLAG_TC: u16 = 0x0200;
+const DNS_RCODE_SERVFAIL: u8 = 2;
+const DNS_RCODE_NXDOMAIN: u8 = 3;
+
+// hostnames may contain anything, labels only letters, digits and hyphens
+pub fn dns_label(s: &str) -> String {
+    s.trim()
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .trim_matches('-')
+        .to_string()
+}
+
+pub fn overlay_domain(global_ctx: &ArcGlobalCtx, config: &DnsConfig) -> String {
+    // the suffix ends up in resolver configuration, keep only valid labels
+    let suffix = config
+        .suffix
+        .as_deref()
+        .unwrap_or(DEFAULT_DNS_SUFFIX)
+        .split('.')
+        .map(dns_label)
+        .filter(|label| !label.is_empty())
+        .collect::<Vec<_>>();
+    format!(
+        "{}.{}",
+        dns_label(&global_ctx.get_network_identity().network_name),
+        if suffix.is_empty() {
+            DEFAULT_DNS_SUFFIX.to_string()
+        } else {
+            suffix.join(".")
+        }
+    )
+}
+
+pub fn dns_listen_addr(global_ctx: &ArcGlobalCtx, config: &DnsConfig) -> Option<SocketAddr> {
+    config.listen.or_else(|| {
+        global_ctx
+            .get_ipv4()
+            .map(|ip| SocketAddr::new(ip.into(), 53))
+    })
+}
+
+fn parse_resolv_conf(content: &str) -> Vec<SocketAddr> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            if parts.next()? != "nameserver" {
+                return None;
+            }
+            let ip: IpAddr = parts.next()?.parse().ok()?;
+            Some(SocketAddr::new(ip, 53))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct DnsQuery<'a> {
+    id: u16,
+    flags: u16,
+    // lowercase, without the trailing dot
+    name: String,
+    qtype: u16,
+    // raw question section, echoed back in the response
+    question: &'a [u8],
+}
+
+fn parse_query(buf: &[u8]) -> Option<DnsQuery<'_>> {
+    if buf.len() < 12 {
+        return None;
+    }
+    let id = u16::from_be_bytes([buf[0], buf[1]]);
+    let flags = u16::from_be_bytes([buf[2], buf[3]]);
+    let qdcount = u16::from_be_bytes([buf[4], buf[5]]);
+    // only standard queries with a single question
+    if flags & 0x8000 != 0 || (flags >> 11) & 0xf != 0 || qdcount != 1 {
+        return None;
+    }
+
+    let mut labels = vec![];
+    let mut pos = 12;
+    loop {
+        let len = *buf.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // no compression pointers in a question that comes first
+        if len > 63 {
+            return None;
+        }
+        labels.push(std::str::from_utf8(buf.get(pos..pos + len)?).ok()?);
+        pos += len;
+    }
+    let qtype = u16::from_be_bytes([*buf.get(pos)?, *buf.get(pos + 1)?]);
+    let question = buf.get(12..pos + 4)?;
+
+    Some(DnsQuery {
+        id,
+        flags,
+        name: labels.join(".").to_ascii_lowercase(),
+        qtype,
+        question,
+    })
+}
+
+fn build_response(query: &DnsQuery, rcode: u8, answers: &[IpAddr]) -> Vec<u8> {
+    // qr, aa and ra set, opcode and rd copied from the query
+    let flags = 0x8000 | 0x0400 | 0x0080 | (query.flags & 0x0100) | rcode as u16;
+    let mut ret = Vec::with_capacity(12 + query.question.len() + 28 * answers.len());
+    ret.extend_from_slice(&query.id.to_be_bytes());
+    ret.extend_from_slice(&flags.to_be_bytes());
+    ret.extend_from_slice(&1u16.to_be_bytes());
+    ret.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    ret.extend_from_slice(&[0, 0, 0, 0]);
+    ret.extend_from_slice(query.question);
+    for ip in answers {
+        // name is a pointer to the question
+        ret.extend_from_slice(&[0xc0, 0x0c]);
+        match ip {
+            IpAddr::V4(_) => ret.extend_from_slice(&DNS_TYPE_A.to_be_bytes()),
+            IpAddr::V6(_) => ret.extend_from_slice(&DNS_TYPE_AAAA.to_be_bytes()),
+        }
+        ret.extend_from_slice(&1u16.to_be_bytes());
+        ret.extend_from_slice(&DNS_TTL_SEC.to_be_bytes());
+        match ip {
+            IpAddr::V4(ip) => {
+                ret.extend_from_slice(&4u16.to_be_bytes());
+                ret.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                ret.extend_from_slice(&16u16.to_be_bytes());
+                ret.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+    ret
+}
+
+fn is_truncated(resp: &[u8]) -> bool {
+    resp.len() >= 4 && u16::from_be_bytes([resp[2], resp[3]]) & DNS_FLAG_TC != 0
+}
+
+struct DnsHandler {
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Arc<PeerManager>,
+    config: DnsConfig,
+    domain: String,
+}
+
+impl DnsHandler {
+    // the virtual ipv4 and overlay ipv6 of the host, empty if unknown
+    async fn lookup(&self, host: &str) -> Vec<IpAddr> {
+        if host.is_empty() {
+            return vec![];
+        }
+        if dns_label(&self.global_ctx.get_hostname()) == host {
+            let ret = self
+                .global_ctx
+                .get_ipv4()
+                .map(IpAddr::from)
+                .into_iter()
+                .chain(self.global_ctx.get_ipv6().map(IpAddr::from))
+                .collect::<Vec<_>>();
+            if !ret.is_empty() {
+                return ret;
+            }
+        }
+        self.peer_mgr
+            .list_routes()
+            .await
+            .iter()
+            .filter(|route| dns_label(&route.hostname) == host)
+            .map(|route| {
+                [&route.ipv4_addr, &route.ipv6_addr]
+                    .into_iter()
+                    .filter_map(|ip| ip.parse().ok())
+                    .collect::<Vec<_>>()
+            })
+            .find(|ips| !ips.is_empty())
+            .unwrap_or_default()
+    }
+
+    fn upstreams(&self, local: SocketAddr) -> Vec<SocketAddr> {
+        let upstreams = match self.config.upstream.as_ref() {
+            Some(upstreams) => upstreams.clone(),
+            None => {
+                parse_resolv_conf(&std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default())
+            }
+        };
+        // never forward to ourselves
+        upstreams
+            .into_iter()
+            .filter(|addr| *addr != local && addr.ip() != local.ip())
+            .collect()
+    }
+
+    async fn forward(&self, buf: &[u8], local: SocketAddr) -> Option<Vec<u8>> {
+        for upstream in self.upstreams(local) {
+            let bind_addr: SocketAddr = match upstream {
+                SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+                SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+            };
+            let socket = {
+                let _g = self.global_ctx.net_ns.guard();
+                UdpSocket::bind(bind_addr).await
+            };
+            let Ok(socket) = socket else {
+                continue;
+            };
+            if socket.send_to(buf, upstream).await.is_err() {
+                continue;
+            }
+            let mut resp = vec![0u8; 4096];
+            match timeout(
+                Duration::from_secs(UPSTREAM_TIMEOUT_SEC),
+                socket.recv_from(&mut resp),
+            )
+            .await
+            {
+                Ok(Ok((len, addr))) if addr == upstream => {
+                    resp.truncate(len);
+                    // the answer does not fit in a datagram, ask again over tcp
+                    if is_truncated(&resp) {
+                        match self.forward_tcp(buf, upstream).await {
+                            Ok(tcp_resp) => return Some(tcp_resp),
+                            Err(e) => tracing::debug!(?upstream, ?e, "dns upstream tcp failed"),
+                        }
+                    }
+                    return Some(resp);
+                }
+                ret => {
+                    tracing::debug!(?upstream, ?ret, "dns upstream failed");
+                }
+            }
+        }
+        None
+    }
+
+    async fn forward_tcp(&self, buf: &[u8], upstream: SocketAddr) -> Result<Vec<u8>, Error> {
+        let socket = {
+            let _g = self.global_ctx.net_ns.guard();
+            match upstream {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            }
+        };
+        let duration = Duration::from_secs(UPSTREAM_TIMEOUT_SEC);
+        timeout(duration, async {
+            let mut stream = socket.connect(upstream).await?;
+            // messages over tcp are prefixed with their length
+            let mut req = (buf.len() as u16).to_be_bytes().to_vec();
+            req.extend_from_slice(buf);
+            stream.write_all(&req).await?;
+            let len = stream.read_u16().await? as usize;
+            let mut resp = vec![0u8; len];
+            stream.read_exact(&mut resp).await?;
+            Ok(resp)
+        })
+        .await?
+    }
+
+    async fn handle(&self, buf: &[u8], local: SocketAddr) -> Option<Vec<u8>> {
+        let query = parse_query(buf)?;
+        let host = if query.name == self.domain {
+            ""
+        } else if let Some(host) = query
+            .name
+            .strip_suffix(&self.domain)
+            .and_then(|h| h.strip_suffix('.'))
+        {
+            host
+        } else {
+            return Some(match self.forward(buf, local).await {
+                Some(resp) => resp,
+                None => build_response(&query, DNS_RCODE_SERVFAIL, &[]),
+            });
+        };
+
+        let ips = self.lookup(host).await;
+        if ips.is_empty() {
+            return Some(build_response(&query, DNS_RCODE_NXDOMAIN, &[]));
+        }
+        // the name exists, other record types just have no data
+        let answers = ips
+            .into_iter()
+            .filter(|ip| match query.qtype {
+                DNS_TYPE_A => ip.is_ipv4(),
+                DNS_TYPE_AAAA => ip.is_ipv6(),
+                DNS_TYPE_ANY => true,
+                _ => false,
+            })
+            .collect::<Vec<_>>();
+        Some(build_response(&query, 0, &answers))
+    }
+}
+
+async fn serve(socket: Arc<UdpSocket>, handler: Arc<DnsHandler>) {
+    let local = socket.local_addr().unwrap();
+    let mut tasks = JoinSet::new();
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buf).await {
+            Ok(ret) => ret,
+            Err(e) => {
+                tracing::warn!(?e, "dns server recv failed");
+                continue;
+            }
+        };
+        let query = buf[..len].to_vec();
+        let socket = socket.clone();
+        let handler = handler.clone();
+        tasks.spawn(async move {
+            if let Some(resp) = handler.handle(&query, local).await {
+                let _ = socket.send_to(&resp, addr).await;
+            }
+        });
+        while tasks.try_join_next().is_some() {}
+    }
+}
+
+pub struct DnsServer {
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Arc<PeerManager>,
+    tasks: JoinSet<()>,
+}
+
+impl DnsServer {
+    pub fn new(global_ctx: ArcGlobalCtx, peer_mgr: Arc<PeerManager>) -> Self {
+        Self {
+            global_ctx,
+            peer_mgr,
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<(), Error> {
+        let Some(config) = self.global_ctx.config.get_dns_config() else {
+            return Ok(());
+        };
+
+        let handler = Arc::new(DnsHandler {
+            domain: overlay_domain(&self.global_ctx, &config),
+            global_ctx: self.global_ctx.clone(),
+            peer_mgr: self.peer_mgr.clone(),
+            config,
+        });
+        // the listen addr follows the virtual ip, and binding may fail until the tun device is up
+        self.tasks.spawn(async move {
+            let mut cur: Option<(SocketAddr, ScopedTask<()>)> = None;
+            loop {
+                let listen = dns_listen_addr(&handler.global_ctx, &handler.config);
+                if cur.as_ref().map(|(addr, _)| *addr) != listen {
+                    cur = None;
+                    if let Some(listen) = listen {
+                        let socket = {
+                            let _g = handler.global_ctx.net_ns.guard();
+                            UdpSocket::bind(listen).await
+                        };
+                        match socket {
+                            Ok(socket) => {
+                                tracing::info!(?listen, domain = ?handler.domain, "dns server started");
+                                let task = tokio::spawn(serve(Arc::new(socket), handler.clone()));
+                                cur = Some((listen, task.into()));
+                            }
+                            Err(e) => {
+                                tracing::debug!(?listen, ?e, "dns server bind failed, retry later");
+                            }
+                        }
+                    }
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use tokio::sync::mpsc;
+
+    use crate::{
+        common::{
+            config::{ConfigLoader, TomlConfigLoader},
+            global_ctx::GlobalCtx,
+        },
+        peers::{
+            peer_manager::RouteAlgoType,
+            tests::{connect_peer_manager, wait_route_appear},
+        },
+    };
+
+    use super::*;
+
+    fn build_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut ret = vec![];
+        ret.extend_from_slice(&id.to_be_bytes());
+        ret.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            ret.push(label.len() as u8);
+            ret.extend_from_slice(label.as_bytes());
+        }
+        ret.push(0);
+        ret.extend_from_slice(&qtype.to_be_bytes());
+        ret.extend_from_slice(&1u16.to_be_bytes());
+        ret
+    }
+
+    #[tokio::test]
+    async fn dns_message_test() {
+        assert_eq!("my-pc", dns_label("My_PC "));
+        assert_eq!("node-1", dns_label("-node.1-"));
+
+        assert_eq!(
+            vec!["127.0.0.53:53".parse::<SocketAddr>().unwrap()],
+            parse_resolv_conf("# generated\nnameserver 127.0.0.53\noptions edns0\n")
+        );
+
+        let buf = build_query(0x1234, "Node-B.Net.et", DNS_TYPE_A);
+        let query = parse_query(&buf).unwrap();
+        assert_eq!(0x1234, query.id);
+        assert_eq!("node-b.net.et", query.name);
+        assert_eq!(DNS_TYPE_A, query.qtype);
+
+        let resp = build_response(&query, 0, &["10.144.144.2".parse().unwrap()]);
+        assert_eq!([0x12, 0x34, 0x85, 0x80, 0, 1, 0, 1, 0, 0, 0, 0], resp[..12]);
+        assert_eq!(buf[12..], resp[12..buf.len()]);
+        assert_eq!([10, 144, 144, 2], resp[resp.len() - 4..]);
+        assert!(!is_truncated(&resp));
+
+        let ipv6: std::net::Ipv6Addr = "fd00::2".parse().unwrap();
+        let resp = build_response(&query, 0, &[ipv6.into()]);
+        assert_eq!(
+            DNS_TYPE_AAAA.to_be_bytes(),
+            resp[buf.len() + 2..buf.len() + 4]
+        );
+        assert_eq!(ipv6.octets(), resp[resp.len() - 16..]);
+
+        let resp = build_response(&query, DNS_RCODE_NXDOMAIN, &[]);
+        assert_eq!([0x85, 0x83, 0, 1, 0, 0], resp[2..8]);
+        assert_eq!(buf.len(), resp.len());
+
+        assert!(parse_query(&buf[..buf.len() - 1]).is_none());
+
+        // the suffix cannot smuggle anything into the resolver configuration
+        let global_ctx = crate::common::global_ctx::tests::get_mock_global_ctx();
+        let config = DnsConfig {
+            listen: None,
+            suffix: Some("Lab.;rm -rf /.".to_string()),
+            upstream: None,
+            register_resolved: true,
+        };
+        assert!(overlay_domain(&global_ctx, &config).ends_with(".lab.rm--rf"));
+    }
+
+    async fn create_peer_manager(hostname: &str, ipv4: Ipv4Addr) -> Arc<PeerManager> {
+        let config = TomlConfigLoader::default();
+        config.set_inst_name(format!("test_{}", config.get_id()));
+        config.set_hostname(Some(hostname.to_string()));
+        config.set_ipv4(Some(ipv4));
+        let mut flags = config.get_flags();
+        flags.no_tun = true;
+        config.set_flags(flags);
+
+        let (s, _r) = mpsc::channel(1000);
+        let peer_mgr = Arc::new(PeerManager::new(
+            RouteAlgoType::Ospf,
+            Arc::new(GlobalCtx::new(config)),
+            s,
+        ));
+        peer_mgr.run().await.unwrap();
+        peer_mgr
+    }
+
+    #[tokio::test]
+    async fn dns_server_resolves_peer_hostname() {
+        let peer_mgr_a = create_peer_manager("node-a", "10.144.146.1".parse().unwrap()).await;
+        let peer_mgr_b = create_peer_manager("Node B", "10.144.146.2".parse().unwrap()).await;
+        let ipv6_b: std::net::Ipv6Addr = "fd00::146:2".parse().unwrap();
+        peer_mgr_b.get_global_ctx().set_ipv6(Some(ipv6_b));
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+
+        // an upstream that answers everything with 1.2.3.4
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, addr) = upstream.recv_from(&mut buf).await.unwrap();
+                let query = parse_query(&buf[..len]).unwrap();
+                let mut resp = build_response(&query, 0, &["1.2.3.4".parse().unwrap()]);
+                if query.name == "big.example.com" {
+                    resp[2] |= (DNS_FLAG_TC >> 8) as u8;
+                }
+                upstream.send_to(&resp, addr).await.unwrap();
+            }
+        });
+        // and the full answers over tcp
+        let upstream_tcp = tokio::net::TcpListener::bind(upstream_addr).await.unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream_tcp.accept().await.unwrap();
+                let len = stream.read_u16().await.unwrap() as usize;
+                let mut buf = vec![0u8; len];
+                stream.read_exact(&mut buf).await.unwrap();
+                let query = parse_query(&buf).unwrap();
+                let resp = build_response(&query, 0, &["5.6.7.8".parse().unwrap()]);
+                stream.write_u16(resp.len() as u16).await.unwrap();
+                stream.write_all(&resp).await.unwrap();
+            }
+        });
+
+        let listen = std::net::UdpSocket::bind("127.0.0.2:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let global_ctx = peer_mgr_a.get_global_ctx();
+        global_ctx.config.set_dns_config(Some(DnsConfig {
+            listen: Some(listen),
+            suffix: None,
+            upstream: Some(vec![upstream_addr]),
+            register_resolved: false,
+        }));
+        let mut server = DnsServer::new(global_ctx.clone(), peer_mgr_a.clone());
+        server.run().await.unwrap();
+
+        let domain = overlay_domain(&global_ctx, &global_ctx.config.get_dns_config().unwrap());
+        assert_eq!(
+            format!(
+                "{}.et",
+                dns_label(&global_ctx.get_network_identity().network_name)
+            ),
+            domain
+        );
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let query_type = |name: String, qtype: u16| {
+            let client = &client;
+            async move {
+                client
+                    .send_to(&build_query(1, &name, qtype), listen)
+                    .await
+                    .unwrap();
+                let mut buf = [0u8; 512];
+                let (len, _) = timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+                    .await
+                    .ok()?
+                    .unwrap();
+                Some(buf[..len].to_vec())
+            }
+        };
+        let query = |name: String| query_type(name, DNS_TYPE_A);
+
+        // the server binds in the background
+        let resp = timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(resp) = query(format!("node-b.{}", domain)).await {
+                    return resp;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(0, resp[3] & 0xf);
+        assert_eq!([10, 144, 146, 2], resp[resp.len() - 4..]);
+
+        let resp = query(format!("NODE-A.{}", domain)).await.unwrap();
+        assert_eq!([10, 144, 146, 1], resp[resp.len() - 4..]);
+
+        let resp = query(format!("node-c.{}", domain)).await.unwrap();
+        assert_eq!(DNS_RCODE_NXDOMAIN, resp[3] & 0xf);
+
+        let resp = query_type(format!("node-b.{}", domain), DNS_TYPE_AAAA)
+            .await
+            .unwrap();
+        assert_eq!(0, resp[3] & 0xf);
+        assert_eq!(ipv6_b.octets(), resp[resp.len() - 16..]);
+
+        let resp = query("example.com".to_string()).await.unwrap();
+        assert_eq!([1, 2, 3, 4], resp[resp.len() - 4..]);
+
+        // truncated upstream answers are retried over tcp
+        let resp = query("big.example.com".to_string()).await.unwrap();
+        assert!(!is_truncated(&resp));
+        assert_eq!([5, 6, 7, 8], resp[resp.len() - 4..]);
+    }
+}
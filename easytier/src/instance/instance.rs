@@ -1,5 +1,5 @@
 use std::any::Any;
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Weak};
@@ -16,9 +16,12 @@ use crate::common::error::Error;
 use crate::common::global_ctx::{ArcGlobalCtx, GlobalCtx, GlobalCtxEvent};
 use crate::common::PeerId;
 use crate::connector::direct::DirectConnectorManager;
+use crate::connector::lan_discovery::LanDiscoveryConnector;
 use crate::connector::manual::{ConnectorManagerRpcService, ManualConnectorManager};
+use crate::connector::tcp_hole_punch::TcpHolePunchConnector;
 use crate::connector::udp_hole_punch::UdpHolePunchConnector;
 use crate::gateway::icmp_proxy::IcmpProxy;
+use crate::gateway::proxy_flow::ProxyFlowRpcService;
 use crate::gateway::tcp_proxy::TcpProxy;
 use crate::gateway::udp_proxy::UdpProxy;
 use crate::peer_center::instance::PeerCenterInstance;
@@ -30,8 +33,14 @@ use crate::rpc::vpn_portal_rpc_server::VpnPortalRpc;
 use crate::rpc::{GetVpnPortalInfoRequest, GetVpnPortalInfoResponse, VpnPortalInfo};
 use crate::vpn_portal::{self, VpnPortal};
 
+use super::dhcp::{add_dhcp_occupant, DhcpAllocator, DhcpLease, DhcpOccupant};
+use super::dns_server::DnsServer;
 use super::listeners::ListenerManager;
+use super::port_mapping::PortMappingManager;
+use super::stun_server::StunServerManager;
 
+#[cfg(feature = "smoltcp")]
+use crate::gateway::port_forward::{PortForwardManager, PortForwardManagerRpcService};
 #[cfg(feature = "socks5")]
 use crate::gateway::socks5::Socks5Server;
 
@@ -74,6 +83,10 @@ impl IpProxy {
         self.udp_proxy.start().await?;
         Ok(())
     }
+
+    fn get_rpc_service(&self) -> ProxyFlowRpcService {
+        ProxyFlowRpcService::new(&self.tcp_proxy, &self.udp_proxy, &self.icmp_proxy)
+    }
 }
 
 #[cfg(feature = "tun")]
@@ -90,7 +103,7 @@ impl NicCtx {
         Self
     }
 
-    pub async fn run(&mut self, _ipv4_addr: Ipv4Addr) -> Result<(), Error> {
+    pub async fn run(&mut self, _ipv4_addr: Option<Ipv4Addr>) -> Result<(), Error> {
         Ok(())
     }
 }
@@ -112,6 +125,11 @@ pub struct Instance {
     conn_manager: Arc<ManualConnectorManager>,
     direct_conn_manager: Arc<DirectConnectorManager>,
     udp_hole_puncher: Arc<Mutex<UdpHolePunchConnector>>,
+    tcp_hole_puncher: Arc<Mutex<TcpHolePunchConnector>>,
+    port_mapping_manager: Arc<Mutex<PortMappingManager>>,
+    lan_discovery: Arc<Mutex<LanDiscoveryConnector>>,
+    stun_server_manager: Arc<Mutex<StunServerManager>>,
+    dns_server: Arc<Mutex<DnsServer>>,
 
     ip_proxy: Option<IpProxy>,
 
@@ -122,6 +140,9 @@ pub struct Instance {
     #[cfg(feature = "socks5")]
     socks5_server: Arc<Socks5Server>,
 
+    #[cfg(feature = "smoltcp")]
+    port_forward_manager: Arc<PortForwardManager>,
+
     global_ctx: ArcGlobalCtx,
 }
 
@@ -144,6 +165,13 @@ impl Instance {
             peer_packet_sender.clone(),
         ));
 
+        if global_ctx.get_flags().auto_ipv6 && global_ctx.get_ipv6().is_none() {
+            let ipv6_addr = global_ctx
+                .get_network_identity()
+                .derive_ipv6_addr(peer_manager.my_peer_id());
+            global_ctx.set_ipv6(Some(ipv6_addr));
+        }
+
         let listener_manager = Arc::new(Mutex::new(ListenerManager::new(
             global_ctx.clone(),
             peer_manager.clone(),
@@ -159,6 +187,11 @@ impl Instance {
         direct_conn_manager.run();
 
         let udp_hole_puncher = UdpHolePunchConnector::new(global_ctx.clone(), peer_manager.clone());
+        let tcp_hole_puncher = TcpHolePunchConnector::new(global_ctx.clone(), peer_manager.clone());
+        let port_mapping_manager = PortMappingManager::new(global_ctx.clone());
+        let lan_discovery = LanDiscoveryConnector::new(global_ctx.clone(), peer_manager.clone());
+        let stun_server_manager = StunServerManager::new(global_ctx.clone(), peer_manager.clone());
+        let dns_server = DnsServer::new(global_ctx.clone(), peer_manager.clone());
 
         let peer_center = Arc::new(PeerCenterInstance::new(peer_manager.clone()));
 
@@ -170,6 +203,10 @@ impl Instance {
         #[cfg(feature = "socks5")]
         let socks5_server = Socks5Server::new(global_ctx.clone(), peer_manager.clone(), None);
 
+        #[cfg(feature = "smoltcp")]
+        let port_forward_manager =
+            PortForwardManager::new(global_ctx.clone(), peer_manager.clone());
+
         Instance {
             inst_name: global_ctx.inst_name.clone(),
             id,
@@ -183,6 +220,11 @@ impl Instance {
             conn_manager,
             direct_conn_manager: Arc::new(direct_conn_manager),
             udp_hole_puncher: Arc::new(Mutex::new(udp_hole_puncher)),
+            tcp_hole_puncher: Arc::new(Mutex::new(tcp_hole_puncher)),
+            port_mapping_manager: Arc::new(Mutex::new(port_mapping_manager)),
+            lan_discovery: Arc::new(Mutex::new(lan_discovery)),
+            stun_server_manager: Arc::new(Mutex::new(stun_server_manager)),
+            dns_server: Arc::new(Mutex::new(dns_server)),
 
             ip_proxy: None,
 
@@ -193,6 +235,9 @@ impl Instance {
             #[cfg(feature = "socks5")]
             socks5_server,
 
+            #[cfg(feature = "smoltcp")]
+            port_forward_manager,
+
             global_ctx,
         }
     }
@@ -235,7 +280,15 @@ impl Instance {
         tracing::debug!("nic ctx updated.");
     }
 
+    fn unix_time_now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
     // Warning, if there is an IP conflict in the network when using DHCP, the IP will be automatically changed.
+    // the address sticks to the node across restarts, and the older lease wins a conflict.
     fn check_dhcp_ip_conflict(&self) {
         use rand::Rng;
         let peer_manager_c = self.peer_manager.clone();
@@ -243,8 +296,13 @@ impl Instance {
         let nic_ctx = self.nic_ctx.clone();
         let _peer_packet_receiver = self.peer_packet_receiver.clone();
         tokio::spawn(async move {
-            let default_ipv4_addr = Ipv4Inet::new(Ipv4Addr::new(10, 126, 126, 0), 24).unwrap();
-            let mut current_dhcp_ip: Option<Ipv4Inet> = None;
+            let my_peer_id = peer_manager_c.my_peer_id();
+            let mut current_dhcp_lease: Option<DhcpLease> = None;
+            let mut saved_dhcp_lease = global_ctx_c
+                .config
+                .get_dhcp_config()
+                .lease_file
+                .and_then(|path| DhcpLease::load(&path));
             let mut next_sleep_time = 0;
             loop {
                 tokio::time::sleep(std::time::Duration::from_secs(next_sleep_time)).await;
@@ -258,7 +316,13 @@ impl Instance {
                     next_sleep_time = rand::thread_rng().gen_range(5..10);
                 }
 
-                let mut used_ipv4 = HashSet::new();
+                let dhcp_config = global_ctx_c.config.get_dhcp_config();
+                let network_length = global_ctx_c.get_network_length();
+                let default_ipv4_addr = dhcp_config
+                    .pool_start
+                    .unwrap_or(Ipv4Addr::new(10, 126, 126, 0));
+
+                let mut used_ipv4 = HashMap::new();
                 for route in routes {
                     if route.ipv4_addr.is_empty() {
                         continue;
@@ -268,63 +332,62 @@ impl Instance {
                         continue;
                     };
 
-                    let Ok(peer_ipv4_addr) = Ipv4Inet::new(peer_ipv4_addr, 24) else {
-                        continue;
-                    };
-
-                    used_ipv4.insert(peer_ipv4_addr);
+                    add_dhcp_occupant(
+                        &mut used_ipv4,
+                        peer_ipv4_addr,
+                        DhcpOccupant {
+                            peer_id: route.peer_id,
+                            lease_time: route.dhcp_lease_time,
+                        },
+                    );
                 }
 
-                let dhcp_inet = used_ipv4.iter().next().unwrap_or(&default_ipv4_addr);
-                // if old ip is already in this subnet and not conflicted, use it
-                if let Some(ip) = current_dhcp_ip {
-                    if ip.network() == dhcp_inet.network() && !used_ipv4.contains(&ip) {
-                        continue;
-                    }
-                }
+                // the smallest used address decides the subnet, so all nodes agree on it
+                let dhcp_ipv4_addr = if dhcp_config.pool_start.is_some() {
+                    default_ipv4_addr
+                } else {
+                    used_ipv4.keys().min().copied().unwrap_or(default_ipv4_addr)
+                };
+                let Ok(dhcp_inet) = Ipv4Inet::new(dhcp_ipv4_addr, network_length) else {
+                    continue;
+                };
 
-                // find an available ip in the subnet
-                let candidate_ipv4_addr = dhcp_inet.network().iter().find(|ip| {
-                    ip.address() != dhcp_inet.first_address()
-                        && ip.address() != dhcp_inet.last_address()
-                        && !used_ipv4.contains(ip)
-                });
+                let now = Self::unix_time_now();
+                let allocator = DhcpAllocator::new(
+                    dhcp_inet,
+                    dhcp_config.clone(),
+                    global_ctx_c.get_hostname(),
+                    global_ctx_c.get_id(),
+                    my_peer_id,
+                );
+                let candidate_lease =
+                    allocator.select(current_dhcp_lease, saved_dhcp_lease, &used_ipv4, now);
 
-                if current_dhcp_ip == candidate_ipv4_addr {
+                if current_dhcp_lease == candidate_lease {
                     continue;
                 }
 
-                let last_ip = current_dhcp_ip.as_ref().map(Ipv4Inet::address);
+                let last_ip = current_dhcp_lease.as_ref().map(|l| l.ipv4);
                 tracing::debug!(
-                    ?current_dhcp_ip,
-                    ?candidate_ipv4_addr,
+                    ?current_dhcp_lease,
+                    ?candidate_lease,
                     "dhcp start changing ip"
                 );
 
                 Self::clear_nic_ctx(nic_ctx.clone(), _peer_packet_receiver.clone()).await;
 
-                if let Some(ip) = candidate_ipv4_addr {
-                    if global_ctx_c.no_tun() {
-                        current_dhcp_ip = Some(ip);
-                        global_ctx_c.set_ipv4(Some(ip.address()));
-                        global_ctx_c.issue_event(GlobalCtxEvent::DhcpIpv4Changed(
-                            last_ip,
-                            Some(ip.address()),
-                        ));
-                        continue;
-                    }
-
+                if let Some(lease) = candidate_lease {
                     #[cfg(not(target_os = "android"))]
-                    {
+                    if !global_ctx_c.no_tun() {
                         let mut new_nic_ctx = NicCtx::new(
                             global_ctx_c.clone(),
                             &peer_manager_c,
                             _peer_packet_receiver.clone(),
                         );
-                        if let Err(e) = new_nic_ctx.run(ip.address()).await {
+                        if let Err(e) = new_nic_ctx.run(Some(lease.ipv4)).await {
                             tracing::error!(
-                                ?current_dhcp_ip,
-                                ?candidate_ipv4_addr,
+                                ?current_dhcp_lease,
+                                ?candidate_lease,
                                 ?e,
                                 "add ip failed"
                             );
@@ -334,12 +397,19 @@ impl Instance {
                         Self::use_new_nic_ctx(nic_ctx.clone(), new_nic_ctx).await;
                     }
 
-                    current_dhcp_ip = Some(ip);
-                    global_ctx_c.set_ipv4(Some(ip.address()));
+                    if let Some(path) = dhcp_config.lease_file.as_ref() {
+                        if let Err(e) = lease.save(path) {
+                            tracing::warn!(?e, ?path, "save dhcp lease failed");
+                        }
+                    }
+                    current_dhcp_lease = Some(lease);
+                    saved_dhcp_lease = Some(lease);
+                    global_ctx_c.set_dhcp_lease_time(lease.lease_time);
+                    global_ctx_c.set_ipv4(Some(lease.ipv4));
                     global_ctx_c
-                        .issue_event(GlobalCtxEvent::DhcpIpv4Changed(last_ip, Some(ip.address())));
+                        .issue_event(GlobalCtxEvent::DhcpIpv4Changed(last_ip, Some(lease.ipv4)));
                 } else {
-                    current_dhcp_ip = None;
+                    current_dhcp_lease = None;
                     global_ctx_c.set_ipv4(None);
                     global_ctx_c.issue_event(GlobalCtxEvent::DhcpIpv4Conflicted(last_ip));
                 }
@@ -347,6 +417,47 @@ impl Instance {
         });
     }
 
+    // keep the tun device down while we lose the conflict over our static ipv4. the oldest
+    // claim wins, so a node whose device is already up keeps it when a newcomer shows up
+    #[cfg(not(target_os = "android"))]
+    fn check_static_ip_conflict(&self) {
+        let peer_manager_c = self.peer_manager.clone();
+        let global_ctx_c = self.get_global_ctx();
+        let nic_ctx = self.nic_ctx.clone();
+        let peer_packet_receiver = self.peer_packet_receiver.clone();
+        tokio::spawn(async move {
+            // give route sync a chance to find conflicts before the device comes up
+            tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+            let mut nic_up = false;
+            loop {
+                let conflicted = global_ctx_c.is_ipv4_conflicted();
+                if conflicted && nic_up {
+                    tracing::warn!(ipv4 = ?global_ctx_c.get_ipv4(), "ipv4 conflicted, remove tun device");
+                    Self::clear_nic_ctx(nic_ctx.clone(), peer_packet_receiver.clone()).await;
+                    global_ctx_c.set_ipv4_claim_time(0);
+                    nic_up = false;
+                } else if !conflicted && !nic_up {
+                    let mut new_nic_ctx = NicCtx::new(
+                        global_ctx_c.clone(),
+                        &peer_manager_c,
+                        peer_packet_receiver.clone(),
+                    );
+                    match new_nic_ctx.run(global_ctx_c.get_ipv4()).await {
+                        Ok(_) => {
+                            Self::use_new_nic_ctx(nic_ctx.clone(), new_nic_ctx).await;
+                            global_ctx_c.set_ipv4_claim_time(Self::unix_time_now());
+                            nic_up = true;
+                        }
+                        Err(e) => {
+                            tracing::error!(?e, "create tun device failed");
+                        }
+                    }
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        });
+    }
+
     pub async fn run(&mut self) -> Result<(), Error> {
         self.listener_manager
             .lock()
@@ -358,16 +469,24 @@ impl Instance {
 
         Self::clear_nic_ctx(self.nic_ctx.clone(), self.peer_packet_receiver.clone()).await;
 
-        if !self.global_ctx.config.get_flags().no_tun {
+        // with a static ipv4, the tun device may have to wait until no conflict is found
+        let defer_tun = self.global_ctx.config.get_flags().no_tun_on_ipv4_conflict
+            && !self.global_ctx.config.get_dhcp()
+            && self.global_ctx.get_ipv4().is_some();
+
+        if !self.global_ctx.config.get_flags().no_tun && !defer_tun {
             #[cfg(not(target_os = "android"))]
-            if let Some(ipv4_addr) = self.global_ctx.get_ipv4() {
+            if self.global_ctx.get_ipv4().is_some() || self.global_ctx.get_ipv6().is_some() {
                 let mut new_nic_ctx = NicCtx::new(
                     self.global_ctx.clone(),
                     &self.peer_manager,
                     self.peer_packet_receiver.clone(),
                 );
-                new_nic_ctx.run(ipv4_addr).await?;
+                new_nic_ctx.run(self.global_ctx.get_ipv4()).await?;
                 Self::use_new_nic_ctx(self.nic_ctx.clone(), new_nic_ctx).await;
+                if !self.global_ctx.config.get_dhcp() && self.global_ctx.get_ipv4().is_some() {
+                    self.global_ctx.set_ipv4_claim_time(Self::unix_time_now());
+                }
             }
         }
 
@@ -375,16 +494,27 @@ impl Instance {
             self.check_dhcp_ip_conflict();
         }
 
-        self.run_rpc_server()?;
+        #[cfg(not(target_os = "android"))]
+        if !self.global_ctx.config.get_flags().no_tun && defer_tun {
+            self.check_static_ip_conflict();
+        }
 
         // run after tun device created, so listener can bind to tun device, which may be required by win 10
         self.ip_proxy = Some(IpProxy::new(
             self.get_global_ctx(),
             self.get_peer_manager(),
         )?);
+
+        self.run_rpc_server()?;
+
         self.run_ip_proxy().await?;
 
         self.udp_hole_puncher.lock().await.run().await?;
+        self.tcp_hole_puncher.lock().await.run().await?;
+        self.port_mapping_manager.lock().await.run();
+        self.lan_discovery.lock().await.run().await?;
+        self.stun_server_manager.lock().await.run().await?;
+        self.dns_server.lock().await.run().await?;
 
         self.peer_center.init().await;
         let route_calc = self.peer_center.get_cost_calculator();
@@ -402,6 +532,10 @@ impl Instance {
         #[cfg(feature = "socks5")]
         self.socks5_server.run().await?;
 
+        // after the ip proxy, so forwarded ports are handled before it sees the packets
+        #[cfg(feature = "smoltcp")]
+        self.port_forward_manager.run().await?;
+
         Ok(())
     }
 
@@ -502,15 +636,19 @@ impl Instance {
         };
         let peer_mgr = self.peer_manager.clone();
         let conn_manager = self.conn_manager.clone();
+        let tcp_hole_puncher = self.tcp_hole_puncher.clone();
         let net_ns = self.global_ctx.net_ns.clone();
         let peer_center = self.peer_center.clone();
         let vpn_portal_rpc = self.get_vpn_portal_rpc_service();
+        let proxy_flow_rpc = self.ip_proxy.as_ref().map(IpProxy::get_rpc_service);
+        #[cfg(feature = "smoltcp")]
+        let port_forward_manager = self.port_forward_manager.clone();
 
         let incoming = TcpIncoming::new(addr, true, None)
             .map_err(|e| anyhow::anyhow!("create rpc server failed. addr: {}, err: {}", addr, e))?;
         self.tasks.spawn(async move {
             let _g = net_ns.guard();
-            Server::builder()
+            let router = Server::builder()
                 .add_service(
                     crate::rpc::peer_manage_rpc_server::PeerManageRpcServer::new(
                         PeerManagerRpcService::new(peer_mgr),
@@ -518,7 +656,10 @@ impl Instance {
                 )
                 .add_service(
                     crate::rpc::connector_manage_rpc_server::ConnectorManageRpcServer::new(
-                        ConnectorManagerRpcService(conn_manager.clone()),
+                        ConnectorManagerRpcService {
+                            conn_manager: conn_manager.clone(),
+                            tcp_hole_puncher: tcp_hole_puncher.clone(),
+                        },
                     ),
                 )
                 .add_service(
@@ -529,6 +670,18 @@ impl Instance {
                 .add_service(crate::rpc::vpn_portal_rpc_server::VpnPortalRpcServer::new(
                     vpn_portal_rpc,
                 ))
+                .add_optional_service(
+                    proxy_flow_rpc.map(crate::rpc::proxy_flow_rpc_server::ProxyFlowRpcServer::new),
+                );
+            #[cfg(feature = "smoltcp")]
+            let router = router.add_service(
+                crate::rpc::port_forward_manage_rpc_server::PortForwardManageRpcServer::new(
+                    PortForwardManagerRpcService {
+                        manager: port_forward_manager,
+                    },
+                ),
+            );
+            router
                 .serve_with_incoming(incoming)
                 .await
                 .with_context(|| format!("rpc server failed. addr: {}", addr))
@@ -545,6 +698,10 @@ impl Instance {
         self.vpn_portal.clone()
     }
 
+    pub fn get_port_mapping_manager(&self) -> Arc<Mutex<PortMappingManager>> {
+        self.port_mapping_manager.clone()
+    }
+
     pub fn get_nic_ctx(&self) -> ArcNicCtx {
         self.nic_ctx.clone()
     }
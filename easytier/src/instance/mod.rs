@@ -1,5 +1,9 @@
+pub mod dhcp;
+pub mod dns_server;
 pub mod instance;
 pub mod listeners;
+pub mod port_mapping;
+pub mod stun_server;
 
 #[cfg(feature = "tun")]
 pub mod virtual_nic;
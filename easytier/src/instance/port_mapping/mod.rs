@@ -0,0 +1,429 @@
+// ask the gateway (home router) to forward ports of our listeners, so peers outside the lan
+// can connect to us directly. both upnp-igd and nat-pmp/pcp are supported.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::{sync::Mutex, task::JoinSet};
+
+use crate::common::{error::Error, global_ctx::ArcGlobalCtx};
+
+pub mod pcp;
+pub mod upnp;
+
+// lifetime requested for each mapping, mappings are renewed before they expire.
+const MAPPING_LIFETIME_SEC: u64 = 3600;
+const MAPPING_RENEW_INTERVAL_SEC: u64 = 1200;
+// retry interval when no gateway is found or some listener is not mapped.
+const MAPPING_RETRY_INTERVAL_SEC: u64 = 60;
+
+const SSDP_MULTICAST_ADDR: &str = "239.255.255.250:1900";
+const PCP_SERVER_PORT: u16 = 5351;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MappingProtocol {
+    Tcp,
+    Udp,
+}
+
+impl MappingProtocol {
+    fn from_scheme(scheme: &str) -> Option<Self> {
+        match scheme {
+            "tcp" | "ws" | "wss" => Some(MappingProtocol::Tcp),
+            "udp" | "wg" | "quic" => Some(MappingProtocol::Udp),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+pub trait PortMapper: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    // returns the external address of the mapping.
+    async fn add_mapping(
+        &self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> Result<SocketAddrV4, Error>;
+
+    async fn remove_mapping(
+        &self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+    ) -> Result<(), Error>;
+}
+
+// the local ip used to reach the gateway, which is the internal address of mappings.
+pub(crate) async fn get_local_ip_to(gateway: SocketAddr) -> Result<Ipv4Addr, Error> {
+    let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(gateway).await?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(ip) => Ok(ip),
+        _ => Err(Error::NotFound),
+    }
+}
+
+// only linux is supported now, other platforms can still use upnp.
+fn get_default_gateway_v4() -> Option<Ipv4Addr> {
+    let content = std::fs::read_to_string("/proc/net/route").ok()?;
+    for line in content.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            continue;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        if gateway != 0 {
+            return Some(Ipv4Addr::from(gateway.swap_bytes()));
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone)]
+struct ActiveMapping {
+    mapper: Arc<dyn PortMapper>,
+    protocol: MappingProtocol,
+    listener: url::Url,
+    external_addr: SocketAddrV4,
+}
+
+impl ActiveMapping {
+    fn mapped_listener(&self) -> url::Url {
+        let mut url = self.listener.clone();
+        url.set_ip_host((*self.external_addr.ip()).into()).unwrap();
+        url.set_port(Some(self.external_addr.port())).unwrap();
+        url
+    }
+}
+
+struct PortMappingManagerData {
+    global_ctx: ArcGlobalCtx,
+    mappers: Mutex<Vec<Arc<dyn PortMapper>>>,
+    mappings: Mutex<Vec<ActiveMapping>>,
+
+    // where to discover the gateway, none means the method is disabled.
+    ssdp_addr: Option<SocketAddr>,
+    pcp_server: Option<SocketAddr>,
+}
+
+pub struct PortMappingManager {
+    data: Arc<PortMappingManagerData>,
+    tasks: JoinSet<()>,
+}
+
+impl PortMappingManager {
+    pub fn new(global_ctx: ArcGlobalCtx) -> Self {
+        Self::new_with_discovery_addrs(
+            global_ctx,
+            Some(SSDP_MULTICAST_ADDR.parse().unwrap()),
+            get_default_gateway_v4().map(|ip| SocketAddr::new(ip.into(), PCP_SERVER_PORT)),
+        )
+    }
+
+    fn new_with_discovery_addrs(
+        global_ctx: ArcGlobalCtx,
+        ssdp_addr: Option<SocketAddr>,
+        pcp_server: Option<SocketAddr>,
+    ) -> Self {
+        Self {
+            data: Arc::new(PortMappingManagerData {
+                global_ctx,
+                mappers: Mutex::new(Vec::new()),
+                mappings: Mutex::new(Vec::new()),
+                ssdp_addr,
+                pcp_server,
+            }),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub fn run(&mut self) {
+        let flags = self.data.global_ctx.get_flags();
+        if flags.disable_p2p || flags.disable_port_mapping {
+            return;
+        }
+
+        let data = self.data.clone();
+        self.tasks.spawn(async move {
+            loop {
+                let all_mapped = Self::refresh_mappings(&data).await;
+                let sleep_sec = if all_mapped {
+                    MAPPING_RENEW_INTERVAL_SEC
+                } else {
+                    MAPPING_RETRY_INTERVAL_SEC
+                };
+                tokio::time::sleep(Duration::from_secs(sleep_sec)).await;
+            }
+        });
+    }
+
+    async fn discover_mappers(data: &PortMappingManagerData) -> Vec<Arc<dyn PortMapper>> {
+        let mut ret: Vec<Arc<dyn PortMapper>> = vec![];
+        if let Some(ssdp_addr) = data.ssdp_addr {
+            match upnp::IgdPortMapper::discover(ssdp_addr).await {
+                Ok(mapper) => ret.push(Arc::new(mapper)),
+                Err(e) => tracing::info!(?e, "no upnp gateway found"),
+            }
+        }
+        if let Some(pcp_server) = data.pcp_server {
+            match pcp::PcpPortMapper::discover(pcp_server).await {
+                Ok(mapper) => ret.push(Arc::new(mapper)),
+                Err(e) => tracing::info!(?e, "no nat-pmp/pcp gateway found"),
+            }
+        }
+        ret
+    }
+
+    // map all tcp/udp ipv4 listeners, renew existing mappings.
+    // returns true if all listeners are mapped.
+    async fn refresh_mappings(data: &PortMappingManagerData) -> bool {
+        let mut mappers = data.mappers.lock().await;
+        if mappers.is_empty() {
+            *mappers = Self::discover_mappers(data).await;
+        }
+
+        let listeners = data
+            .global_ctx
+            .get_running_listeners()
+            .into_iter()
+            .filter(|l| MappingProtocol::from_scheme(l.scheme()).is_some())
+            .filter(|l| {
+                l.socket_addrs(|| None)
+                    .ok()
+                    .and_then(|addrs| addrs.first().cloned())
+                    .is_some_and(|addr| addr.is_ipv4() && addr.port() != 0)
+            })
+            .collect::<Vec<_>>();
+
+        let mut mappings = data.mappings.lock().await;
+        let mut new_mappings = vec![];
+        let mut all_mapped = true;
+        for listener in listeners.iter() {
+            let protocol = MappingProtocol::from_scheme(listener.scheme()).unwrap();
+            let internal_port = listener.port().unwrap();
+            let old = mappings.iter().find(|m| &m.listener == listener);
+
+            // renew with the old mapper and port first, then try all mappers.
+            let mut candidates = vec![];
+            if let Some(old) = old {
+                candidates.push((old.mapper.clone(), old.external_addr.port()));
+            }
+            for mapper in mappers.iter() {
+                candidates.push((mapper.clone(), internal_port));
+            }
+
+            let mut mapped = None;
+            for (mapper, external_port) in candidates {
+                match mapper
+                    .add_mapping(
+                        protocol,
+                        internal_port,
+                        external_port,
+                        Duration::from_secs(MAPPING_LIFETIME_SEC),
+                    )
+                    .await
+                {
+                    Ok(external_addr) => {
+                        mapped = Some(ActiveMapping {
+                            mapper,
+                            protocol,
+                            listener: listener.clone(),
+                            external_addr,
+                        });
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::info!(?e, ?listener, mapper = mapper.name(), "add mapping failed");
+                    }
+                }
+            }
+
+            match mapped {
+                Some(m) => {
+                    tracing::info!(
+                        ?listener,
+                        external_addr = ?m.external_addr,
+                        mapper = m.mapper.name(),
+                        "port mapping added"
+                    );
+                    new_mappings.push(m);
+                }
+                None => all_mapped = false,
+            }
+        }
+
+        if !all_mapped {
+            // the gateway may be changed, discover it again next time
+            mappers.clear();
+        }
+
+        // delete what is not renewed, e.g. the listener is gone or moved to another mapping
+        for old in mappings.iter() {
+            if new_mappings
+                .iter()
+                .any(|m| m.listener == old.listener && m.external_addr == old.external_addr)
+            {
+                continue;
+            }
+            let ret = old
+                .mapper
+                .remove_mapping(
+                    old.protocol,
+                    old.listener.port().unwrap(),
+                    old.external_addr.port(),
+                )
+                .await;
+            tracing::info!(?ret, listener = ?old.listener, "stale port mapping removed");
+        }
+
+        *mappings = new_mappings;
+        data.global_ctx
+            .set_mapped_listeners(mappings.iter().map(|m| m.mapped_listener()).collect());
+
+        all_mapped
+    }
+
+    // remove all mappings from the gateway, should be called before the instance exits.
+    pub async fn remove_all_mappings(&mut self) {
+        // a refresh running in the background could add the mappings back
+        self.tasks.abort_all();
+        while self.tasks.join_next().await.is_some() {}
+
+        let mut mappings = self.data.mappings.lock().await;
+        for m in mappings.drain(..) {
+            let ret = m
+                .mapper
+                .remove_mapping(
+                    m.protocol,
+                    m.listener.port().unwrap(),
+                    m.external_addr.port(),
+                )
+                .await;
+            tracing::info!(?ret, listener = ?m.listener, "port mapping removed");
+        }
+        self.data.global_ctx.set_mapped_listeners(vec![]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        common::global_ctx::tests::get_mock_global_ctx,
+        instance::port_mapping::{pcp::tests::FakePcpServer, upnp::tests::FakeIgd},
+    };
+
+    use super::*;
+
+    fn add_test_listeners(global_ctx: &ArcGlobalCtx) {
+        global_ctx.add_running_listener("tcp://0.0.0.0:11010".parse().unwrap());
+        global_ctx.add_running_listener("udp://0.0.0.0:11010".parse().unwrap());
+        // not mappable
+        global_ctx.add_running_listener("udp://[::]:11010".parse().unwrap());
+        global_ctx.add_running_listener(
+            "ring://00000000-0000-0000-0000-000000000000"
+                .parse()
+                .unwrap(),
+        );
+    }
+
+    #[tokio::test]
+    async fn upnp_port_mapping() {
+        let igd = FakeIgd::start().await;
+        let global_ctx = get_mock_global_ctx();
+        add_test_listeners(&global_ctx);
+
+        let mut mgr = PortMappingManager::new_with_discovery_addrs(
+            global_ctx.clone(),
+            Some(igd.ssdp_addr()),
+            None,
+        );
+        assert!(PortMappingManager::refresh_mappings(&mgr.data).await);
+
+        let mut mapped = global_ctx.get_mapped_listeners();
+        mapped.sort();
+        assert_eq!(
+            mapped,
+            vec![
+                "tcp://1.2.3.4:11010".parse::<url::Url>().unwrap(),
+                "udp://1.2.3.4:11010".parse().unwrap()
+            ]
+        );
+
+        // renew the mappings
+        assert!(PortMappingManager::refresh_mappings(&mgr.data).await);
+        assert_eq!(igd.mapping_count(), 2);
+
+        // a mapping whose listener is gone is deleted from the gateway
+        let mapper = mgr.data.mappers.lock().await[0].clone();
+        let external_addr = mapper
+            .add_mapping(
+                MappingProtocol::Tcp,
+                11020,
+                11020,
+                Duration::from_secs(MAPPING_LIFETIME_SEC),
+            )
+            .await
+            .unwrap();
+        mgr.data.mappings.lock().await.push(ActiveMapping {
+            mapper,
+            protocol: MappingProtocol::Tcp,
+            listener: "tcp://0.0.0.0:11020".parse().unwrap(),
+            external_addr,
+        });
+        assert_eq!(igd.mapping_count(), 3);
+        assert!(PortMappingManager::refresh_mappings(&mgr.data).await);
+        assert_eq!(igd.mapping_count(), 2);
+
+        mgr.remove_all_mappings().await;
+        assert!(global_ctx.get_mapped_listeners().is_empty());
+        assert_eq!(igd.mapping_count(), 0);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    async fn pcp_port_mapping(#[values(true, false)] nat_pmp_only: bool) {
+        let server = FakePcpServer::start(nat_pmp_only).await;
+        let global_ctx = get_mock_global_ctx();
+        add_test_listeners(&global_ctx);
+
+        let mut mgr = PortMappingManager::new_with_discovery_addrs(
+            global_ctx.clone(),
+            None,
+            Some(server.addr()),
+        );
+        assert!(PortMappingManager::refresh_mappings(&mgr.data).await);
+
+        let mut mapped = global_ctx.get_mapped_listeners();
+        mapped.sort();
+        assert_eq!(
+            mapped,
+            vec![
+                "tcp://5.6.7.8:12010".parse::<url::Url>().unwrap(),
+                "udp://5.6.7.8:12010".parse().unwrap()
+            ]
+        );
+        assert_eq!(server.mapping_count(), 2);
+
+        mgr.remove_all_mappings().await;
+        assert!(global_ctx.get_mapped_listeners().is_empty());
+        assert_eq!(server.mapping_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn no_gateway() {
+        let global_ctx = get_mock_global_ctx();
+        add_test_listeners(&global_ctx);
+
+        let mgr = PortMappingManager::new_with_discovery_addrs(global_ctx.clone(), None, None);
+        assert!(!PortMappingManager::refresh_mappings(&mgr.data).await);
+        assert!(global_ctx.get_mapped_listeners().is_empty());
+    }
+}
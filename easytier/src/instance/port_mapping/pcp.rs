@@ -0,0 +1,321 @@
+// port control protocol (rfc 6887) client, falls back to nat-pmp (rfc 6886) if the gateway
+// only speaks the old protocol.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+use crate::common::error::Error;
+
+use super::{get_local_ip_to, MappingProtocol, PortMapper};
+
+const PCP_VERSION: u8 = 2;
+const NAT_PMP_VERSION: u8 = 0;
+
+const PCP_OPCODE_ANNOUNCE: u8 = 0;
+const PCP_OPCODE_MAP: u8 = 1;
+const PCP_RESPONSE_BIT: u8 = 0x80;
+const PCP_HEADER_LEN: usize = 24;
+const PCP_MAP_PAYLOAD_LEN: usize = 36;
+
+const NAT_PMP_OPCODE_EXTERNAL_ADDR: u8 = 0;
+const NAT_PMP_OPCODE_MAP_UDP: u8 = 1;
+const NAT_PMP_OPCODE_MAP_TCP: u8 = 2;
+
+// first retransmit timeout, doubled on each retry
+const INITIAL_TIMEOUT_MS: u64 = 250;
+const MAX_RETRIES: u32 = 4;
+
+fn ip_protocol_number(protocol: MappingProtocol) -> u8 {
+    match protocol {
+        MappingProtocol::Tcp => 6,
+        MappingProtocol::Udp => 17,
+    }
+}
+
+#[derive(Debug)]
+pub struct PcpPortMapper {
+    server: SocketAddr,
+    local_ip: Ipv4Addr,
+    nat_pmp: bool,
+    // pcp identifies a mapping with the nonce, so renew and delete must use the same one.
+    nonce: [u8; 12],
+}
+
+impl PcpPortMapper {
+    #[tracing::instrument(ret, err)]
+    pub async fn discover(server: SocketAddr) -> Result<Self, Error> {
+        let mut mapper = Self {
+            server,
+            local_ip: get_local_ip_to(server).await?,
+            nat_pmp: false,
+            nonce: rand::random(),
+        };
+
+        let resp = mapper
+            .request(&mapper.pcp_header(PCP_OPCODE_ANNOUNCE, 0))
+            .await?;
+        // a nat-pmp gateway answers pcp requests with its own version
+        if resp.len() >= 2 && resp[0] == NAT_PMP_VERSION {
+            mapper.nat_pmp = true;
+            mapper.nat_pmp_external_ip().await?;
+        } else if resp.len() < PCP_HEADER_LEN || resp[0] != PCP_VERSION {
+            return Err(anyhow::anyhow!("invalid pcp announce response: {:?}", resp).into());
+        }
+
+        Ok(mapper)
+    }
+
+    async fn request(&self, req: &[u8]) -> Result<Vec<u8>, Error> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(self.server).await?;
+
+        let mut timeout = Duration::from_millis(INITIAL_TIMEOUT_MS);
+        let mut buf = [0u8; 1100];
+        for _ in 0..MAX_RETRIES {
+            socket.send(req).await?;
+            if let Ok(ret) = tokio::time::timeout(timeout, socket.recv(&mut buf)).await {
+                return Ok(buf[..ret?].to_vec());
+            }
+            timeout *= 2;
+        }
+
+        Err(anyhow::anyhow!("no response from {}", self.server).into())
+    }
+
+    fn pcp_header(&self, opcode: u8, lifetime: u32) -> Vec<u8> {
+        let mut req = Vec::with_capacity(PCP_HEADER_LEN + PCP_MAP_PAYLOAD_LEN);
+        req.push(PCP_VERSION);
+        req.push(opcode);
+        req.extend_from_slice(&[0, 0]);
+        req.extend_from_slice(&lifetime.to_be_bytes());
+        req.extend_from_slice(&self.local_ip.to_ipv6_mapped().octets());
+        req
+    }
+
+    async fn pcp_map(
+        &self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<SocketAddrV4, Error> {
+        let mut req = self.pcp_header(PCP_OPCODE_MAP, lifetime);
+        req.extend_from_slice(&self.nonce);
+        req.push(ip_protocol_number(protocol));
+        req.extend_from_slice(&[0, 0, 0]);
+        req.extend_from_slice(&internal_port.to_be_bytes());
+        req.extend_from_slice(&external_port.to_be_bytes());
+        req.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+        let resp = self.request(&req).await?;
+        if resp.len() < PCP_HEADER_LEN + PCP_MAP_PAYLOAD_LEN
+            || resp[1] != PCP_RESPONSE_BIT | PCP_OPCODE_MAP
+            || resp[24..36] != self.nonce
+        {
+            return Err(anyhow::anyhow!("invalid pcp map response: {:?}", resp).into());
+        }
+        if resp[3] != 0 {
+            return Err(anyhow::anyhow!("pcp map failed, result code: {}", resp[3]).into());
+        }
+
+        let port = u16::from_be_bytes([resp[42], resp[43]]);
+        let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&resp[44..60]).unwrap())
+            .to_ipv4_mapped()
+            .with_context(|| "pcp assigned external ip is not ipv4")?;
+        Ok(SocketAddrV4::new(ip, port))
+    }
+
+    async fn nat_pmp_external_ip(&self) -> Result<Ipv4Addr, Error> {
+        let resp = self
+            .request(&[NAT_PMP_VERSION, NAT_PMP_OPCODE_EXTERNAL_ADDR])
+            .await?;
+        if resp.len() < 12 || resp[1] != 128 + NAT_PMP_OPCODE_EXTERNAL_ADDR {
+            return Err(anyhow::anyhow!("invalid nat-pmp response: {:?}", resp).into());
+        }
+        let result_code = u16::from_be_bytes([resp[2], resp[3]]);
+        if result_code != 0 {
+            return Err(anyhow::anyhow!("nat-pmp failed, result code: {}", result_code).into());
+        }
+        Ok(Ipv4Addr::new(resp[8], resp[9], resp[10], resp[11]))
+    }
+
+    async fn nat_pmp_map(
+        &self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<u16, Error> {
+        let opcode = match protocol {
+            MappingProtocol::Udp => NAT_PMP_OPCODE_MAP_UDP,
+            MappingProtocol::Tcp => NAT_PMP_OPCODE_MAP_TCP,
+        };
+        let mut req = vec![NAT_PMP_VERSION, opcode, 0, 0];
+        req.extend_from_slice(&internal_port.to_be_bytes());
+        req.extend_from_slice(&external_port.to_be_bytes());
+        req.extend_from_slice(&lifetime.to_be_bytes());
+
+        let resp = self.request(&req).await?;
+        if resp.len() < 16 || resp[1] != 128 + opcode {
+            return Err(anyhow::anyhow!("invalid nat-pmp response: {:?}", resp).into());
+        }
+        let result_code = u16::from_be_bytes([resp[2], resp[3]]);
+        if result_code != 0 {
+            return Err(anyhow::anyhow!("nat-pmp failed, result code: {}", result_code).into());
+        }
+        Ok(u16::from_be_bytes([resp[10], resp[11]]))
+    }
+}
+
+#[async_trait]
+impl PortMapper for PcpPortMapper {
+    fn name(&self) -> &'static str {
+        if self.nat_pmp {
+            "nat-pmp"
+        } else {
+            "pcp"
+        }
+    }
+
+    async fn add_mapping(
+        &self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> Result<SocketAddrV4, Error> {
+        let lifetime = lifetime.as_secs() as u32;
+        if self.nat_pmp {
+            let ip = self.nat_pmp_external_ip().await?;
+            let port = self
+                .nat_pmp_map(protocol, internal_port, external_port, lifetime)
+                .await?;
+            Ok(SocketAddrV4::new(ip, port))
+        } else {
+            self.pcp_map(protocol, internal_port, external_port, lifetime)
+                .await
+        }
+    }
+
+    async fn remove_mapping(
+        &self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        _external_port: u16,
+    ) -> Result<(), Error> {
+        // a mapping is deleted by requesting it with zero lifetime
+        if self.nat_pmp {
+            self.nat_pmp_map(protocol, internal_port, 0, 0).await?;
+        } else {
+            self.pcp_map(protocol, internal_port, 0, 0).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        collections::HashSet,
+        net::{Ipv4Addr, SocketAddr},
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::{net::UdpSocket, task::JoinSet};
+
+    // external ip is 5.6.7.8, external port is internal port + 1000
+    pub struct FakePcpServer {
+        addr: SocketAddr,
+        // (protocol, internal port)
+        mappings: Arc<Mutex<HashSet<(u8, u16)>>>,
+        _tasks: JoinSet<()>,
+    }
+
+    impl FakePcpServer {
+        pub async fn start(nat_pmp_only: bool) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let mappings = Arc::new(Mutex::new(HashSet::new()));
+            let mut tasks = JoinSet::new();
+
+            let m = mappings.clone();
+            tasks.spawn(async move {
+                let mut buf = [0u8; 1100];
+                while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                    let req = &buf[..len];
+                    let resp = if req[0] == 2 && nat_pmp_only {
+                        // unsupported version
+                        vec![0, 128 + req[1], 0, 1, 0, 0, 0, 0]
+                    } else if req[0] == 2 {
+                        Self::handle_pcp(req, &m)
+                    } else {
+                        Self::handle_nat_pmp(req, &m)
+                    };
+                    socket.send_to(&resp, from).await.unwrap();
+                }
+            });
+
+            Self {
+                addr,
+                mappings,
+                _tasks: tasks,
+            }
+        }
+
+        fn handle_pcp(req: &[u8], m: &Mutex<HashSet<(u8, u16)>>) -> Vec<u8> {
+            let mut resp = vec![2, 0x80 | req[1], 0, 0];
+            resp.extend_from_slice(&req[4..8]);
+            resp.extend_from_slice(&[0u8; 16]);
+            if req[1] != 1 {
+                return resp;
+            }
+
+            let lifetime = u32::from_be_bytes(req[4..8].try_into().unwrap());
+            let proto = req[36];
+            let internal_port = u16::from_be_bytes([req[40], req[41]]);
+            if lifetime == 0 {
+                m.lock().unwrap().remove(&(proto, internal_port));
+            } else {
+                m.lock().unwrap().insert((proto, internal_port));
+            }
+            resp.extend_from_slice(&req[24..42]);
+            resp.extend_from_slice(&(internal_port + 1000).to_be_bytes());
+            resp.extend_from_slice(&Ipv4Addr::new(5, 6, 7, 8).to_ipv6_mapped().octets());
+            resp
+        }
+
+        fn handle_nat_pmp(req: &[u8], m: &Mutex<HashSet<(u8, u16)>>) -> Vec<u8> {
+            let mut resp = vec![0, 128 + req[1], 0, 0, 0, 0, 0, 1];
+            if req[1] == 0 {
+                resp.extend_from_slice(&[5, 6, 7, 8]);
+                return resp;
+            }
+
+            let internal_port = u16::from_be_bytes([req[4], req[5]]);
+            let lifetime = &req[8..12];
+            if lifetime == [0, 0, 0, 0] {
+                m.lock().unwrap().remove(&(req[1], internal_port));
+            } else {
+                m.lock().unwrap().insert((req[1], internal_port));
+            }
+            resp.extend_from_slice(&req[4..6]);
+            resp.extend_from_slice(&(internal_port + 1000).to_be_bytes());
+            resp.extend_from_slice(lifetime);
+            resp
+        }
+
+        pub fn addr(&self) -> SocketAddr {
+            self.addr
+        }
+
+        pub fn mapping_count(&self) -> usize {
+            self.mappings.lock().unwrap().len()
+        }
+    }
+}
@@ -0,0 +1,471 @@
+// minimal upnp internet gateway device client: ssdp discovery + soap over http/1.0.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::common::error::Error;
+
+use super::{MappingProtocol, PortMapper};
+
+const SSDP_TIMEOUT_MS: u64 = 3000;
+const HTTP_TIMEOUT_MS: u64 = 5000;
+
+// services that support AddPortMapping, in order of preference.
+const WAN_SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+// upnp error code of AddPortMapping, the gateway does not support lease duration.
+const ONLY_PERMANENT_LEASES_SUPPORTED: u16 = 725;
+
+fn xml_tag_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start_tag = format!("<{}>", tag);
+    let end_tag = format!("</{}>", tag);
+    let start = xml.find(&start_tag)? + start_tag.len();
+    let end = start + xml[start..].find(&end_tag)?;
+    Some(xml[start..end].trim())
+}
+
+// returns (service type, control url) of the wan connection service in device description.
+fn parse_wan_service(desc: &str) -> Option<(String, String)> {
+    let services = desc
+        .split("<service>")
+        .skip(1)
+        .filter_map(|s| {
+            let service_type = xml_tag_text(s, "serviceType")?;
+            let control_url = xml_tag_text(s, "controlURL")?;
+            Some((service_type.to_owned(), control_url.to_owned()))
+        })
+        .collect::<Vec<_>>();
+
+    WAN_SERVICE_TYPES
+        .iter()
+        .find_map(|t| services.iter().find(|(st, _)| st == t).cloned())
+}
+
+// send a http/1.0 request so the response is never chunked.
+// returns status code, response body and the local ip used to reach the server.
+async fn http_request(
+    url: &url::Url,
+    method: &str,
+    headers: &[(&str, String)],
+    body: &str,
+) -> Result<(u16, String, Ipv4Addr), Error> {
+    let addr = url
+        .socket_addrs(|| Some(80))?
+        .into_iter()
+        .find(|a| a.is_ipv4())
+        .ok_or(Error::InvalidUrl(url.to_string()))?;
+
+    let mut req = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n",
+        method,
+        &url[url::Position::BeforePath..],
+        addr,
+        body.len()
+    );
+    for (k, v) in headers {
+        req.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    req.push_str("\r\n");
+    req.push_str(body);
+
+    let timeout = Duration::from_millis(HTTP_TIMEOUT_MS);
+    let mut stream = tokio::time::timeout(timeout, TcpStream::connect(addr)).await??;
+    let std::net::IpAddr::V4(local_ip) = stream.local_addr()?.ip() else {
+        return Err(Error::NotFound);
+    };
+    stream.write_all(req.as_bytes()).await?;
+    let mut resp = vec![];
+    tokio::time::timeout(timeout, stream.read_to_end(&mut resp)).await??;
+
+    let resp = String::from_utf8_lossy(&resp);
+    let (head, body) = resp
+        .split_once("\r\n\r\n")
+        .with_context(|| format!("invalid http response: {:?}", resp))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .with_context(|| format!("invalid http status line: {:?}", head))?;
+
+    Ok((status, body.to_owned(), local_ip))
+}
+
+// returns the location url of the device description.
+async fn ssdp_search(ssdp_addr: SocketAddr) -> Result<url::Url, Error> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let req = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {}\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 2\r\n\r\n",
+        ssdp_addr
+    );
+    socket.send_to(req.as_bytes(), ssdp_addr).await?;
+
+    let search = async {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, addr) = socket.recv_from(&mut buf).await?;
+            let resp = String::from_utf8_lossy(&buf[..len]);
+            let location = resp.lines().find_map(|line| {
+                let (k, v) = line.split_once(':')?;
+                k.trim()
+                    .eq_ignore_ascii_case("location")
+                    .then(|| v.trim().to_owned())
+            });
+            match location.map(|l| url::Url::parse(&l)) {
+                Some(Ok(location)) => return Ok::<_, Error>(location),
+                _ => tracing::debug!(?addr, ?resp, "ignore invalid ssdp response"),
+            }
+        }
+    };
+
+    tokio::time::timeout(Duration::from_millis(SSDP_TIMEOUT_MS), search).await?
+}
+
+#[derive(Debug)]
+pub struct IgdPortMapper {
+    control_url: url::Url,
+    service_type: String,
+    local_ip: Ipv4Addr,
+}
+
+impl IgdPortMapper {
+    #[tracing::instrument(ret, err)]
+    pub async fn discover(ssdp_addr: SocketAddr) -> Result<Self, Error> {
+        let location = ssdp_search(ssdp_addr).await?;
+        let (status, desc, local_ip) = http_request(&location, "GET", &[], "").await?;
+        if status != 200 {
+            return Err(anyhow::anyhow!("get igd description failed, status: {}", status).into());
+        }
+
+        let (service_type, control_url) = parse_wan_service(&desc)
+            .with_context(|| format!("no wan connection service in {}", location))?;
+        let control_url = location
+            .join(&control_url)
+            .map_err(|_| Error::InvalidUrl(control_url))?;
+
+        Ok(Self {
+            control_url,
+            service_type,
+            local_ip,
+        })
+    }
+
+    // returns the http status and the response body, the upnp error code is in the body
+    async fn soap_request(
+        &self,
+        action: &str,
+        args: &[(&str, String)],
+    ) -> Result<(u16, String), Error> {
+        let args = args
+            .iter()
+            .map(|(k, v)| format!("<{}>{}</{}>", k, v, k))
+            .collect::<String>();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{} xmlns:u=\"{}\">{}</u:{}></s:Body></s:Envelope>\r\n",
+            action, self.service_type, args, action
+        );
+        let headers = [
+            ("Content-Type", "text/xml; charset=\"utf-8\"".to_owned()),
+            (
+                "SOAPAction",
+                format!("\"{}#{}\"", self.service_type, action),
+            ),
+        ];
+
+        let (status, resp, _) = http_request(&self.control_url, "POST", &headers, &body).await?;
+        Ok((status, resp))
+    }
+
+    fn soap_error_code(resp: &str) -> Option<u16> {
+        xml_tag_text(resp, "errorCode")?.parse().ok()
+    }
+
+    fn soap_error(action: &str, status: u16, resp: &str) -> Error {
+        anyhow::anyhow!(
+            "upnp {} failed, status: {}, error code: {:?}",
+            action,
+            status,
+            Self::soap_error_code(resp)
+        )
+        .into()
+    }
+
+    async fn soap_call(&self, action: &str, args: &[(&str, String)]) -> Result<String, Error> {
+        let (status, resp) = self.soap_request(action, args).await?;
+        if status != 200 {
+            return Err(Self::soap_error(action, status, &resp));
+        }
+        Ok(resp)
+    }
+
+    async fn get_external_ip(&self) -> Result<Ipv4Addr, Error> {
+        let resp = self.soap_call("GetExternalIPAddress", &[]).await?;
+        let ip = xml_tag_text(&resp, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .with_context(|| format!("invalid GetExternalIPAddress response: {}", resp))?;
+        Ok(ip)
+    }
+
+    fn protocol_str(protocol: MappingProtocol) -> String {
+        match protocol {
+            MappingProtocol::Tcp => "TCP".to_owned(),
+            MappingProtocol::Udp => "UDP".to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl PortMapper for IgdPortMapper {
+    fn name(&self) -> &'static str {
+        "upnp"
+    }
+
+    async fn add_mapping(
+        &self,
+        protocol: MappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> Result<SocketAddrV4, Error> {
+        let external_ip = self.get_external_ip().await?;
+
+        let args = |lease: u64| {
+            [
+                ("NewRemoteHost", "".to_owned()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", Self::protocol_str(protocol)),
+                ("NewInternalPort", internal_port.to_string()),
+                ("NewInternalClient", self.local_ip.to_string()),
+                ("NewEnabled", "1".to_owned()),
+                ("NewPortMappingDescription", "easytier".to_owned()),
+                ("NewLeaseDuration", lease.to_string()),
+            ]
+        };
+
+        let (status, resp) = self
+            .soap_request("AddPortMapping", &args(lifetime.as_secs()))
+            .await?;
+        if status != 200 {
+            if Self::soap_error_code(&resp) != Some(ONLY_PERMANENT_LEASES_SUPPORTED) {
+                return Err(Self::soap_error("AddPortMapping", status, &resp));
+            }
+            // permanent mapping will be removed when we exit
+            self.soap_call("AddPortMapping", &args(0)).await?;
+        }
+
+        Ok(SocketAddrV4::new(external_ip, external_port))
+    }
+
+    async fn remove_mapping(
+        &self,
+        protocol: MappingProtocol,
+        _internal_port: u16,
+        external_port: u16,
+    ) -> Result<(), Error> {
+        self.soap_call(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", "".to_owned()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", Self::protocol_str(protocol)),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        collections::HashSet,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UdpSocket},
+        task::JoinSet,
+    };
+
+    use crate::instance::port_mapping::{MappingProtocol, PortMapper};
+
+    use super::{parse_wan_service, xml_tag_text, IgdPortMapper};
+
+    const DEVICE_DESC: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+<controlURL>/ctl/L3F</controlURL>
+</service>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<controlURL>/ctl/IPConn</controlURL>
+</service>
+</serviceList>
+</device>
+</root>"#;
+
+    // answers ssdp search and soap requests like a home router.
+    pub struct FakeIgd {
+        ssdp_addr: SocketAddr,
+        // (protocol, external port)
+        mappings: Arc<Mutex<HashSet<(String, String)>>>,
+        _tasks: JoinSet<()>,
+    }
+
+    impl FakeIgd {
+        pub async fn start() -> Self {
+            Self::start_with(false).await
+        }
+
+        // permanent_only: reject mappings with a lease duration like some old routers
+        pub async fn start_with(permanent_only: bool) -> Self {
+            let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let http_addr = http.local_addr().unwrap();
+            let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let ssdp_addr = ssdp.local_addr().unwrap();
+            let mappings = Arc::new(Mutex::new(HashSet::new()));
+            let mut tasks = JoinSet::new();
+
+            tasks.spawn(async move {
+                let mut buf = [0u8; 2048];
+                while let Ok((len, addr)) = ssdp.recv_from(&mut buf).await {
+                    let req = String::from_utf8_lossy(&buf[..len]);
+                    assert!(req.starts_with("M-SEARCH"));
+                    let resp = format!(
+                        "HTTP/1.1 200 OK\r\n\
+                         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                         Location: http://{}/rootDesc.xml\r\n\r\n",
+                        http_addr
+                    );
+                    ssdp.send_to(resp.as_bytes(), addr).await.unwrap();
+                }
+            });
+
+            let m = mappings.clone();
+            tasks.spawn(async move {
+                while let Ok((mut stream, _)) = http.accept().await {
+                    let mut req = vec![];
+                    let mut buf = [0u8; 4096];
+                    // read until the whole body is received
+                    loop {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        req.extend_from_slice(&buf[..n]);
+                        let s = String::from_utf8_lossy(&req).to_string();
+                        if let Some((head, body)) = s.split_once("\r\n\r\n") {
+                            let len = head
+                                .lines()
+                                .find_map(|l| l.strip_prefix("Content-Length: "))
+                                .map(|l| l.parse::<usize>().unwrap())
+                                .unwrap_or(0);
+                            if body.len() >= len {
+                                break;
+                            }
+                        }
+                        if n == 0 {
+                            break;
+                        }
+                    }
+
+                    let req = String::from_utf8_lossy(&req).to_string();
+                    let body = if req.starts_with("GET /rootDesc.xml") {
+                        DEVICE_DESC.to_owned()
+                    } else if req.contains("#GetExternalIPAddress") {
+                        "<NewExternalIPAddress>1.2.3.4</NewExternalIPAddress>".to_owned()
+                    } else if req.contains("#AddPortMapping")
+                        && permanent_only
+                        && xml_tag_text(&req, "NewLeaseDuration") != Some("0")
+                    {
+                        let resp = "HTTP/1.0 500 Internal Server Error\r\n\r\n\
+                                    <s:Fault><detail><UPnPError><errorCode>725</errorCode>\
+                                    </UPnPError></detail></s:Fault>";
+                        stream.write_all(resp.as_bytes()).await.unwrap();
+                        continue;
+                    } else if req.contains("#AddPortMapping") {
+                        let proto = xml_tag_text(&req, "NewProtocol").unwrap().to_owned();
+                        let port = xml_tag_text(&req, "NewExternalPort").unwrap().to_owned();
+                        assert_eq!(xml_tag_text(&req, "NewInternalClient"), Some("127.0.0.1"));
+                        m.lock().unwrap().insert((proto, port));
+                        "".to_owned()
+                    } else if req.contains("#DeletePortMapping") {
+                        let proto = xml_tag_text(&req, "NewProtocol").unwrap().to_owned();
+                        let port = xml_tag_text(&req, "NewExternalPort").unwrap().to_owned();
+                        m.lock().unwrap().remove(&(proto, port));
+                        "".to_owned()
+                    } else {
+                        panic!("unexpected request: {}", req);
+                    };
+                    let resp = format!("HTTP/1.0 200 OK\r\n\r\n{}", body);
+                    stream.write_all(resp.as_bytes()).await.unwrap();
+                }
+            });
+
+            Self {
+                ssdp_addr,
+                mappings,
+                _tasks: tasks,
+            }
+        }
+
+        pub fn ssdp_addr(&self) -> SocketAddr {
+            self.ssdp_addr
+        }
+
+        pub fn mapping_count(&self) -> usize {
+            self.mappings.lock().unwrap().len()
+        }
+    }
+
+    #[test]
+    fn parse_device_description() {
+        assert_eq!(
+            parse_wan_service(DEVICE_DESC),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1".to_owned(),
+                "/ctl/IPConn".to_owned()
+            ))
+        );
+        assert_eq!(parse_wan_service("<root></root>"), None);
+    }
+
+    #[tokio::test]
+    async fn fallback_to_permanent_mapping() {
+        let igd = FakeIgd::start_with(true).await;
+        let mapper = IgdPortMapper::discover(igd.ssdp_addr()).await.unwrap();
+        let addr = mapper
+            .add_mapping(
+                MappingProtocol::Tcp,
+                11010,
+                11010,
+                std::time::Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+        assert_eq!(addr.to_string(), "1.2.3.4:11010");
+        assert_eq!(igd.mapping_count(), 1);
+    }
+}
@@ -0,0 +1,499 @@
+// a minimal stun server (rfc 5389 binding with rfc 5780 change-request), so nodes with public ip
+// can serve nat type detection for other nodes without relying on public stun servers.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use bytecodec::{DecodeExt, EncodeExt};
+use stun_codec::{
+    rfc5389::{
+        attributes::{MappedAddress, XorMappedAddress},
+        methods::BINDING,
+    },
+    rfc5780::attributes::{OtherAddress, ResponseOrigin},
+    Message, MessageClass, MessageDecoder, MessageEncoder,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinSet,
+};
+use tracing::Instrument;
+
+use crate::{
+    common::{
+        error::Error, global_ctx::ArcGlobalCtx, netns::NetNS, stun::StunInfoCollectorTrait,
+        stun_codec_ext::*, PeerId,
+    },
+    peers::peer_manager::PeerManager,
+    rpc::{NatType, PeerConnInfo},
+};
+
+const PEER_STUN_SERVER_UPDATE_INTERVAL_SEC: u64 = 30;
+const TCP_STUN_TIMEOUT_SEC: u64 = 5;
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // shared address space (rfc 6598), used by carrier grade nat
+        || (ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64))
+}
+
+fn encode_message(msg: Message<Attribute>) -> Result<Vec<u8>, Error> {
+    Ok(MessageEncoder::new()
+        .encode_into_bytes(msg)
+        .with_context(|| "encode stun message")?)
+}
+
+fn decode_binding_request(buf: &[u8]) -> Option<Message<Attribute>> {
+    let msg = MessageDecoder::<Attribute>::new()
+        .decode_from_bytes(buf)
+        .ok()?
+        .ok()?;
+    if msg.class() != MessageClass::Request || msg.method() != BINDING {
+        return None;
+    }
+    Some(msg)
+}
+
+// ips and ports a server answers on, the alternate ones are used for change-request.
+#[derive(Debug, Clone, Copy)]
+struct StunServerAddrs {
+    primary_ip: Ipv4Addr,
+    // none if we have only one ip, requests asking to change ip are ignored then.
+    alternate_ip: Option<Ipv4Addr>,
+    primary_port: u16,
+    alternate_port: u16,
+}
+
+impl StunServerAddrs {
+    fn all(&self) -> Vec<SocketAddr> {
+        let mut ret = vec![];
+        for ip in std::iter::once(self.primary_ip).chain(self.alternate_ip) {
+            for port in [self.primary_port, self.alternate_port] {
+                ret.push(SocketAddr::new(ip.into(), port));
+            }
+        }
+        ret
+    }
+
+    fn other(&self, local: SocketAddr, change_ip: bool, change_port: bool) -> Option<SocketAddr> {
+        let ip = match (change_ip, self.alternate_ip) {
+            (false, _) => local.ip(),
+            (true, Some(alternate_ip)) if local.ip() == IpAddr::from(alternate_ip) => {
+                self.primary_ip.into()
+            }
+            (true, Some(alternate_ip)) => alternate_ip.into(),
+            (true, None) => return None,
+        };
+        let port = match (change_port, local.port() == self.primary_port) {
+            (false, _) => local.port(),
+            (true, true) => self.alternate_port,
+            (true, false) => self.primary_port,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+}
+
+pub struct StunServer {
+    addrs: StunServerAddrs,
+    tasks: JoinSet<()>,
+}
+
+impl StunServer {
+    // listen on port and port + 1, on the two given ips if there are, or on all ips otherwise.
+    // port must be below 65535.
+    pub fn new(ips: Vec<Ipv4Addr>, port: u16) -> Self {
+        let (primary_ip, alternate_ip) = if ips.len() >= 2 {
+            (ips[0], Some(ips[1]))
+        } else {
+            (Ipv4Addr::UNSPECIFIED, None)
+        };
+        Self {
+            addrs: StunServerAddrs {
+                primary_ip,
+                alternate_ip,
+                primary_port: port,
+                alternate_port: port + 1,
+            },
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub async fn start(&mut self, net_ns: &NetNS) -> Result<(), Error> {
+        let mut udp_sockets = HashMap::new();
+        let mut tcp_listeners = vec![];
+        {
+            let _g = net_ns.guard();
+            for addr in self.addrs.all() {
+                let socket = std::net::UdpSocket::bind(addr)
+                    .with_context(|| format!("bind stun server udp socket {}", addr))?;
+                socket.set_nonblocking(true)?;
+                udp_sockets.insert(addr, Arc::new(UdpSocket::from_std(socket)?));
+
+                // most clients only ask the mapped address over tcp, so serve on the primary port
+                if addr.port() == self.addrs.primary_port {
+                    let listener = std::net::TcpListener::bind(addr)
+                        .with_context(|| format!("bind stun server tcp listener {}", addr))?;
+                    listener.set_nonblocking(true)?;
+                    tcp_listeners.push(TcpListener::from_std(listener)?);
+                }
+            }
+        }
+
+        let udp_sockets = Arc::new(udp_sockets);
+        for (local, socket) in udp_sockets.iter() {
+            let local = *local;
+            let socket = socket.clone();
+            let udp_sockets = udp_sockets.clone();
+            let addrs = self.addrs;
+            self.tasks.spawn(
+                async move {
+                    let mut buf = [0u8; 1500];
+                    loop {
+                        let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+                            tracing::error!("stun server udp recv_from error");
+                            break;
+                        };
+                        if let Err(e) =
+                            Self::handle_udp_request(&buf[..len], from, local, &addrs, &udp_sockets)
+                                .await
+                        {
+                            tracing::debug!(?e, ?from, "handle stun request failed");
+                        }
+                    }
+                }
+                .instrument(tracing::info_span!("stun_server_udp", ?local)),
+            );
+        }
+
+        for listener in tcp_listeners {
+            let addrs = self.addrs;
+            self.tasks.spawn(
+                async move {
+                    let mut tasks = JoinSet::new();
+                    loop {
+                        let (stream, from) = tokio::select! {
+                            ret = listener.accept() => match ret {
+                                Ok(ret) => ret,
+                                Err(e) => {
+                                    tracing::error!(?e, "stun server tcp accept error");
+                                    break;
+                                }
+                            },
+                            Some(_) = tasks.join_next() => continue,
+                        };
+                        tasks.spawn(async move {
+                            let ret = tokio::time::timeout(
+                                Duration::from_secs(TCP_STUN_TIMEOUT_SEC),
+                                Self::handle_tcp_conn(stream, from, addrs),
+                            )
+                            .await;
+                            tracing::debug!(?ret, ?from, "stun tcp connection finished");
+                        });
+                    }
+                }
+                .instrument(tracing::info_span!("stun_server_tcp")),
+            );
+        }
+
+        tracing::info!(addrs = ?self.addrs, "stun server started");
+        Ok(())
+    }
+
+    fn build_response(
+        req: &Message<Attribute>,
+        from: SocketAddr,
+        response_origin: SocketAddr,
+        addrs: &StunServerAddrs,
+    ) -> Message<Attribute> {
+        let mut resp =
+            Message::<Attribute>::new(MessageClass::SuccessResponse, BINDING, req.transaction_id());
+        resp.add_attribute(XorMappedAddress::new(from));
+        // for rfc 3489 clients
+        resp.add_attribute(MappedAddress::new(from));
+        // the addresses are meaningless if we listen on unspecified ip
+        if !response_origin.ip().is_unspecified() {
+            resp.add_attribute(ResponseOrigin::new(response_origin));
+            if let Some(other) = addrs.other(response_origin, true, true) {
+                resp.add_attribute(OtherAddress::new(other));
+            }
+        }
+        resp
+    }
+
+    async fn handle_udp_request(
+        buf: &[u8],
+        from: SocketAddr,
+        local: SocketAddr,
+        addrs: &StunServerAddrs,
+        udp_sockets: &HashMap<SocketAddr, Arc<UdpSocket>>,
+    ) -> Result<(), Error> {
+        let Some(req) = decode_binding_request(buf) else {
+            return Ok(());
+        };
+
+        let (change_ip, change_port) = req
+            .attributes()
+            .find_map(|x| match x {
+                Attribute::ChangeRequest(c) => Some((c.ip(), c.port())),
+                _ => None,
+            })
+            .unwrap_or((false, false));
+        let Some(response_origin) = addrs.other(local, change_ip, change_port) else {
+            // cannot change ip, no response lets the client know the ip is not changed.
+            return Ok(());
+        };
+
+        let resp = Self::build_response(&req, from, response_origin, addrs);
+        udp_sockets
+            .get(&response_origin)
+            .ok_or(Error::NotFound)?
+            .send_to(&encode_message(resp)?, from)
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_tcp_conn(
+        mut stream: TcpStream,
+        from: SocketAddr,
+        addrs: StunServerAddrs,
+    ) -> Result<(), Error> {
+        let local = stream.local_addr()?;
+        loop {
+            // stun message header is 20 bytes, the length field does not include the header
+            let mut buf = vec![0u8; 20];
+            stream.read_exact(&mut buf).await?;
+            let body_len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            buf.resize(20 + body_len, 0);
+            stream.read_exact(&mut buf[20..]).await?;
+
+            let Some(req) = decode_binding_request(&buf) else {
+                return Err(anyhow::anyhow!("invalid stun request").into());
+            };
+            let resp = Self::build_response(&req, from, local, &addrs);
+            stream.write_all(&encode_message(resp)?).await?;
+        }
+    }
+}
+
+// use the ip we connect to a peer with and the stun port it announces as a stun server.
+fn peer_stun_server_addr(conns: &[PeerConnInfo], stun_server_port: u16) -> Option<SocketAddr> {
+    conns.iter().find_map(|c| {
+        let remote: url::Url = c.tunnel.as_ref()?.remote_addr.parse().ok()?;
+        let ip: Ipv4Addr = remote.host_str()?.parse().ok()?;
+        is_public_ipv4(&ip).then(|| SocketAddr::new(ip.into(), stun_server_port))
+    })
+}
+
+pub struct StunServerManager {
+    global_ctx: ArcGlobalCtx,
+    peer_mgr: Arc<PeerManager>,
+    tasks: JoinSet<()>,
+}
+
+impl StunServerManager {
+    pub fn new(global_ctx: ArcGlobalCtx, peer_mgr: Arc<PeerManager>) -> Self {
+        Self {
+            global_ctx,
+            peer_mgr,
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<(), Error> {
+        let stun_server_port = self.global_ctx.get_flags().stun_server_port;
+        if stun_server_port == u16::MAX {
+            return Err(anyhow::anyhow!(
+                "stun server port must be below 65535, the next port is used too"
+            )
+            .into());
+        }
+
+        if stun_server_port != 0 {
+            let global_ctx = self.global_ctx.clone();
+            self.tasks.spawn(async move {
+                let ips = loop {
+                    if let Some(ips) = Self::detect_public_ips(&global_ctx).await {
+                        break ips;
+                    }
+                    tokio::time::sleep(Duration::from_secs(PEER_STUN_SERVER_UPDATE_INTERVAL_SEC))
+                        .await;
+                };
+                let mut server = StunServer::new(ips, stun_server_port);
+                if let Err(e) = server.start(&global_ctx.net_ns).await {
+                    tracing::error!(?e, "start stun server failed");
+                    return;
+                }
+                global_ctx.set_stun_server_port(stun_server_port);
+                // the server stops when it is dropped
+                std::future::pending::<()>().await;
+            });
+        }
+
+        let global_ctx = self.global_ctx.clone();
+        let peer_mgr = self.peer_mgr.clone();
+        self.tasks.spawn(async move {
+            loop {
+                let servers = Self::collect_peer_stun_servers(&peer_mgr).await;
+                global_ctx
+                    .get_stun_info_collector()
+                    .set_peer_stun_servers(servers);
+                tokio::time::sleep(Duration::from_secs(PEER_STUN_SERVER_UPDATE_INTERVAL_SEC)).await;
+            }
+        });
+
+        Ok(())
+    }
+
+    // behind a nat the server would report wrong mapped addresses, so it only starts once we
+    // have a public ip, either on an interface or mapped one to one as detected by stun.
+    async fn detect_public_ips(global_ctx: &ArcGlobalCtx) -> Option<Vec<Ipv4Addr>> {
+        let ips = global_ctx
+            .get_ip_collector()
+            .collect_ip_addrs()
+            .await
+            .interface_ipv4s
+            .iter()
+            .filter_map(|ip| ip.parse::<Ipv4Addr>().ok())
+            .filter(is_public_ipv4)
+            .collect::<Vec<_>>();
+        if !ips.is_empty() {
+            return Some(ips);
+        }
+
+        let udp_nat_type = global_ctx
+            .get_stun_info_collector()
+            .get_stun_info()
+            .udp_nat_type;
+        if udp_nat_type == NatType::OpenInternet as i32 || udp_nat_type == NatType::NoPat as i32 {
+            return Some(vec![]);
+        }
+        tracing::debug!(?udp_nat_type, "no public ip, stun server not started");
+        None
+    }
+
+    async fn collect_peer_stun_servers(peer_mgr: &PeerManager) -> Vec<SocketAddr> {
+        let mut ret = vec![];
+        for route in peer_mgr.list_routes().await {
+            if route.stun_server_port == 0 {
+                continue;
+            }
+            let peer_id: PeerId = route.peer_id;
+            let Some(conns) = peer_mgr.get_peer_map().list_peer_conns(peer_id).await else {
+                continue;
+            };
+            if let Some(addr) = peer_stun_server_addr(&conns, route.stun_server_port as u16) {
+                ret.push(addr);
+            }
+        }
+        ret.sort();
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        common::{
+            config::ConfigLoader,
+            netns::NetNS,
+            stun::{TcpNatTypeDetector, UdpNatTypeDetector},
+        },
+        peers::tests::create_mock_peer_manager,
+        rpc::{NatType, PeerConnInfo, TunnelInfo},
+    };
+
+    use super::{peer_stun_server_addr, StunServer, StunServerManager};
+
+    #[tokio::test]
+    async fn stun_server_nat_type_detect() {
+        let mut server = StunServer::new(
+            vec![Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2)],
+            13478,
+        );
+        server.start(&NetNS::new(None)).await.unwrap();
+
+        // the client is not behind nat, but udp binds on unspecified ip so looks like no pat.
+        let detector = UdpNatTypeDetector::new(
+            vec!["127.0.0.1:13478".to_string(), "127.0.0.2:13478".to_string()],
+            1,
+        );
+        let ret = detector.detect_nat_type(0).await.unwrap();
+        assert_eq!(ret.nat_type(), NatType::NoPat);
+
+        let detector = TcpNatTypeDetector::new(
+            NetNS::new(None),
+            vec![
+                "127.0.0.1:13478".parse().unwrap(),
+                "127.0.0.2:13478".parse().unwrap(),
+            ],
+        );
+        // tcp knows the local ip each connection was made from
+        let ret = detector.detect_nat_type().await.unwrap();
+        assert_eq!(ret.nat_type(), NatType::OpenInternet);
+    }
+
+    #[tokio::test]
+    async fn stun_server_single_ip() {
+        let mut server = StunServer::new(vec![], 13480);
+        server.start(&NetNS::new(None)).await.unwrap();
+
+        // without alternate ip, the filtering behavior can only be partially detected.
+        let detector = UdpNatTypeDetector::new(
+            vec!["127.0.0.1:13480".to_string(), "127.0.0.1:13481".to_string()],
+            1,
+        );
+        let ret = detector.detect_nat_type(0).await.unwrap();
+        assert_eq!(ret.nat_type(), NatType::Restricted);
+    }
+
+    #[test]
+    fn peer_stun_server_only_public_ip() {
+        let conn = |remote_addr: &str| PeerConnInfo {
+            tunnel: Some(TunnelInfo {
+                tunnel_type: "tcp".to_string(),
+                local_addr: "tcp://0.0.0.0:11010".to_string(),
+                remote_addr: remote_addr.to_string(),
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            peer_stun_server_addr(&[conn("tcp://192.168.1.1:11010")], 3478),
+            None
+        );
+        assert_eq!(
+            peer_stun_server_addr(
+                &[conn("tcp://192.168.1.1:11010"), conn("udp://8.8.8.8:11010")],
+                3478
+            ),
+            Some("8.8.8.8:3478".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn stun_server_rejects_last_port() {
+        let peer_mgr = create_mock_peer_manager().await;
+        let global_ctx = peer_mgr.get_global_ctx();
+        let mut f = global_ctx.get_flags();
+        f.stun_server_port = u16::MAX;
+        global_ctx.config.set_flags(f);
+
+        let mut manager = StunServerManager::new(global_ctx.clone(), peer_mgr);
+        assert!(manager.run().await.is_err());
+        assert_eq!(0, global_ctx.get_stun_server_port());
+    }
+}
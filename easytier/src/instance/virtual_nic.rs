@@ -1,6 +1,6 @@
 use std::{
     io,
-    net::Ipv4Addr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
     sync::{Arc, Weak},
     task::{Context, Poll},
@@ -8,10 +8,12 @@ use std::{
 
 use crate::{
     common::{
+        config::IPV6_OVERLAY_PREFIX_LEN,
         error::Error,
         global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
         ifcfg::{IfConfiger, IfConfiguerTrait},
     },
+    instance::dns_server::{dns_listen_addr, overlay_domain},
     peers::{peer_manager::PeerManager, PacketRecvChanReceiver},
     tunnel::{
         common::{reserve_buf, FramedWriter, TunnelWrapper, ZCPacketToBytes},
@@ -24,10 +26,10 @@ use byteorder::WriteBytesExt as _;
 use bytes::{BufMut, BytesMut};
 use futures::{lock::BiLock, ready, SinkExt, Stream, StreamExt};
 use pin_project_lite::pin_project;
-use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::{ipv4::Ipv4Packet, ipv6::Ipv6Packet};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
-    sync::Mutex,
+    sync::{broadcast::error::RecvError, Mutex},
     task::JoinSet,
 };
 use tokio_util::bytes::Bytes;
@@ -419,11 +421,7 @@ impl VirtualNic {
 
         let dev = AsyncDevice::new(dev)?;
 
-        let flags = self.global_ctx.config.get_flags();
-        let mut mtu_in_config = flags.mtu;
-        if flags.enable_encryption {
-            mtu_in_config -= 20;
-        }
+        let mtu_in_config = self.global_ctx.get_nic_mtu();
         {
             // set mtu by ourselves, rust-tun does not handle it correctly on windows
             let _g = self.global_ctx.net_ns.guard();
@@ -465,6 +463,14 @@ impl VirtualNic {
         Ok(())
     }
 
+    pub async fn remove_route(&self, address: Ipv4Addr, cidr: u8) -> Result<(), Error> {
+        let _g = self.global_ctx.net_ns.guard();
+        self.ifcfg
+            .remove_ipv4_route(self.ifname(), address, cidr)
+            .await?;
+        Ok(())
+    }
+
     pub async fn remove_ip(&self, ip: Option<Ipv4Addr>) -> Result<(), Error> {
         let _g = self.global_ctx.net_ns.guard();
         self.ifcfg.remove_ip(self.ifname(), ip).await?;
@@ -479,6 +485,28 @@ impl VirtualNic {
         Ok(())
     }
 
+    pub async fn add_ipv6_ip(&self, ip: Ipv6Addr, cidr: u8) -> Result<(), Error> {
+        let _g = self.global_ctx.net_ns.guard();
+        self.ifcfg.add_ipv6_ip(self.ifname(), ip, cidr).await?;
+        Ok(())
+    }
+
+    pub async fn add_ipv6_route(&self, address: Ipv6Addr, cidr: u8) -> Result<(), Error> {
+        let _g = self.global_ctx.net_ns.guard();
+        self.ifcfg
+            .add_ipv6_route(self.ifname(), address, cidr)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_dns_domain(&self, server: SocketAddr, domain: &str) -> Result<(), Error> {
+        let _g = self.global_ctx.net_ns.guard();
+        self.ifcfg
+            .set_dns_domain(self.ifname(), server, domain)
+            .await?;
+        Ok(())
+    }
+
     pub fn get_ifcfg(&self) -> impl IfConfiguerTrait {
         IfConfiger {}
     }
@@ -509,17 +537,96 @@ impl NicCtx {
     }
 
     async fn assign_ipv4_to_tun_device(&self, ipv4_addr: Ipv4Addr) -> Result<(), Error> {
+        let network_length = self.global_ctx.get_network_length();
         let nic = self.nic.lock().await;
         nic.link_up().await?;
         nic.remove_ip(None).await?;
-        nic.add_ip(ipv4_addr, 24).await?;
+        nic.add_ip(ipv4_addr, network_length as i32).await?;
+        #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+        {
+            nic.add_route(ipv4_addr, network_length).await?;
+        }
+        Ok(())
+    }
+
+    // a network length learned from peers after the device is up replaces the address prefix
+    fn run_network_length_updater(&mut self, ipv4_addr: Ipv4Addr) {
+        let global_ctx = self.global_ctx.clone();
+        let nic = self.nic.clone();
+        let mut network_length = global_ctx.get_network_length();
+        let mut event_recv = global_ctx.subscribe();
+        self.tasks.spawn(async move {
+            while !matches!(event_recv.recv().await, Err(RecvError::Closed)) {
+                let cur_network_length = global_ctx.get_network_length();
+                if cur_network_length == network_length {
+                    continue;
+                }
+                #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+                let old_network_length = network_length;
+                network_length = cur_network_length;
+
+                let nic = nic.lock().await;
+                let ret = async {
+                    nic.remove_ip(Some(ipv4_addr)).await?;
+                    nic.add_ip(ipv4_addr, network_length as i32).await?;
+                    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+                    {
+                        let _ = nic.remove_route(ipv4_addr, old_network_length).await;
+                        nic.add_route(ipv4_addr, network_length).await?;
+                    }
+                    Ok::<_, Error>(())
+                }
+                .await;
+                tracing::info!(
+                    ?ipv4_addr,
+                    network_length,
+                    ?ret,
+                    "network length of tun device changed"
+                );
+            }
+        });
+    }
+
+    async fn assign_ipv6_to_tun_device(&self, ipv6_addr: Ipv6Addr) -> Result<(), Error> {
+        let nic = self.nic.lock().await;
+        nic.link_up().await?;
+        nic.add_ipv6_ip(ipv6_addr, IPV6_OVERLAY_PREFIX_LEN).await?;
         #[cfg(any(target_os = "macos", target_os = "freebsd"))]
         {
-            nic.add_route(ipv4_addr, 24).await?;
+            nic.add_ipv6_route(ipv6_addr, IPV6_OVERLAY_PREFIX_LEN)
+                .await?;
         }
         Ok(())
     }
 
+    // a dns listen addr outside the virtual network is added to the tun device so we can bind it
+    async fn setup_overlay_dns(&self) {
+        let Some(config) = self.global_ctx.config.get_dns_config() else {
+            return;
+        };
+        let Some(listen) = dns_listen_addr(&self.global_ctx, &config) else {
+            return;
+        };
+        let nic = self.nic.lock().await;
+        if let IpAddr::V4(ip) = listen.ip() {
+            if Some(ip) != self.global_ctx.get_ipv4() && !ip.is_loopback() && !ip.is_unspecified() {
+                if let Err(e) = nic.add_ip(ip, 32).await {
+                    tracing::warn!(?ip, ?e, "add dns listen addr to tun device failed");
+                }
+            }
+        }
+        if config.register_resolved {
+            let domain = overlay_domain(&self.global_ctx, &config);
+            if let Err(e) = nic.set_dns_domain(listen, &domain).await {
+                tracing::warn!(
+                    ?domain,
+                    ?e,
+                    "register overlay domain to systemd-resolved failed"
+                );
+            }
+        }
+    }
+
     async fn do_forward_nic_to_peers_ipv4(ret: ZCPacket, mgr: &PeerManager) {
         if let Some(ipv4) = Ipv4Packet::new(ret.payload()) {
             if ipv4.get_version() != 4 {
@@ -542,6 +649,30 @@ impl NicCtx {
         }
     }
 
+    async fn do_forward_nic_to_peers_ipv6(ret: ZCPacket, mgr: &PeerManager) {
+        if let Some(ipv6) = Ipv6Packet::new(ret.payload()) {
+            let dst_ipv6 = ipv6.get_destination();
+            tracing::trace!(
+                ?ret,
+                "[USER_PACKET] recv new ipv6 packet from tun device and forward to peers."
+            );
+
+            let send_ret = mgr.send_msg_ipv6(ret, dst_ipv6).await;
+            if send_ret.is_err() {
+                tracing::trace!(?send_ret, "[USER_PACKET] send_msg_ipv6 failed")
+            }
+        } else {
+            tracing::warn!(?ret, "[USER_PACKET] not ipv6 packet");
+        }
+    }
+
+    async fn do_forward_nic_to_peers_ip(ret: ZCPacket, mgr: &PeerManager) {
+        match ret.payload().first().map(|x| x >> 4) {
+            Some(6) => Self::do_forward_nic_to_peers_ipv6(ret, mgr).await,
+            _ => Self::do_forward_nic_to_peers_ipv4(ret, mgr).await,
+        }
+    }
+
     fn do_forward_nic_to_peers(
         &mut self,
         mut stream: Pin<Box<dyn ZCPacketStream>>,
@@ -556,7 +687,7 @@ impl NicCtx {
                     tracing::error!("read from nic failed: {:?}", ret);
                     break;
                 }
-                Self::do_forward_nic_to_peers_ipv4(ret.unwrap(), mgr.as_ref()).await;
+                Self::do_forward_nic_to_peers_ip(ret.unwrap(), mgr.as_ref()).await;
             }
         });
 
@@ -596,9 +727,9 @@ impl NicCtx {
             loop {
                 let mut proxy_cidrs = vec![];
                 let routes = peer_mgr.list_routes().await;
-                for r in routes {
+                for r in routes.into_iter().filter(|r| !r.segment_denied) {
                     for cidr in r.proxy_cidrs {
-                        let Ok(cidr) = cidr.parse::<cidr::Ipv4Cidr>() else {
+                        let Ok(cidr) = cidr.parse::<cidr::IpCidr>() else {
                             continue;
                         };
                         proxy_cidrs.push(cidr);
@@ -606,12 +737,12 @@ impl NicCtx {
                 }
                 // add vpn portal cidr to proxy_cidrs
                 if let Some(vpn_cfg) = global_ctx.config.get_vpn_portal_config() {
-                    proxy_cidrs.push(vpn_cfg.client_cidr);
+                    proxy_cidrs.push(vpn_cfg.client_cidr.into());
                 }
 
                 if let Some(routes) = global_ctx.config.get_routes() {
                     // if has manual routes, just override entire proxy_cidrs
-                    proxy_cidrs = routes;
+                    proxy_cidrs = routes.into_iter().map(Into::into).collect();
                 }
 
                 // if route is in cur_proxy_cidrs but not in proxy_cidrs, delete it.
@@ -621,13 +752,26 @@ impl NicCtx {
                     }
 
                     let _g = net_ns.guard();
-                    let ret = ifcfg
-                        .remove_ipv4_route(
-                            ifname.as_str(),
-                            cidr.first_address(),
-                            cidr.network_length(),
-                        )
-                        .await;
+                    let ret = match cidr {
+                        cidr::IpCidr::V4(cidr) => {
+                            ifcfg
+                                .remove_ipv4_route(
+                                    ifname.as_str(),
+                                    cidr.first_address(),
+                                    cidr.network_length(),
+                                )
+                                .await
+                        }
+                        cidr::IpCidr::V6(cidr) => {
+                            ifcfg
+                                .remove_ipv6_route(
+                                    ifname.as_str(),
+                                    cidr.first_address(),
+                                    cidr.network_length(),
+                                )
+                                .await
+                        }
+                    };
 
                     if ret.is_err() {
                         tracing::trace!(
@@ -643,13 +787,26 @@ impl NicCtx {
                         continue;
                     }
                     let _g = net_ns.guard();
-                    let ret = ifcfg
-                        .add_ipv4_route(
-                            ifname.as_str(),
-                            cidr.first_address(),
-                            cidr.network_length(),
-                        )
-                        .await;
+                    let ret = match cidr {
+                        cidr::IpCidr::V4(cidr) => {
+                            ifcfg
+                                .add_ipv4_route(
+                                    ifname.as_str(),
+                                    cidr.first_address(),
+                                    cidr.network_length(),
+                                )
+                                .await
+                        }
+                        cidr::IpCidr::V6(cidr) => {
+                            ifcfg
+                                .add_ipv6_route(
+                                    ifname.as_str(),
+                                    cidr.first_address(),
+                                    cidr.network_length(),
+                                )
+                                .await
+                        }
+                    };
 
                     if ret.is_err() {
                         tracing::trace!(
@@ -668,7 +825,7 @@ impl NicCtx {
         Ok(())
     }
 
-    pub async fn run(&mut self, ipv4_addr: Ipv4Addr) -> Result<(), Error> {
+    pub async fn run(&mut self, ipv4_addr: Option<Ipv4Addr>) -> Result<(), Error> {
         let tunnel = {
             let mut nic = self.nic.lock().await;
             match nic.create_dev().await {
@@ -690,7 +847,14 @@ impl NicCtx {
         self.do_forward_nic_to_peers(stream)?;
         self.do_forward_peers_to_nic(sink);
 
-        self.assign_ipv4_to_tun_device(ipv4_addr).await?;
+        if let Some(ipv4_addr) = ipv4_addr {
+            self.assign_ipv4_to_tun_device(ipv4_addr).await?;
+            self.run_network_length_updater(ipv4_addr);
+        }
+        if let Some(ipv6_addr) = self.global_ctx.get_ipv6() {
+            self.assign_ipv6_to_tun_device(ipv6_addr).await?;
+        }
+        self.setup_overlay_dns().await;
         self.run_proxy_cidrs_route_updater().await?;
 
         Ok(())
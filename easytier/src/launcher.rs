@@ -24,6 +24,8 @@ use tokio::task::JoinSet;
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct MyNodeInfo {
     pub virtual_ipv4: String,
+    // another node uses the same static ipv4
+    pub ipv4_conflict: bool,
     pub hostname: String,
     pub version: String,
     pub ips: GetIpListResponse,
@@ -139,6 +141,7 @@ impl EasyTierLauncher {
                         .get_ipv4()
                         .map(|x| x.to_string())
                         .unwrap_or_default(),
+                    ipv4_conflict: global_ctx_c.is_ipv4_conflicted(),
                     hostname: global_ctx_c.get_hostname(),
                     version: env!("CARGO_PKG_VERSION").to_string(),
                     ips: global_ctx_c.get_ip_collector().collect_ip_addrs().await,
@@ -171,6 +174,13 @@ impl EasyTierLauncher {
         instance.run().await?;
         stop_signal.notified().await;
 
+        instance
+            .get_port_mapping_manager()
+            .lock()
+            .await
+            .remove_all_mappings()
+            .await;
+
         tasks.abort_all();
         drop(tasks);
 
@@ -0,0 +1,356 @@
+// active health checks of exit nodes. each exit node serves a probe rpc that connects to its own
+// configured target from its own network, so an exit whose upstream is down reports unhealthy
+// even though it is still reachable in the route table. the caller cannot choose the target,
+// otherwise any peer could use exit nodes to scan their networks.
+
+use std::{
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::{net::TcpSocket, task::JoinSet};
+
+use crate::common::{constants, global_ctx::ArcGlobalCtx};
+
+use super::{peer_map::PeerMap, peer_rpc::PeerRpcManager};
+
+const PROBE_INTERVAL_MS: u64 = 5000;
+const PROBE_TIMEOUT_MS: u64 = 3000;
+// an exit node is marked unhealthy after this many probes failed in a row
+const FAILURE_THRESHOLD: u32 = 2;
+
+#[tarpc::service]
+pub trait ExitNodeProbeService {
+    // returns the tcp connect latency to the exit node's probe target in milliseconds, none if
+    // the exit node has no target
+    async fn probe() -> Result<Option<u32>, String>;
+}
+
+#[derive(Clone)]
+struct ExitNodeProbeServer {
+    global_ctx: ArcGlobalCtx,
+}
+
+#[tarpc::server]
+impl ExitNodeProbeService for ExitNodeProbeServer {
+    async fn probe(self, _: tarpc::context::Context) -> Result<Option<u32>, String> {
+        if !self.global_ctx.enable_exit_node() {
+            return Err("not an exit node".to_string());
+        }
+
+        // without its own target the exit node cannot tell whether its upstream works
+        let target = self.global_ctx.get_flags().exit_node_probe_target;
+        if target.is_empty() {
+            return Ok(None);
+        }
+
+        let addr = tokio::net::lookup_host(target.as_str())
+            .await
+            .map_err(|e| e.to_string())?
+            .find(|addr| addr.is_ipv4())
+            .ok_or_else(|| format!("no ipv4 address for {}", target))?;
+
+        let socket = {
+            let _g = self.global_ctx.net_ns.guard();
+            TcpSocket::new_v4().map_err(|e| e.to_string())?
+        };
+
+        let start = Instant::now();
+        tokio::time::timeout(
+            Duration::from_millis(PROBE_TIMEOUT_MS),
+            socket.connect(addr),
+        )
+        .await
+        .map_err(|_| "probe timeout".to_string())?
+        .map_err(|e| e.to_string())?;
+
+        Ok(Some(start.elapsed().as_millis() as u32))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExitNodeState {
+    pub healthy: bool,
+    pub latency_ms: u32,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    // the exit node has no probe target, it is still used
+    pub unknown: bool,
+}
+
+pub struct ExitNodeHealthChecker {
+    global_ctx: ArcGlobalCtx,
+    peers: Arc<PeerMap>,
+    peer_rpc_mgr: Arc<PeerRpcManager>,
+    states: Arc<DashMap<Ipv4Addr, ExitNodeState>>,
+    tasks: std::sync::Mutex<JoinSet<()>>,
+}
+
+impl ExitNodeHealthChecker {
+    pub fn new(
+        global_ctx: ArcGlobalCtx,
+        peers: Arc<PeerMap>,
+        peer_rpc_mgr: Arc<PeerRpcManager>,
+    ) -> Self {
+        Self {
+            global_ctx,
+            peers,
+            peer_rpc_mgr,
+            states: Arc::new(DashMap::new()),
+            tasks: std::sync::Mutex::new(JoinSet::new()),
+        }
+    }
+
+    fn probe_enabled(&self) -> bool {
+        self.global_ctx.get_flags().enable_exit_node_health_check
+    }
+
+    // exit nodes not probed yet are considered healthy, so traffic is not blocked on startup
+    pub fn is_healthy(&self, exit_node: &Ipv4Addr) -> bool {
+        !self.probe_enabled()
+            || self
+                .states
+                .get(exit_node)
+                .map(|s| s.healthy)
+                .unwrap_or(true)
+    }
+
+    pub fn get_state(&self, exit_node: &Ipv4Addr) -> Option<ExitNodeState> {
+        self.states.get(exit_node).map(|s| s.clone())
+    }
+
+    fn list_exit_nodes(global_ctx: &ArcGlobalCtx) -> Vec<Ipv4Addr> {
+        let mut exit_nodes = global_ctx.config.get_exit_nodes();
+        for rule in global_ctx.get_exit_policy().iter() {
+            if let Some(exit_node) = rule.exit_node {
+                if !exit_nodes.contains(&exit_node) {
+                    exit_nodes.push(exit_node);
+                }
+            }
+        }
+        exit_nodes
+    }
+
+    async fn probe_once(
+        global_ctx: &ArcGlobalCtx,
+        peers: &Arc<PeerMap>,
+        peer_rpc_mgr: &Arc<PeerRpcManager>,
+        states: &Arc<DashMap<Ipv4Addr, ExitNodeState>>,
+    ) {
+        let exit_nodes = Self::list_exit_nodes(global_ctx);
+        states.retain(|k, _| exit_nodes.contains(k));
+
+        for exit_node in exit_nodes {
+            let Some(peer_id) = peers.get_peer_id_by_ipv4(&exit_node).await else {
+                states.remove(&exit_node);
+                continue;
+            };
+
+            let ret = peer_rpc_mgr
+                .do_client_rpc_scoped(constants::EXIT_NODE_PROBE_SERVICE_ID, peer_id, |c| async {
+                    let client =
+                        ExitNodeProbeServiceClient::new(tarpc::client::Config::default(), c)
+                            .spawn();
+                    let mut ctx = tarpc::context::current();
+                    ctx.deadline =
+                        std::time::SystemTime::now() + Duration::from_millis(PROBE_TIMEOUT_MS * 2);
+                    client.probe(ctx).await
+                })
+                .await;
+
+            let ret = ret.unwrap_or_else(|e| Err(e.to_string()));
+            tracing::debug!(?exit_node, ?ret, "exit node probe result");
+
+            let mut state = states.entry(exit_node).or_insert_with(|| ExitNodeState {
+                healthy: true,
+                ..Default::default()
+            });
+            match ret {
+                Ok(latency_ms) => {
+                    state.healthy = true;
+                    state.unknown = latency_ms.is_none();
+                    state.latency_ms = latency_ms.unwrap_or_default();
+                    state.consecutive_failures = 0;
+                    state.last_error = None;
+                }
+                Err(e) => {
+                    state.unknown = false;
+                    state.consecutive_failures += 1;
+                    state.last_error = Some(e);
+                    if state.consecutive_failures >= FAILURE_THRESHOLD {
+                        state.healthy = false;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn run(&self) {
+        self.peer_rpc_mgr.run_service(
+            constants::EXIT_NODE_PROBE_SERVICE_ID,
+            ExitNodeProbeServer {
+                global_ctx: self.global_ctx.clone(),
+            }
+            .serve(),
+        );
+
+        if !self.probe_enabled() {
+            return;
+        }
+
+        let global_ctx = self.global_ctx.clone();
+        let peers = self.peers.clone();
+        let peer_rpc_mgr = self.peer_rpc_mgr.clone();
+        let states = self.states.clone();
+        self.tasks.lock().unwrap().spawn(async move {
+            loop {
+                Self::probe_once(&global_ctx, &peers, &peer_rpc_mgr, &states).await;
+                tokio::time::sleep(Duration::from_millis(PROBE_INTERVAL_MS)).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+
+    use crate::{
+        common::{
+            config::{ConfigLoader, Flags, TomlConfigLoader},
+            global_ctx::GlobalCtx,
+        },
+        connector::udp_hole_punch::tests::replace_stun_info_collector,
+        peers::{
+            peer_manager::{PeerManager, RouteAlgoType},
+            tests::connect_peer_manager,
+        },
+        rpc::NatType,
+        tunnel::common::tests::wait_for_condition,
+    };
+
+    pub async fn create_mock_peer_manager_with_exit_nodes(
+        flags: Flags,
+        ipv4: Option<Ipv4Addr>,
+        exit_nodes: Vec<Ipv4Addr>,
+    ) -> Arc<PeerManager> {
+        let config = TomlConfigLoader::default();
+        config.set_inst_name(format!("test_{}", config.get_id()));
+        config.set_flags(flags);
+        config.set_ipv4(ipv4);
+        config.set_exit_nodes(exit_nodes);
+        let (s, _r) = tokio::sync::mpsc::channel(1000);
+        let peer_mgr = Arc::new(PeerManager::new(
+            RouteAlgoType::Ospf,
+            Arc::new(GlobalCtx::new(config)),
+            s,
+        ));
+        replace_stun_info_collector(peer_mgr.clone(), NatType::Unknown);
+        peer_mgr.run().await.unwrap();
+        peer_mgr
+    }
+
+    async fn get_exit_node_health(
+        peer_mgr: &Arc<PeerManager>,
+        exit_node: &str,
+    ) -> Option<crate::rpc::ExitNodeHealth> {
+        peer_mgr
+            .list_routes()
+            .await
+            .into_iter()
+            .find(|r| r.ipv4_addr == exit_node)
+            .and_then(|r| r.exit_node_health)
+    }
+
+    #[tokio::test]
+    async fn exit_node_probe_health() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+
+        let exit_ip: Ipv4Addr = "10.126.126.2".parse().unwrap();
+        let exit_mgr = create_mock_peer_manager_with_exit_nodes(
+            Flags {
+                enable_exit_node: true,
+                exit_node_probe_target: target.to_string(),
+                ..Default::default()
+            },
+            Some(exit_ip),
+            vec![],
+        )
+        .await;
+        let client_mgr = create_mock_peer_manager_with_exit_nodes(
+            Flags {
+                // the client's own target is never sent to the exit node
+                exit_node_probe_target: "127.0.0.1:1".to_string(),
+                enable_exit_node_health_check: true,
+                ..Default::default()
+            },
+            None,
+            vec![exit_ip],
+        )
+        .await;
+        connect_peer_manager(client_mgr.clone(), exit_mgr.clone()).await;
+
+        wait_for_condition(
+            || async {
+                get_exit_node_health(&client_mgr, "10.126.126.2")
+                    .await
+                    .map(|h| h.healthy)
+                    .unwrap_or(false)
+            },
+            Duration::from_secs(15),
+        )
+        .await;
+
+        // the exit node cannot reach the target any more
+        drop(listener);
+        wait_for_condition(
+            || async {
+                get_exit_node_health(&client_mgr, "10.126.126.2")
+                    .await
+                    .map(|h| !h.healthy)
+                    .unwrap_or(false)
+            },
+            Duration::from_secs(20),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn exit_node_without_probe_target() {
+        let exit_ip: Ipv4Addr = "10.126.126.3".parse().unwrap();
+        let exit_mgr = create_mock_peer_manager_with_exit_nodes(
+            Flags {
+                enable_exit_node: true,
+                ..Default::default()
+            },
+            Some(exit_ip),
+            vec![],
+        )
+        .await;
+        let client_mgr = create_mock_peer_manager_with_exit_nodes(
+            Flags {
+                enable_exit_node_health_check: true,
+                ..Default::default()
+            },
+            None,
+            vec![exit_ip],
+        )
+        .await;
+        connect_peer_manager(client_mgr.clone(), exit_mgr.clone()).await;
+
+        // reported as unknown rather than healthy
+        wait_for_condition(
+            || async {
+                get_exit_node_health(&client_mgr, "10.126.126.3")
+                    .await
+                    .map(|h| h.unknown && !h.healthy)
+                    .unwrap_or(false)
+            },
+            Duration::from_secs(15),
+        )
+        .await;
+    }
+}
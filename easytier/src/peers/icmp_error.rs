@@ -0,0 +1,335 @@
+// lets the overlay behave like a chain of routers toward the inner ip packet: the hop count
+// carried in forward_counter is mapped to the inner ttl, and icmp errors are built for
+// packets that expire on a relay or have no route, so traceroute and mtr show the relays.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use pnet::packet::{
+    icmp::{self, IcmpPacket, IcmpTypes, MutableIcmpPacket},
+    icmpv6::{self, Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet},
+    ip::IpNextHeaderProtocols,
+    ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+    ipv6::{Ipv6Packet, MutableIpv6Packet},
+    Packet as _,
+};
+
+// relays drop packets whose forward counter exceeds this
+pub const MAX_FORWARD_COUNTER: u8 = 7;
+
+const ICMP_ERROR_TTL: u8 = 64;
+// icmpv6 errors must fit in the minimum ipv6 mtu
+const ICMPV6_ERROR_MAX_LEN: usize = 1280;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpErrorKind {
+    TimeExceeded,
+    // the destination is in the virtual network but no peer owns it
+    HostUnreachable,
+    // no peer announces a route to the destination
+    NetUnreachable,
+    // the packet doesn't fit the path mtu and must not be fragmented
+    PacketTooBig(u16),
+}
+
+fn inner_ttl(ip_packet: &[u8]) -> Option<u8> {
+    match ip_packet.first()? >> 4 {
+        4 => Some(Ipv4Packet::new(ip_packet)?.get_ttl()),
+        6 => Some(Ipv6Packet::new(ip_packet)?.get_hop_limit()),
+        _ => None,
+    }
+}
+
+// a packet with ttl n must expire on the n-th relay, so the counter starts that many hops
+// below the limit. packets with a large ttl start at 1 as before.
+pub fn initial_forward_counter(ip_packet: &[u8]) -> u8 {
+    let Some(ttl) = inner_ttl(ip_packet) else {
+        return 1;
+    };
+    (MAX_FORWARD_COUNTER + 2).saturating_sub(ttl.max(1)).max(1)
+}
+
+// called on the receiver, takes off one ttl for every relay the packet went through
+pub fn decrement_inner_ttl(ip_packet: &mut [u8], forward_counter: u8) {
+    let Some(ttl) = inner_ttl(ip_packet) else {
+        return;
+    };
+    let relays = forward_counter.saturating_sub(initial_forward_counter(ip_packet));
+    if relays == 0 {
+        return;
+    }
+    let ttl = ttl.saturating_sub(relays).max(1);
+    match ip_packet[0] >> 4 {
+        4 => {
+            let mut ipv4 = MutableIpv4Packet::new(ip_packet).unwrap();
+            ipv4.set_ttl(ttl);
+            ipv4.set_checksum(ipv4::checksum(&ipv4.to_immutable()));
+        }
+        _ => {
+            MutableIpv6Packet::new(ip_packet)
+                .unwrap()
+                .set_hop_limit(ttl);
+        }
+    }
+}
+
+fn is_icmp_error(ipv4: &Ipv4Packet) -> bool {
+    if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
+        return false;
+    }
+    let Some(icmp) = IcmpPacket::new(ipv4.payload()) else {
+        return true;
+    };
+    !matches!(
+        icmp.get_icmp_type(),
+        IcmpTypes::EchoRequest | IcmpTypes::EchoReply
+    )
+}
+
+fn build_icmpv4_error(kind: IcmpErrorKind, original: &[u8], src: Ipv4Addr) -> Option<Vec<u8>> {
+    let orig = Ipv4Packet::new(original)?;
+    let dst = orig.get_source();
+    // never answer errors, non-first fragments or packets we can't send back
+    if is_icmp_error(&orig)
+        || orig.get_fragment_offset() != 0
+        || dst.is_unspecified()
+        || dst.is_broadcast()
+        || dst.is_multicast()
+        || orig.get_destination().is_multicast()
+        || orig.get_destination().is_broadcast()
+    {
+        return None;
+    }
+
+    // the original ip header and the first 8 bytes of its payload
+    let quoted_len = (orig.get_header_length() as usize * 4 + 8).min(original.len());
+    let icmp_len = 8 + quoted_len;
+    let mut buf = vec![0u8; 20 + icmp_len];
+
+    let mut icmp_packet = MutableIcmpPacket::new(&mut buf[20..]).unwrap();
+    let mut rest_of_header = [0u8; 4];
+    let (icmp_type, code) = match kind {
+        IcmpErrorKind::TimeExceeded => (IcmpTypes::TimeExceeded, 0),
+        IcmpErrorKind::HostUnreachable => (IcmpTypes::DestinationUnreachable, 1),
+        IcmpErrorKind::NetUnreachable => (IcmpTypes::DestinationUnreachable, 0),
+        IcmpErrorKind::PacketTooBig(mtu) => {
+            // fragmentation needed, next-hop mtu in the low 16 bits (rfc 1191)
+            rest_of_header[2..].copy_from_slice(&mtu.to_be_bytes());
+            (IcmpTypes::DestinationUnreachable, 4)
+        }
+    };
+    icmp_packet.set_icmp_type(icmp_type);
+    icmp_packet.set_icmp_code(icmp::IcmpCode::new(code));
+    icmp_packet.set_payload(&[&rest_of_header, &original[..quoted_len]].concat());
+    icmp_packet.set_checksum(icmp::checksum(&icmp_packet.to_immutable()));
+
+    let mut ipv4 = MutableIpv4Packet::new(&mut buf).unwrap();
+    ipv4.set_version(4);
+    ipv4.set_header_length(5);
+    ipv4.set_total_length((20 + icmp_len) as u16);
+    ipv4.set_identification(rand::random());
+    ipv4.set_ttl(ICMP_ERROR_TTL);
+    ipv4.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    ipv4.set_source(src);
+    ipv4.set_destination(dst);
+    ipv4.set_checksum(ipv4::checksum(&ipv4.to_immutable()));
+
+    Some(buf)
+}
+
+fn build_icmpv6_error(kind: IcmpErrorKind, original: &[u8], src: Ipv6Addr) -> Option<Vec<u8>> {
+    let orig = Ipv6Packet::new(original)?;
+    let dst = orig.get_source();
+    if dst.is_unspecified() || dst.is_multicast() || orig.get_destination().is_multicast() {
+        return None;
+    }
+    if orig.get_next_header() == IpNextHeaderProtocols::Icmpv6 {
+        // error messages have types below 128
+        match Icmpv6Packet::new(orig.payload()) {
+            Some(icmp) if icmp.get_icmpv6_type().0 >= 128 => {}
+            _ => return None,
+        }
+    }
+
+    let quoted_len = original.len().min(ICMPV6_ERROR_MAX_LEN - 40 - 8);
+    let icmp_len = 8 + quoted_len;
+    let mut buf = vec![0u8; 40 + icmp_len];
+
+    let mut icmp_packet = MutableIcmpv6Packet::new(&mut buf[40..]).unwrap();
+    let mut rest_of_header = [0u8; 4];
+    let (icmp_type, code) = match kind {
+        IcmpErrorKind::TimeExceeded => (Icmpv6Types::TimeExceeded, 0),
+        IcmpErrorKind::HostUnreachable => (Icmpv6Types::DestinationUnreachable, 3),
+        IcmpErrorKind::NetUnreachable => (Icmpv6Types::DestinationUnreachable, 0),
+        IcmpErrorKind::PacketTooBig(mtu) => {
+            rest_of_header.copy_from_slice(&(mtu as u32).to_be_bytes());
+            (Icmpv6Types::PacketTooBig, 0)
+        }
+    };
+    icmp_packet.set_icmpv6_type(icmp_type);
+    icmp_packet.set_icmpv6_code(icmpv6::Icmpv6Code::new(code));
+    icmp_packet.set_payload(&[&rest_of_header, &original[..quoted_len]].concat());
+    icmp_packet.set_checksum(icmpv6::checksum(&icmp_packet.to_immutable(), &src, &dst));
+
+    let mut ipv6 = MutableIpv6Packet::new(&mut buf).unwrap();
+    ipv6.set_version(6);
+    ipv6.set_payload_length(icmp_len as u16);
+    ipv6.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    ipv6.set_hop_limit(ICMP_ERROR_TTL);
+    ipv6.set_source(src);
+    ipv6.set_destination(dst);
+
+    Some(buf)
+}
+
+// builds the icmp error for original, sourced from our virtual address of the same family.
+// returns none if no error should be sent for it.
+pub fn build_icmp_error(
+    kind: IcmpErrorKind,
+    original: &[u8],
+    my_ipv4: Option<Ipv4Addr>,
+    my_ipv6: Option<Ipv6Addr>,
+) -> Option<Vec<u8>> {
+    match original.first()? >> 4 {
+        4 => build_icmpv4_error(kind, original, my_ipv4?),
+        6 => build_icmpv6_error(kind, original, my_ipv6?),
+        _ => None,
+    }
+}
+
+// the inner destination of an ip packet, used to tell host from network unreachable
+pub fn inner_destination(ip_packet: &[u8]) -> Option<IpAddr> {
+    match ip_packet.first()? >> 4 {
+        4 => Some(Ipv4Packet::new(ip_packet)?.get_destination().into()),
+        6 => Some(Ipv6Packet::new(ip_packet)?.get_destination().into()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::udp::MutableUdpPacket;
+
+    use super::*;
+
+    fn build_udp_packet(ttl: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; 20 + 8 + 4];
+        let mut udp = MutableUdpPacket::new(&mut buf[20..]).unwrap();
+        udp.set_source(40000);
+        udp.set_destination(33434);
+        udp.set_length(12);
+        let mut ipv4 = MutableIpv4Packet::new(&mut buf).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(32);
+        ipv4.set_ttl(ttl);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ipv4.set_source("10.144.144.1".parse().unwrap());
+        ipv4.set_destination("10.144.144.3".parse().unwrap());
+        ipv4.set_checksum(ipv4::checksum(&ipv4.to_immutable()));
+        buf
+    }
+
+    #[test]
+    fn forward_counter_follows_ttl() {
+        // ttl 1 expires on the first relay, ttl 2 on the second
+        let packet = build_udp_packet(1);
+        assert_eq!(MAX_FORWARD_COUNTER + 1, initial_forward_counter(&packet));
+        let packet = build_udp_packet(2);
+        assert_eq!(MAX_FORWARD_COUNTER, initial_forward_counter(&packet));
+        let packet = build_udp_packet(64);
+        assert_eq!(1, initial_forward_counter(&packet));
+        assert_eq!(1, initial_forward_counter(&[0u8; 4]));
+
+        // one relay in between
+        let mut packet = build_udp_packet(64);
+        decrement_inner_ttl(&mut packet, 2);
+        let ipv4 = Ipv4Packet::new(&packet).unwrap();
+        assert_eq!(63, ipv4.get_ttl());
+        assert_eq!(ipv4::checksum(&ipv4), ipv4.get_checksum());
+
+        let mut packet = build_udp_packet(3);
+        let counter = initial_forward_counter(&packet) + 2;
+        decrement_inner_ttl(&mut packet, counter);
+        assert_eq!(1, Ipv4Packet::new(&packet).unwrap().get_ttl());
+
+        let mut packet = build_udp_packet(3);
+        let counter = initial_forward_counter(&packet);
+        decrement_inner_ttl(&mut packet, counter);
+        assert_eq!(3, Ipv4Packet::new(&packet).unwrap().get_ttl());
+    }
+
+    #[test]
+    fn icmp_error_for_expired_packet() {
+        let packet = build_udp_packet(1);
+        let relay_ip = "10.144.144.2".parse().unwrap();
+        let reply =
+            build_icmp_error(IcmpErrorKind::TimeExceeded, &packet, Some(relay_ip), None).unwrap();
+
+        let ipv4 = Ipv4Packet::new(&reply).unwrap();
+        assert_eq!(relay_ip, ipv4.get_source());
+        assert_eq!(
+            "10.144.144.1".parse::<Ipv4Addr>().unwrap(),
+            ipv4.get_destination()
+        );
+        assert_eq!(ipv4::checksum(&ipv4), ipv4.get_checksum());
+        let icmp_packet = IcmpPacket::new(ipv4.payload()).unwrap();
+        assert_eq!(IcmpTypes::TimeExceeded, icmp_packet.get_icmp_type());
+        assert_eq!(icmp::checksum(&icmp_packet), icmp_packet.get_checksum());
+        assert_eq!(&packet[..28], &icmp_packet.payload()[4..]);
+
+        // errors about errors are never sent
+        assert!(
+            build_icmp_error(IcmpErrorKind::HostUnreachable, &reply, Some(relay_ip), None)
+                .is_none()
+        );
+        // nor without an address of the right family
+        assert!(build_icmp_error(IcmpErrorKind::TimeExceeded, &packet, None, None).is_none());
+
+        let reply = build_icmp_error(
+            IcmpErrorKind::PacketTooBig(1360),
+            &packet,
+            Some(relay_ip),
+            None,
+        )
+        .unwrap();
+        let ipv4 = Ipv4Packet::new(&reply).unwrap();
+        let icmp_packet = IcmpPacket::new(ipv4.payload()).unwrap();
+        assert_eq!(
+            IcmpTypes::DestinationUnreachable,
+            icmp_packet.get_icmp_type()
+        );
+        assert_eq!(4, icmp_packet.get_icmp_code().0);
+        assert_eq!(&[0, 0, 0x05, 0x50], &icmp_packet.payload()[..4]);
+    }
+
+    #[test]
+    fn icmpv6_error_for_unreachable_packet() {
+        let src: Ipv6Addr = "fd00::1".parse().unwrap();
+        let dst: Ipv6Addr = "fd00::3".parse().unwrap();
+        let mut packet = vec![0u8; 40 + 8];
+        let mut ipv6 = MutableIpv6Packet::new(&mut packet).unwrap();
+        ipv6.set_version(6);
+        ipv6.set_payload_length(8);
+        ipv6.set_next_header(IpNextHeaderProtocols::Udp);
+        ipv6.set_hop_limit(64);
+        ipv6.set_source(src);
+        ipv6.set_destination(dst);
+
+        let my_ipv6 = "fd00::2".parse().unwrap();
+        let reply =
+            build_icmp_error(IcmpErrorKind::HostUnreachable, &packet, None, Some(my_ipv6)).unwrap();
+        let ipv6 = Ipv6Packet::new(&reply).unwrap();
+        assert_eq!(src, ipv6.get_destination());
+        let icmp_packet = Icmpv6Packet::new(ipv6.payload()).unwrap();
+        assert_eq!(
+            Icmpv6Types::DestinationUnreachable,
+            icmp_packet.get_icmpv6_type()
+        );
+        assert_eq!(3, icmp_packet.get_icmpv6_code().0);
+        assert_eq!(
+            icmpv6::checksum(&icmp_packet, &my_ipv6, &src),
+            icmp_packet.get_checksum()
+        );
+        assert_eq!(Some(IpAddr::V6(dst)), inner_destination(&packet));
+    }
+}
@@ -0,0 +1,181 @@
+// igmp snooping of packets coming from the tun device, used to learn which multicast groups
+// the local hosts joined so multicast is only forwarded to peers that need it.
+
+use std::{net::Ipv4Addr, time::Duration};
+
+use pnet::packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+    Packet as _,
+};
+
+// joined by every host and never reported, packets to it are always flooded
+pub const ALL_HOSTS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+
+// we query the local hosts this often so their memberships are refreshed before they expire
+pub const IGMP_QUERY_INTERVAL: Duration = Duration::from_secs(125);
+// in tenths of a second
+const IGMP_QUERY_RESPONSE_INTERVAL: u8 = 100;
+
+const IGMP_MEMBERSHIP_QUERY: u8 = 0x11;
+const IGMP_V1_MEMBERSHIP_REPORT: u8 = 0x12;
+const IGMP_V2_MEMBERSHIP_REPORT: u8 = 0x16;
+const IGMP_V2_LEAVE_GROUP: u8 = 0x17;
+const IGMP_V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+const MODE_IS_INCLUDE: u8 = 1;
+const MODE_IS_EXCLUDE: u8 = 2;
+const CHANGE_TO_INCLUDE_MODE: u8 = 3;
+const CHANGE_TO_EXCLUDE_MODE: u8 = 4;
+const ALLOW_NEW_SOURCES: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IgmpMembership {
+    Join(Ipv4Addr),
+    Leave(Ipv4Addr),
+}
+
+// returns the membership changes carried by an ipv4 packet, empty if it is not an igmp report
+pub fn parse_igmp_membership(ipv4_packet: &[u8]) -> Vec<IgmpMembership> {
+    let Some(ipv4) = Ipv4Packet::new(ipv4_packet) else {
+        return vec![];
+    };
+    if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Igmp {
+        return vec![];
+    }
+
+    let igmp = ipv4.payload();
+    if igmp.len() < 8 {
+        return vec![];
+    }
+    let group = || Ipv4Addr::new(igmp[4], igmp[5], igmp[6], igmp[7]);
+
+    match igmp[0] {
+        IGMP_V1_MEMBERSHIP_REPORT | IGMP_V2_MEMBERSHIP_REPORT => {
+            vec![IgmpMembership::Join(group())]
+        }
+        IGMP_V2_LEAVE_GROUP => vec![IgmpMembership::Leave(group())],
+        IGMP_V3_MEMBERSHIP_REPORT => parse_igmp_v3_records(igmp),
+        _ => vec![],
+    }
+}
+
+// an igmpv2 general query, hosts answer it with a report for every group they joined
+pub fn build_igmp_general_query(src: Ipv4Addr) -> Vec<u8> {
+    let mut buf = vec![0u8; 28];
+    buf[20] = IGMP_MEMBERSHIP_QUERY;
+    buf[21] = IGMP_QUERY_RESPONSE_INTERVAL;
+    let checksum = pnet::packet::util::checksum(&buf[20..], 1);
+    buf[22..24].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut ipv4 = MutableIpv4Packet::new(&mut buf).unwrap();
+    ipv4.set_version(4);
+    ipv4.set_header_length(5);
+    ipv4.set_total_length(28);
+    ipv4.set_ttl(1);
+    ipv4.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+    ipv4.set_source(src);
+    ipv4.set_destination(ALL_HOSTS_GROUP);
+    ipv4.set_checksum(ipv4::checksum(&ipv4.to_immutable()));
+    buf
+}
+
+fn parse_igmp_v3_records(igmp: &[u8]) -> Vec<IgmpMembership> {
+    let num_records = u16::from_be_bytes([igmp[6], igmp[7]]);
+    let mut ret = vec![];
+    let mut offset = 8;
+    for _ in 0..num_records {
+        // record type, aux data len, number of sources, multicast address
+        let Some(record) = igmp.get(offset..offset + 8) else {
+            break;
+        };
+        let num_sources = u16::from_be_bytes([record[2], record[3]]) as usize;
+        let group = Ipv4Addr::new(record[4], record[5], record[6], record[7]);
+        match record[0] {
+            // exclude mode means receiving from all but the listed sources
+            MODE_IS_EXCLUDE | CHANGE_TO_EXCLUDE_MODE => ret.push(IgmpMembership::Join(group)),
+            MODE_IS_INCLUDE | CHANGE_TO_INCLUDE_MODE if num_sources == 0 => {
+                ret.push(IgmpMembership::Leave(group))
+            }
+            MODE_IS_INCLUDE | CHANGE_TO_INCLUDE_MODE | ALLOW_NEW_SOURCES => {
+                ret.push(IgmpMembership::Join(group))
+            }
+            _ => {}
+        }
+        offset += 8 + num_sources * 4 + record[1] as usize * 4;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::{ip::IpNextHeaderProtocols, ipv4::MutableIpv4Packet};
+
+    use super::*;
+
+    fn build_igmp_packet(dst: Ipv4Addr, igmp: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; 20 + igmp.len()];
+        let mut ipv4 = MutableIpv4Packet::new(&mut buf).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length((20 + igmp.len()) as u16);
+        ipv4.set_ttl(1);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+        ipv4.set_source("10.126.126.1".parse().unwrap());
+        ipv4.set_destination(dst);
+        ipv4.set_payload(igmp);
+        buf
+    }
+
+    #[test]
+    fn igmp_v2_membership() {
+        let group = Ipv4Addr::new(239, 255, 255, 250);
+        let join = build_igmp_packet(group, &[0x16, 0, 0, 0, 239, 255, 255, 250]);
+        assert_eq!(
+            vec![IgmpMembership::Join(group)],
+            parse_igmp_membership(&join)
+        );
+
+        let leave = build_igmp_packet(
+            Ipv4Addr::new(224, 0, 0, 2),
+            &[0x17, 0, 0, 0, 239, 255, 255, 250],
+        );
+        assert_eq!(
+            vec![IgmpMembership::Leave(group)],
+            parse_igmp_membership(&leave)
+        );
+    }
+
+    #[test]
+    fn igmp_v3_membership() {
+        #[rustfmt::skip]
+        let report = [
+            0x22, 0, 0, 0, 0, 0, 0, 3,
+            // change to exclude, no sources: join
+            4, 0, 0, 0, 224, 0, 0, 251,
+            // change to include, no sources: leave
+            3, 0, 0, 0, 239, 1, 1, 1,
+            // allow new sources, one source
+            5, 0, 0, 1, 239, 2, 2, 2, 10, 0, 0, 1,
+        ];
+        let packet = build_igmp_packet(Ipv4Addr::new(224, 0, 0, 22), &report);
+        assert_eq!(
+            vec![
+                IgmpMembership::Join(Ipv4Addr::new(224, 0, 0, 251)),
+                IgmpMembership::Leave(Ipv4Addr::new(239, 1, 1, 1)),
+                IgmpMembership::Join(Ipv4Addr::new(239, 2, 2, 2)),
+            ],
+            parse_igmp_membership(&packet)
+        );
+
+        // a general query is not a report
+        let query = build_igmp_general_query(Ipv4Addr::new(10, 126, 126, 1));
+        assert!(parse_igmp_membership(&query).is_empty());
+        assert_eq!(0, pnet::packet::util::checksum(&query[20..], 5));
+
+        // not igmp
+        let mut udp = build_igmp_packet(Ipv4Addr::new(224, 0, 0, 251), &[0u8; 8]);
+        udp[9] = 17;
+        assert!(parse_igmp_membership(&udp).is_empty());
+    }
+}
@@ -1,3 +1,7 @@
+pub mod exit_node_health;
+pub mod icmp_error;
+pub mod igmp;
+pub mod path_mtu;
 pub mod peer;
 // pub mod peer_conn;
 pub mod peer_conn;
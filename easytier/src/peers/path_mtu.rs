@@ -0,0 +1,208 @@
+// keeps inner packets within the mtu of the path to a peer: tcp syns get their mss clamped so
+// both ends pick segments that fit, and packets that can't be fragmented are detected so the
+// sender can be told with an icmp fragmentation needed / packet too big error.
+
+use pnet::packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::Ipv4Packet,
+    ipv6::Ipv6Packet,
+    tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket},
+};
+
+const TCP_HEADER_LEN: usize = 20;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
+// true if the packet is larger than mtu and must not be fragmented on the way
+pub fn is_oversized(ip_packet: &[u8], mtu: u16) -> bool {
+    match ip_packet.first().map(|b| b >> 4) {
+        Some(4) => {
+            let Some(ipv4) = Ipv4Packet::new(ip_packet) else {
+                return false;
+            };
+            ipv4.get_total_length() > mtu && ipv4.get_flags() & 0b010 != 0
+        }
+        // ipv6 is never fragmented by routers
+        Some(6) => {
+            let Some(ipv6) = Ipv6Packet::new(ip_packet) else {
+                return false;
+            };
+            40 + ipv6.get_payload_length() as usize > mtu as usize
+        }
+        _ => false,
+    }
+}
+
+// lowers the mss option of a tcp syn so segments fit in mtu, returns true if it was changed
+pub fn clamp_tcp_mss(ip_packet: &mut [u8], mtu: u16) -> bool {
+    do_clamp_tcp_mss(ip_packet, mtu).unwrap_or(false)
+}
+
+// header length and total length of an unfragmented tcp packet
+fn tcp_packet_bounds(ip_packet: &[u8]) -> Option<(usize, usize)> {
+    let (ip_header_len, ip_len) = match ip_packet.first()? >> 4 {
+        4 => {
+            let ipv4 = Ipv4Packet::new(ip_packet)?;
+            if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Tcp
+                || ipv4.get_fragment_offset() != 0
+            {
+                return None;
+            }
+            (
+                ipv4.get_header_length() as usize * 4,
+                ipv4.get_total_length() as usize,
+            )
+        }
+        6 => {
+            let ipv6 = Ipv6Packet::new(ip_packet)?;
+            if ipv6.get_next_header() != IpNextHeaderProtocols::Tcp {
+                return None;
+            }
+            (40, 40 + ipv6.get_payload_length() as usize)
+        }
+        _ => return None,
+    };
+    if ip_len > ip_packet.len() || ip_header_len + TCP_HEADER_LEN > ip_len {
+        return None;
+    }
+    Some((ip_header_len, ip_len))
+}
+
+pub fn is_tcp_syn(ip_packet: &[u8]) -> bool {
+    let Some((ip_header_len, ip_len)) = tcp_packet_bounds(ip_packet) else {
+        return false;
+    };
+    TcpPacket::new(&ip_packet[ip_header_len..ip_len])
+        .map(|tcp_packet| tcp_packet.get_flags() & TcpFlags::SYN != 0)
+        .unwrap_or(false)
+}
+
+fn do_clamp_tcp_mss(ip_packet: &mut [u8], mtu: u16) -> Option<bool> {
+    let Some((ip_header_len, ip_len)) = tcp_packet_bounds(ip_packet) else {
+        return Some(false);
+    };
+
+    let tcp_segment = &mut ip_packet[ip_header_len..ip_len];
+    let tcp_packet = TcpPacket::new(tcp_segment)?;
+    if tcp_packet.get_flags() & TcpFlags::SYN == 0 {
+        return Some(false);
+    }
+    let data_offset = (tcp_packet.get_data_offset() as usize * 4).min(tcp_segment.len());
+
+    let max_mss = mtu.saturating_sub((ip_header_len + TCP_HEADER_LEN) as u16);
+    let mut changed = false;
+    let mut i = TCP_HEADER_LEN;
+    while i < data_offset {
+        match tcp_segment[i] {
+            TCP_OPTION_END => break,
+            TCP_OPTION_NOP => i += 1,
+            kind => {
+                let len = *tcp_segment.get(i + 1)? as usize;
+                if len < 2 || i + len > data_offset {
+                    break;
+                }
+                if kind == TCP_OPTION_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([tcp_segment[i + 2], tcp_segment[i + 3]]);
+                    if mss > max_mss {
+                        tcp_segment[i + 2..i + 4].copy_from_slice(&max_mss.to_be_bytes());
+                        changed = true;
+                    }
+                }
+                i += len;
+            }
+        }
+    }
+    if !changed {
+        return Some(false);
+    }
+
+    let checksum = match ip_packet[0] >> 4 {
+        4 => {
+            let ipv4 = Ipv4Packet::new(&ip_packet[..ip_len])?;
+            let tcp_packet = TcpPacket::new(&ip_packet[ip_header_len..ip_len])?;
+            tcp::ipv4_checksum(&tcp_packet, &ipv4.get_source(), &ipv4.get_destination())
+        }
+        _ => {
+            let ipv6 = Ipv6Packet::new(&ip_packet[..ip_len])?;
+            let tcp_packet = TcpPacket::new(&ip_packet[ip_header_len..ip_len])?;
+            tcp::ipv6_checksum(&tcp_packet, &ipv6.get_source(), &ipv6.get_destination())
+        }
+    };
+    MutableTcpPacket::new(&mut ip_packet[ip_header_len..ip_len])?.set_checksum(checksum);
+    Some(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::{
+        ipv4::{self, MutableIpv4Packet},
+        tcp::TcpOption,
+        Packet as _,
+    };
+
+    use super::*;
+
+    fn build_syn_packet(mss: u16, df: bool) -> Vec<u8> {
+        let mut buf = vec![0u8; 20 + 28];
+        let mut tcp_packet = MutableTcpPacket::new(&mut buf[20..]).unwrap();
+        tcp_packet.set_source(40000);
+        tcp_packet.set_destination(22);
+        tcp_packet.set_data_offset(7);
+        tcp_packet.set_flags(TcpFlags::SYN);
+        tcp_packet.set_options(&[TcpOption::mss(mss), TcpOption::nop(), TcpOption::wscale(7)]);
+        let mut ipv4 = MutableIpv4Packet::new(&mut buf).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(48);
+        ipv4.set_ttl(64);
+        ipv4.set_flags(if df { 0b010 } else { 0 });
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        ipv4.set_source("10.144.144.1".parse().unwrap());
+        ipv4.set_destination("10.144.144.2".parse().unwrap());
+        ipv4.set_checksum(ipv4::checksum(&ipv4.to_immutable()));
+        buf
+    }
+
+    fn get_mss(packet: &[u8]) -> u16 {
+        let tcp_packet = TcpPacket::new(&packet[20..]).unwrap();
+        let options = tcp_packet.get_options_raw();
+        u16::from_be_bytes([options[2], options[3]])
+    }
+
+    #[test]
+    fn clamp_mss_of_syn() {
+        let mut packet = build_syn_packet(1460, true);
+        assert!(clamp_tcp_mss(&mut packet, 1360));
+        assert_eq!(1320, get_mss(&packet));
+        let ipv4 = Ipv4Packet::new(&packet).unwrap();
+        let tcp_packet = TcpPacket::new(ipv4.payload()).unwrap();
+        assert_eq!(
+            tcp::ipv4_checksum(&tcp_packet, &ipv4.get_source(), &ipv4.get_destination()),
+            tcp_packet.get_checksum()
+        );
+
+        // smaller mss is kept
+        let mut packet = build_syn_packet(1200, true);
+        assert!(!clamp_tcp_mss(&mut packet, 1360));
+        assert_eq!(1200, get_mss(&packet));
+
+        // only syns carry the option
+        let mut packet = build_syn_packet(1460, true);
+        assert!(is_tcp_syn(&packet));
+        MutableTcpPacket::new(&mut packet[20..])
+            .unwrap()
+            .set_flags(TcpFlags::ACK);
+        assert!(!is_tcp_syn(&packet));
+        assert!(!clamp_tcp_mss(&mut packet, 1360));
+    }
+
+    #[test]
+    fn oversized_packet_needs_df() {
+        let packet = build_syn_packet(1460, true);
+        assert!(is_oversized(&packet, 40));
+        assert!(!is_oversized(&packet, 48));
+        let packet = build_syn_packet(1460, false);
+        assert!(!is_oversized(&packet, 40));
+    }
+}
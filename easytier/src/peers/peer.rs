@@ -136,6 +136,14 @@ impl Peer {
         Ok(())
     }
 
+    // any conn may carry the next packet, so the smallest probed one counts
+    pub fn get_path_mtu(&self) -> Option<u16> {
+        self.conns
+            .iter()
+            .filter_map(|conn| conn.get_path_mtu())
+            .min()
+    }
+
     pub async fn close_peer_conn(&self, conn_id: &PeerConnId) -> Result<(), Error> {
         let has_key = self.conns.contains_key(conn_id);
         if !has_key {
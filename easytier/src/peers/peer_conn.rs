@@ -30,10 +30,13 @@ use crate::{
         PeerId,
     },
     rpc::{HandshakeRequest, PeerConnInfo, PeerConnStats, TunnelInfo},
-    tunnel::{filter::{StatsRecorderTunnelFilter, TunnelFilter, TunnelWithFilter}, mpsc::{MpscTunnel, MpscTunnelSender}, packet_def::{PacketType, ZCPacket}, stats::{Throughput, WindowLatency}, Tunnel, TunnelError, ZCPacketStream},
+    tunnel::{filter::{StatsRecorderTunnelFilter, TunnelFilter, TunnelWithFilter}, mpsc::{MpscTunnel, MpscTunnelSender}, packet_def::{PacketType, ZCPacket, AES_GCM_ENCRYPTION_RESERVED}, stats::{Throughput, WindowLatency}, Tunnel, TunnelError, ZCPacketStream},
 };
 
-use super::{peer_conn_ping::PeerConnPinger, PacketRecvChan};
+use super::{
+    peer_conn_ping::{PathMtuProber, PeerConnPinger, MAX_PATH_MTU},
+    PacketRecvChan,
+};
 
 pub type PeerConnId = uuid::Uuid;
 
@@ -63,6 +66,7 @@ pub struct PeerConn {
     latency_stats: Arc<WindowLatency>,
     throughput: Arc<Throughput>,
     loss_rate_stats: Arc<AtomicU32>,
+    path_mtu: Arc<AtomicU32>,
 }
 
 impl Debug for PeerConn {
@@ -109,6 +113,7 @@ impl PeerConn {
             latency_stats: Arc::new(WindowLatency::new(15)),
             throughput,
             loss_rate_stats: Arc::new(AtomicU32::new(0)),
+            path_mtu: Arc::new(AtomicU32::new(0)),
         }
     }
 
@@ -300,6 +305,7 @@ impl PeerConn {
             self.latency_stats.clone(),
             self.loss_rate_stats.clone(),
         );
+        pingpong.set_path_mtu_prober(self.new_path_mtu_prober());
 
         let close_event_sender = self.close_event_sender.clone().unwrap();
         let conn_id = self.conn_id;
@@ -317,10 +323,36 @@ impl PeerConn {
         });
     }
 
+    fn new_path_mtu_prober(&self) -> PathMtuProber {
+        let overhead = if self.global_ctx.get_flags().enable_encryption {
+            AES_GCM_ENCRYPTION_RESERVED as u16
+        } else {
+            0
+        };
+        PathMtuProber::new(
+            self.my_peer_id,
+            self.get_peer_id(),
+            self.sink.clone(),
+            self.ctrl_resp_sender.clone(),
+            // not our tun mtu, relays forward packets larger than it
+            MAX_PATH_MTU,
+            overhead,
+            self.path_mtu.clone(),
+        )
+    }
+
     pub async fn send_msg(&self, msg: ZCPacket) -> Result<(), Error> {
         Ok(self.sink.send(msg).await?)
     }
 
+    // none until the first probe finished
+    pub fn get_path_mtu(&self) -> Option<u16> {
+        match self.path_mtu.load(Ordering::Relaxed) {
+            0 => None,
+            mtu => Some(mtu as u16),
+        }
+    }
+
     pub fn get_peer_id(&self) -> PeerId {
         self.info.as_ref().unwrap().my_peer_id
     }
@@ -367,6 +399,7 @@ impl PeerConn {
             loss_rate: (f64::from(self.loss_rate_stats.load(Ordering::Relaxed)) / 100.0) as f32,
             is_client: self.is_client.unwrap_or_default(),
             network_name: info.network_name.clone(),
+            path_mtu: self.path_mtu.load(Ordering::Relaxed),
         }
     }
 }
@@ -468,6 +501,51 @@ mod tests {
         peer_conn_pingpong_test_common(5, 12, true).await;
     }
 
+    struct DropLargeSendTunnelFilter(usize);
+
+    impl TunnelFilter for DropLargeSendTunnelFilter {
+        type FilterOutput = ();
+
+        fn before_send(&self, data: ZCPacket) -> Option<ZCPacket> {
+            (data.payload_len() <= self.0).then_some(data)
+        }
+
+        fn filter_output(&self) {}
+    }
+
+    #[tokio::test]
+    async fn peer_conn_discover_path_mtu() {
+        let (c, s) = create_ring_tunnel_pair();
+        let c = TunnelWithFilter::new(c, DropLargeSendTunnelFilter(1000));
+
+        let mut c_peer = PeerConn::new(new_peer_id(), get_mock_global_ctx(), Box::new(c));
+        let mut s_peer = PeerConn::new(new_peer_id(), get_mock_global_ctx(), Box::new(s));
+        let (c_ret, s_ret) = tokio::join!(
+            c_peer.do_handshake_as_client(),
+            s_peer.do_handshake_as_server()
+        );
+        c_ret.unwrap();
+        s_ret.unwrap();
+
+        s_peer.set_close_event_sender(tokio::sync::mpsc::channel(1).0);
+        s_peer
+            .start_recv_loop(tokio::sync::mpsc::channel(200).0)
+            .await;
+        c_peer.set_close_event_sender(tokio::sync::mpsc::channel(1).0);
+        c_peer
+            .start_recv_loop(tokio::sync::mpsc::channel(200).0)
+            .await;
+
+        let overhead = if c_peer.global_ctx.get_flags().enable_encryption {
+            AES_GCM_ENCRYPTION_RESERVED
+        } else {
+            0
+        };
+        let expected = (1000 - overhead) as u16;
+        let mtu = c_peer.new_path_mtu_prober().discover().await;
+        assert!(mtu <= expected && mtu + 8 > expected, "mtu: {}", mtu);
+    }
+
     #[tokio::test]
     async fn close_tunnel_during_handshake() {
         let (c, s) = create_ring_tunnel_pair();
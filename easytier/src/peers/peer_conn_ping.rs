@@ -7,6 +7,7 @@ use std::{
 };
 
 use tokio::{sync::broadcast, task::JoinSet, time::timeout};
+use tracing::Instrument;
 
 use crate::{
     common::{error::Error, PeerId},
@@ -18,6 +19,108 @@ use crate::{
     },
 };
 
+// probes take the upper half of the seq space so their pongs never match a ping
+const PMTU_PROBE_SEQ_FLAG: u32 = 1 << 31;
+// every ipv4 path must carry this, so it is never probed
+pub const MIN_PATH_MTU: u16 = 576;
+// probes never go above this, the mtu of most links
+pub const MAX_PATH_MTU: u16 = 1500;
+// the binary search stops once the bounds are this close
+const PMTU_PROBE_PRECISION: u16 = 8;
+// a probe is only considered too big after this many losses in a row
+const PMTU_PROBE_RETRIES: usize = 2;
+const PMTU_PROBE_DELAY: Duration = Duration::from_secs(10);
+const PMTU_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+// finds the largest inner packet a peer conn carries by sending pings padded to the size
+// of a full data packet.
+pub struct PathMtuProber {
+    my_peer_id: PeerId,
+    peer_id: PeerId,
+    sink: MpscTunnelSender,
+    ctrl_sender: broadcast::Sender<ZCPacket>,
+    // largest inner packet probed
+    max_mtu: u16,
+    // bytes the encryption adds to every data packet
+    overhead: u16,
+    path_mtu: Arc<AtomicU32>,
+    seq: u32,
+}
+
+impl PathMtuProber {
+    pub fn new(
+        my_peer_id: PeerId,
+        peer_id: PeerId,
+        sink: MpscTunnelSender,
+        ctrl_sender: broadcast::Sender<ZCPacket>,
+        max_mtu: u16,
+        overhead: u16,
+        path_mtu: Arc<AtomicU32>,
+    ) -> Self {
+        Self {
+            my_peer_id,
+            peer_id,
+            sink,
+            ctrl_sender,
+            max_mtu,
+            overhead,
+            path_mtu,
+            seq: 0,
+        }
+    }
+
+    async fn probe(&mut self, mtu: u16) -> bool {
+        for _ in 0..PMTU_PROBE_RETRIES {
+            let seq = PMTU_PROBE_SEQ_FLAG | self.seq;
+            self.seq = (self.seq + 1) & !PMTU_PROBE_SEQ_FLAG;
+            let mut receiver = self.ctrl_sender.subscribe();
+            let ret = PeerConnPinger::do_pingpong_once(
+                self.my_peer_id,
+                self.peer_id,
+                &mut self.sink,
+                &mut receiver,
+                seq,
+                mtu as usize + self.overhead as usize,
+            )
+            .await;
+            tracing::trace!(?ret, ?mtu, "path mtu probe done");
+            if ret.is_ok() {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub async fn discover(&mut self) -> u16 {
+        if self.max_mtu <= MIN_PATH_MTU || self.probe(self.max_mtu).await {
+            return self.max_mtu;
+        }
+
+        let (mut lo, mut hi) = (MIN_PATH_MTU, self.max_mtu);
+        while hi - lo > PMTU_PROBE_PRECISION {
+            let mid = lo + (hi - lo) / 2;
+            if self.probe(mid).await {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    async fn run(mut self) -> Result<(), TunnelError> {
+        tokio::time::sleep(PMTU_PROBE_DELAY).await;
+        loop {
+            let mtu = self.discover().await;
+            let old = self.path_mtu.swap(mtu as u32, Ordering::Relaxed);
+            if old != mtu as u32 {
+                tracing::info!(?old, ?mtu, "path mtu of peer conn changed");
+            }
+            tokio::time::sleep(PMTU_PROBE_INTERVAL).await;
+        }
+    }
+}
+
 pub struct PeerConnPinger {
     my_peer_id: PeerId,
     peer_id: PeerId,
@@ -25,6 +128,7 @@ pub struct PeerConnPinger {
     ctrl_sender: broadcast::Sender<ZCPacket>,
     latency_stats: Arc<WindowLatency>,
     loss_rate_stats: Arc<AtomicU32>,
+    path_mtu_prober: Option<PathMtuProber>,
     tasks: JoinSet<Result<(), TunnelError>>,
 }
 
@@ -54,11 +158,24 @@ impl PeerConnPinger {
             latency_stats,
             ctrl_sender,
             loss_rate_stats,
+            path_mtu_prober: None,
         }
     }
 
-    fn new_ping_packet(my_node_id: PeerId, peer_id: PeerId, seq: u32) -> ZCPacket {
-        let mut packet = ZCPacket::new_with_payload(&seq.to_le_bytes());
+    pub fn set_path_mtu_prober(&mut self, prober: PathMtuProber) {
+        self.path_mtu_prober = Some(prober);
+    }
+
+    // the payload is padded with zeros up to payload_len, the peer echoes it unchanged
+    fn new_ping_packet(
+        my_node_id: PeerId,
+        peer_id: PeerId,
+        seq: u32,
+        payload_len: usize,
+    ) -> ZCPacket {
+        let mut payload = seq.to_le_bytes().to_vec();
+        payload.resize(payload_len.max(payload.len()), 0);
+        let mut packet = ZCPacket::new_with_payload(&payload);
         packet.fill_peer_manager_hdr(my_node_id, peer_id, PacketType::Ping as u8);
         packet
     }
@@ -69,9 +186,10 @@ impl PeerConnPinger {
         sink: &mut MpscTunnelSender,
         receiver: &mut broadcast::Receiver<ZCPacket>,
         seq: u32,
+        payload_len: usize,
     ) -> Result<u128, Error> {
         // should add seq here. so latency can be calculated more accurately
-        let req = Self::new_ping_packet(my_node_id, peer_id, seq);
+        let req = Self::new_ping_packet(my_node_id, peer_id, seq, payload_len);
         sink.send(req).await?;
 
         let now = std::time::Instant::now();
@@ -125,6 +243,14 @@ impl PeerConnPinger {
 
         let (ping_res_sender, mut ping_res_receiver) = tokio::sync::mpsc::channel(100);
 
+        if let Some(prober) = self.path_mtu_prober.take() {
+            self.tasks.spawn(
+                prober
+                    .run()
+                    .instrument(tracing::info_span!("path mtu probe", ?peer_id)),
+            );
+        }
+
         let stopped = Arc::new(AtomicU32::new(0));
 
         // generate a pingpong task every 200ms
@@ -154,6 +280,7 @@ impl PeerConnPinger {
                         &mut sink,
                         &mut receiver,
                         req_seq,
+                        4,
                     )
                     .await;
 
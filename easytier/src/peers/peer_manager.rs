@@ -1,11 +1,17 @@
 use std::{
+    collections::hash_map::DefaultHasher,
     fmt::Debug,
-    net::Ipv4Addr,
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     sync::{Arc, Weak},
 };
 
 use anyhow::Context;
 use async_trait::async_trait;
+use pnet::packet::{
+    ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, ipv6::Ipv6Packet, tcp::TcpPacket, udp::UdpPacket,
+    Packet as _,
+};
 
 use futures::StreamExt;
 
@@ -20,7 +26,10 @@ use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::bytes::Bytes;
 
 use crate::{
-    common::{error::Error, global_ctx::ArcGlobalCtx, stun::StunInfoCollectorTrait, PeerId},
+    common::{
+        config::IPV6_OVERLAY_PREFIX_LEN, error::Error, global_ctx::ArcGlobalCtx,
+        stun::StunInfoCollectorTrait, PeerId,
+    },
     peers::{
         peer_conn::PeerConn,
         peer_rpc::PeerRpcManagerTransport,
@@ -29,15 +38,25 @@ use crate::{
     },
     tunnel::{
         self,
-        packet_def::{PacketType, ZCPacket},
+        packet_def::{PacketType, ZCPacket, AES_GCM_ENCRYPTION_RESERVED},
         SinkItem, Tunnel, TunnelConnector,
     },
 };
 
 use super::{
     encrypt::{Encryptor, NullCipher},
+    exit_node_health::ExitNodeHealthChecker,
     foreign_network_client::ForeignNetworkClient,
     foreign_network_manager::ForeignNetworkManager,
+    icmp_error::{
+        build_icmp_error, decrement_inner_ttl, initial_forward_counter, inner_destination,
+        IcmpErrorKind, MAX_FORWARD_COUNTER,
+    },
+    igmp::{
+        build_igmp_general_query, parse_igmp_membership, IgmpMembership, ALL_HOSTS_GROUP,
+        IGMP_QUERY_INTERVAL,
+    },
+    path_mtu::{clamp_tcp_mss, is_oversized},
     peer_conn::PeerConnId,
     peer_map::PeerMap,
     peer_ospf_route::PeerRoute,
@@ -76,7 +95,7 @@ impl PeerRpcManagerTransport for RpcTransport {
         let peers = self.peers.upgrade().ok_or(Error::Unknown)?;
 
         if let Some(gateway_id) = peers
-            .get_gateway_peer_id(dst_peer_id, NextHopPolicy::LeastHop)
+            .get_gateway_peer_id(dst_peer_id, NextHopPolicy::LeastHop, 0)
             .await
         {
             tracing::trace!(
@@ -131,6 +150,27 @@ enum RouteAlgoInst {
     None,
 }
 
+// where a packet leaves this node, looked up once per packet
+#[derive(Debug, Clone, Copy)]
+enum NextHop {
+    // path_mtu is none until the conns on the path are probed
+    Peer {
+        gateway: PeerId,
+        path_mtu: Option<u16>,
+    },
+    // the destination is reached through the foreign network client
+    Foreign,
+}
+
+impl NextHop {
+    fn path_mtu(&self) -> Option<u16> {
+        match self {
+            NextHop::Peer { path_mtu, .. } => *path_mtu,
+            NextHop::Foreign => None,
+        }
+    }
+}
+
 pub struct PeerManager {
     my_peer_id: PeerId,
 
@@ -157,6 +197,11 @@ pub struct PeerManager {
     encryptor: Arc<Box<dyn Encryptor>>,
 
     exit_nodes: Vec<Ipv4Addr>,
+    exit_node_load_balance: bool,
+    exit_node_health: Arc<ExitNodeHealthChecker>,
+
+    // multicast groups allowed across the overlay, none allows all groups
+    multicast_allowlist: Option<Vec<cidr::Ipv4Cidr>>,
 }
 
 impl Debug for PeerManager {
@@ -241,6 +286,13 @@ impl PeerManager {
         ));
 
         let exit_nodes = global_ctx.config.get_exit_nodes();
+        let exit_node_load_balance = global_ctx.get_flags().exit_node_load_balance;
+        let exit_node_health = Arc::new(ExitNodeHealthChecker::new(
+            global_ctx.clone(),
+            peers.clone(),
+            peer_rpc_mgr.clone(),
+        ));
+        let multicast_allowlist = global_ctx.config.get_multicast_allowlist();
 
         PeerManager {
             my_peer_id,
@@ -267,6 +319,9 @@ impl PeerManager {
 
             encryptor,
             exit_nodes,
+            exit_node_load_balance,
+            exit_node_health,
+            multicast_allowlist,
         }
     }
 
@@ -332,6 +387,11 @@ impl PeerManager {
         let pipe_line = self.peer_packet_process_pipeline.clone();
         let foreign_client = self.foreign_network_client.clone();
         let encryptor = self.encryptor.clone();
+        let global_ctx = self.global_ctx.clone();
+        let relay_for_denied_segments = !self
+            .global_ctx
+            .get_flags()
+            .disable_relay_for_denied_segments;
         self.tasks.lock().await.spawn(async move {
             tracing::trace!("start_peer_recv");
             while let Some(mut ret) = recv.next().await {
@@ -342,9 +402,36 @@ impl PeerManager {
                 tracing::trace!(?hdr, "peer recv a packet...");
                 let from_peer_id = hdr.from_peer_id.get();
                 let to_peer_id = hdr.to_peer_id.get();
+                let forward_counter = hdr.forward_counter;
+                let is_data = hdr.packet_type == PacketType::Data as u8;
                 if to_peer_id != my_peer_id {
-                    if hdr.forward_counter > 7 {
-                        tracing::warn!(?hdr, "forward counter exceed, drop packet");
+                    if forward_counter > MAX_FORWARD_COUNTER {
+                        tracing::debug!(?hdr, "forward counter exceed, drop packet");
+                        if is_data {
+                            Self::reply_icmp_error_to_peer(
+                                &peers,
+                                &foreign_client,
+                                &encryptor,
+                                &global_ctx,
+                                my_peer_id,
+                                ret,
+                                IcmpErrorKind::TimeExceeded,
+                            )
+                            .await;
+                        }
+                        continue;
+                    }
+
+                    if is_data
+                        && !relay_for_denied_segments
+                        && (peers.is_peer_segment_denied(from_peer_id)
+                            || peers.is_peer_segment_denied(to_peer_id))
+                    {
+                        tracing::trace!(
+                            ?from_peer_id,
+                            ?to_peer_id,
+                            "drop relay for denied segment"
+                        );
                         continue;
                     }
 
@@ -355,17 +442,70 @@ impl PeerManager {
 
                     hdr.forward_counter += 1;
                     tracing::trace!(?to_peer_id, ?my_peer_id, "need forward");
-                    let ret =
-                        Self::send_msg_internal(&peers, &foreign_client, ret, to_peer_id).await;
+                    let Some(next_hop) =
+                        Self::resolve_next_hop(&peers, &foreign_client, &ret, to_peer_id).await
+                    else {
+                        tracing::debug!(?to_peer_id, ?from_peer_id, "no route to forward packet");
+                        if is_data {
+                            Self::reply_icmp_error_to_peer(
+                                &peers,
+                                &foreign_client,
+                                &encryptor,
+                                &global_ctx,
+                                my_peer_id,
+                                ret,
+                                IcmpErrorKind::HostUnreachable,
+                            )
+                            .await;
+                        }
+                        continue;
+                    };
+
+                    // transit packets are only limited by the path, our tun mtu does not apply
+                    if let Some(mtu) = next_hop.path_mtu().filter(|_| is_data) {
+                        if let Some(packet) = Self::decrypt_if_oversized(&encryptor, &ret, mtu) {
+                            tracing::debug!(?to_peer_id, ?mtu, "packet too big for path, drop");
+                            Self::reply_icmp_error_to_peer(
+                                &peers,
+                                &foreign_client,
+                                &encryptor,
+                                &global_ctx,
+                                my_peer_id,
+                                packet,
+                                IcmpErrorKind::PacketTooBig(mtu),
+                            )
+                            .await;
+                            continue;
+                        }
+                    }
+
+                    let ret = Self::send_msg_to_next_hop(
+                        &peers,
+                        &foreign_client,
+                        ret,
+                        to_peer_id,
+                        next_hop,
+                    )
+                    .await;
                     if ret.is_err() {
                         tracing::error!(?ret, ?to_peer_id, ?from_peer_id, "forward packet error");
                     }
                 } else {
+                    // control packets still pass so routes keep syncing
+                    if is_data && peers.is_peer_segment_denied(from_peer_id) {
+                        tracing::trace!(?from_peer_id, "drop data from denied segment");
+                        continue;
+                    }
+
                     if let Err(e) = encryptor.decrypt(&mut ret) {
                         tracing::error!(?e, "decrypt failed");
                         continue;
                     }
 
+                    if is_data {
+                        decrement_inner_ttl(ret.mut_payload(), forward_counter);
+                    }
+
                     let mut processed = false;
                     let mut zc_packet = Some(ret);
                     let mut idx = 0;
@@ -389,6 +529,106 @@ impl PeerManager {
         });
     }
 
+    // relays only look inside packets that are too long for the path, others stay encrypted
+    fn decrypt_if_oversized(
+        encryptor: &Arc<Box<dyn Encryptor>>,
+        packet: &ZCPacket,
+        mtu: u16,
+    ) -> Option<ZCPacket> {
+        let overhead = if packet.peer_manager_header()?.is_encrypted() {
+            AES_GCM_ENCRYPTION_RESERVED
+        } else {
+            0
+        };
+        if packet.payload_len().saturating_sub(overhead) <= mtu as usize {
+            return None;
+        }
+        let mut packet = packet.clone();
+        encryptor.decrypt(&mut packet).ok()?;
+        is_oversized(packet.payload(), mtu).then_some(packet)
+    }
+
+    // answers a data packet we can't forward with an icmp error sent back to its origin peer
+    async fn reply_icmp_error_to_peer(
+        peers: &Arc<PeerMap>,
+        foreign_client: &Arc<ForeignNetworkClient>,
+        encryptor: &Arc<Box<dyn Encryptor>>,
+        global_ctx: &ArcGlobalCtx,
+        my_peer_id: PeerId,
+        mut packet: ZCPacket,
+        kind: IcmpErrorKind,
+    ) {
+        let from_peer_id = packet.peer_manager_header().unwrap().from_peer_id.get();
+        if encryptor.decrypt(&mut packet).is_err() {
+            return;
+        }
+        let Some(reply) = build_icmp_error(
+            kind,
+            packet.payload(),
+            global_ctx.get_ipv4(),
+            global_ctx.get_ipv6(),
+        ) else {
+            return;
+        };
+
+        let mut reply = ZCPacket::new_with_payload(&reply);
+        reply.fill_peer_manager_hdr(my_peer_id, from_peer_id, PacketType::Data as u8);
+        if let Err(e) = encryptor.encrypt(&mut reply) {
+            tracing::warn!(?e, "encrypt icmp error failed");
+            return;
+        }
+        let ret = Self::send_msg_internal(peers, foreign_client, reply, from_peer_id).await;
+        tracing::trace!(?ret, ?kind, ?from_peer_id, "icmp error sent to peer");
+    }
+
+    // answers a packet from the nic that has no peer to go to
+    fn reply_icmp_unreachable_to_nic(&self, msg: &ZCPacket) {
+        if self.global_ctx.no_tun() {
+            return;
+        }
+        let Some(dst) = inner_destination(msg.payload()) else {
+            return;
+        };
+        let in_virtual_network = match dst {
+            IpAddr::V4(dst) => self
+                .global_ctx
+                .get_ipv4_inet()
+                .map(|inet| inet.network().contains(&dst))
+                .unwrap_or(false),
+            IpAddr::V6(dst) => self
+                .global_ctx
+                .get_ipv6()
+                .and_then(|ip| cidr::Ipv6Cidr::new(ip, IPV6_OVERLAY_PREFIX_LEN).ok())
+                .map(|cidr| cidr.contains(&dst))
+                .unwrap_or(false),
+        };
+        let kind = if in_virtual_network {
+            IcmpErrorKind::HostUnreachable
+        } else {
+            IcmpErrorKind::NetUnreachable
+        };
+        self.reply_icmp_error_to_nic(msg, kind);
+    }
+
+    fn reply_icmp_error_to_nic(&self, msg: &ZCPacket, kind: IcmpErrorKind) {
+        if self.global_ctx.no_tun() {
+            return;
+        }
+        let Some(reply) = build_icmp_error(
+            kind,
+            msg.payload(),
+            self.global_ctx.get_ipv4(),
+            self.global_ctx.get_ipv6(),
+        ) else {
+            return;
+        };
+
+        let mut reply = ZCPacket::new_with_payload(&reply);
+        reply.fill_peer_manager_hdr(self.my_peer_id, self.my_peer_id, PacketType::Data as u8);
+        // best effort, never block the sender on a full nic channel
+        let _ = self.nic_channel.try_send(reply);
+    }
+
     pub async fn add_packet_process_pipeline(&self, pipeline: BoxPeerPacketFilter) {
         // newest pipeline will be executed first
         self.peer_packet_process_pipeline
@@ -507,6 +747,12 @@ impl PeerManager {
             fn my_peer_id(&self) -> PeerId {
                 self.my_peer_id
             }
+            async fn list_link_mtus(&self) -> Vec<(PeerId, u16)> {
+                let Some(peer_map) = self.peers.upgrade() else {
+                    return vec![];
+                };
+                peer_map.list_link_mtus().await
+            }
         }
 
         let my_peer_id = self.my_peer_id;
@@ -532,7 +778,21 @@ impl PeerManager {
     }
 
     pub async fn list_routes(&self) -> Vec<crate::rpc::Route> {
-        self.get_route().list_routes().await
+        let mut routes = self.get_route().list_routes().await;
+        for route in routes.iter_mut() {
+            let Ok(ipv4_addr) = route.ipv4_addr.parse::<Ipv4Addr>() else {
+                continue;
+            };
+            if let Some(state) = self.exit_node_health.get_state(&ipv4_addr) {
+                route.exit_node_health = Some(crate::rpc::ExitNodeHealth {
+                    healthy: state.healthy && !state.unknown,
+                    latency_ms: state.latency_ms,
+                    last_error: state.last_error.unwrap_or_default(),
+                    unknown: state.unknown,
+                });
+            }
+        }
+        routes
     }
 
     pub async fn dump_route(&self) -> String {
@@ -545,7 +805,7 @@ impl PeerManager {
         }
     }
 
-    fn get_next_hop_policy(is_first_latency: bool) -> NextHopPolicy {
+    pub fn get_next_hop_policy(is_first_latency: bool) -> NextHopPolicy {
         if is_first_latency {
             NextHopPolicy::LeastCost
         } else {
@@ -563,19 +823,63 @@ impl PeerManager {
         msg: ZCPacket,
         dst_peer_id: PeerId,
     ) -> Result<(), Error> {
-        let policy =
-            Self::get_next_hop_policy(msg.peer_manager_header().unwrap().is_latency_first());
+        let Some(next_hop) =
+            Self::resolve_next_hop(peers, foreign_network_client, &msg, dst_peer_id).await
+        else {
+            return Err(Error::RouteError(None));
+        };
+        Self::send_msg_to_next_hop(peers, foreign_network_client, msg, dst_peer_id, next_hop).await
+    }
+
+    async fn resolve_next_hop(
+        peers: &Arc<PeerMap>,
+        foreign_network_client: &Arc<ForeignNetworkClient>,
+        msg: &ZCPacket,
+        dst_peer_id: PeerId,
+    ) -> Option<NextHop> {
+        let hdr = msg.peer_manager_header().unwrap();
+        let policy = Self::get_next_hop_policy(hdr.is_latency_first());
+        Self::lookup_next_hop(
+            peers,
+            foreign_network_client,
+            dst_peer_id,
+            policy,
+            hdr.flow_hash,
+        )
+        .await
+    }
 
-        if let Some(gateway) = peers.get_gateway_peer_id(dst_peer_id, policy).await {
-            peers.send_msg_directly(msg, gateway).await
+    async fn lookup_next_hop(
+        peers: &Arc<PeerMap>,
+        foreign_network_client: &Arc<ForeignNetworkClient>,
+        dst_peer_id: PeerId,
+        policy: NextHopPolicy,
+        flow_hash: u8,
+    ) -> Option<NextHop> {
+        if let Some((gateway, path_mtu)) = peers.get_next_hop(dst_peer_id, policy, flow_hash).await
+        {
+            Some(NextHop::Peer { gateway, path_mtu })
         } else if foreign_network_client.has_next_hop(dst_peer_id) {
-            foreign_network_client.send_msg(msg, dst_peer_id).await
+            Some(NextHop::Foreign)
         } else {
-            Err(Error::RouteError(None))
+            None
+        }
+    }
+
+    async fn send_msg_to_next_hop(
+        peers: &Arc<PeerMap>,
+        foreign_network_client: &Arc<ForeignNetworkClient>,
+        msg: ZCPacket,
+        dst_peer_id: PeerId,
+        next_hop: NextHop,
+    ) -> Result<(), Error> {
+        match next_hop {
+            NextHop::Peer { gateway, .. } => peers.send_msg_directly(msg, gateway).await,
+            NextHop::Foreign => foreign_network_client.send_msg(msg, dst_peer_id).await,
         }
     }
 
-    pub async fn send_msg_ipv4(&self, mut msg: ZCPacket, ipv4_addr: Ipv4Addr) -> Result<(), Error> {
+    pub async fn send_msg_ipv4(&self, msg: ZCPacket, ipv4_addr: Ipv4Addr) -> Result<(), Error> {
         tracing::trace!(
             "do send_msg in peer manager, msg: {:?}, ipv4_addr: {}",
             msg,
@@ -584,8 +888,14 @@ impl PeerManager {
 
         let mut is_exit_node = false;
         let mut dst_peers = vec![];
-        // NOTE: currently we only support ipv4 and cidr is 24
-        if ipv4_addr.is_broadcast() || ipv4_addr.is_multicast() || ipv4_addr.octets()[3] == 255 {
+        let is_subnet_broadcast = self
+            .global_ctx
+            .get_ipv4_inet()
+            .map(|inet| inet.last_address() == ipv4_addr)
+            .unwrap_or(false);
+        if ipv4_addr.is_multicast() {
+            dst_peers = self.get_multicast_dst_peers(&msg, ipv4_addr).await;
+        } else if ipv4_addr.is_broadcast() || is_subnet_broadcast {
             dst_peers.extend(
                 self.peers
                     .list_routes()
@@ -593,39 +903,178 @@ impl PeerManager {
                     .iter()
                     .map(|x| x.key().clone()),
             );
-        } else if let Some(peer_id) = self.peers.get_peer_id_by_ipv4(&ipv4_addr).await {
+        } else if let Some(peer_id) = self
+            .peers
+            .get_peer_id_by_ip_for_flow(&ipv4_addr.into(), compute_flow_hash(msg.payload()))
+            .await
+        {
             dst_peers.push(peer_id);
+        } else if let Some(peer_id) = self.get_exit_node_peer_id(&msg, ipv4_addr).await {
+            dst_peers.push(peer_id);
+            is_exit_node = true;
+        }
+
+        if dst_peers.is_empty() {
+            tracing::info!("no peer id for ipv4: {}", ipv4_addr);
+            self.reply_icmp_unreachable_to_nic(&msg);
+            return Ok(());
+        }
+
+        self.send_msg_to_peers(msg, dst_peers, is_exit_node).await
+    }
+
+    async fn get_multicast_dst_peers(&self, msg: &ZCPacket, group: Ipv4Addr) -> Vec<PeerId> {
+        for membership in parse_igmp_membership(msg.payload()) {
+            match membership {
+                IgmpMembership::Join(group) => self.global_ctx.join_multicast_group(group),
+                IgmpMembership::Leave(group) => self.global_ctx.leave_multicast_group(group),
+            };
+        }
+
+        if let Some(allowlist) = &self.multicast_allowlist {
+            if !allowlist.iter().any(|cidr| cidr.contains(&group)) {
+                tracing::trace!(?group, "multicast group not allowed, drop");
+                return vec![];
+            }
+        }
+
+        let members = if group == ALL_HOSTS_GROUP {
+            None
         } else {
-            for exit_node in &self.exit_nodes {
-                if let Some(peer_id) = self.peers.get_peer_id_by_ipv4(exit_node).await {
-                    dst_peers.push(peer_id);
-                    is_exit_node = true;
-                    break;
+            self.peers.list_peers_in_multicast_group(&group).await
+        };
+        match members {
+            Some(members) => members,
+            None => self
+                .peers
+                .list_routes()
+                .await
+                .iter()
+                .map(|x| *x.key())
+                .collect(),
+        }
+    }
+
+    async fn get_healthy_exit_node_peer_id(&self, exit_node: &Ipv4Addr) -> Option<PeerId> {
+        if !self.exit_node_health.is_healthy(exit_node) {
+            return None;
+        }
+        self.peers.get_peer_id_by_ipv4(exit_node).await
+    }
+
+    async fn get_exit_node_peer_id(&self, msg: &ZCPacket, ipv4_addr: Ipv4Addr) -> Option<PeerId> {
+        let (src, src_port, dst_port) = parse_ipv4_flow(msg.payload());
+        let policy = self.global_ctx.get_exit_policy();
+        for rule in policy
+            .iter()
+            .filter(|r| r.matches(src, ipv4_addr, src_port, dst_port))
+        {
+            // a direct rule keeps the traffic away from all exit nodes
+            let exit_node = rule.exit_node?;
+            if let Some(peer_id) = self.get_healthy_exit_node_peer_id(&exit_node).await {
+                return Some(peer_id);
+            }
+        }
+
+        let load_balance = self.exit_node_load_balance;
+        let mut candidates = vec![];
+        for exit_node in &self.exit_nodes {
+            if let Some(peer_id) = self.get_healthy_exit_node_peer_id(exit_node).await {
+                if !load_balance {
+                    return Some(peer_id);
                 }
+                candidates.push(peer_id);
             }
         }
 
+        if candidates.is_empty() {
+            return None;
+        }
+
+        // hash the flow so all packets of a connection leave through the same exit node
+        let mut hasher = DefaultHasher::new();
+        (src, ipv4_addr, src_port, dst_port).hash(&mut hasher);
+        Some(candidates[(hasher.finish() % candidates.len() as u64) as usize])
+    }
+
+    pub async fn send_msg_ipv6(&self, msg: ZCPacket, ipv6_addr: Ipv6Addr) -> Result<(), Error> {
+        tracing::trace!(
+            "do send_msg in peer manager, msg: {:?}, ipv6_addr: {}",
+            msg,
+            ipv6_addr
+        );
+
+        let mut dst_peers = vec![];
+        if ipv6_addr.is_multicast() {
+            dst_peers.extend(self.peers.list_routes().await.iter().map(|x| *x.key()));
+        } else if let Some(peer_id) = self
+            .peers
+            .get_peer_id_by_ip_for_flow(&ipv6_addr.into(), compute_flow_hash(msg.payload()))
+            .await
+        {
+            dst_peers.push(peer_id);
+        }
+
         if dst_peers.is_empty() {
-            tracing::info!("no peer id for ipv4: {}", ipv4_addr);
+            tracing::info!("no peer id for ipv6: {}", ipv6_addr);
+            self.reply_icmp_unreachable_to_nic(&msg);
             return Ok(());
         }
 
+        self.send_msg_to_peers(msg, dst_peers, false).await
+    }
+
+    async fn send_msg_to_peers(
+        &self,
+        mut msg: ZCPacket,
+        dst_peers: Vec<PeerId>,
+        is_exit_node: bool,
+    ) -> Result<(), Error> {
         msg.fill_peer_manager_hdr(
             self.my_peer_id,
             0,
             tunnel::packet_def::PacketType::Data as u8,
         );
         self.run_nic_packet_process_pipeline(&mut msg).await;
+        let is_latency_first = self.global_ctx.get_flags().latency_first;
+        let next_hop_policy = Self::get_next_hop_policy(is_latency_first);
+        // computed before encryption, relays only see the header
+        let flow_hash = compute_flow_hash(msg.payload());
+        let mut next_hops = Vec::with_capacity(dst_peers.len());
+        for dst_peer_id in dst_peers.iter() {
+            next_hops.push(
+                Self::lookup_next_hop(
+                    &self.peers,
+                    &self.foreign_network_client,
+                    *dst_peer_id,
+                    next_hop_policy.clone(),
+                    flow_hash,
+                )
+                .await,
+            );
+        }
+        if let ([dst_peer_id], [Some(next_hop)]) = (&dst_peers[..], &next_hops[..]) {
+            let nic_mtu = self.peers.get_nic_mtu();
+            let mtu = next_hop.path_mtu().map_or(nic_mtu, |mtu| mtu.min(nic_mtu));
+            if is_oversized(msg.payload(), mtu) {
+                tracing::debug!(?dst_peer_id, ?mtu, "packet too big for path, drop");
+                self.reply_icmp_error_to_nic(&msg, IcmpErrorKind::PacketTooBig(mtu));
+                return Ok(());
+            }
+            clamp_tcp_mss(msg.mut_payload(), mtu);
+        }
+        let forward_counter = initial_forward_counter(msg.payload());
+        let hdr = msg.mut_peer_manager_header().unwrap();
+        hdr.flow_hash = flow_hash;
+        hdr.forward_counter = forward_counter;
         self.encryptor
             .encrypt(&mut msg)
             .with_context(|| "encrypt failed")?;
 
-        let is_latency_first = self.global_ctx.get_flags().latency_first;
         msg.mut_peer_manager_header()
             .unwrap()
             .set_latency_first(is_latency_first)
             .set_exit_node(is_exit_node);
-        let next_hop_policy = Self::get_next_hop_policy(is_latency_first);
 
         let mut errs: Vec<Error> = vec![];
 
@@ -644,18 +1093,19 @@ impl PeerManager {
                 .to_peer_id
                 .set(*peer_id);
 
-            if let Some(gateway) = self
-                .peers
-                .get_gateway_peer_id(*peer_id, next_hop_policy.clone())
-                .await
+            let Some(next_hop) = next_hops[i] else {
+                continue;
+            };
+            if let Err(e) = Self::send_msg_to_next_hop(
+                &self.peers,
+                &self.foreign_network_client,
+                msg,
+                *peer_id,
+                next_hop,
+            )
+            .await
             {
-                if let Err(e) = self.peers.send_msg_directly(msg, gateway).await {
-                    errs.push(e);
-                }
-            } else if self.foreign_network_client.has_next_hop(*peer_id) {
-                if let Err(e) = self.foreign_network_client.send_msg(msg, *peer_id).await {
-                    errs.push(e);
-                }
+                errs.push(e);
             }
         }
 
@@ -679,6 +1129,27 @@ impl PeerManager {
         });
     }
 
+    // hosts report when joining, later only when queried, so we query them before memberships
+    // expire
+    async fn run_igmp_querier_routine(&self) {
+        if self.global_ctx.no_tun() {
+            return;
+        }
+        let global_ctx = self.global_ctx.clone();
+        let nic_channel = self.nic_channel.clone();
+        let my_peer_id = self.my_peer_id;
+        self.tasks.lock().await.spawn(async move {
+            loop {
+                tokio::time::sleep(IGMP_QUERY_INTERVAL).await;
+                if let Some(ipv4) = global_ctx.get_ipv4() {
+                    let mut query = ZCPacket::new_with_payload(&build_igmp_general_query(ipv4));
+                    query.fill_peer_manager_hdr(my_peer_id, my_peer_id, PacketType::Data as u8);
+                    let _ = nic_channel.try_send(query);
+                }
+            }
+        });
+    }
+
     async fn run_foriegn_network(&self) {
         self.peer_rpc_tspt
             .foreign_peers
@@ -699,9 +1170,11 @@ impl PeerManager {
 
         self.init_packet_process_pipeline().await;
         self.peer_rpc_mgr.run();
+        self.exit_node_health.run();
 
         self.start_peer_recv().await;
         self.run_clean_peer_without_conn_routine().await;
+        self.run_igmp_querier_routine().await;
 
         self.run_foriegn_network().await;
 
@@ -764,10 +1237,26 @@ impl PeerManager {
             hostname: self.global_ctx.get_hostname(),
             stun_info: Some(self.global_ctx.get_stun_info_collector().get_stun_info()),
             inst_id: self.global_ctx.get_id().to_string(),
+            network_length: self.global_ctx.get_network_length() as u32,
+            tags: self.global_ctx.config.get_tags(),
+            ipv4_conflict: self.global_ctx.is_ipv4_conflicted(),
+            netmaps: self
+                .global_ctx
+                .config
+                .get_netmaps()
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+            ipv6_addr: self
+                .global_ctx
+                .get_ipv6()
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
             listeners: self
                 .global_ctx
                 .get_running_listeners()
                 .iter()
+                .chain(self.global_ctx.get_mapped_listeners().iter())
                 .map(|x| x.to_string())
                 .collect(),
             config: self.global_ctx.config.dump(),
@@ -776,6 +1265,55 @@ impl PeerManager {
     }
 }
 
+fn parse_ipv4_flow(payload: &[u8]) -> (Ipv4Addr, Option<u16>, Option<u16>) {
+    let Some(ipv4) = Ipv4Packet::new(payload) else {
+        return (Ipv4Addr::UNSPECIFIED, None, None);
+    };
+
+    let ports = match ipv4.get_next_level_protocol() {
+        IpNextHeaderProtocols::Tcp => {
+            TcpPacket::new(ipv4.payload()).map(|p| (p.get_source(), p.get_destination()))
+        }
+        IpNextHeaderProtocols::Udp => {
+            UdpPacket::new(ipv4.payload()).map(|p| (p.get_source(), p.get_destination()))
+        }
+        _ => None,
+    };
+
+    (ipv4.get_source(), ports.map(|p| p.0), ports.map(|p| p.1))
+}
+
+fn compute_flow_hash(payload: &[u8]) -> u8 {
+    let mut hasher = DefaultHasher::new();
+    match payload.first().map(|b| b >> 4) {
+        Some(4) => {
+            let Some(ipv4) = Ipv4Packet::new(payload) else {
+                return 0;
+            };
+            let (src, src_port, dst_port) = parse_ipv4_flow(payload);
+            let proto = ipv4.get_next_level_protocol();
+            (src, ipv4.get_destination(), proto.0, src_port, dst_port).hash(&mut hasher);
+        }
+        Some(6) => {
+            let Some(ipv6) = Ipv6Packet::new(payload) else {
+                return 0;
+            };
+            let proto = ipv6.get_next_header();
+            let ports =
+                match proto {
+                    IpNextHeaderProtocols::Tcp => TcpPacket::new(ipv6.payload())
+                        .map(|p| (p.get_source(), p.get_destination())),
+                    IpNextHeaderProtocols::Udp => UdpPacket::new(ipv6.payload())
+                        .map(|p| (p.get_source(), p.get_destination())),
+                    _ => None,
+                };
+            (ipv6.get_source(), ipv6.get_destination(), proto.0, ports).hash(&mut hasher);
+        }
+        _ => return 0,
+    }
+    hasher.finish() as u8
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -798,6 +1336,10 @@ mod tests {
     };
 
     use super::PeerManager;
+    use crate::peers::exit_node_health::tests::create_mock_peer_manager_with_exit_nodes;
+    use crate::tunnel::packet_def::ZCPacket;
+    use pnet::packet::{ip::IpNextHeaderProtocols, ipv4::MutableIpv4Packet, udp::MutableUdpPacket};
+    use std::net::Ipv4Addr;
 
     #[tokio::test]
     async fn drop_peer_manager() {
@@ -956,4 +1498,312 @@ mod tests {
         connect_peer_manager(peer_mgr_b.clone(), mgr_d.clone()).await;
         wait_route_appear(mgr_d, peer_mgr_b).await.unwrap();
     }
+
+    fn build_udp_packet(src: &str, dst: &str, dst_port: u16) -> ZCPacket {
+        let mut buf = vec![0u8; 28];
+        let mut ipv4 = MutableIpv4Packet::new(&mut buf).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(28);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ipv4.set_source(src.parse().unwrap());
+        ipv4.set_destination(dst.parse().unwrap());
+        let mut udp = MutableUdpPacket::new(&mut buf[20..]).unwrap();
+        udp.set_source(40000);
+        udp.set_destination(dst_port);
+        udp.set_length(8);
+        ZCPacket::new_with_payload(&buf)
+    }
+
+    #[tokio::test]
+    async fn exit_policy_select_exit_node() {
+        let peer_mgr_a = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        let peer_mgr_b = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        let peer_mgr_c = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_c.clone()).await;
+
+        let ip_b: Ipv4Addr = "10.126.126.2".parse().unwrap();
+        let ip_c: Ipv4Addr = "10.126.126.3".parse().unwrap();
+        peer_mgr_b.get_global_ctx().set_ipv4(Some(ip_b));
+        peer_mgr_c.get_global_ctx().set_ipv4(Some(ip_c));
+
+        wait_for_condition(
+            || async {
+                peer_mgr_a
+                    .get_peer_map()
+                    .get_peer_id_by_ipv4(&ip_b)
+                    .await
+                    .is_some()
+                    && peer_mgr_a
+                        .get_peer_map()
+                        .get_peer_id_by_ipv4(&ip_c)
+                        .await
+                        .is_some()
+            },
+            Duration::from_secs(10),
+        )
+        .await;
+
+        peer_mgr_a.get_global_ctx().set_exit_policy(vec![
+            "dst=0.0.0.0/0,via=10.126.126.2".parse().unwrap(),
+            // same prefix length, the earlier rule wins
+            "dst=10.20.0.0/16,dport=53,via=direct".parse().unwrap(),
+            "dst=10.20.0.0/16,via=10.126.126.3".parse().unwrap(),
+            "dst=10.30.0.0/16,via=10.126.126.99".parse().unwrap(),
+        ]);
+
+        let select = |dst: &'static str, dst_port: u16| {
+            let peer_mgr_a = peer_mgr_a.clone();
+            async move {
+                let packet = build_udp_packet("10.126.126.1", dst, dst_port);
+                peer_mgr_a
+                    .get_exit_node_peer_id(&packet, dst.parse().unwrap())
+                    .await
+            }
+        };
+
+        assert_eq!(Some(peer_mgr_b.my_peer_id()), select("8.8.8.8", 80).await);
+        assert_eq!(Some(peer_mgr_c.my_peer_id()), select("10.20.1.1", 80).await);
+        assert_eq!(None, select("10.20.1.1", 53).await);
+        // unreachable exit node falls back to the next matching rule
+        assert_eq!(Some(peer_mgr_b.my_peer_id()), select("10.30.1.1", 80).await);
+    }
+
+    #[tokio::test]
+    async fn exit_node_load_balance() {
+        let ip_b: Ipv4Addr = "10.126.126.2".parse().unwrap();
+        let ip_c: Ipv4Addr = "10.126.126.3".parse().unwrap();
+        let exit_flags = Flags {
+            enable_exit_node: true,
+            ..Default::default()
+        };
+        let peer_mgr_a = create_mock_peer_manager_with_exit_nodes(
+            Flags {
+                exit_node_load_balance: true,
+                ..Default::default()
+            },
+            None,
+            vec![ip_b, ip_c],
+        )
+        .await;
+        let peer_mgr_b =
+            create_mock_peer_manager_with_exit_nodes(exit_flags.clone(), Some(ip_b), vec![]).await;
+        let peer_mgr_c =
+            create_mock_peer_manager_with_exit_nodes(exit_flags, Some(ip_c), vec![]).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_c.clone()).await;
+
+        wait_for_condition(
+            || async {
+                peer_mgr_a
+                    .get_peer_map()
+                    .get_peer_id_by_ipv4(&ip_b)
+                    .await
+                    .is_some()
+                    && peer_mgr_a
+                        .get_peer_map()
+                        .get_peer_id_by_ipv4(&ip_c)
+                        .await
+                        .is_some()
+            },
+            Duration::from_secs(10),
+        )
+        .await;
+
+        let mut used = std::collections::HashSet::new();
+        for port in 1000..1064 {
+            let packet = build_udp_packet("10.126.126.1", "8.8.8.8", port);
+            let peer_id = peer_mgr_a
+                .get_exit_node_peer_id(&packet, "8.8.8.8".parse().unwrap())
+                .await
+                .unwrap();
+            // the same flow always leaves through the same exit node
+            let packet = build_udp_packet("10.126.126.1", "8.8.8.8", port);
+            assert_eq!(
+                Some(peer_id),
+                peer_mgr_a
+                    .get_exit_node_peer_id(&packet, "8.8.8.8".parse().unwrap())
+                    .await
+            );
+            used.insert(peer_id);
+        }
+        assert_eq!(2, used.len());
+    }
+
+    #[tokio::test]
+    async fn multicast_only_to_group_members() {
+        let peer_mgr_a = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        let peer_mgr_b = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        let peer_mgr_c = create_mock_peer_manager_with_mock_stun(NatType::Unknown).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_c.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_b.clone())
+            .await
+            .unwrap();
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_c.clone())
+            .await
+            .unwrap();
+
+        // b joins the group by an igmpv2 report coming from its tun device
+        let group: Ipv4Addr = "239.1.2.3".parse().unwrap();
+        let mut buf = vec![0u8; 28];
+        let mut ipv4 = MutableIpv4Packet::new(&mut buf).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(28);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+        ipv4.set_destination(group);
+        buf[20..].copy_from_slice(&[0x16, 0, 0, 0, 239, 1, 2, 3]);
+        let report = ZCPacket::new_with_payload(&buf);
+        assert!(peer_mgr_b
+            .get_multicast_dst_peers(&report, group)
+            .await
+            .is_empty());
+        assert_eq!(
+            vec![group],
+            peer_mgr_b.get_global_ctx().get_multicast_groups()
+        );
+
+        let packet = build_udp_packet("10.126.126.1", "239.1.2.3", 1900);
+        wait_for_condition(
+            || async {
+                peer_mgr_a.get_multicast_dst_peers(&packet, group).await
+                    == vec![peer_mgr_b.my_peer_id()]
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // all hosts group still reaches everyone
+        let packet = build_udp_packet("10.126.126.1", "224.0.0.1", 1900);
+        assert_eq!(
+            2,
+            peer_mgr_a
+                .get_multicast_dst_peers(&packet, "224.0.0.1".parse().unwrap())
+                .await
+                .len()
+        );
+    }
+
+    #[tokio::test]
+    async fn relay_replies_icmp_errors() {
+        use pnet::packet::{
+            icmp::{IcmpPacket, IcmpTypes},
+            ipv4::Ipv4Packet,
+            Packet as _,
+        };
+
+        let create_mgr = |ip: &str, mtu: u16| {
+            let ip = ip.parse().unwrap();
+            async move {
+                let (s, r) = tokio::sync::mpsc::channel(1000);
+                let mock_global_ctx = get_mock_global_ctx();
+                mock_global_ctx.set_ipv4(Some(ip));
+                let mut flags = mock_global_ctx.get_flags();
+                flags.mtu = mtu;
+                mock_global_ctx.config.set_flags(flags);
+                let peer_mgr = Arc::new(PeerManager::new(RouteAlgoType::Ospf, mock_global_ctx, s));
+                peer_mgr.run().await.unwrap();
+                (peer_mgr, r)
+            }
+        };
+        // the tun mtu of the relay does not limit what it forwards
+        let (peer_mgr_a, mut nic_a) = create_mgr("10.144.144.1", 1380).await;
+        let (peer_mgr_b, _nic_b) = create_mgr("10.144.144.2", 600).await;
+        let (peer_mgr_c, mut nic_c) = create_mgr("10.144.144.3", 1380).await;
+        connect_peer_manager(peer_mgr_a.clone(), peer_mgr_b.clone()).await;
+        connect_peer_manager(peer_mgr_b.clone(), peer_mgr_c.clone()).await;
+        wait_route_appear(peer_mgr_a.clone(), peer_mgr_c.clone())
+            .await
+            .unwrap();
+        let ip_c: Ipv4Addr = "10.144.144.3".parse().unwrap();
+        wait_for_condition(
+            || async {
+                peer_mgr_a
+                    .get_peer_map()
+                    .get_peer_id_by_ipv4(&ip_c)
+                    .await
+                    .is_some()
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        let send_with_ttl = |dst: &'static str, ttl: u8| {
+            let peer_mgr_a = peer_mgr_a.clone();
+            async move {
+                let mut packet = build_udp_packet("10.144.144.1", dst, 33434);
+                let mut ipv4 = MutableIpv4Packet::new(packet.mut_payload()).unwrap();
+                ipv4.set_ttl(ttl);
+                ipv4.set_checksum(pnet::packet::ipv4::checksum(&ipv4.to_immutable()));
+                peer_mgr_a
+                    .send_msg_ipv4(packet, dst.parse().unwrap())
+                    .await
+                    .unwrap();
+            }
+        };
+        let recv_icmp = |packet: ZCPacket| {
+            let ipv4 = Ipv4Packet::new(packet.payload()).unwrap();
+            let icmp = IcmpPacket::new(ipv4.payload()).unwrap();
+            (
+                ipv4.get_source(),
+                icmp.get_icmp_type(),
+                icmp.get_icmp_code().0,
+            )
+        };
+
+        // ttl 1 expires on b
+        send_with_ttl("10.144.144.3", 1).await;
+        let packet = tokio::time::timeout(Duration::from_secs(5), nic_a.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            ("10.144.144.2".parse().unwrap(), IcmpTypes::TimeExceeded, 0),
+            recv_icmp(packet)
+        );
+
+        // ttl 2 reaches c, with one hop taken off
+        send_with_ttl("10.144.144.3", 2).await;
+        let packet = tokio::time::timeout(Duration::from_secs(5), nic_c.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(1, Ipv4Packet::new(packet.payload()).unwrap().get_ttl());
+
+        // larger than the mtu of b, but not of the path
+        let mut packet = ZCPacket::new_with_payload(&[0u8; 1000]);
+        let mut ipv4 = MutableIpv4Packet::new(packet.mut_payload()).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(1000);
+        ipv4.set_ttl(64);
+        ipv4.set_flags(pnet::packet::ipv4::Ipv4Flags::DontFragment);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ipv4.set_source("10.144.144.1".parse().unwrap());
+        ipv4.set_destination(ip_c);
+        ipv4.set_checksum(pnet::packet::ipv4::checksum(&ipv4.to_immutable()));
+        peer_mgr_a.send_msg_ipv4(packet, ip_c).await.unwrap();
+        let packet = tokio::time::timeout(Duration::from_secs(5), nic_c.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(1000, packet.payload().len());
+
+        // nobody owns this address
+        send_with_ttl("10.144.144.9", 64).await;
+        let packet = tokio::time::timeout(Duration::from_secs(5), nic_a.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (
+                "10.144.144.1".parse().unwrap(),
+                IcmpTypes::DestinationUnreachable,
+                1
+            ),
+            recv_icmp(packet)
+        );
+    }
 }
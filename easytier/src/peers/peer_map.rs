@@ -1,7 +1,10 @@
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
 
 use anyhow::Context;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use tokio::sync::RwLock;
 
 use crate::{
@@ -28,16 +31,21 @@ pub struct PeerMap {
     peer_map: DashMap<PeerId, Arc<Peer>>,
     packet_send: PacketRecvChan,
     routes: RwLock<Vec<ArcRoute>>,
+    // filled by the routes, checked for every packet without awaiting them
+    denied_peers: std::sync::RwLock<Vec<Arc<DashSet<PeerId>>>>,
+    nic_mtu: u16,
 }
 
 impl PeerMap {
     pub fn new(packet_send: PacketRecvChan, global_ctx: ArcGlobalCtx, my_peer_id: PeerId) -> Self {
         PeerMap {
-            global_ctx,
+            global_ctx: global_ctx.clone(),
             my_peer_id,
             peer_map: DashMap::new(),
             packet_send,
             routes: RwLock::new(Vec::new()),
+            denied_peers: std::sync::RwLock::new(Vec::new()),
+            nic_mtu: global_ctx.get_nic_mtu(),
         }
     }
 
@@ -94,42 +102,100 @@ impl PeerMap {
         Ok(())
     }
 
+    // largest inner ip packet that can be sent to dst_peer_id without being fragmented, the
+    // smallest probed mtu on the route if known, otherwise the mtu of our tun device
+    pub async fn get_path_mtu(&self, dst_peer_id: PeerId, policy: NextHopPolicy) -> u16 {
+        if self.has_peer(dst_peer_id) && matches!(policy, NextHopPolicy::LeastHop) {
+            return self
+                .get_peer_by_id(dst_peer_id)
+                .and_then(|peer| peer.get_path_mtu())
+                .map_or(self.nic_mtu, |mtu| mtu.min(self.nic_mtu));
+        }
+
+        for route in self.routes.read().await.iter() {
+            if let Some(mtu) = route.get_path_mtu(dst_peer_id, policy.clone()).await {
+                return mtu.min(self.nic_mtu);
+            }
+        }
+        self.nic_mtu
+    }
+
+    // probed mtu of the conns to each directly connected peer
+    pub async fn list_link_mtus(&self) -> Vec<(PeerId, u16)> {
+        let mut ret = self
+            .peer_map
+            .iter()
+            .filter_map(|item| Some((*item.key(), item.value().get_path_mtu()?)))
+            .collect::<Vec<_>>();
+        ret.sort();
+        ret
+    }
+
     pub async fn get_gateway_peer_id(
         &self,
         dst_peer_id: PeerId,
         policy: NextHopPolicy,
+        flow_hash: u8,
     ) -> Option<PeerId> {
+        self.get_next_hop(dst_peer_id, policy, flow_hash)
+            .await
+            .map(|(gateway_peer_id, _)| gateway_peer_id)
+    }
+
+    // the gateway to dst_peer_id and the probed path mtu through it. unlike get_path_mtu it
+    // is not capped by our tun mtu, relayed packets never pass our tun
+    pub async fn get_next_hop(
+        &self,
+        dst_peer_id: PeerId,
+        policy: NextHopPolicy,
+        flow_hash: u8,
+    ) -> Option<(PeerId, Option<u16>)> {
         if dst_peer_id == self.my_peer_id {
-            return Some(dst_peer_id);
+            return Some((dst_peer_id, None));
         }
 
-        if self.has_peer(dst_peer_id) && matches!(policy, NextHopPolicy::LeastHop) {
-            return Some(dst_peer_id);
+        if matches!(policy, NextHopPolicy::LeastHop) {
+            if let Some(peer) = self.get_peer_by_id(dst_peer_id) {
+                return Some((dst_peer_id, peer.get_path_mtu()));
+            }
         }
 
         // get route info
         for route in self.routes.read().await.iter() {
-            if let Some(gateway_peer_id) = route
-                .get_next_hop_with_policy(dst_peer_id, policy.clone())
-                .await
-            {
-                // for foreign network, gateway_peer_id may not connect to me
-                if self.has_peer(gateway_peer_id) {
-                    return Some(gateway_peer_id);
-                }
+            let entry = route.get_route_entry(dst_peer_id, policy.clone()).await;
+            // for foreign network, gateway_peer_id may not connect to me
+            let gateways = entry
+                .next_hops
+                .into_iter()
+                .filter(|gateway_peer_id| self.has_peer(*gateway_peer_id))
+                .collect::<Vec<_>>();
+            if !gateways.is_empty() {
+                // packets of the same flow always take the same path
+                return Some((
+                    gateways[flow_hash as usize % gateways.len()],
+                    entry.path_mtu,
+                ));
             }
         }
 
         None
     }
 
+    pub fn get_nic_mtu(&self) -> u16 {
+        self.nic_mtu
+    }
+
     pub async fn send_msg(
         &self,
         msg: ZCPacket,
         dst_peer_id: PeerId,
         policy: NextHopPolicy,
     ) -> Result<(), Error> {
-        let Some(gateway_peer_id) = self.get_gateway_peer_id(dst_peer_id, policy).await else {
+        let flow_hash = msg.peer_manager_header().unwrap().flow_hash;
+        let Some(gateway_peer_id) = self
+            .get_gateway_peer_id(dst_peer_id, policy, flow_hash)
+            .await
+        else {
             return Err(Error::RouteError(Some(format!(
                 "peer map sengmsg no gateway for dst_peer_id: {}",
                 dst_peer_id
@@ -150,6 +216,44 @@ impl PeerMap {
         None
     }
 
+    pub async fn get_peer_id_by_ipv6(&self, ipv6: &Ipv6Addr) -> Option<PeerId> {
+        for route in self.routes.read().await.iter() {
+            let peer_id = route.get_peer_id_by_ipv6(ipv6).await;
+            if peer_id.is_some() {
+                return peer_id;
+            }
+        }
+        None
+    }
+
+    pub async fn get_peer_id_by_ip_for_flow(&self, ip: &IpAddr, flow_hash: u8) -> Option<PeerId> {
+        for route in self.routes.read().await.iter() {
+            let peer_id = route.get_peer_id_by_ip_for_flow(ip, flow_hash).await;
+            if peer_id.is_some() {
+                return peer_id;
+            }
+        }
+        None
+    }
+
+    pub async fn list_peers_in_multicast_group(&self, group: &Ipv4Addr) -> Option<Vec<PeerId>> {
+        for route in self.routes.read().await.iter() {
+            let peers = route.list_peers_in_multicast_group(group).await;
+            if peers.is_some() {
+                return peers;
+            }
+        }
+        None
+    }
+
+    pub fn is_peer_segment_denied(&self, peer_id: PeerId) -> bool {
+        self.denied_peers
+            .read()
+            .unwrap()
+            .iter()
+            .any(|denied| denied.contains(&peer_id))
+    }
+
     pub fn is_empty(&self) -> bool {
         self.peer_map.is_empty()
     }
@@ -211,6 +315,9 @@ impl PeerMap {
     }
 
     pub async fn add_route(&self, route: ArcRoute) {
+        if let Some(denied) = route.get_denied_peers() {
+            self.denied_peers.write().unwrap().push(denied);
+        }
         let mut routes = self.routes.write().await;
         routes.insert(0, route);
     }
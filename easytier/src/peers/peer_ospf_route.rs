@@ -1,18 +1,19 @@
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     fmt::Debug,
-    net::Ipv4Addr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     sync::{
         atomic::{AtomicBool, AtomicU32, Ordering},
         Arc, Weak,
     },
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use petgraph::{
     algo::{all_simple_paths, astar, dijkstra},
     graph::NodeIndex,
+    visit::{EdgeRef, Reversed},
     Directed, Graph,
 };
 use serde::{Deserialize, Serialize};
@@ -23,7 +24,12 @@ use tokio::{
 };
 
 use crate::{
-    common::{global_ctx::ArcGlobalCtx, stun::StunInfoCollectorTrait, PeerId},
+    common::{
+        config::SegmentRule,
+        global_ctx::{ArcGlobalCtx, GlobalCtxEvent},
+        stun::StunInfoCollectorTrait,
+        PeerId,
+    },
     peers::route_trait::{Route, RouteInterfaceBox},
     rpc::{NatType, StunInfo},
 };
@@ -32,7 +38,7 @@ use super::{
     peer_rpc::PeerRpcManager,
     route_trait::{
         DefaultRouteCostCalculator, NextHopPolicy, RouteCostCalculator,
-        RouteCostCalculatorInterface,
+        RouteCostCalculatorInterface, RouteEntry,
     },
     PeerPacketFilter,
 };
@@ -40,6 +46,8 @@ use super::{
 static SERVICE_ID: u32 = 7;
 static UPDATE_PEER_INFO_PERIOD: Duration = Duration::from_secs(3600);
 static REMOVE_DEAD_PEER_INFO_AFTER: Duration = Duration::from_secs(3660);
+// a proxied destination keeps its advertiser until it has been idle this long
+static PROXY_STICKY_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 type Version = u32;
 
@@ -76,16 +84,37 @@ impl From<Version> for AtomicVersion {
     }
 }
 
+// RoutePeerInfo goes on the wire in its baseline layout, the fields added since
+// then travel separately as tagged ext fields so older peers can still decode it.
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(from = "RoutePeerInfoBase", into = "RoutePeerInfoBase")]
 struct RoutePeerInfo {
     // means next hop in route table.
     peer_id: PeerId,
     inst_id: uuid::Uuid,
     cost: u8,
     ipv4_addr: Option<Ipv4Addr>,
+    ipv6_addr: Option<Ipv6Addr>,
     proxy_cidrs: Vec<String>,
+    // real->mapped pairs of mapped proxy cidrs, informational only
+    netmaps: Vec<String>,
     hostname: Option<String>,
     udp_stun_info: i8,
+    tcp_stun_info: i8,
+    // non-zero if the peer serves stun on this port.
+    stun_server_port: u16,
+    // non-zero if the peer has a configured network length.
+    network_length: u8,
+    tags: Vec<String>,
+    multicast_groups: Vec<Ipv4Addr>,
+    dhcp_lease_time: u64,
+    // probed mtu of the links to directly connected peers, sorted by peer id
+    link_mtus: Vec<(PeerId, u16)>,
+    has_global_ipv6: bool,
+    // the peer snoops igmp and advertises its multicast groups
+    igmp_snooping: bool,
+    // unix time the peer brought up its tun device with the static ipv4, 0 if it is not up
+    ipv4_claim_time: u64,
     last_update: SystemTime,
     version: Version,
 }
@@ -97,31 +126,63 @@ impl RoutePeerInfo {
             inst_id: uuid::Uuid::nil(),
             cost: 0,
             ipv4_addr: None,
+            ipv6_addr: None,
             proxy_cidrs: Vec::new(),
+            netmaps: Vec::new(),
             hostname: None,
             udp_stun_info: 0,
+            tcp_stun_info: 0,
+            stun_server_port: 0,
+            network_length: 0,
+            tags: Vec::new(),
+            multicast_groups: Vec::new(),
+            dhcp_lease_time: 0,
+            link_mtus: Vec::new(),
+            has_global_ipv6: false,
+            igmp_snooping: false,
+            ipv4_claim_time: 0,
             last_update: SystemTime::now(),
             version: 0,
         }
     }
 
-    pub fn update_self(&self, my_peer_id: PeerId, global_ctx: &ArcGlobalCtx) -> Self {
+    pub fn update_self(
+        &self,
+        my_peer_id: PeerId,
+        global_ctx: &ArcGlobalCtx,
+        link_mtus: Vec<(PeerId, u16)>,
+    ) -> Self {
+        let stun_info = global_ctx.get_stun_info_collector().get_stun_info();
         let mut new = Self {
             peer_id: my_peer_id,
             inst_id: global_ctx.get_id(),
             cost: 0,
             ipv4_addr: global_ctx.get_ipv4(),
+            ipv6_addr: global_ctx.get_ipv6(),
             proxy_cidrs: global_ctx
                 .get_proxy_cidrs()
                 .iter()
                 .map(|x| x.to_string())
                 .chain(global_ctx.get_vpn_portal_cidr().map(|x| x.to_string()))
                 .collect(),
+            netmaps: global_ctx
+                .config
+                .get_netmaps()
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
             hostname: Some(global_ctx.get_hostname()),
-            udp_stun_info: global_ctx
-                .get_stun_info_collector()
-                .get_stun_info()
-                .udp_nat_type as i8,
+            udp_stun_info: stun_info.udp_nat_type as i8,
+            tcp_stun_info: stun_info.tcp_nat_type as i8,
+            stun_server_port: global_ctx.get_stun_server_port(),
+            network_length: global_ctx.config.get_network_length().unwrap_or(0),
+            tags: global_ctx.config.get_tags(),
+            multicast_groups: global_ctx.get_multicast_groups(),
+            dhcp_lease_time: global_ctx.get_dhcp_lease_time(),
+            link_mtus,
+            has_global_ipv6: global_ctx.has_global_ipv6(),
+            igmp_snooping: true,
+            ipv4_claim_time: global_ctx.get_ipv4_claim_time(),
             // following fields do not participate in comparison.
             last_update: self.last_update,
             version: self.version,
@@ -142,6 +203,152 @@ impl RoutePeerInfo {
     }
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+struct RoutePeerInfoBase {
+    peer_id: PeerId,
+    inst_id: uuid::Uuid,
+    cost: u8,
+    ipv4_addr: Option<Ipv4Addr>,
+    proxy_cidrs: Vec<String>,
+    hostname: Option<String>,
+    udp_stun_info: i8,
+    last_update: SystemTime,
+    version: Version,
+}
+
+impl From<RoutePeerInfoBase> for RoutePeerInfo {
+    fn from(base: RoutePeerInfoBase) -> Self {
+        Self {
+            peer_id: base.peer_id,
+            inst_id: base.inst_id,
+            cost: base.cost,
+            ipv4_addr: base.ipv4_addr,
+            proxy_cidrs: base.proxy_cidrs,
+            hostname: base.hostname,
+            udp_stun_info: base.udp_stun_info,
+            last_update: base.last_update,
+            version: base.version,
+            ..Self::new()
+        }
+    }
+}
+
+impl From<RoutePeerInfo> for RoutePeerInfoBase {
+    fn from(info: RoutePeerInfo) -> Self {
+        Self {
+            peer_id: info.peer_id,
+            inst_id: info.inst_id,
+            cost: info.cost,
+            ipv4_addr: info.ipv4_addr,
+            proxy_cidrs: info.proxy_cidrs,
+            hostname: info.hostname,
+            udp_stun_info: info.udp_stun_info,
+            last_update: info.last_update,
+            version: info.version,
+        }
+    }
+}
+
+// postcard is not self-describing, so each field added since the baseline travels as a
+// (tag, postcard bytes) pair. decoders skip tags they don't know and keep defaults for tags
+// they don't get. a tag is never reused, new fields take the next one.
+type RoutePeerInfoExtFields = Vec<(u16, Vec<u8>)>;
+
+fn encode_ext_field<T: Serialize>(tag: u16, value: &T) -> (u16, Vec<u8>) {
+    (tag, postcard::to_allocvec(value).unwrap_or_default())
+}
+
+impl RoutePeerInfo {
+    fn ext_fields(&self) -> RoutePeerInfoExtFields {
+        vec![
+            encode_ext_field(1, &self.ipv6_addr),
+            encode_ext_field(2, &self.netmaps),
+            encode_ext_field(3, &self.tcp_stun_info),
+            encode_ext_field(4, &self.stun_server_port),
+            encode_ext_field(5, &self.network_length),
+            encode_ext_field(6, &self.tags),
+            encode_ext_field(7, &self.multicast_groups),
+            encode_ext_field(8, &self.dhcp_lease_time),
+            encode_ext_field(9, &self.link_mtus),
+            encode_ext_field(10, &self.has_global_ipv6),
+            encode_ext_field(11, &self.igmp_snooping),
+            encode_ext_field(12, &self.ipv4_claim_time),
+        ]
+    }
+
+    fn set_ext_field(&mut self, tag: u16, buf: &[u8]) -> Result<(), postcard::Error> {
+        match tag {
+            1 => self.ipv6_addr = postcard::from_bytes(buf)?,
+            2 => self.netmaps = postcard::from_bytes(buf)?,
+            3 => self.tcp_stun_info = postcard::from_bytes(buf)?,
+            4 => self.stun_server_port = postcard::from_bytes(buf)?,
+            5 => self.network_length = postcard::from_bytes(buf)?,
+            6 => self.tags = postcard::from_bytes(buf)?,
+            7 => self.multicast_groups = postcard::from_bytes(buf)?,
+            8 => self.dhcp_lease_time = postcard::from_bytes(buf)?,
+            9 => self.link_mtus = postcard::from_bytes(buf)?,
+            10 => self.has_global_ipv6 = postcard::from_bytes(buf)?,
+            11 => self.igmp_snooping = postcard::from_bytes(buf)?,
+            12 => self.ipv4_claim_time = postcard::from_bytes(buf)?,
+            // added by a newer peer
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+// postcard encoded RoutePeerInfoExtFields of each entry in peer_infos, in the same order.
+// it is the last argument of sync_route_info, so it ends the request: baseline
+// peers ignore the trailing bytes and requests from them decode it as empty.
+#[derive(Serialize, Clone, Debug, Default)]
+struct RoutePeerInfoExts(Vec<Vec<u8>>);
+
+impl<'de> Deserialize<'de> for RoutePeerInfoExts {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self(Vec::deserialize(deserializer).unwrap_or_default()))
+    }
+}
+
+impl RoutePeerInfoExts {
+    fn new(peer_infos: Option<&Vec<RoutePeerInfo>>) -> Self {
+        let Some(peer_infos) = peer_infos else {
+            return Self::default();
+        };
+        Self(
+            peer_infos
+                .iter()
+                .map(|info| postcard::to_allocvec(&info.ext_fields()).unwrap_or_default())
+                .collect(),
+        )
+    }
+
+    fn apply(&self, peer_infos: &mut [RoutePeerInfo]) {
+        for (info, ext) in peer_infos.iter_mut().zip(self.0.iter()) {
+            let fields = match postcard::from_bytes::<RoutePeerInfoExtFields>(ext) {
+                Ok(fields) => fields,
+                Err(e) => {
+                    tracing::warn!(
+                        ?e,
+                        peer_id = info.peer_id,
+                        "decode route peer info ext failed"
+                    );
+                    continue;
+                }
+            };
+            for (tag, buf) in fields {
+                if let Err(e) = info.set_ext_field(tag, &buf) {
+                    tracing::warn!(
+                        ?e,
+                        ?tag,
+                        peer_id = info.peer_id,
+                        "decode route peer info ext field failed"
+                    );
+                }
+            }
+        }
+    }
+}
+
 impl Into<crate::rpc::Route> for RoutePeerInfo {
     fn into(self) -> crate::rpc::Route {
         crate::rpc::Route {
@@ -160,10 +367,23 @@ impl Into<crate::rpc::Route> for RoutePeerInfo {
                 if let Ok(udp_nat_type) = NatType::try_from(self.udp_stun_info as i32) {
                     stun_info.set_udp_nat_type(udp_nat_type);
                 }
+                if let Ok(tcp_nat_type) = NatType::try_from(self.tcp_stun_info as i32) {
+                    stun_info.set_tcp_nat_type(tcp_nat_type);
+                }
                 Some(stun_info)
             },
             inst_id: self.inst_id.to_string(),
             version: env!("CARGO_PKG_VERSION").to_string(),
+            stun_server_port: self.stun_server_port as u32,
+            ipv6_addr: self.ipv6_addr.map(|x| x.to_string()).unwrap_or_default(),
+            network_length: self.network_length as u32,
+            exit_node_health: None,
+            tags: self.tags,
+            segment_denied: false,
+            dhcp_lease_time: self.dhcp_lease_time,
+            ipv4_conflict: false,
+            netmaps: self.netmaps,
+            has_global_ipv6: self.has_global_ipv6,
         }
     }
 }
@@ -214,12 +434,14 @@ struct SyncRouteInfoResponse {
 
 #[tarpc::service]
 trait RouteService {
+    #[allow(clippy::too_many_arguments)]
     async fn sync_route_info(
         my_peer_id: PeerId,
         my_session_id: SessionId,
         is_initiator: bool,
         peer_infos: Option<Vec<RoutePeerInfo>>,
         conn_bitmap: Option<RouteConnBitmap>,
+        peer_info_exts: RoutePeerInfoExts,
     ) -> Result<SyncRouteInfoResponse, Error>;
 }
 
@@ -338,12 +560,17 @@ impl SyncedRouteInfo {
         }
     }
 
-    fn update_my_peer_info(&self, my_peer_id: PeerId, global_ctx: &ArcGlobalCtx) -> bool {
+    fn update_my_peer_info(
+        &self,
+        my_peer_id: PeerId,
+        global_ctx: &ArcGlobalCtx,
+        link_mtus: Vec<(PeerId, u16)>,
+    ) -> bool {
         let mut old = self
             .peer_infos
             .entry(my_peer_id)
             .or_insert(RoutePeerInfo::new());
-        let new = old.update_self(my_peer_id, &global_ctx);
+        let new = old.update_self(my_peer_id, &global_ctx, link_mtus);
         let new_version = new.version;
         let old_version = old.version;
         *old = new;
@@ -384,14 +611,28 @@ impl SyncedRouteInfo {
 type PeerGraph = Graph<PeerId, i32, Directed>;
 type PeerIdToNodexIdxMap = DashMap<PeerId, NodeIndex>;
 type NextHopMap = DashMap<PeerId, (PeerId, i32)>;
+type EcmpNextHopMap = DashMap<PeerId, Vec<PeerId>>;
+// the chosen path to each peer, starting with us
+type PathMap = DashMap<PeerId, Vec<PeerId>>;
 
 // computed with SyncedRouteInfo. used to get next hop.
 #[derive(Debug)]
 struct RouteTable {
     peer_infos: DashMap<PeerId, RoutePeerInfo>,
     next_hop_map: NextHopMap,
+    // equal-cost next hops, only filled when ecmp is enabled
+    ecmp_next_hop_map: EcmpNextHopMap,
+    // smallest probed link mtu on the path, only for peers with a probed link on the way
+    path_mtu_map: DashMap<PeerId, u16>,
     ipv4_peer_id_map: DashMap<Ipv4Addr, PeerId>,
-    cidr_peer_id_map: DashMap<cidr::IpCidr, PeerId>,
+    ipv6_peer_id_map: DashMap<Ipv6Addr, PeerId>,
+    // several peers may advertise the same cidr (anycast)
+    cidr_peer_id_map: DashMap<cidr::IpCidr, Vec<PeerId>>,
+    multicast_group_peer_map: DashMap<Ipv4Addr, Vec<PeerId>>,
+    // false if a reachable peer does not advertise its multicast groups
+    igmp_snooping_complete: AtomicBool,
+    // static ipv4 addresses claimed by more than one peer, oldest claim first, then by peer id
+    ipv4_conflict_map: DashMap<Ipv4Addr, Vec<PeerId>>,
 }
 
 impl RouteTable {
@@ -399,8 +640,14 @@ impl RouteTable {
         RouteTable {
             peer_infos: DashMap::new(),
             next_hop_map: DashMap::new(),
+            ecmp_next_hop_map: DashMap::new(),
+            path_mtu_map: DashMap::new(),
             ipv4_peer_id_map: DashMap::new(),
+            ipv6_peer_id_map: DashMap::new(),
             cidr_peer_id_map: DashMap::new(),
+            multicast_group_peer_map: DashMap::new(),
+            igmp_snooping_complete: AtomicBool::new(false),
+            ipv4_conflict_map: DashMap::new(),
         }
     }
 
@@ -408,6 +655,40 @@ impl RouteTable {
         self.next_hop_map.get(&dst_peer_id).map(|x| *x)
     }
 
+    fn list_next_hops(&self, dst_peer_id: PeerId) -> Vec<PeerId> {
+        if let Some(next_hops) = self.ecmp_next_hop_map.get(&dst_peer_id) {
+            return next_hops.clone();
+        }
+        self.get_next_hop(dst_peer_id)
+            .map(|x| vec![x.0])
+            .unwrap_or_default()
+    }
+
+    fn get_path_mtu(&self, dst_peer_id: PeerId) -> Option<u16> {
+        self.path_mtu_map.get(&dst_peer_id).map(|x| *x)
+    }
+
+    fn get_link_mtu(&self, src_peer_id: PeerId, dst_peer_id: PeerId) -> Option<u16> {
+        let info = self.peer_infos.get(&src_peer_id)?;
+        info.link_mtus
+            .iter()
+            .find(|(peer_id, _)| *peer_id == dst_peer_id)
+            .map(|(_, mtu)| *mtu)
+    }
+
+    // both ends probe the link, the smaller result wins
+    fn get_path_mtu_of(&self, path: &[PeerId]) -> Option<u16> {
+        path.windows(2)
+            .flat_map(|hop| {
+                [
+                    self.get_link_mtu(hop[0], hop[1]),
+                    self.get_link_mtu(hop[1], hop[0]),
+                ]
+            })
+            .flatten()
+            .min()
+    }
+
     fn peer_reachable(&self, peer_id: PeerId) -> bool {
         self.next_hop_map.contains_key(&peer_id)
     }
@@ -454,9 +735,10 @@ impl RouteTable {
         graph: &PeerGraph,
         idx_map: &PeerIdToNodexIdxMap,
         cost_calc: &mut T,
-    ) -> NextHopMap {
+    ) -> (NextHopMap, PathMap) {
         let res = dijkstra(&graph, *idx_map.get(&my_peer_id).unwrap(), None, |_| 1);
         let next_hop_map = NextHopMap::new();
+        let path_map = PathMap::new();
         for (node_idx, cost) in res.iter() {
             if *cost == 0 {
                 continue;
@@ -492,17 +774,25 @@ impl RouteTable {
                 *graph.node_weight(*node_idx).unwrap(),
                 (*graph.node_weight(min_path[1]).unwrap(), *cost as i32),
             );
+            path_map.insert(
+                *graph.node_weight(*node_idx).unwrap(),
+                min_path
+                    .iter()
+                    .map(|idx| *graph.node_weight(*idx).unwrap())
+                    .collect(),
+            );
         }
 
-        next_hop_map
+        (next_hop_map, path_map)
     }
 
     fn gen_next_hop_map_with_least_cost(
         my_peer_id: PeerId,
         graph: &PeerGraph,
         idx_map: &PeerIdToNodexIdxMap,
-    ) -> NextHopMap {
+    ) -> (NextHopMap, PathMap) {
         let next_hop_map = NextHopMap::new();
+        let path_map = PathMap::new();
         for item in idx_map.iter() {
             if *item.key() == my_peer_id {
                 continue;
@@ -521,9 +811,72 @@ impl RouteTable {
             };
 
             next_hop_map.insert(*item.key(), (*graph.node_weight(path[1]).unwrap(), cost));
+            path_map.insert(
+                *item.key(),
+                path.iter()
+                    .map(|idx| *graph.node_weight(*idx).unwrap())
+                    .collect(),
+            );
+        }
+
+        (next_hop_map, path_map)
+    }
+
+    // a neighbor is an equal-cost next hop if the path through it costs at most best + tolerance.
+    // it must also be strictly closer to the destination than we are, so relays that choose
+    // their own next hop can never send the packet back to us.
+    fn gen_ecmp_next_hop_map(
+        my_peer_id: PeerId,
+        graph: &PeerGraph,
+        idx_map: &PeerIdToNodexIdxMap,
+        next_hop_map: &NextHopMap,
+        policy: &NextHopPolicy,
+        tolerance: i32,
+    ) -> EcmpNextHopMap {
+        let ecmp_next_hop_map = EcmpNextHopMap::new();
+        let my_idx = *idx_map.get(&my_peer_id).unwrap();
+        let least_hop = matches!(policy, NextHopPolicy::LeastHop);
+        let edge_cost = |w: i32| if least_hop { 1 } else { w };
+
+        for item in next_hop_map.iter() {
+            let (dst_peer_id, (primary, _)) = (*item.key(), *item.value());
+            if dst_peer_id == my_peer_id {
+                continue;
+            }
+            let Some(dst_idx) = idx_map.get(&dst_peer_id).map(|x| *x) else {
+                continue;
+            };
+
+            // distance of every node to dst
+            let dist = dijkstra(Reversed(graph), dst_idx, None, |e| edge_cost(*e.weight()));
+            let Some(my_dist) = dist.get(&my_idx).copied() else {
+                continue;
+            };
+
+            let mut next_hops = vec![primary];
+            for edge in graph.edges(my_idx) {
+                let neighbor = *graph.node_weight(edge.target()).unwrap();
+                if neighbor == primary {
+                    continue;
+                }
+                let Some(neighbor_dist) = dist.get(&edge.target()).copied() else {
+                    continue;
+                };
+                if neighbor_dist < my_dist
+                    && edge_cost(*edge.weight()) + neighbor_dist <= my_dist + tolerance
+                    && !next_hops.contains(&neighbor)
+                {
+                    next_hops.push(neighbor);
+                }
+            }
+            next_hops[1..].sort();
+
+            if next_hops.len() > 1 {
+                ecmp_next_hop_map.insert(dst_peer_id, next_hops);
+            }
         }
 
-        next_hop_map
+        ecmp_next_hop_map
     }
 
     fn build_from_synced_info<T: RouteCostCalculatorInterface>(
@@ -531,6 +884,8 @@ impl RouteTable {
         my_peer_id: PeerId,
         synced_info: &SyncedRouteInfo,
         policy: NextHopPolicy,
+        ecmp_tolerance: Option<i32>,
+        denied_peers: &DashSet<PeerId>,
         mut cost_calc: T,
     ) {
         // build  peer_infos
@@ -558,7 +913,7 @@ impl RouteTable {
             &synced_info,
             &mut cost_calc,
         );
-        let next_hop_map = if matches!(policy, NextHopPolicy::LeastHop) {
+        let (next_hop_map, path_map) = if matches!(policy, NextHopPolicy::LeastHop) {
             Self::gen_next_hop_map_with_least_hop(my_peer_id, &graph, &idx_map, &mut cost_calc)
         } else {
             Self::gen_next_hop_map_with_least_cost(my_peer_id, &graph, &idx_map)
@@ -566,14 +921,41 @@ impl RouteTable {
         for item in next_hop_map.iter() {
             self.next_hop_map.insert(*item.key(), *item.value());
         }
+
+        self.path_mtu_map.clear();
+        for item in path_map.iter() {
+            if let Some(mtu) = self.get_path_mtu_of(item.value()) {
+                self.path_mtu_map.insert(*item.key(), mtu);
+            }
+        }
+
+        self.ecmp_next_hop_map.clear();
+        if let Some(tolerance) = ecmp_tolerance {
+            let ecmp_next_hop_map = Self::gen_ecmp_next_hop_map(
+                my_peer_id,
+                &graph,
+                &idx_map,
+                &next_hop_map,
+                &policy,
+                tolerance,
+            );
+            for item in ecmp_next_hop_map.iter() {
+                self.ecmp_next_hop_map
+                    .insert(*item.key(), item.value().clone());
+            }
+        }
         // build graph
 
-        // build ipv4_peer_id_map, cidr_peer_id_map
+        // build ipv4_peer_id_map, ipv6_peer_id_map, cidr_peer_id_map, multicast_group_peer_map
+        let mut static_ipv4_claims: BTreeMap<Ipv4Addr, Vec<(u64, PeerId)>> = BTreeMap::new();
         self.ipv4_peer_id_map.clear();
+        self.ipv6_peer_id_map.clear();
         self.cidr_peer_id_map.clear();
+        self.multicast_group_peer_map.clear();
+        let mut igmp_snooping_complete = true;
         for item in self.peer_infos.iter() {
-            // only set ipv4 map for peers we can reach.
-            if !self.next_hop_map.contains_key(item.key()) {
+            // only set ipv4 map for peers we can reach and may talk to.
+            if !self.next_hop_map.contains_key(item.key()) || denied_peers.contains(item.key()) {
                 continue;
             }
 
@@ -582,24 +964,67 @@ impl RouteTable {
 
             if let Some(ipv4_addr) = info.ipv4_addr {
                 self.ipv4_peer_id_map.insert(ipv4_addr, *peer_id);
+                // dhcp nodes resolve conflicts by themselves, reservations count as static
+                if info.dhcp_lease_time == 0 {
+                    // claims not backed by a tun device sort last
+                    let claim_time = match info.ipv4_claim_time {
+                        0 => u64::MAX,
+                        t => t,
+                    };
+                    static_ipv4_claims
+                        .entry(ipv4_addr)
+                        .or_default()
+                        .push((claim_time, *peer_id));
+                }
+            }
+
+            if let Some(ipv6_addr) = info.ipv6_addr {
+                self.ipv6_peer_id_map.insert(ipv6_addr, *peer_id);
             }
 
             for cidr in info.proxy_cidrs.iter() {
                 self.cidr_peer_id_map
-                    .insert(cidr.parse().unwrap(), *peer_id);
+                    .entry(cidr.parse().unwrap())
+                    .or_default()
+                    .push(*peer_id);
+            }
+
+            if *peer_id != my_peer_id {
+                igmp_snooping_complete &= info.igmp_snooping;
+                for group in info.multicast_groups.iter() {
+                    self.multicast_group_peer_map
+                        .entry(*group)
+                        .or_default()
+                        .push(*peer_id);
+                }
             }
         }
-    }
 
-    fn get_peer_id_for_proxy(&self, ipv4: &Ipv4Addr) -> Option<PeerId> {
-        let ipv4 = std::net::IpAddr::V4(*ipv4);
-        for item in self.cidr_peer_id_map.iter() {
-            let (k, v) = item.pair();
-            if k.contains(&ipv4) {
-                return Some(*v);
+        self.igmp_snooping_complete
+            .store(igmp_snooping_complete, Ordering::Relaxed);
+
+        self.ipv4_conflict_map.clear();
+        for (ipv4_addr, mut claims) in static_ipv4_claims {
+            if claims.len() > 1 {
+                claims.sort();
+                self.ipv4_conflict_map
+                    .insert(ipv4_addr, claims.into_iter().map(|(_, p)| p).collect());
             }
         }
-        None
+    }
+
+    // all peers advertising the longest proxy cidr that contains ip
+    fn list_peer_ids_for_proxy(&self, ip: &IpAddr) -> Vec<PeerId> {
+        self.cidr_peer_id_map
+            .iter()
+            .filter(|item| item.key().contains(ip))
+            .max_by_key(|item| item.key().network_length())
+            .map(|item| item.value().clone())
+            .unwrap_or_default()
+    }
+
+    fn get_cost(&self, peer_id: PeerId) -> i32 {
+        self.get_next_hop(peer_id).map(|x| x.1).unwrap_or(i32::MAX)
     }
 }
 
@@ -763,6 +1188,12 @@ struct PeerRouteServiceImpl {
     route_table_with_cost: RouteTable,
     synced_route_info: Arc<SyncedRouteInfo>,
     cached_local_conn_map: std::sync::Mutex<RouteConnBitmap>,
+    // (destination, flow hash) -> (advertiser, last used)
+    proxy_sticky_map: DashMap<(IpAddr, u8), (PeerId, Instant)>,
+    // peers outside our segments, still routable for relaying
+    denied_peers: Arc<DashSet<PeerId>>,
+    reported_ipv4_conflicts: DashSet<Ipv4Addr>,
+    reported_network_length_conflict: std::sync::Mutex<BTreeSet<u8>>,
 }
 
 impl Debug for PeerRouteServiceImpl {
@@ -802,6 +1233,10 @@ impl PeerRouteServiceImpl {
                 conn_map: DashMap::new(),
             }),
             cached_local_conn_map: std::sync::Mutex::new(RouteConnBitmap::new()),
+            proxy_sticky_map: DashMap::new(),
+            denied_peers: Arc::new(DashSet::new()),
+            reported_ipv4_conflicts: DashSet::new(),
+            reported_network_length_conflict: std::sync::Mutex::new(BTreeSet::new()),
         }
     }
 
@@ -837,10 +1272,21 @@ impl PeerRouteServiceImpl {
             .collect()
     }
 
-    fn update_my_peer_info(&self) -> bool {
+    async fn list_link_mtus_from_interface(&self) -> Vec<(PeerId, u16)> {
+        self.interface
+            .lock()
+            .await
+            .as_ref()
+            .unwrap()
+            .list_link_mtus()
+            .await
+    }
+
+    async fn update_my_peer_info(&self) -> bool {
+        let link_mtus = self.list_link_mtus_from_interface().await;
         if self
             .synced_route_info
-            .update_my_peer_info(self.my_peer_id, &self.global_ctx)
+            .update_my_peer_info(self.my_peer_id, &self.global_ctx, link_mtus)
         {
             self.update_route_table_and_cached_local_conn_bitmap();
             return true;
@@ -864,11 +1310,22 @@ impl PeerRouteServiceImpl {
     fn update_route_table(&self) {
         let mut calc_locked = self.cost_calculator.lock().unwrap();
 
+        let flags = self.global_ctx.get_flags();
+        let ecmp_tolerance = |tolerance: u32| {
+            flags
+                .enable_ecmp
+                .then_some(tolerance.min(i32::MAX as u32) as i32)
+        };
+
+        self.update_denied_peers();
+
         calc_locked.as_mut().unwrap().begin_update();
         self.route_table.build_from_synced_info(
             self.my_peer_id,
             &self.synced_route_info,
             NextHopPolicy::LeastHop,
+            ecmp_tolerance(0),
+            &self.denied_peers,
             calc_locked.as_mut().unwrap(),
         );
 
@@ -876,9 +1333,161 @@ impl PeerRouteServiceImpl {
             self.my_peer_id,
             &self.synced_route_info,
             NextHopPolicy::LeastCost,
+            ecmp_tolerance(flags.ecmp_latency_tolerance),
+            &self.denied_peers,
             calc_locked.as_mut().unwrap(),
         );
         calc_locked.as_mut().unwrap().end_update();
+
+        self.update_learned_network_length();
+        self.update_ipv4_conflicts();
+
+        self.proxy_sticky_map
+            .retain(|_, v| v.1.elapsed() < PROXY_STICKY_IDLE_TIMEOUT);
+    }
+
+    // advertisers with the fewest hops and the lowest latency cost among them. with ecmp the
+    // ones within the latency tolerance are equally good and flows are spread over them.
+    fn list_best_peer_ids_for_proxy(&self, candidates: Vec<PeerId>) -> Vec<PeerId> {
+        let cost = |peer_id: PeerId| {
+            (
+                self.route_table.get_cost(peer_id),
+                self.route_table_with_cost.get_cost(peer_id),
+            )
+        };
+        let Some((min_hop, min_latency)) = candidates.iter().map(|p| cost(*p)).min() else {
+            return vec![];
+        };
+        let flags = self.global_ctx.get_flags();
+        let tolerance = if flags.enable_ecmp {
+            flags.ecmp_latency_tolerance.min(i32::MAX as u32) as i32
+        } else {
+            0
+        };
+
+        let mut best = candidates
+            .into_iter()
+            .filter(|p| {
+                let (hop, latency) = cost(*p);
+                hop == min_hop && latency <= min_latency.saturating_add(tolerance)
+            })
+            .collect::<Vec<_>>();
+        best.sort();
+        best
+    }
+
+    // choose among the advertisers of a proxied address by hop count, then by latency cost.
+    // a flow sticks to its advertiser as long as that one still advertises the best matching
+    // prefix, so established flows are not moved by small cost changes.
+    fn get_peer_id_for_proxy(&self, ip: &IpAddr, flow_hash: Option<u8>) -> Option<PeerId> {
+        let candidates = self.route_table.list_peer_ids_for_proxy(ip);
+        if candidates.is_empty() {
+            return None;
+        }
+        let Some(flow_hash) = flow_hash else {
+            return self
+                .list_best_peer_ids_for_proxy(candidates)
+                .first()
+                .copied();
+        };
+
+        let key = (*ip, flow_hash);
+        if let Some(mut sticky) = self.proxy_sticky_map.get_mut(&key) {
+            if candidates.contains(&sticky.0) {
+                sticky.1 = Instant::now();
+                return Some(sticky.0);
+            }
+        }
+
+        let best = self.list_best_peer_ids_for_proxy(candidates);
+        let peer_id = best[flow_hash as usize % best.len()];
+        self.proxy_sticky_map.insert(key, (peer_id, Instant::now()));
+        Some(peer_id)
+    }
+
+    fn update_denied_peers(&self) {
+        let rules = self.global_ctx.config.get_segment_rules();
+        let my_tags = self.global_ctx.config.get_tags();
+        let denied = self
+            .synced_route_info
+            .peer_infos
+            .iter()
+            .filter(|item| {
+                *item.key() != self.my_peer_id
+                    && !SegmentRule::is_allowed(&rules, &my_tags, &item.value().tags)
+            })
+            .map(|item| *item.key())
+            .collect::<BTreeSet<_>>();
+
+        // update in place so a denied peer never shows up as allowed in between
+        self.denied_peers.retain(|peer_id| denied.contains(peer_id));
+        for peer_id in denied {
+            self.denied_peers.insert(peer_id);
+        }
+    }
+
+    // report each newly found address conflict once, and whether our own address is affected
+    fn update_ipv4_conflicts(&self) {
+        let conflicts = &self.route_table.ipv4_conflict_map;
+        self.reported_ipv4_conflicts
+            .retain(|ipv4_addr| conflicts.contains_key(ipv4_addr));
+        for item in conflicts.iter() {
+            if self.reported_ipv4_conflicts.insert(*item.key()) {
+                tracing::warn!(ipv4_addr = ?item.key(), peer_ids = ?item.value(), "ipv4 conflict");
+                self.global_ctx.issue_event(GlobalCtxEvent::Ipv4Conflicted(
+                    *item.key(),
+                    item.value().clone(),
+                ));
+            }
+        }
+
+        // only the losers yield, the first claimant keeps the address
+        let my_conflicted = self
+            .global_ctx
+            .get_ipv4()
+            .and_then(|ipv4_addr| conflicts.get(&ipv4_addr))
+            .map(|peer_ids| peer_ids[0] != self.my_peer_id)
+            .unwrap_or(false);
+        self.global_ctx.set_ipv4_conflicted(my_conflicted);
+    }
+
+    // nodes without a configured network length follow the reachable peer with the smallest id
+    // that has one, so all of them agree on the same value. peers configured with different
+    // lengths are reported once per set of lengths.
+    fn update_learned_network_length(&self) {
+        if self.global_ctx.config.get_network_length().is_some() {
+            return;
+        }
+
+        let lengths = self
+            .route_table
+            .peer_infos
+            .iter()
+            .filter(|x| *x.key() != self.my_peer_id && x.value().network_length != 0)
+            .map(|x| (*x.key(), x.value().network_length))
+            .collect::<BTreeMap<_, _>>();
+
+        let distinct = lengths.values().copied().collect::<BTreeSet<_>>();
+        let mut reported = self.reported_network_length_conflict.lock().unwrap();
+        if distinct.len() > 1 && *reported != distinct {
+            tracing::warn!(
+                ?lengths,
+                "peers are configured with different network lengths"
+            );
+            self.global_ctx
+                .issue_event(GlobalCtxEvent::NetworkLengthConflicted(
+                    lengths.iter().map(|(k, v)| (*k, *v)).collect(),
+                ));
+        }
+        *reported = if distinct.len() > 1 {
+            distinct
+        } else {
+            BTreeSet::new()
+        };
+        drop(reported);
+
+        let learned = lengths.first_key_value().map(|(_, len)| *len);
+        self.global_ctx.set_learned_network_length(learned);
     }
 
     fn cost_calculator_need_update(&self) -> bool {
@@ -977,7 +1586,7 @@ impl PeerRouteServiceImpl {
     }
 
     async fn update_my_infos(&self) -> bool {
-        let mut ret = self.update_my_peer_info();
+        let mut ret = self.update_my_peer_info().await;
         ret |= self.update_my_conn_info().await;
         ret
     }
@@ -1048,6 +1657,7 @@ impl PeerRouteServiceImpl {
                         session.we_are_initiator.load(Ordering::Relaxed),
                         peer_infos.clone(),
                         conn_bitmap.clone(),
+                        RoutePeerInfoExts::new(peer_infos.as_ref()),
                     )
                     .await
             })
@@ -1111,9 +1721,14 @@ impl RouteService for RouteSessionManager {
         from_peer_id: PeerId,
         from_session_id: SessionId,
         is_initiator: bool,
-        peer_infos: Option<Vec<RoutePeerInfo>>,
+        mut peer_infos: Option<Vec<RoutePeerInfo>>,
         conn_bitmap: Option<RouteConnBitmap>,
+        peer_info_exts: RoutePeerInfoExts,
     ) -> Result<SyncRouteInfoResponse, Error> {
+        if let Some(peer_infos) = &mut peer_infos {
+            peer_info_exts.apply(peer_infos);
+        }
+
         let Some(service_impl) = self.service_impl.upgrade() else {
             return Err(Error::Stopped);
         };
@@ -1507,6 +2122,40 @@ impl Route for PeerRoute {
         route_table.get_next_hop(dst_peer_id).map(|x| x.0)
     }
 
+    async fn list_next_hops_with_policy(
+        &self,
+        dst_peer_id: PeerId,
+        policy: NextHopPolicy,
+    ) -> Vec<PeerId> {
+        let route_table = if matches!(policy, NextHopPolicy::LeastCost) {
+            &self.service_impl.route_table_with_cost
+        } else {
+            &self.service_impl.route_table
+        };
+        route_table.list_next_hops(dst_peer_id)
+    }
+
+    async fn get_path_mtu(&self, dst_peer_id: PeerId, policy: NextHopPolicy) -> Option<u16> {
+        let route_table = if matches!(policy, NextHopPolicy::LeastCost) {
+            &self.service_impl.route_table_with_cost
+        } else {
+            &self.service_impl.route_table
+        };
+        route_table.get_path_mtu(dst_peer_id)
+    }
+
+    async fn get_route_entry(&self, dst_peer_id: PeerId, policy: NextHopPolicy) -> RouteEntry {
+        let route_table = if matches!(policy, NextHopPolicy::LeastCost) {
+            &self.service_impl.route_table_with_cost
+        } else {
+            &self.service_impl.route_table
+        };
+        RouteEntry {
+            next_hops: route_table.list_next_hops(dst_peer_id),
+            path_mtu: route_table.get_path_mtu(dst_peer_id),
+        }
+    }
+
     async fn list_routes(&self) -> Vec<crate::rpc::Route> {
         let route_table = &self.service_impl.route_table;
         let mut routes = Vec::new();
@@ -1520,6 +2169,12 @@ impl Route for PeerRoute {
             let mut route: crate::rpc::Route = item.value().clone().into();
             route.next_hop_peer_id = next_hop_peer.0;
             route.cost = next_hop_peer.1;
+            route.segment_denied = self.service_impl.denied_peers.contains(item.key());
+            route.ipv4_conflict = item
+                .value()
+                .ipv4_addr
+                .map(|ipv4_addr| route_table.ipv4_conflict_map.contains_key(&ipv4_addr))
+                .unwrap_or(false);
             routes.push(route);
         }
         routes
@@ -1531,7 +2186,10 @@ impl Route for PeerRoute {
             return Some(*peer_id);
         }
 
-        if let Some(peer_id) = route_table.get_peer_id_for_proxy(ipv4_addr) {
+        if let Some(peer_id) = self
+            .service_impl
+            .get_peer_id_for_proxy(&IpAddr::V4(*ipv4_addr), None)
+        {
             return Some(peer_id);
         }
 
@@ -1539,6 +2197,51 @@ impl Route for PeerRoute {
         None
     }
 
+    async fn get_peer_id_by_ipv6(&self, ipv6_addr: &Ipv6Addr) -> Option<PeerId> {
+        let route_table = &self.service_impl.route_table;
+        if let Some(peer_id) = route_table.ipv6_peer_id_map.get(ipv6_addr) {
+            return Some(*peer_id);
+        }
+
+        if let Some(peer_id) = self
+            .service_impl
+            .get_peer_id_for_proxy(&IpAddr::V6(*ipv6_addr), None)
+        {
+            return Some(peer_id);
+        }
+
+        tracing::debug!(?ipv6_addr, "no peer id for ipv6");
+        None
+    }
+
+    async fn get_peer_id_by_ip_for_flow(&self, ip: &IpAddr, flow_hash: u8) -> Option<PeerId> {
+        let route_table = &self.service_impl.route_table;
+        let peer_id = match ip {
+            IpAddr::V4(ipv4_addr) => route_table.ipv4_peer_id_map.get(ipv4_addr).map(|x| *x),
+            IpAddr::V6(ipv6_addr) => route_table.ipv6_peer_id_map.get(ipv6_addr).map(|x| *x),
+        };
+        peer_id.or_else(|| self.service_impl.get_peer_id_for_proxy(ip, Some(flow_hash)))
+    }
+
+    async fn list_peers_in_multicast_group(&self, group: &Ipv4Addr) -> Option<Vec<PeerId>> {
+        let route_table = &self.service_impl.route_table;
+        // peers without snooping would never get the group, flood instead
+        if !route_table.igmp_snooping_complete.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(
+            route_table
+                .multicast_group_peer_map
+                .get(group)
+                .map(|x| x.clone())
+                .unwrap_or_default(),
+        )
+    }
+
+    fn get_denied_peers(&self) -> Option<Arc<DashSet<PeerId>>> {
+        Some(self.service_impl.denied_peers.clone())
+    }
+
     async fn set_route_cost_fn(&self, _cost_fn: RouteCostCalculator) {
         *self.service_impl.cost_calculator.lock().unwrap() = Some(_cost_fn);
         self.service_impl.update_route_table();
@@ -1555,12 +2258,16 @@ impl PeerPacketFilter for Arc<PeerRoute> {}
 mod tests {
     use std::{
         collections::BTreeSet,
+        net::{IpAddr, Ipv4Addr},
         sync::{atomic::Ordering, Arc},
         time::Duration,
     };
 
     use crate::{
-        common::{global_ctx::tests::get_mock_global_ctx, PeerId},
+        common::{
+            global_ctx::{tests::get_mock_global_ctx, GlobalCtxEvent},
+            PeerId,
+        },
         connector::udp_hole_punch::tests::replace_stun_info_collector,
         peers::{
             peer_manager::{PeerManager, RouteAlgoType},
@@ -1571,7 +2278,7 @@ mod tests {
         tunnel::common::tests::wait_for_condition,
     };
 
-    use super::PeerRoute;
+    use super::{PeerRoute, RoutePeerInfo, RouteTable};
 
     async fn create_mock_route(peer_mgr: Arc<PeerManager>) -> Arc<PeerRoute> {
         let peer_route = PeerRoute::new(
@@ -1989,4 +2696,602 @@ mod tests {
         )
         .await;
     }
+
+    #[tokio::test]
+    async fn ospf_route_multicast_group() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+
+        let group: Ipv4Addr = "239.1.2.3".parse().unwrap();
+        p_b.get_global_ctx().join_multicast_group(group);
+
+        let r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+
+        wait_for_condition(
+            || async {
+                r_a.list_peers_in_multicast_group(&group).await == Some(vec![p_b.my_peer_id()])
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // a peer that does not snoop igmp never advertises groups, so we flood to it
+        let service_impl = &r_a.service_impl;
+        service_impl
+            .synced_route_info
+            .peer_infos
+            .get_mut(&p_b.my_peer_id())
+            .unwrap()
+            .igmp_snooping = false;
+        service_impl.update_route_table();
+        assert_eq!(None, r_a.list_peers_in_multicast_group(&group).await);
+    }
+
+    #[tokio::test]
+    async fn ospf_route_sync_ipv6_addr() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+
+        let ipv6_b = p_b
+            .get_global_ctx()
+            .get_network_identity()
+            .derive_ipv6_addr(p_b.my_peer_id());
+        p_b.get_global_ctx().set_ipv6(Some(ipv6_b));
+
+        let r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+
+        wait_for_condition(
+            || async { r_a.get_peer_id_by_ipv6(&ipv6_b).await == Some(p_b.my_peer_id()) },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        let route = r_a
+            .list_routes()
+            .await
+            .into_iter()
+            .find(|r| r.peer_id == p_b.my_peer_id())
+            .unwrap();
+        assert_eq!(ipv6_b.to_string(), route.ipv6_addr);
+        assert_eq!(
+            None,
+            r_a.get_peer_id_by_ipv6(&"fd00::1".parse().unwrap()).await
+        );
+    }
+
+    #[tokio::test]
+    async fn ospf_route_learn_network_length() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+
+        p_a.get_global_ctx().config.set_network_length(Some(16));
+        let mut event_recv = p_b.get_global_ctx().subscribe();
+
+        let _r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+
+        wait_for_condition(
+            || async { p_b.get_global_ctx().get_network_length() == 16 },
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(16, p_a.get_global_ctx().get_network_length());
+
+        async fn wait_event(
+            event_recv: &mut tokio::sync::broadcast::Receiver<GlobalCtxEvent>,
+            f: fn(&GlobalCtxEvent) -> bool,
+        ) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while !f(&event_recv.recv().await.unwrap()) {}
+            })
+            .await
+            .unwrap();
+        }
+        wait_event(&mut event_recv, |e| {
+            matches!(e, GlobalCtxEvent::NetworkLengthChanged(24, 16))
+        })
+        .await;
+
+        // a peer with another length is reported, the smallest peer id still decides
+        let p_c = create_mock_pmgr().await;
+        p_c.get_global_ctx().config.set_network_length(Some(20));
+        connect_peer_manager(p_b.clone(), p_c.clone()).await;
+        let _r_c = create_mock_route(p_c.clone()).await;
+        let expected = if p_a.my_peer_id() < p_c.my_peer_id() {
+            16
+        } else {
+            20
+        };
+        wait_event(
+            &mut event_recv,
+            |e| matches!(e, GlobalCtxEvent::NetworkLengthConflicted(lengths) if lengths.len() == 2),
+        )
+        .await;
+        wait_for_condition(
+            || async { p_b.get_global_ctx().get_network_length() == expected },
+            Duration::from_secs(5),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn ospf_route_anycast_proxy_cidr() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        let p_c = create_mock_pmgr().await;
+        let p_d = create_mock_pmgr().await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_a.clone(), p_c.clone()).await;
+        connect_peer_manager(p_c.clone(), p_d.clone()).await;
+
+        // b is one hop away and d two hops, both advertise the same cidr.
+        // d also advertises a more specific one.
+        let shared_cidr = "192.168.100.0/24".parse().unwrap();
+        p_b.get_global_ctx().add_proxy_cidr(shared_cidr).unwrap();
+        p_d.get_global_ctx().add_proxy_cidr(shared_cidr).unwrap();
+        p_d.get_global_ctx()
+            .add_proxy_cidr("192.168.100.128/25".parse().unwrap())
+            .unwrap();
+
+        let r_a = create_mock_route(p_a.clone()).await;
+        let r_b = create_mock_route(p_b.clone()).await;
+        let _r_c = create_mock_route(p_c.clone()).await;
+        let _r_d = create_mock_route(p_d.clone()).await;
+
+        let near_ip = "192.168.100.1".parse().unwrap();
+        let specific_ip = "192.168.100.200".parse().unwrap();
+        wait_for_condition(
+            || async {
+                r_a.get_peer_id_by_ipv4(&near_ip).await == Some(p_b.my_peer_id())
+                    && r_a.get_peer_id_by_ipv4(&specific_ip).await == Some(p_d.my_peer_id())
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // fail over to the remaining advertiser
+        drop(r_b);
+        drop(p_b);
+        wait_for_condition(
+            || async { r_a.get_peer_id_by_ipv4(&near_ip).await == Some(p_d.my_peer_id()) },
+            Duration::from_secs(10),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn ospf_route_anycast_proxy_per_flow() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        let p_c = create_mock_pmgr().await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_a.clone(), p_c.clone()).await;
+
+        let mut flags = p_a.get_global_ctx().get_flags();
+        flags.enable_ecmp = true;
+        flags.ecmp_latency_tolerance = 10;
+        p_a.get_global_ctx().config.set_flags(flags);
+
+        let shared_cidr = "192.168.100.0/24".parse().unwrap();
+        p_b.get_global_ctx().add_proxy_cidr(shared_cidr).unwrap();
+        p_c.get_global_ctx().add_proxy_cidr(shared_cidr).unwrap();
+
+        let r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+        let _r_c = create_mock_route(p_c.clone()).await;
+
+        let ip: IpAddr = "192.168.100.1".parse().unwrap();
+        wait_for_condition(
+            || async {
+                r_a.service_impl
+                    .route_table
+                    .list_peer_ids_for_proxy(&ip)
+                    .len()
+                    == 2
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // flows to the same destination are spread over both advertisers
+        let mut used = BTreeSet::new();
+        let mut flows_via_c = vec![];
+        for flow_hash in 0..=255u8 {
+            let peer_id = r_a
+                .get_peer_id_by_ip_for_flow(&ip, flow_hash)
+                .await
+                .unwrap();
+            if peer_id == p_c.my_peer_id() {
+                flows_via_c.push(flow_hash);
+            }
+            used.insert(peer_id);
+        }
+        assert_eq!(2, used.len());
+
+        struct TestCostCalculator {
+            p_c_peer_id: PeerId,
+        }
+
+        impl RouteCostCalculatorInterface for TestCostCalculator {
+            fn calculate_cost(&self, _src: PeerId, dst: PeerId) -> i32 {
+                if dst == self.p_c_peer_id {
+                    return 100;
+                }
+                20
+            }
+        }
+
+        // c becomes much slower, new flows avoid it but established ones stay
+        r_a.set_route_cost_fn(Box::new(TestCostCalculator {
+            p_c_peer_id: p_c.my_peer_id(),
+        }))
+        .await;
+        for flow_hash in 0..=255u8 {
+            let expected = if flows_via_c.contains(&flow_hash) {
+                p_c.my_peer_id()
+            } else {
+                p_b.my_peer_id()
+            };
+            assert_eq!(
+                Some(expected),
+                r_a.get_peer_id_by_ip_for_flow(&ip, flow_hash).await
+            );
+        }
+        r_a.service_impl.proxy_sticky_map.clear();
+        assert_eq!(
+            Some(p_b.my_peer_id()),
+            r_a.get_peer_id_by_ip_for_flow(&ip, flows_via_c[0]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn ospf_route_ecmp() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        let p_c = create_mock_pmgr().await;
+        let p_d = create_mock_pmgr().await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_a.clone(), p_c.clone()).await;
+        connect_peer_manager(p_d.clone(), p_b.clone()).await;
+        connect_peer_manager(p_d.clone(), p_c.clone()).await;
+
+        let mut flags = p_a.get_global_ctx().get_flags();
+        flags.enable_ecmp = true;
+        flags.ecmp_latency_tolerance = 10;
+        p_a.get_global_ctx().config.set_flags(flags);
+
+        let r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+        let _r_c = create_mock_route(p_c.clone()).await;
+        let _r_d = create_mock_route(p_d.clone()).await;
+
+        let mut both = vec![p_b.my_peer_id(), p_c.my_peer_id()];
+        both.sort();
+        wait_for_condition(
+            || async {
+                let mut hops = r_a
+                    .list_next_hops_with_policy(p_d.my_peer_id(), NextHopPolicy::LeastHop)
+                    .await;
+                hops.sort();
+                hops == both
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // flows are spread over both paths
+        let peer_map = p_a.get_peer_map();
+        let mut used = BTreeSet::new();
+        for flow_hash in 0..=255u8 {
+            used.insert(
+                peer_map
+                    .get_gateway_peer_id(p_d.my_peer_id(), NextHopPolicy::LeastHop, flow_hash)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(2, used.len());
+
+        struct TestCostCalculator {
+            p_a_peer_id: PeerId,
+            p_c_peer_id: PeerId,
+        }
+
+        impl RouteCostCalculatorInterface for TestCostCalculator {
+            fn calculate_cost(&self, src: PeerId, dst: PeerId) -> i32 {
+                if src == self.p_a_peer_id && dst == self.p_c_peer_id {
+                    return 25;
+                }
+                20
+            }
+        }
+
+        // the path through c is 5ms slower, still within the tolerance
+        r_a.set_route_cost_fn(Box::new(TestCostCalculator {
+            p_a_peer_id: p_a.my_peer_id(),
+            p_c_peer_id: p_c.my_peer_id(),
+        }))
+        .await;
+        let mut hops = r_a
+            .list_next_hops_with_policy(p_d.my_peer_id(), NextHopPolicy::LeastCost)
+            .await;
+        assert_eq!(p_b.my_peer_id(), hops[0]);
+        hops.sort();
+        assert_eq!(both, hops);
+
+        let mut flags = p_a.get_global_ctx().get_flags();
+        flags.ecmp_latency_tolerance = 1;
+        p_a.get_global_ctx().config.set_flags(flags);
+        r_a.service_impl.update_route_table();
+        assert_eq!(
+            vec![p_b.my_peer_id()],
+            r_a.list_next_hops_with_policy(p_d.my_peer_id(), NextHopPolicy::LeastCost)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn ospf_route_segment_denied() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        let p_c = create_mock_pmgr().await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_b.clone(), p_c.clone()).await;
+
+        let ip_b = "10.126.126.2".parse().unwrap();
+        let ip_c = "10.126.126.3".parse().unwrap();
+        p_b.get_global_ctx().set_ipv4(Some(ip_b));
+        p_c.get_global_ctx().set_ipv4(Some(ip_c));
+        p_b.get_global_ctx()
+            .config
+            .set_tags(vec!["role=relay".to_string()]);
+        p_c.get_global_ctx()
+            .config
+            .set_tags(vec!["team=qa".to_string()]);
+        p_a.get_global_ctx()
+            .config
+            .set_tags(vec!["team=dev".to_string()]);
+        p_a.get_global_ctx()
+            .config
+            .set_segment_rules(vec!["team=dev<->role=relay".parse().unwrap()]);
+
+        let r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+        let _r_c = create_mock_route(p_c.clone()).await;
+
+        wait_for_condition(
+            || async {
+                r_a.get_peer_id_by_ipv4(&ip_b).await == Some(p_b.my_peer_id())
+                    && p_a.get_peer_map().is_peer_segment_denied(p_c.my_peer_id())
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        // c is not reachable by address but still routable for relaying
+        assert_eq!(None, r_a.get_peer_id_by_ipv4(&ip_c).await);
+        assert_eq!(
+            Some(p_b.my_peer_id()),
+            r_a.get_next_hop(p_c.my_peer_id()).await
+        );
+        let routes = r_a.list_routes().await;
+        let route_c = routes
+            .iter()
+            .find(|r| r.peer_id == p_c.my_peer_id())
+            .unwrap();
+        assert!(route_c.segment_denied);
+        assert_eq!(vec!["team=qa".to_string()], route_c.tags);
+        assert!(!p_a.get_peer_map().is_peer_segment_denied(p_b.my_peer_id()));
+    }
+
+    #[tokio::test]
+    async fn ospf_route_ipv4_conflict() {
+        let p_a = create_mock_pmgr().await;
+        let p_b = create_mock_pmgr().await;
+        let p_c = create_mock_pmgr().await;
+        connect_peer_manager(p_a.clone(), p_b.clone()).await;
+        connect_peer_manager(p_b.clone(), p_c.clone()).await;
+
+        let ip: Ipv4Addr = "10.126.126.2".parse().unwrap();
+        p_a.get_global_ctx().set_ipv4(Some(ip));
+        p_b.get_global_ctx().set_ipv4(Some(ip));
+        // b brought up its tun device first, a yields
+        p_a.get_global_ctx().set_ipv4_claim_time(200);
+        p_b.get_global_ctx().set_ipv4_claim_time(100);
+        // dhcp nodes are not reported, they move away by themselves
+        p_c.get_global_ctx().set_ipv4(Some(ip));
+        p_c.get_global_ctx().set_dhcp_lease_time(100);
+
+        let mut events = p_a.get_global_ctx().subscribe();
+        let r_a = create_mock_route(p_a.clone()).await;
+        let _r_b = create_mock_route(p_b.clone()).await;
+        let _r_c = create_mock_route(p_c.clone()).await;
+
+        wait_for_condition(
+            || async {
+                r_a.list_routes().await.len() == 2 && p_a.get_global_ctx().is_ipv4_conflicted()
+            },
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert!(!p_b.get_global_ctx().is_ipv4_conflicted());
+
+        let expected = vec![p_b.my_peer_id(), p_a.my_peer_id()];
+        let mut conflicts = vec![];
+        while let Ok(event) = events.try_recv() {
+            if let GlobalCtxEvent::Ipv4Conflicted(ipv4, peer_ids) = event {
+                conflicts.push((ipv4, peer_ids));
+            }
+        }
+        assert_eq!(vec![(ip, expected)], conflicts);
+
+        for route in r_a.list_routes().await {
+            assert!(route.ipv4_conflict);
+        }
+
+        // resolved once b moves away
+        p_b.get_global_ctx()
+            .set_ipv4(Some("10.126.126.3".parse().unwrap()));
+        p_c.get_global_ctx().set_ipv4(None);
+        wait_for_condition(
+            || async { !p_a.get_global_ctx().is_ipv4_conflicted() },
+            Duration::from_secs(5),
+        )
+        .await;
+    }
+
+    #[test]
+    fn path_mtu_is_smallest_probed_link() {
+        let route_table = RouteTable::new();
+        let add_info = |peer_id: PeerId, link_mtus: Vec<(PeerId, u16)>| {
+            let mut info = RoutePeerInfo::new();
+            info.peer_id = peer_id;
+            info.link_mtus = link_mtus;
+            route_table.peer_infos.insert(peer_id, info);
+        };
+        add_info(1, vec![(2, 1360)]);
+        add_info(2, vec![(1, 1360), (3, 1400)]);
+        // 3 saw a smaller mtu toward 2 than 2 did
+        add_info(3, vec![(2, 1200)]);
+        add_info(4, vec![]);
+
+        assert_eq!(Some(1360), route_table.get_path_mtu_of(&[1, 2]));
+        assert_eq!(Some(1200), route_table.get_path_mtu_of(&[1, 2, 3]));
+        assert_eq!(None, route_table.get_path_mtu_of(&[3, 4]));
+    }
+
+    // the layout of sync_route_info requests sent by the baseline release
+    #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+    struct BaselineRoutePeerInfo {
+        peer_id: PeerId,
+        inst_id: uuid::Uuid,
+        cost: u8,
+        ipv4_addr: Option<Ipv4Addr>,
+        proxy_cidrs: Vec<String>,
+        hostname: Option<String>,
+        udp_stun_info: i8,
+        last_update: std::time::SystemTime,
+        version: u32,
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug)]
+    enum BaselineRouteServiceRequest {
+        SyncRouteInfo {
+            my_peer_id: PeerId,
+            my_session_id: u64,
+            is_initiator: bool,
+            peer_infos: Option<Vec<BaselineRoutePeerInfo>>,
+            conn_bitmap: Option<super::RouteConnBitmap>,
+        },
+    }
+
+    #[test]
+    fn route_peer_info_baseline_compat() {
+        use super::{RoutePeerInfoExts, RouteServiceRequest};
+
+        let baseline_info = BaselineRoutePeerInfo {
+            peer_id: 3,
+            inst_id: uuid::Uuid::new_v4(),
+            cost: 2,
+            ipv4_addr: Some("10.144.144.3".parse().unwrap()),
+            proxy_cidrs: vec!["192.168.1.0/24".to_owned()],
+            hostname: Some("old".to_owned()),
+            udp_stun_info: NatType::FullCone as i8,
+            last_update: std::time::SystemTime::now(),
+            version: 7,
+        };
+
+        // a request from a baseline peer decodes, the new fields take defaults
+        let buf = postcard::to_allocvec(&BaselineRouteServiceRequest::SyncRouteInfo {
+            my_peer_id: 1,
+            my_session_id: 2,
+            is_initiator: true,
+            peer_infos: Some(vec![baseline_info.clone()]),
+            conn_bitmap: None,
+        })
+        .unwrap();
+        let RouteServiceRequest::SyncRouteInfo {
+            my_peer_id,
+            peer_infos,
+            peer_info_exts,
+            ..
+        } = postcard::from_bytes(&buf).unwrap();
+        assert_eq!(1, my_peer_id);
+        assert!(peer_info_exts.0.is_empty());
+        let info = &peer_infos.unwrap()[0];
+        assert_eq!(baseline_info.peer_id, info.peer_id);
+        assert_eq!(baseline_info.inst_id, info.inst_id);
+        assert_eq!(baseline_info.hostname, info.hostname);
+        assert_eq!(baseline_info.version, info.version);
+        assert_eq!(None, info.ipv6_addr);
+        assert!(info.link_mtus.is_empty());
+
+        // a baseline peer decodes our request, and we get the new fields back
+        let mut info = RoutePeerInfo::new();
+        info.peer_id = 4;
+        info.hostname = Some("new".to_owned());
+        info.ipv6_addr = Some("fd00::4".parse().unwrap());
+        info.tags = vec!["lab".to_owned()];
+        info.link_mtus = vec![(3, 1360)];
+        let peer_infos = Some(vec![info.clone()]);
+        let buf = postcard::to_allocvec(&RouteServiceRequest::SyncRouteInfo {
+            my_peer_id: 4,
+            my_session_id: 5,
+            is_initiator: false,
+            peer_info_exts: RoutePeerInfoExts::new(peer_infos.as_ref()),
+            peer_infos,
+            conn_bitmap: Some(super::RouteConnBitmap::new()),
+        })
+        .unwrap();
+
+        let BaselineRouteServiceRequest::SyncRouteInfo {
+            my_peer_id,
+            peer_infos,
+            conn_bitmap,
+            ..
+        } = postcard::from_bytes(&buf).unwrap();
+        assert_eq!(4, my_peer_id);
+        assert!(conn_bitmap.is_some());
+        let baseline_info = &peer_infos.unwrap()[0];
+        assert_eq!(info.peer_id, baseline_info.peer_id);
+        assert_eq!(info.hostname, baseline_info.hostname);
+
+        let RouteServiceRequest::SyncRouteInfo {
+            peer_infos,
+            peer_info_exts,
+            ..
+        } = postcard::from_bytes(&buf).unwrap();
+        let mut peer_infos = peer_infos.unwrap();
+        peer_info_exts.apply(&mut peer_infos);
+        assert_eq!(info, peer_infos[0]);
+    }
+
+    #[test]
+    fn route_peer_info_ext_fields_compat() {
+        use super::{encode_ext_field, RoutePeerInfoExts};
+
+        // an older peer knows fewer fields, a newer one sends tags we have never seen
+        let ext = vec![
+            encode_ext_field(1, &Some("fd00::5".parse::<std::net::Ipv6Addr>().unwrap())),
+            encode_ext_field(6, &vec!["lab".to_owned()]),
+            encode_ext_field(9, &vec![(3 as PeerId, 1360u16)]),
+            encode_ext_field(1000, &"from the future".to_owned()),
+        ];
+        let exts = RoutePeerInfoExts(vec![postcard::to_allocvec(&ext).unwrap()]);
+
+        let mut peer_infos = vec![RoutePeerInfo::new()];
+        exts.apply(&mut peer_infos);
+        let info = &peer_infos[0];
+        assert_eq!(Some("fd00::5".parse().unwrap()), info.ipv6_addr);
+        assert_eq!(vec!["lab".to_owned()], info.tags);
+        assert_eq!(vec![(3, 1360)], info.link_mtus);
+        assert!(!info.has_global_ipv6);
+        assert_eq!(0, info.stun_server_port);
+    }
 }
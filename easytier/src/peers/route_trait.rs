@@ -1,6 +1,10 @@
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
+use dashmap::DashSet;
 use tokio_util::bytes::Bytes;
 
 use crate::common::{error::Error, PeerId};
@@ -17,6 +21,14 @@ impl Default for NextHopPolicy {
     }
 }
 
+// what the forwarding path needs to know about a destination
+#[derive(Clone, Debug, Default)]
+pub struct RouteEntry {
+    pub next_hops: Vec<PeerId>,
+    // the smallest probed link mtu on the route, none if no link was probed
+    pub path_mtu: Option<u16>,
+}
+
 #[async_trait]
 pub trait RouteInterface {
     async fn list_peers(&self) -> Vec<PeerId>;
@@ -27,6 +39,10 @@ pub trait RouteInterface {
         dst_peer_id: PeerId,
     ) -> Result<(), Error>;
     fn my_peer_id(&self) -> PeerId;
+    // probed mtu of the links to directly connected peers
+    async fn list_link_mtus(&self) -> Vec<(PeerId, u16)> {
+        vec![]
+    }
 }
 
 pub type RouteInterfaceBox = Box<dyn RouteInterface + Send + Sync>;
@@ -71,12 +87,62 @@ pub trait Route {
         self.get_next_hop(peer_id).await
     }
 
+    // all usable next hops, the preferred one first. more than one is returned only when
+    // equal-cost multipath is enabled.
+    async fn list_next_hops_with_policy(
+        &self,
+        peer_id: PeerId,
+        policy: NextHopPolicy,
+    ) -> Vec<PeerId> {
+        self.get_next_hop_with_policy(peer_id, policy)
+            .await
+            .into_iter()
+            .collect()
+    }
+
     async fn list_routes(&self) -> Vec<crate::rpc::Route>;
 
     async fn get_peer_id_by_ipv4(&self, _ipv4: &Ipv4Addr) -> Option<PeerId> {
         None
     }
 
+    async fn get_peer_id_by_ipv6(&self, _ipv6: &Ipv6Addr) -> Option<PeerId> {
+        None
+    }
+
+    // same as above, but flows to a proxied address may be spread over its advertisers
+    async fn get_peer_id_by_ip_for_flow(&self, ip: &IpAddr, _flow_hash: u8) -> Option<PeerId> {
+        match ip {
+            IpAddr::V4(ipv4) => self.get_peer_id_by_ipv4(ipv4).await,
+            IpAddr::V6(ipv6) => self.get_peer_id_by_ipv6(ipv6).await,
+        }
+    }
+
+    // peers that joined the multicast group, none if the route does not track membership
+    async fn list_peers_in_multicast_group(&self, _group: &Ipv4Addr) -> Option<Vec<PeerId>> {
+        None
+    }
+
+    // the smallest probed link mtu on the route to the peer, none if no link was probed
+    async fn get_path_mtu(&self, _peer_id: PeerId, _policy: NextHopPolicy) -> Option<u16> {
+        None
+    }
+
+    // next hops and path mtu in one lookup, for the per packet path
+    async fn get_route_entry(&self, peer_id: PeerId, policy: NextHopPolicy) -> RouteEntry {
+        RouteEntry {
+            next_hops: self
+                .list_next_hops_with_policy(peer_id, policy.clone())
+                .await,
+            path_mtu: self.get_path_mtu(peer_id, policy).await,
+        }
+    }
+
+    // peers outside the segments we may exchange data with, kept up to date by the route
+    fn get_denied_peers(&self) -> Option<Arc<DashSet<PeerId>>> {
+        None
+    }
+
     async fn set_route_cost_fn(&self, _cost_fn: RouteCostCalculator) {}
 
     async fn dump(&self) -> String {
@@ -1,9 +1,14 @@
 use std::sync::Arc;
 
-use crate::rpc::{
-    cli::PeerInfo, peer_manage_rpc_server::PeerManageRpc, DumpRouteRequest, DumpRouteResponse,
-    ListForeignNetworkRequest, ListForeignNetworkResponse, ListPeerRequest, ListPeerResponse,
-    ListRouteRequest, ListRouteResponse, ShowNodeInfoRequest, ShowNodeInfoResponse,
+use crate::{
+    common::config::{ConfigLoader, ExitPolicyRule},
+    rpc::{
+        cli::PeerInfo, peer_manage_rpc_server::PeerManageRpc, DumpRouteRequest, DumpRouteResponse,
+        ListExitPolicyRequest, ListExitPolicyResponse, ListForeignNetworkRequest,
+        ListForeignNetworkResponse, ListPeerRequest, ListPeerResponse, ListRouteRequest,
+        ListRouteResponse, SetExitPolicyRequest, SetExitPolicyResponse, ShowNodeInfoRequest,
+        ShowNodeInfoResponse,
+    },
 };
 use tonic::{Request, Response, Status};
 
@@ -90,4 +95,29 @@ impl PeerManageRpc for PeerManagerRpcService {
             node_info: Some(self.peer_manager.get_my_info()),
         }))
     }
+
+    async fn list_exit_policy(
+        &self,
+        _request: Request<ListExitPolicyRequest>,
+    ) -> Result<Response<ListExitPolicyResponse>, Status> {
+        let rules = self.peer_manager.get_global_ctx().config.get_exit_policy();
+        Ok(Response::new(ListExitPolicyResponse {
+            rules: rules.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn set_exit_policy(
+        &self,
+        request: Request<SetExitPolicyRequest>,
+    ) -> Result<Response<SetExitPolicyResponse>, Status> {
+        let rules = request
+            .into_inner()
+            .rules
+            .into_iter()
+            .map(ExitPolicyRule::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Status::invalid_argument(format!("invalid exit policy: {:?}", e)))?;
+        self.peer_manager.get_global_ctx().set_exit_policy(rules);
+        Ok(Response::new(SetExitPolicyResponse::default()))
+    }
 }
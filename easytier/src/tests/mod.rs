@@ -89,6 +89,26 @@ pub fn create_netns(name: &str, ipv4: &str) {
         .unwrap();
 }
 
+pub fn add_ipv6_addr(name: &str, ipv6: &str) {
+    // skip duplicate address detection, the address is used right away
+    let _ = std::process::Command::new("ip")
+        .args(&[
+            "netns",
+            "exec",
+            name,
+            "ip",
+            "-6",
+            "addr",
+            "add",
+            ipv6,
+            "dev",
+            get_guest_veth_name(name),
+            "nodad",
+        ])
+        .output()
+        .unwrap();
+}
+
 pub fn prepare_bridge(name: &str) {
     // del bridge with brctl
     let _ = std::process::Command::new("brctl")
@@ -368,6 +368,160 @@ pub async fn subnet_proxy_three_node_test(
     subnet_proxy_test_udp().await;
 }
 
+async fn subnet_proxy_test_ipv6() {
+    use crate::tunnel::{
+        common::tests::_tunnel_pingpong_netns, tcp::TcpTunnelListener, udp::UdpTunnelListener,
+    };
+    use rand::Rng;
+
+    wait_for_condition(
+        || async { ping_test("net_a", "fd00:1:2::4", None).await },
+        Duration::from_secs(5),
+    )
+    .await;
+
+    wait_for_condition(
+        || async { ping_test("net_a", "fd00:1:2::4", Some(5 * 1024)).await },
+        Duration::from_secs(5),
+    )
+    .await;
+
+    let tcp_listener = TcpTunnelListener::new("tcp://[fd00:1:2::4]:22224".parse().unwrap());
+    let tcp_connector = TcpTunnelConnector::new("tcp://[fd00:1:2::4]:22224".parse().unwrap());
+    let mut buf = vec![0; 32];
+    rand::thread_rng().fill(&mut buf[..]);
+    _tunnel_pingpong_netns(
+        tcp_listener,
+        tcp_connector,
+        NetNS::new(Some("net_d".into())),
+        NetNS::new(Some("net_a".into())),
+        buf,
+    )
+    .await;
+
+    // fragmented
+    let udp_listener = UdpTunnelListener::new("udp://[fd00:1:2::4]:22236".parse().unwrap());
+    let udp_connector = UdpTunnelConnector::new("udp://[fd00:1:2::4]:22236".parse().unwrap());
+    let mut buf = vec![0; 20 * 1024];
+    rand::thread_rng().fill(&mut buf[..]);
+    _tunnel_pingpong_netns(
+        udp_listener,
+        udp_connector,
+        NetNS::new(Some("net_d".into())),
+        NetNS::new(Some("net_a".into())),
+        buf,
+    )
+    .await;
+
+    // no fragment
+    let udp_listener = UdpTunnelListener::new("udp://[fd00:1:2::4]:22237".parse().unwrap());
+    let udp_connector = UdpTunnelConnector::new("udp://[fd00:1:2::4]:22237".parse().unwrap());
+    let mut buf = vec![0; 1024];
+    rand::thread_rng().fill(&mut buf[..]);
+    _tunnel_pingpong_netns(
+        udp_listener,
+        udp_connector,
+        NetNS::new(Some("net_d".into())),
+        NetNS::new(Some("net_a".into())),
+        buf,
+    )
+    .await;
+}
+
+#[rstest::rstest]
+#[tokio::test]
+#[serial_test::serial]
+pub async fn subnet_proxy_three_node_ipv6_test(
+    #[values("tcp", "udp")] proto: &str,
+    #[values(true, false)] no_tun: bool,
+) {
+    let insts = init_three_node_ex(proto, |cfg| {
+        if cfg.get_inst_name() == "inst1" {
+            // gives the tun device of net_a a source address for the proxied subnet
+            cfg.set_ipv6(Some("fd00:144::1".parse().unwrap()));
+        }
+        if cfg.get_inst_name() == "inst3" {
+            let mut flags = cfg.get_flags();
+            flags.no_tun = no_tun;
+            cfg.set_flags(flags);
+            cfg.add_proxy_cidr("fd00:1:2::/64".parse().unwrap());
+        }
+        cfg
+    })
+    .await;
+
+    add_ipv6_addr("net_c", "fd00:1:2::3/64");
+    add_ipv6_addr("net_d", "fd00:1:2::4/64");
+
+    wait_proxy_route_appear(
+        &insts[0].get_peer_manager(),
+        "10.144.144.3",
+        insts[2].peer_id(),
+        "fd00:1:2::/64",
+    )
+    .await;
+
+    subnet_proxy_test_ipv6().await;
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn subnet_proxy_netmap_test() {
+    use crate::tunnel::{
+        common::tests::_tunnel_pingpong_netns, tcp::TcpTunnelListener, udp::UdpTunnelListener,
+    };
+    use rand::Rng;
+
+    let insts = init_three_node_ex("udp", |cfg| {
+        if cfg.get_inst_name() == "inst3" {
+            cfg.add_netmap("10.1.2.0/24->10.200.2.0/24".parse().unwrap());
+        }
+        cfg
+    })
+    .await;
+
+    wait_proxy_route_appear(
+        &insts[0].get_peer_manager(),
+        "10.144.144.3",
+        insts[2].peer_id(),
+        "10.200.2.0/24",
+    )
+    .await;
+
+    wait_for_condition(
+        || async { ping_test("net_a", "10.200.2.4", None).await },
+        Duration::from_secs(5),
+    )
+    .await;
+
+    let tcp_listener = TcpTunnelListener::new("tcp://10.1.2.4:22223".parse().unwrap());
+    let tcp_connector = TcpTunnelConnector::new("tcp://10.200.2.4:22223".parse().unwrap());
+    let mut buf = vec![0; 32];
+    rand::thread_rng().fill(&mut buf[..]);
+    _tunnel_pingpong_netns(
+        tcp_listener,
+        tcp_connector,
+        NetNS::new(Some("net_d".into())),
+        NetNS::new(Some("net_a".into())),
+        buf,
+    )
+    .await;
+
+    // replies must come from the mapped address
+    let udp_listener = UdpTunnelListener::new("udp://10.1.2.4:22233".parse().unwrap());
+    let udp_connector = UdpTunnelConnector::new("udp://10.200.2.4:22233".parse().unwrap());
+    let mut buf = vec![0; 1024];
+    rand::thread_rng().fill(&mut buf[..]);
+    _tunnel_pingpong_netns(
+        udp_listener,
+        udp_connector,
+        NetNS::new(Some("net_d".into())),
+        NetNS::new(Some("net_a".into())),
+        buf,
+    )
+    .await;
+}
+
 #[cfg(feature = "wireguard")]
 #[rstest::rstest]
 #[tokio::test]
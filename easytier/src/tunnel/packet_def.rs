@@ -75,7 +75,8 @@ pub struct PeerManagerHeader {
     pub packet_type: u8,
     pub flags: u8,
     pub forward_counter: u8,
-    reserved: u8,
+    // hash of the inner flow set by the sender, relays use it to pick among equal-cost next hops
+    pub flow_hash: u8,
     pub len: U32<DefaultEndian>,
 }
 pub const PEER_MANAGER_HEADER_SIZE: usize = std::mem::size_of::<PeerManagerHeader>();
@@ -423,6 +424,7 @@ impl ZCPacket {
         hdr.packet_type = packet_type;
         hdr.flags = 0;
         hdr.forward_counter = 1;
+        hdr.flow_hash = 0;
         hdr.len.set(payload_len as u32);
     }
 
@@ -80,6 +80,16 @@ impl PeerRoutePair {
         }
     }
 
+    // smallest probed mtu among the direct conns
+    pub fn get_path_mtu(&self) -> Option<u32> {
+        let p = self.peer.as_ref()?;
+        p.conns
+            .iter()
+            .map(|conn| conn.path_mtu)
+            .filter(|mtu| *mtu != 0)
+            .min()
+    }
+
     pub fn get_conn_protos(&self) -> Option<Vec<String>> {
         let mut ret = vec![];
         let p = self.peer.as_ref()?;
@@ -276,7 +276,12 @@ impl VpnPortal for WireGuard {
             return "ERROR: VPN Portal Config Not Set".to_string();
         }
 
-        let routes = peer_mgr.list_routes().await;
+        let routes = peer_mgr
+            .list_routes()
+            .await
+            .into_iter()
+            .filter(|r| !r.segment_denied)
+            .collect::<Vec<_>>();
         let mut allow_ips = routes
             .iter()
             .map(|x| x.proxy_cidrs.iter().map(String::to_string))
@@ -290,7 +295,9 @@ impl VpnPortal for WireGuard {
             let Ok(ipv4) = ipv4.parse() else {
                 continue;
             };
-            let inet = Ipv4Inet::new(ipv4, 24).unwrap();
+            let Ok(inet) = Ipv4Inet::new(ipv4, global_ctx.get_network_length()) else {
+                continue;
+            };
             allow_ips.push(inet.network().to_string());
             break;
         }